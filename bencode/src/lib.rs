@@ -46,10 +46,10 @@ impl ValueRef<'_> {
 }
 
 impl Value {
-    pub fn as_ref(&self) -> ValueRef {
+    pub fn as_ref(&self) -> ValueRef<'_> {
         match self {
             Value::Int(n) => ValueRef::Int(*n),
-            Value::Bytes(buf) => ValueRef::Bytes(&buf),
+            Value::Bytes(buf) => ValueRef::Bytes(buf),
             Value::List(list) => ValueRef::List(list.iter().map(|v| v.as_ref()).collect()),
             Value::Dict(dict) => {
                 ValueRef::Dict(dict.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect())
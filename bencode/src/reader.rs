@@ -15,7 +15,7 @@ impl fmt::Debug for Reader<'_> {
 }
 
 impl<'a> Reader<'a> {
-    pub fn new(buf: &[u8]) -> Reader {
+    pub fn new(buf: &[u8]) -> Reader<'_> {
         Reader { buf, curr_idx: 0 }
     }
 
@@ -25,6 +25,10 @@ impl<'a> Reader<'a> {
         Some(*byte)
     }
 
+    pub fn pos(&self) -> usize {
+        self.curr_idx
+    }
+
     pub fn move_back(&mut self) {
         debug_assert!(self.curr_idx > 0);
         self.curr_idx -= 1;
@@ -40,7 +44,7 @@ impl<'a> Reader<'a> {
         let pos = slice
             .iter()
             .position(|&b| b == stop_byte)
-            .ok_or_else(|| Error::ExpectedChar(stop_byte))?;
+            .ok_or(Error::ExpectedChar(stop_byte))?;
         self.curr_idx += pos + 1; // Plus one to ignore the stop byte
         Ok(&slice[..pos])
     }
@@ -178,12 +178,12 @@ impl Value {
                     }
                     Self::Bytes(v) => {
                         write!(w, "{}:", v.len())?;
-                        w.write_all(&v)?;
+                        w.write_all(v)?;
                     }
                     Self::List(v) => {
                         write!(w, "l")?;
                         stack.push(E);
-                        stack.extend(v.iter().rev().map(|e| B(e)));
+                        stack.extend(v.iter().rev().map(B));
                     }
                     Self::Dict(m) => {
                         write!(w, "d")?;
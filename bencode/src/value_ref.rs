@@ -171,12 +171,12 @@ impl<'a> ValueRef<'a> {
                     }
                     Self::Bytes(v) => {
                         write!(w, "{}:", v.len())?;
-                        w.write_all(&v)?;
+                        w.write_all(v)?;
                     }
                     Self::List(v) => {
                         write!(w, "l")?;
                         stack.push(E);
-                        stack.extend(v.iter().rev().map(|e| B(e)));
+                        stack.extend(v.iter().rev().map(B));
                     }
                     Self::Dict(m) => {
                         write!(w, "d")?;
@@ -198,6 +198,28 @@ impl<'a> ValueRef<'a> {
         Ok(())
     }
 
+    /// Finds `key` in the dictionary encoded in `bytes` and returns the
+    /// exact bytes of its value. This is what has to be hashed to get e.g.
+    /// an info-hash, re-encoding the decoded value only gives the same
+    /// bytes if the input was canonical.
+    pub fn dict_find_raw(bytes: &'a [u8], key: &str) -> Option<&'a [u8]> {
+        if bytes.first() != Some(&b'd') {
+            return None;
+        }
+        let mut pos = 1;
+        while *bytes.get(pos)? != b'e' {
+            let (k, n) = Self::decode_prefix(&bytes[pos..]).ok()?;
+            pos += n;
+            let (_, n) = Self::decode_prefix(&bytes[pos..]).ok()?;
+            // The first of duplicate keys wins, as in `decode`
+            if k.as_bytes() == Some(key.as_bytes()) {
+                return Some(&bytes[pos..pos + n]);
+            }
+            pos += n;
+        }
+        None
+    }
+
     pub fn decode(bytes: &'a [u8]) -> Result<Self> {
         Self::decode_with_limits(bytes, None, None)
     }
@@ -207,6 +229,29 @@ impl<'a> ValueRef<'a> {
         depth_limit: Option<usize>,
         item_limit: Option<usize>,
     ) -> Result<Self> {
+        Self::decode_impl(bytes, depth_limit, item_limit, false).map(|(v, _)| v)
+    }
+
+    /// Decodes the value at the start of `bytes`, which may be followed by
+    /// other data. Returns the value and the number of bytes it took up.
+    pub fn decode_prefix(bytes: &'a [u8]) -> Result<(Self, usize)> {
+        Self::decode_prefix_with_limits(bytes, None, None)
+    }
+
+    pub fn decode_prefix_with_limits(
+        bytes: &'a [u8],
+        depth_limit: Option<usize>,
+        item_limit: Option<usize>,
+    ) -> Result<(Self, usize)> {
+        Self::decode_impl(bytes, depth_limit, item_limit, true)
+    }
+
+    fn decode_impl(
+        bytes: &'a [u8],
+        depth_limit: Option<usize>,
+        item_limit: Option<usize>,
+        prefix: bool,
+    ) -> Result<(Self, usize)> {
         enum Kind {
             Dict(usize),
             List(usize),
@@ -218,6 +263,9 @@ impl<'a> ValueRef<'a> {
         let mut items = 0;
 
         loop {
+            if prefix && c_stack.is_empty() && !v_stack.is_empty() {
+                break;
+            }
             match rdr.next_byte() {
                 Some(b'e') => match c_stack.pop() {
                     Some(Kind::List(len)) => {
@@ -281,7 +329,7 @@ impl<'a> ValueRef<'a> {
         }
 
         if c_stack.is_empty() && v_stack.len() == 1 {
-            Ok(v_stack.into_iter().next().unwrap())
+            Ok((v_stack.into_iter().next().unwrap(), rdr.pos()))
         } else {
             Err(Error::EOF)
         }
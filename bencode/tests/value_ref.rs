@@ -16,3 +16,32 @@ fn to_owned() {
     let v = v.to_owned();
     assert_eq!("d3:cow3:moo4:spam4:eggse", v.to_string());
 }
+
+#[test]
+fn dict_find_raw() {
+    // Keys out of order, as they would be in a non-canonical torrent
+    let buf = b"d1:ld1:ci1e1:b4:abcde1:ai2e1:li3ee";
+    assert_eq!(
+        Some(&b"d1:ci1e1:b4:abcde"[..]),
+        ValueRef::dict_find_raw(buf, "l")
+    );
+    assert_eq!(Some(&b"i2e"[..]), ValueRef::dict_find_raw(buf, "a"));
+    assert_eq!(None, ValueRef::dict_find_raw(buf, "x"));
+    assert_eq!(None, ValueRef::dict_find_raw(b"li1ee", "a"));
+    assert_eq!(None, ValueRef::dict_find_raw(b"d1:ai1e", "b"));
+}
+
+#[test]
+fn decode_prefix() {
+    let buf = b"d8:msg_typei1e5:piecei0eeDATA";
+    let (v, n) = ValueRef::decode_prefix(buf).unwrap();
+    assert_eq!(Some(1), v.dict_find_int_value("msg_type"));
+    assert_eq!(b"DATA", &buf[n..]);
+
+    let (v, n) = ValueRef::decode_prefix(b"i42e").unwrap();
+    assert_eq!(Some(42), v.as_int());
+    assert_eq!(4, n);
+
+    assert!(ValueRef::decode_prefix(b"d1:ai1e").is_err());
+    assert!(ValueRef::decode(buf).is_err());
+}
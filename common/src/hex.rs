@@ -30,7 +30,7 @@ pub fn from_hex(s: &[u8], out: &mut [u8]) -> Option<()> {
         }
         let t2 = hex_to_int(s[i])? & 0xf;
         i += 1;
-        out[j] = t1 & t2;
+        out[j] = t1 | t2;
         j += 1;
     }
     Some(())
@@ -54,6 +54,8 @@ mod test {
         let s = b"0123456789012345678901234567890123456789";
         let mut out = [0u8; 20];
         assert!(from_hex(s, &mut out).is_some());
+        assert_eq!(to_hex(&out).as_bytes(), &s[..]);
+        assert!(from_hex(b"0g", &mut out).is_none());
     }
 
     #[test]
@@ -66,7 +68,7 @@ mod test {
     fn test_is_hex() {
         let hex_chars = b"0123456789abcdefABCDEF";
         for i in 1..255 {
-            let hex_loop = hex_chars.iter().any(|&c| c == i);
+            let hex_loop = hex_chars.contains(&i);
             assert_eq!(is_hex(&[i]), hex_loop);
         }
     }
@@ -3,6 +3,7 @@ pub mod ed25519;
 pub mod hex;
 pub mod random;
 pub mod sha1;
+pub mod sha256;
 pub mod types;
 
 pub fn clamp<T: PartialOrd>(v: T, lo: T, hi: T) -> T {
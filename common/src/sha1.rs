@@ -15,13 +15,13 @@ impl Sha1Hash {
 
     pub const fn max() -> Self {
         Self {
-            data: [u8::max_value(); SIZE],
+            data: [u8::MAX; SIZE],
         }
     }
 
     pub const fn min() -> Self {
         Self {
-            data: [u8::min_value(); SIZE],
+            data: [u8::MIN; SIZE],
         }
     }
 
@@ -120,14 +120,7 @@ impl std::ops::ShlAssign<usize> for Sha1Hash {
         }
 
         if shift_bytes > 0 {
-            debug_assert!(shift_bytes < self.data.len());
-            unsafe {
-                std::ptr::copy(
-                    self.data[shift_bytes..].as_mut_ptr(),
-                    self.data.as_mut_ptr(),
-                    (SIZE - shift_bytes) * 8,
-                );
-            }
+            self.data.copy_within(shift_bytes.., 0);
             self.data[(SIZE - shift_bytes)..]
                 .iter_mut()
                 .for_each(|v| *v = 0);
@@ -157,14 +150,7 @@ impl std::ops::ShrAssign<usize> for Sha1Hash {
         }
 
         if shift_bytes > 0 {
-            debug_assert!(shift_bytes < self.data.len());
-            unsafe {
-                std::ptr::copy(
-                    self.data.as_mut_ptr(),
-                    self.data[shift_bytes..].as_mut_ptr(),
-                    (SIZE - shift_bytes) * 8,
-                );
-            }
+            self.data.copy_within(..SIZE - shift_bytes, shift_bytes);
             self.data[..shift_bytes].iter_mut().for_each(|v| *v = 0);
             shift -= shift_bytes * 8;
         }
@@ -0,0 +1,54 @@
+use std::fmt;
+
+const SIZE: usize = 32;
+
+/// A SHA-256 digest, as used by BitTorrent v2 info-hashes.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct Sha256Hash {
+    data: [u8; SIZE],
+}
+
+impl Sha256Hash {
+    pub const fn new() -> Self {
+        Self { data: [0; SIZE] }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != SIZE {
+            return None;
+        }
+        let mut buf = [0; SIZE];
+        buf.copy_from_slice(bytes);
+        Some(buf.into())
+    }
+
+    pub fn all_zeroes(&self) -> bool {
+        self.data.iter().all(|v| *v == 0)
+    }
+}
+
+impl fmt::Display for Sha256Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", crate::hex::to_hex(self))
+    }
+}
+
+impl std::ops::Deref for Sha256Hash {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl std::ops::DerefMut for Sha256Hash {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl From<[u8; SIZE]> for Sha256Hash {
+    fn from(data: [u8; SIZE]) -> Self {
+        Sha256Hash { data }
+    }
+}
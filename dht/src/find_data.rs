@@ -8,11 +8,11 @@ use crate::observer::Observer;
 pub struct FindData;
 
 impl FindData {
-    pub fn find_data(&self, dht_node: &Node, target: &NodeId) -> Vec<(NodeEntry, String)> {
+    pub fn find_data(&self, _dht_node: &Node, _target: &NodeId) -> Vec<(NodeEntry, String)> {
         unimplemented!()
     }
 
-    pub fn got_write_token(&self, n: &NodeId, write_token: String) {
+    pub fn got_write_token(&self, _n: &NodeId, _write_token: String) {
         unimplemented!();
     }
 
@@ -28,7 +28,7 @@ impl FindData {
         unimplemented!()
     }
 
-    fn new_observer(&self, ep: &SocketAddr, node_id: &NodeId) -> Arc<&dyn Observer> {
+    fn new_observer(&self, _ep: &SocketAddr, _node_id: &NodeId) -> Arc<dyn Observer> {
         unimplemented!();
     }
 }
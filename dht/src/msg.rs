@@ -35,12 +35,12 @@ pub fn verify_message_impl<'a, 'b>(
         return Err("Not a dictionary");
     }
 
-    let stack = [msg; 5];
+    let _stack = [msg; 5];
     let size = ret.len();
 
     for i in 0..size {
         let k = &desc[i];
-        ret[i] = msg.dict_find(k.name).ok_or_else(|| "Key not found")?;
+        ret[i] = msg.dict_find(k.name).ok_or("Key not found")?;
     }
     Ok(())
 }
@@ -70,6 +70,7 @@ pub trait DhtStorage {
         item: &mut Value,
     ) -> bool;
 
+    #[allow(clippy::too_many_arguments)]
     fn put_mutable_item(
         &mut self,
         target: &Sha1Hash,
@@ -195,6 +196,12 @@ pub struct InfohashesSample {
     created: Instant,
 }
 
+impl Default for InfohashesSample {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InfohashesSample {
     pub fn new() -> InfohashesSample {
         InfohashesSample {
@@ -220,7 +227,7 @@ pub struct DefaultDhtStorage<'a> {
 }
 
 impl DefaultDhtStorage<'_> {
-    pub fn new(settings: &DhtSettings) -> DefaultDhtStorage {
+    pub fn new(settings: &DhtSettings) -> DefaultDhtStorage<'_> {
         DefaultDhtStorage {
             settings,
             counters: DhtStorageCounter::default(),
@@ -235,7 +242,7 @@ impl DefaultDhtStorage<'_> {
 
 impl DhtStorage for DefaultDhtStorage<'_> {
     fn update_node_ids(&mut self, ids: &[NodeId]) {
-        self.node_ids = ids.iter().cloned().collect();
+        self.node_ids = ids.to_vec();
     }
 
     fn get_peers(
@@ -257,9 +264,7 @@ impl DhtStorage for DefaultDhtStorage<'_> {
             &v.peers6
         };
 
-        let peer_map = peers
-            .as_dict_mut()
-            .ok_or_else(|| bencode::Error::ParseDict)?;
+        let peer_map = peers.as_dict_mut().ok_or(bencode::Error::ParseDict)?;
 
         if !v.name.is_empty() {
             peer_map.insert(String::from("n"), Value::with_str(&v.name));
@@ -291,7 +296,7 @@ impl DhtStorage for DefaultDhtStorage<'_> {
                 .get_mut("values")
                 .unwrap()
                 .as_list_mut()
-                .ok_or_else(|| bencode::Error::ParseList)?;
+                .ok_or(bencode::Error::ParseList)?;
 
             let mut candidates = peersv.iter().filter(|v| !(no_seed && v.seed)).count();
             to_pick = to_pick.min(candidates);
@@ -333,6 +338,7 @@ impl DhtStorage for DefaultDhtStorage<'_> {
         }
     }
 
+    #[allow(clippy::needless_return)]
     fn announce_peer(
         &mut self,
         info_hash: &Sha1Hash,
@@ -365,7 +371,7 @@ impl DhtStorage for DefaultDhtStorage<'_> {
         };
 
         let peer = PeerEntry {
-            addr: endpoint.clone(),
+            addr: *endpoint,
             added: Instant::now(),
             seed,
         };
@@ -0,0 +1,689 @@
+//! A torrent added to the client, along with its storage and state.
+
+use crate::bitfield::Bitfield;
+use crate::error::{Error, Result};
+use crate::extensions::{Extensions, TorrentExtension};
+use crate::flags::TorrentFlags;
+use crate::hasher::{self, PieceChecker};
+use crate::info::{TorrentInfo, TorrentLimits};
+use crate::params::TorrentParams;
+use crate::piece_picker::{PieceBlock, PiecePicker};
+use crate::status::{State, TorrentStatus};
+use crate::storage::{
+    default_storage_constructor, memory_storage_constructor, Storage, StorageParams,
+};
+use crate::tracker_list::TrackerList;
+use crate::ut_metadata::{Metadata, UtMetadata};
+use crate::ut_pex::{PexFlags, Swarm, UtPex};
+use common::sha1::Sha1Hash;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub struct ActiveTorrent {
+    info: Arc<TorrentInfo>,
+    info_hash: Sha1Hash,
+    storage: Box<dyn Storage>,
+    state: State,
+    have: Bitfield,
+    picker: PiecePicker,
+    checker: Option<PieceChecker>,
+    flags: TorrentFlags,
+    save_path: String,
+    name: String,
+
+    /// Pieces that passed the hash check since the last
+    /// `take_new_pieces`, to be announced to peers.
+    new_pieces: Vec<usize>,
+
+    /// Blocks that came in while still requested from other peers
+    /// (end-game), to be cancelled by those peers' connections.
+    cancels: Vec<(SocketAddr, PieceBlock)>,
+    total_payload_download: u64,
+    total_payload_upload: u64,
+    extensions: Vec<Box<dyn TorrentExtension>>,
+
+    /// Our metadata, or the part of it downloaded so far.
+    metadata: Arc<Mutex<Metadata>>,
+
+    /// Peers we're connected to and heard of through PEX.
+    swarm: Arc<Mutex<Swarm>>,
+
+    trackers: TrackerList,
+
+    /// URLs of web seeds (BEP 19) and HTTP seeds (BEP 17) that haven't
+    /// been banned.
+    web_seeds: Vec<String>,
+    http_seeds: Vec<String>,
+
+    /// Parameters to finish setting up the torrent with once metadata
+    /// arrives.
+    pending_params: Option<TorrentParams>,
+}
+
+impl ActiveTorrent {
+    pub fn new(params: TorrentParams) -> Result<Self> {
+        Self::new_with_limits(params, TorrentLimits::default())
+    }
+
+    /// Creates the torrent from its metadata in `params.torrent_info`. If
+    /// `params.have_pieces` covers all pieces it's trusted as resume data,
+    /// otherwise the data on disk is checked first.
+    ///
+    /// Without metadata (e.g. from a magnet link) the torrent starts in
+    /// `State::DownloadingMetadata` and fetches it from peers, `limits`
+    /// apply to what they send.
+    pub fn new_with_limits(params: TorrentParams, limits: TorrentLimits) -> Result<Self> {
+        let has_metadata = params.torrent_info.is_valid();
+        let info_hash = if has_metadata {
+            params.torrent_info.info_hash().clone()
+        } else if params.info_hash.has_v1() {
+            params.info_hash.v1.clone()
+        } else {
+            return Err(Error::MissingInfoHash);
+        };
+        let metadata = if has_metadata {
+            Metadata::with_info_section(info_hash.clone(), params.torrent_info.info_section())
+        } else {
+            Metadata::new(info_hash.clone(), limits)
+        };
+        let metadata = Arc::new(Mutex::new(metadata));
+
+        let mut t = Self {
+            info: Arc::new(TorrentInfo::new()),
+            info_hash,
+            storage: memory_storage_constructor(StorageParams::default()),
+            state: State::DownloadingMetadata,
+            have: Bitfield::new(0),
+            picker: PiecePicker::new(1, 0),
+            checker: None,
+            flags: params.flags,
+            save_path: params.save_path.clone(),
+            name: params.name.clone(),
+            new_pieces: vec![],
+            cancels: vec![],
+            total_payload_download: 0,
+            total_payload_upload: 0,
+            extensions: vec![],
+            metadata: metadata.clone(),
+            swarm: Arc::new(Mutex::new(Swarm::new())),
+            trackers: TrackerList::new(),
+            web_seeds: vec![],
+            http_seeds: vec![],
+            pending_params: None,
+        };
+        for (i, url) in params.trackers.iter().enumerate() {
+            let tier = params.tracker_tiers.get(i).cloned().unwrap_or(0);
+            t.trackers
+                .add_tracker(url.clone(), tier.clamp(0, 255) as u8);
+        }
+        for url in &params.url_seeds {
+            t.add_web_seed(url.clone());
+        }
+        for url in &params.http_seeds {
+            t.add_http_seed(url.clone());
+        }
+        if !params.torrent_info.is_private() {
+            t.add_extension(Box::new(UtMetadata::new(metadata)));
+        }
+        if !t.flags.contains(TorrentFlags::DISABLE_PEX) {
+            t.add_extension(Box::new(UtPex::new(t.swarm.clone())));
+        }
+        if has_metadata {
+            t.init(params)?;
+        } else {
+            t.pending_params = Some(params);
+        }
+        Ok(t)
+    }
+
+    /// Sets up storage and piece state once the metadata is known.
+    fn init(&mut self, params: TorrentParams) -> Result<()> {
+        let info = params.torrent_info;
+        if info.is_private() {
+            self.swarm.lock().unwrap().disable_pex();
+        }
+        if !self.flags.contains(TorrentFlags::OVERRIDE_TRACKERS) {
+            for tracker in info.trackers() {
+                self.trackers.add_tracker(tracker.url.clone(), tracker.tier);
+            }
+        }
+        if !self.flags.contains(TorrentFlags::OVERRIDE_WEB_SEEDS) {
+            for url in info.url_seeds() {
+                self.add_web_seed(url.clone());
+            }
+            for url in info.http_seeds() {
+                self.add_http_seed(url.clone());
+            }
+        }
+        let construct = params.storage.unwrap_or(default_storage_constructor);
+        let mut storage = construct(StorageParams {
+            files: info.files().clone(),
+            save_path: PathBuf::from(&params.save_path),
+            mode: params.storage_mode,
+            renamed_files: params.renamed_files,
+        });
+        storage.initialize()?;
+
+        if self.name.is_empty() {
+            self.name = info.name().to_string();
+        }
+
+        let mut picker = PiecePicker::from_files(info.files());
+        if !params.file_priorities.is_empty() {
+            picker.set_file_priorities(info.files(), &params.file_priorities);
+        }
+        if params.piece_priorities.len() == info.num_pieces() {
+            for (piece, priority) in params.piece_priorities.iter().enumerate() {
+                picker.set_piece_priority(piece, *priority);
+            }
+        }
+        picker.set_sequential(self.flags.contains(TorrentFlags::SEQUENTIAL_DOWNLOAD));
+
+        self.storage = storage;
+        self.picker = picker;
+        self.have = Bitfield::new(info.num_pieces());
+        self.info = info;
+        if params.have_pieces.len() == self.info.num_pieces() {
+            self.set_have(params.have_pieces);
+        } else {
+            self.force_recheck();
+        }
+        Ok(())
+    }
+
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.info_hash
+    }
+
+    pub fn has_metadata(&self) -> bool {
+        self.info.is_valid()
+    }
+
+    /// Switches a torrent that was waiting for metadata over to the given
+    /// one, e.g. loaded from a .torrent file by the user.
+    pub fn set_metadata(&mut self, info: TorrentInfo) -> Result<()> {
+        if self.has_metadata() {
+            return Ok(());
+        }
+        if *info.info_hash() != self.info_hash {
+            return Err(Error::InfoHashMismatch);
+        }
+        let mut params = self.pending_params.take().unwrap_or_default();
+        params.torrent_info = Arc::new(info);
+        self.init(params)
+    }
+
+    /// Picks up metadata downloaded from peers. Returns true if the torrent
+    /// has metadata now.
+    pub fn update_metadata(&mut self) -> Result<bool> {
+        if self.has_metadata() {
+            return Ok(true);
+        }
+        let info = self.metadata.lock().unwrap().take_torrent_info();
+        match info {
+            Some(info) => self.set_metadata(info).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Adds a peer to connect to, e.g. one found through local service
+    /// discovery.
+    pub fn add_peer(&mut self, addr: SocketAddr) {
+        self.swarm
+            .lock()
+            .unwrap()
+            .add_discovered(addr, PexFlags::empty());
+    }
+
+    /// The peers we're connected to and those learned through peer
+    /// exchange, for connections to keep up to date.
+    pub fn swarm(&self) -> &Arc<Mutex<Swarm>> {
+        &self.swarm
+    }
+
+    /// The trackers to announce to, in tier order.
+    pub fn trackers(&self) -> &TrackerList {
+        &self.trackers
+    }
+
+    pub fn trackers_mut(&mut self) -> &mut TrackerList {
+        &mut self.trackers
+    }
+
+    pub fn web_seeds(&self) -> &[String] {
+        &self.web_seeds
+    }
+
+    /// Adds a web seed to download from. Returns false if it's already
+    /// known.
+    pub fn add_web_seed(&mut self, url: String) -> bool {
+        if self.web_seeds.contains(&url) {
+            return false;
+        }
+        self.web_seeds.push(url);
+        true
+    }
+
+    pub fn remove_web_seed(&mut self, url: &str) {
+        self.web_seeds.retain(|u| u != url);
+    }
+
+    pub fn http_seeds(&self) -> &[String] {
+        &self.http_seeds
+    }
+
+    /// Adds an HTTP seed to download from. Returns false if it's already
+    /// known.
+    pub fn add_http_seed(&mut self, url: String) -> bool {
+        if self.http_seeds.contains(&url) {
+            return false;
+        }
+        self.http_seeds.push(url);
+        true
+    }
+
+    pub fn remove_http_seed(&mut self, url: &str) {
+        self.http_seeds.retain(|u| u != url);
+    }
+
+    pub fn info(&self) -> &Arc<TorrentInfo> {
+        &self.info
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn have_pieces(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn storage(&mut self) -> &mut dyn Storage {
+        &mut *self.storage
+    }
+
+    pub fn picker(&mut self) -> &mut PiecePicker {
+        &mut self.picker
+    }
+
+    pub fn flags(&self) -> TorrentFlags {
+        self.flags
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.flags.contains(TorrentFlags::PAUSED)
+    }
+
+    /// Stops the torrent until `resume`. Paused torrents are left out of
+    /// automatic management and their session counters start over.
+    pub fn pause(&mut self) {
+        if self.is_paused() {
+            return;
+        }
+        self.flags.insert(TorrentFlags::PAUSED);
+        self.flags.remove(TorrentFlags::AUTO_MANAGED);
+        self.total_payload_download = 0;
+        self.total_payload_upload = 0;
+    }
+
+    pub fn resume(&mut self) {
+        self.flags.remove(TorrentFlags::PAUSED);
+    }
+
+    pub fn set_sequential_download(&mut self, sequential: bool) {
+        self.flags
+            .set(TorrentFlags::SEQUENTIAL_DOWNLOAD, sequential);
+        self.picker.set_sequential(sequential);
+    }
+
+    /// Discards what we know about the pieces on disk and checks them all
+    /// again. The check runs as `check_pieces` is called.
+    pub fn force_recheck(&mut self) {
+        self.storage.release_files();
+        self.set_have(Bitfield::new(self.info.num_pieces()));
+        self.checker = Some(PieceChecker::new(self.info.num_pieces()));
+        self.state = State::CheckingFiles;
+    }
+
+    /// Checks up to `max` pieces while in `State::CheckingFiles`. Returns
+    /// true once the check is complete.
+    pub fn check_pieces(&mut self, max: usize) -> Result<bool> {
+        let checker = match &mut self.checker {
+            Some(checker) => checker,
+            None => return Ok(true),
+        };
+        for _ in 0..max {
+            if checker.is_done() {
+                break;
+            }
+            checker.check_next(&mut *self.storage, &self.info)?;
+        }
+        if !checker.is_done() {
+            return Ok(false);
+        }
+
+        let have = self.checker.take().unwrap().into_have();
+        self.set_have(have);
+        Ok(true)
+    }
+
+    /// Verifies a piece that finished downloading and marks it as had if
+    /// its hash matches.
+    pub fn verify_piece(&mut self, piece: usize) -> Result<bool> {
+        let ok = hasher::verify_piece(&mut *self.storage, &self.info, piece)?;
+        if ok {
+            self.have.set(piece);
+            self.new_pieces.push(piece);
+            self.update_state();
+        }
+        self.picker.piece_checked(piece, ok);
+        Ok(ok)
+    }
+
+    pub fn add_extension(&mut self, extension: Box<dyn TorrentExtension>) {
+        self.extensions.push(extension);
+    }
+
+    /// The extensions to use for a new peer connection.
+    pub fn new_connection_extensions(&mut self, addr: SocketAddr) -> Extensions {
+        let mut extensions = Extensions::new();
+        for ext in &mut self.extensions {
+            if let Some(ext) = ext.new_connection(addr) {
+                extensions.add(ext);
+            }
+        }
+        extensions
+    }
+
+    pub fn take_new_pieces(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.new_pieces)
+    }
+
+    /// Records that `peer` delivered `block` and queues cancels for the
+    /// other peers it's still requested from. Returns true if the piece is
+    /// complete and should be verified.
+    pub fn finish_block(&mut self, block: PieceBlock, peer: SocketAddr) -> bool {
+        let mut cancel = vec![];
+        let complete = self.picker.mark_as_finished(block, peer, &mut cancel);
+        self.cancels
+            .extend(cancel.into_iter().map(|addr| (addr, block)));
+        complete
+    }
+
+    /// Takes the blocks `peer` should no longer send us.
+    pub fn take_cancels(&mut self, peer: SocketAddr) -> Vec<PieceBlock> {
+        let mut blocks = vec![];
+        self.cancels.retain(|&(addr, block)| {
+            if addr == peer {
+                blocks.push(block);
+            }
+            addr != peer
+        });
+        blocks
+    }
+
+    /// Counts payload bytes received from peers.
+    pub fn add_downloaded(&mut self, bytes: usize) {
+        self.total_payload_download += bytes as u64;
+    }
+
+    /// Counts payload bytes sent to peers.
+    pub fn add_uploaded(&mut self, bytes: usize) {
+        self.total_payload_upload += bytes as u64;
+    }
+
+    /// Bytes of the pieces we don't have yet, as reported to trackers.
+    pub fn bytes_left(&self) -> u64 {
+        let files = self.info.files();
+        (0..self.info.num_pieces())
+            .filter(|&piece| !self.have.get(piece))
+            .map(|piece| files.piece_size(piece) as u64)
+            .sum()
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let total_pieces = self.info.num_pieces();
+        let progress = match &self.checker {
+            Some(checker) => checker.progress(),
+            None if total_pieces == 0 => 1.0,
+            None => self.have.count() as f32 / total_pieces as f32,
+        };
+        TorrentStatus {
+            save_path: self.save_path.clone(),
+            name: self.name.clone(),
+            torrent_file: Arc::downgrade(&self.info),
+            state: self.state,
+            flags: self.flags,
+            progress,
+            num_pieces: self.have.count(),
+            total_pieces,
+            total_payload_download: self.total_payload_download,
+            total_payload_upload: self.total_payload_upload,
+            current_tracker: self.trackers.current_tracker().to_string(),
+            next_announce: self.trackers.next_announce(Instant::now()),
+            ..TorrentStatus::default()
+        }
+    }
+
+    fn set_have(&mut self, have: Bitfield) {
+        for piece in 0..have.len() {
+            if have.get(piece) {
+                self.picker.we_have(piece);
+            } else {
+                self.picker.we_dont_have(piece);
+            }
+        }
+        self.have = have;
+        self.update_state();
+    }
+
+    fn update_state(&mut self) {
+        if self.checker.is_some() {
+            return;
+        }
+        self.state = if self.have.all_set() {
+            self.trackers.set_completed();
+            State::Seeding
+        } else {
+            State::Downloading
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::extensions::ExtendedHandshake;
+    use crate::peer_message::Message;
+    use crate::test_util::{content, temp_dir, torrent_info};
+    use crate::tracker::{Event, TrackerResponse};
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Creates a 3.5 piece torrent from a directory of two files.
+    fn make_torrent(base: &Path) -> Arc<TorrentInfo> {
+        let data = content(56 * 1024);
+        let files: &[(&str, &[u8])] = &[("data/a", &data[..20_000]), ("data/b", &data[20_000..])];
+        torrent_info(base, 16 * 1024, files)
+    }
+
+    fn params(info: &Arc<TorrentInfo>, save_path: &Path) -> TorrentParams {
+        let mut p = TorrentParams::default();
+        p.torrent_info = info.clone();
+        p.save_path = save_path.to_string_lossy().into_owned();
+        p
+    }
+
+    #[test]
+    fn test_check_existing_data() {
+        let base = temp_dir("check-existing");
+        let info = make_torrent(&base);
+        assert_eq!(4, info.num_pieces());
+
+        let mut t = ActiveTorrent::new(params(&info, &base)).unwrap();
+        assert_eq!(State::CheckingFiles, t.state());
+        assert_eq!(0.0, t.status().progress);
+
+        assert!(!t.check_pieces(2).unwrap());
+        let st = t.status();
+        assert_eq!(State::CheckingFiles, st.state);
+        assert_eq!(0.5, st.progress);
+
+        assert!(t.check_pieces(2).unwrap());
+        let st = t.status();
+        assert_eq!(State::Seeding, st.state);
+        assert_eq!(1.0, st.progress);
+        assert_eq!(4, st.num_pieces);
+
+        // Corrupt the second piece and check again
+        let path = base.join("data/a");
+        let mut data = fs::read(&path).unwrap();
+        data[17_000] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        t.force_recheck();
+        assert_eq!(State::CheckingFiles, t.state());
+        assert!(t.check_pieces(usize::MAX).unwrap());
+        assert_eq!(State::Downloading, t.state());
+        assert_eq!(
+            vec![true, false, true, true],
+            t.have_pieces().iter().collect::<Vec<_>>()
+        );
+        assert_eq!(0.75, t.status().progress);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let base = temp_dir("check-missing");
+        let info = make_torrent(&base);
+        let empty = base.join("empty");
+
+        let mut t = ActiveTorrent::new(params(&info, &empty)).unwrap();
+        assert!(t.check_pieces(usize::MAX).unwrap());
+        assert_eq!(State::Downloading, t.state());
+        assert!(t.have_pieces().none_set());
+
+        // Download the first piece and verify it
+        let piece = fs::read(base.join("data/a")).unwrap();
+        t.storage().write(&piece[..16 * 1024], 0, 0).unwrap();
+        assert!(t.verify_piece(0).unwrap());
+        t.storage().write(&[0; 16], 1, 0).unwrap();
+        assert!(!t.verify_piece(1).unwrap());
+        assert_eq!(1, t.status().num_pieces);
+        assert!(t.picker().have_piece(0));
+        assert!(!t.picker().have_piece(1));
+        assert_eq!(vec![0], t.take_new_pieces());
+        assert!(t.take_new_pieces().is_empty());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_resume_data() {
+        let base = temp_dir("check-resume");
+        let info = make_torrent(&base);
+
+        let mut p = params(&info, &base);
+        p.have_pieces = Bitfield::with_all_set(4);
+        let t = ActiveTorrent::new(p).unwrap();
+        assert_eq!(State::Seeding, t.state());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_set_metadata() {
+        let base = temp_dir("set-metadata");
+        let info = make_torrent(&base);
+
+        let p = params(&Arc::new(TorrentInfo::new()), &base);
+        assert!(matches!(ActiveTorrent::new(p), Err(Error::MissingInfoHash)));
+
+        let mut p = params(&Arc::new(TorrentInfo::new()), &base);
+        p.info_hash.v1 = Sha1Hash::from([1; 20]);
+        let mut t = ActiveTorrent::new(p).unwrap();
+        assert!(matches!(
+            t.set_metadata((*info).clone()),
+            Err(Error::InfoHashMismatch)
+        ));
+
+        let mut p = params(&Arc::new(TorrentInfo::new()), &base);
+        p.info_hash.v1 = info.info_hash().clone();
+        let mut t = ActiveTorrent::new(p).unwrap();
+        assert!(!t.has_metadata());
+        assert_eq!(State::DownloadingMetadata, t.state());
+        assert!(!t.update_metadata().unwrap());
+
+        t.set_metadata((*info).clone()).unwrap();
+        assert!(t.has_metadata());
+        assert_eq!(State::CheckingFiles, t.state());
+        assert!(t.check_pieces(usize::MAX).unwrap());
+        assert_eq!(State::Seeding, t.state());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_disable_pex() {
+        let base = temp_dir("disable-pex");
+        let info = make_torrent(&base);
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let names = |t: &mut ActiveTorrent| {
+            let msg = t
+                .new_connection_extensions(addr)
+                .handshake(&ExtendedHandshake::default());
+            match msg {
+                Message::Extended { payload, .. } => ExtendedHandshake::decode(&payload).unwrap().m,
+                _ => unreachable!(),
+            }
+        };
+
+        let mut t = ActiveTorrent::new(params(&info, &base)).unwrap();
+        assert!(names(&mut t).contains_key("ut_pex"));
+
+        let mut p = params(&info, &base);
+        p.flags |= TorrentFlags::DISABLE_PEX;
+        let mut t = ActiveTorrent::new(p).unwrap();
+        let m = names(&mut t);
+        assert!(m.contains_key("ut_metadata"));
+        assert!(!m.contains_key("ut_pex"));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_trackers() {
+        let base = temp_dir("trackers");
+        let mut p = params(&Arc::new(TorrentInfo::new()), &base);
+        p.info_hash.v1 = Sha1Hash::from([1; 20]);
+        p.trackers = vec!["udp://a".to_string(), "udp://b".to_string()];
+        p.tracker_tiers = vec![1];
+        let mut t = ActiveTorrent::new(p).unwrap();
+
+        let tiers: Vec<_> = t.trackers().trackers().iter().map(|e| e.tier).collect();
+        assert_eq!(vec![0, 1], tiers);
+        assert_eq!("udp://b", t.trackers().trackers()[0].url);
+
+        let st = t.status();
+        assert_eq!("", st.current_tracker);
+        assert_eq!(Duration::from_secs(0), st.next_announce);
+
+        let now = Instant::now();
+        let r = TrackerResponse::default();
+        t.trackers_mut()
+            .on_failure("udp://b", "timeout".to_string(), now);
+        t.trackers_mut()
+            .on_success("udp://a", Event::Started, &r, now);
+        let st = t.status();
+        assert_eq!("udp://a", st.current_tracker);
+        assert!(st.next_announce > Duration::from_secs(0));
+        assert!(st.next_announce <= Duration::from_millis(17_500));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+/// A fixed size set of bits, packed most significant bit first like the
+/// BitTorrent bitfield message. Used for piece and block sets.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn with_all_set(len: usize) -> Self {
+        let mut b = Self::new(len);
+        b.set_all();
+        b
+    }
+
+    /// Takes the first `len` bits of `bytes`. Missing bytes are zero and
+    /// excess bits are cleared.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut b = Self::new(len);
+        let n = b.bytes.len().min(bytes.len());
+        b.bytes[..n].copy_from_slice(&bytes[..n]);
+        b.clear_trailing_bits();
+        b
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        debug_assert!(index < self.len);
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        debug_assert!(index < self.len);
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        debug_assert!(index < self.len);
+        self.bytes[index / 8] &= !(0x80 >> (index % 8));
+    }
+
+    pub fn set_all(&mut self) {
+        self.bytes.iter_mut().for_each(|b| *b = 0xff);
+        self.clear_trailing_bits();
+    }
+
+    pub fn clear_all(&mut self) {
+        self.bytes.iter_mut().for_each(|b| *b = 0);
+    }
+
+    /// Number of set bits.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn all_set(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn none_set(&self) -> bool {
+        self.bytes.iter().all(|&b| b == 0)
+    }
+
+    pub fn resize(&mut self, len: usize) {
+        self.bytes.resize(len.div_ceil(8), 0);
+        self.len = len;
+        self.clear_trailing_bits();
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(move |i| self.get(i))
+    }
+
+    fn clear_trailing_bits(&mut self) {
+        let rem = self.len % 8;
+        if rem != 0 {
+            if let Some(last) = self.bytes.last_mut() {
+                *last &= 0xff << (8 - rem);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_get() {
+        let mut b = Bitfield::new(10);
+        assert_eq!(10, b.len());
+        assert_eq!(2, b.as_bytes().len());
+        assert!(b.none_set());
+
+        b.set(0);
+        b.set(9);
+        assert!(b.get(0) && b.get(9) && !b.get(1));
+        assert_eq!(&[0x80, 0x40], b.as_bytes());
+        assert_eq!(2, b.count());
+
+        b.clear(0);
+        assert!(!b.get(0));
+        assert_eq!(1, b.count());
+    }
+
+    #[test]
+    fn test_all_set() {
+        let mut b = Bitfield::with_all_set(10);
+        assert_eq!(&[0xff, 0xc0], b.as_bytes());
+        assert!(b.all_set());
+        b.clear(3);
+        assert!(!b.all_set());
+        b.clear_all();
+        assert!(b.none_set());
+        assert!(Bitfield::new(0).all_set());
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let b = Bitfield::from_bytes(&[0xff, 0xff], 12);
+        assert_eq!(&[0xff, 0xf0], b.as_bytes());
+        assert_eq!(12, b.count());
+
+        let b = Bitfield::from_bytes(&[0xa0], 12);
+        assert_eq!(&[0xa0, 0x00], b.as_bytes());
+        assert_eq!(
+            vec![true, false, true, false],
+            b.iter().take(4).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut b = Bitfield::with_all_set(16);
+        b.resize(3);
+        assert_eq!(&[0xe0], b.as_bytes());
+        b.resize(9);
+        assert_eq!(&[0xe0, 0x00], b.as_bytes());
+        assert_eq!(3, b.count());
+    }
+}
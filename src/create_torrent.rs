@@ -0,0 +1,546 @@
+use crate::error::{Error, Result};
+use crate::fs::{FileEntry, FileStorage};
+use crate::info::AnnounceEntry;
+use bencode::Value;
+use bitflags::bitflags;
+use common::sha1::Sha1Hash;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+bitflags! {
+    pub struct CreateFlags: u8 {
+        /// Insert pad files (BEP 47) so that every file starts at a piece
+        /// boundary. Only has an effect on multi-file torrents.
+        const OPTIMIZE_ALIGNMENT = 1;
+    }
+}
+
+impl Default for CreateFlags {
+    fn default() -> Self {
+        CreateFlags::empty()
+    }
+}
+
+const MIN_PIECE_LEN: usize = 16 * 1024;
+const MAX_PIECE_LEN: usize = 16 * 1024 * 1024;
+
+/// Recursively adds the file or directory at `path` to `storage`. The
+/// last component of `path` becomes the torrent name. Files are added in
+/// lexicographic order so the result doesn't depend on the file system.
+/// Symbolic links within the directory are skipped, as they may point
+/// back up the tree.
+pub fn add_files(storage: &mut FileStorage, path: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or(Error::TorrentInvalidName)?;
+    storage.set_name(name.clone());
+    add_files_impl(storage, path, PathBuf::from(name))
+}
+
+fn add_files_impl(storage: &mut FileStorage, path: &Path, torrent_path: PathBuf) -> Result<()> {
+    let meta = fs::metadata(path)?;
+    if meta.is_dir() {
+        let mut entries = vec![];
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_symlink() {
+                entries.push(entry.file_name());
+            }
+        }
+        entries.sort();
+        for name in entries {
+            add_files_impl(storage, &path.join(&name), torrent_path.join(&name))?;
+        }
+    } else {
+        storage.add_file(FileEntry {
+            path: torrent_path,
+            size: meta.len(),
+            executable_attr: is_executable(&meta),
+            ..FileEntry::default()
+        });
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Picks a piece length for a torrent of the given size, aiming for
+/// about 1000 pieces without going outside of 16 KiB - 16 MiB.
+pub fn auto_piece_len(total_size: u64) -> usize {
+    let mut piece_len = MIN_PIECE_LEN;
+    while piece_len < MAX_PIECE_LEN && total_size.div_ceil(piece_len as u64) > 1000 {
+        piece_len *= 2;
+    }
+    piece_len
+}
+
+/// Builds a .torrent file from a `FileStorage`. Create the storage with
+/// `add_files`, compute the piece hashes with `set_piece_hashes` and
+/// encode the result with `generate`.
+pub struct CreateTorrent {
+    files: FileStorage,
+    piece_hashes: Vec<u8>,
+    trackers: Vec<AnnounceEntry>,
+    url_seeds: Vec<String>,
+    http_seeds: Vec<String>,
+    nodes: Vec<(String, u16)>,
+    comment: String,
+    created_by: String,
+    creation_date: Option<i64>,
+    private: bool,
+}
+
+impl CreateTorrent {
+    /// `piece_len` of 0 picks one based on the total size. Otherwise it
+    /// must be a power of two and at least 16 KiB.
+    pub fn new(storage: FileStorage, piece_len: usize, flags: CreateFlags) -> Result<Self> {
+        if storage.num_files() == 0 || storage.total_size() == 0 {
+            return Err(Error::NoFiles);
+        }
+
+        let piece_len = match piece_len {
+            0 => auto_piece_len(storage.total_size()),
+            n if n < MIN_PIECE_LEN || !n.is_power_of_two() => {
+                return Err(Error::TorrentInvalidPieceLength)
+            }
+            n => n,
+        };
+
+        let mut files = if flags.contains(CreateFlags::OPTIMIZE_ALIGNMENT) {
+            align_files(storage, piece_len)
+        } else {
+            storage
+        };
+        files.set_piece_len(piece_len);
+        let num_pieces = files.total_size().div_ceil(piece_len as u64);
+        files.set_num_pieces(num_pieces as usize);
+
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .ok();
+
+        Ok(Self {
+            files,
+            piece_hashes: vec![],
+            trackers: vec![],
+            url_seeds: vec![],
+            http_seeds: vec![],
+            nodes: vec![],
+            comment: String::new(),
+            created_by: String::new(),
+            creation_date,
+            private: false,
+        })
+    }
+
+    pub fn files(&self) -> &FileStorage {
+        &self.files
+    }
+
+    pub fn add_tracker(&mut self, url: &str, tier: u8) {
+        self.trackers
+            .push(AnnounceEntry::new(url.to_string(), tier));
+        self.trackers.sort_by_key(|a| a.tier);
+    }
+
+    pub fn add_url_seed(&mut self, url: &str) {
+        self.url_seeds.push(url.to_string());
+    }
+
+    pub fn add_http_seed(&mut self, url: &str) {
+        self.http_seeds.push(url.to_string());
+    }
+
+    pub fn add_node(&mut self, host: &str, port: u16) {
+        self.nodes.push((host.to_string(), port));
+    }
+
+    pub fn set_comment(&mut self, comment: &str) {
+        self.comment = comment.to_string();
+    }
+
+    pub fn set_creator(&mut self, created_by: &str) {
+        self.created_by = created_by.to_string();
+    }
+
+    /// Seconds since epoch, `None` to leave it out of the torrent.
+    /// Defaults to the time the object was created.
+    pub fn set_creation_date(&mut self, date: Option<i64>) {
+        self.creation_date = date;
+    }
+
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    /// Reads the content from disk and hashes every piece. `base` is the
+    /// directory containing the file or directory passed to `add_files`.
+    pub fn set_piece_hashes(&mut self, base: &Path) -> Result<()> {
+        let piece_len = self.files.piece_len();
+        let mut hashes = Vec::with_capacity(self.files.num_pieces() * 20);
+        let mut piece = Vec::with_capacity(piece_len);
+        for file in self.files.files() {
+            let mut remaining = file.size;
+            let mut reader = if file.pad_file {
+                None
+            } else {
+                Some(File::open(base.join(&file.path))?)
+            };
+
+            while remaining > 0 {
+                let n = (piece_len - piece.len()).min(remaining as usize);
+                let start = piece.len();
+                piece.resize(start + n, 0);
+                if let Some(reader) = &mut reader {
+                    reader.read_exact(&mut piece[start..])?;
+                }
+                remaining -= n as u64;
+
+                if piece.len() == piece_len {
+                    hashes.extend_from_slice(&Sha1Hash::update(&piece));
+                    piece.clear();
+                }
+            }
+        }
+        if !piece.is_empty() {
+            hashes.extend_from_slice(&Sha1Hash::update(&piece));
+        }
+
+        debug_assert_eq!(hashes.len(), self.files.num_pieces() * 20);
+        self.piece_hashes = hashes;
+        Ok(())
+    }
+
+    /// Encodes the torrent. Fails if `set_piece_hashes` hasn't been called.
+    pub fn generate(&self) -> Result<Value> {
+        if self.piece_hashes.is_empty() {
+            return Err(Error::PieceHashesNotSet);
+        }
+
+        let mut root = BTreeMap::new();
+        if let Some(first) = self.trackers.first() {
+            root.insert("announce".to_owned(), Value::with_str(&first.url));
+        }
+        if self.trackers.len() > 1 {
+            let mut tiers: Vec<Value> = vec![];
+            let mut last_tier = None;
+            for tracker in &self.trackers {
+                if last_tier != Some(tracker.tier) {
+                    tiers.push(Value::with_list(vec![]));
+                    last_tier = Some(tracker.tier);
+                }
+                let tier = tiers.last_mut().and_then(|t| t.as_list_mut()).unwrap();
+                tier.push(Value::with_str(&tracker.url));
+            }
+            root.insert("announce-list".to_owned(), Value::with_list(tiers));
+        }
+        if !self.comment.is_empty() {
+            root.insert("comment".to_owned(), Value::with_str(&self.comment));
+        }
+        if !self.created_by.is_empty() {
+            root.insert("created by".to_owned(), Value::with_str(&self.created_by));
+        }
+        if let Some(date) = self.creation_date {
+            root.insert("creation date".to_owned(), Value::with_int(date));
+        }
+        match self.url_seeds.len() {
+            0 => {}
+            1 => {
+                root.insert("url-list".to_owned(), Value::with_str(&self.url_seeds[0]));
+            }
+            _ => {
+                root.insert("url-list".to_owned(), str_list(&self.url_seeds));
+            }
+        }
+        if !self.http_seeds.is_empty() {
+            root.insert("httpseeds".to_owned(), str_list(&self.http_seeds));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self
+                .nodes
+                .iter()
+                .map(|(host, port)| {
+                    Value::with_list(vec![Value::with_str(host), Value::with_int(*port as i64)])
+                })
+                .collect();
+            root.insert("nodes".to_owned(), Value::with_list(nodes));
+        }
+        root.insert("info".to_owned(), self.generate_info());
+        Ok(Value::with_dict(root))
+    }
+
+    fn generate_info(&self) -> Value {
+        let mut info = BTreeMap::new();
+        let name = self.files.name();
+        info.insert("name".to_owned(), Value::with_str(name));
+        info.insert(
+            "piece length".to_owned(),
+            Value::with_int(self.files.piece_len() as i64),
+        );
+        info.insert("pieces".to_owned(), Value::from(&self.piece_hashes[..]));
+        if self.private {
+            info.insert("private".to_owned(), Value::with_int(1));
+        }
+
+        let files = self.files.files();
+        if files.len() == 1 && files[0].path == Path::new(name) {
+            info.insert("length".to_owned(), Value::with_int(files[0].size as i64));
+            if let Some(attr) = file_attributes(&files[0]) {
+                info.insert("attr".to_owned(), attr);
+            }
+            return Value::with_dict(info);
+        }
+
+        let mut list = vec![];
+        for file in files {
+            let mut entry = BTreeMap::new();
+            entry.insert("length".to_owned(), Value::with_int(file.size as i64));
+            // the first component is the torrent name
+            let path = file
+                .path
+                .iter()
+                .skip(1)
+                .map(|c| Value::with_string(c.to_string_lossy().into_owned()))
+                .collect();
+            entry.insert("path".to_owned(), Value::with_list(path));
+            if let Some(attr) = file_attributes(file) {
+                entry.insert("attr".to_owned(), attr);
+            }
+            list.push(Value::with_dict(entry));
+        }
+        info.insert("files".to_owned(), Value::with_list(list));
+        Value::with_dict(info)
+    }
+}
+
+/// Returns a copy of `storage` with a pad file in front of every file
+/// that doesn't start at a piece boundary.
+fn align_files(storage: FileStorage, piece_len: usize) -> FileStorage {
+    let mut aligned = FileStorage::new();
+    aligned.set_name(storage.name().to_string());
+    let name = PathBuf::from(storage.name());
+    for file in storage.files() {
+        let misalignment = aligned.total_size() % piece_len as u64;
+        if file.size > 0 && misalignment != 0 {
+            let pad_size = piece_len as u64 - misalignment;
+            aligned.add_file(FileEntry {
+                path: name.join(".pad").join(pad_size.to_string()),
+                size: pad_size,
+                pad_file: true,
+                ..FileEntry::default()
+            });
+        }
+        aligned.add_file(file.clone());
+    }
+    aligned
+}
+
+fn file_attributes(file: &FileEntry) -> Option<Value> {
+    let mut attr = String::new();
+    if file.pad_file {
+        attr.push('p');
+    }
+    if file.executable_attr {
+        attr.push('x');
+    }
+    if file.hidden_attr {
+        attr.push('h');
+    }
+    if attr.is_empty() {
+        None
+    } else {
+        Some(Value::with_string(attr))
+    }
+}
+
+fn str_list(values: &[String]) -> Value {
+    Value::with_list(values.iter().map(|s| Value::with_str(s)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::info::TorrentInfo;
+    use crate::test_util::{content, temp_dir};
+
+    #[test]
+    fn test_auto_piece_len() {
+        assert_eq!(16 * 1024, auto_piece_len(0));
+        assert_eq!(16 * 1024, auto_piece_len(1000 * 16 * 1024));
+        assert_eq!(32 * 1024, auto_piece_len(1000 * 16 * 1024 + 1));
+        assert_eq!(16 * 1024 * 1024, auto_piece_len(u64::MAX));
+    }
+
+    #[test]
+    fn test_single_file() {
+        let base = temp_dir("create-single");
+        let content = content(40_000);
+        fs::write(base.join("a.bin"), &content).unwrap();
+
+        let mut storage = FileStorage::new();
+        add_files(&mut storage, &base.join("a.bin")).unwrap();
+        let mut t = CreateTorrent::new(storage, 0, CreateFlags::default()).unwrap();
+        t.add_tracker("http://a/announce", 0);
+        t.set_comment("comment");
+        t.set_creator("torrent-rs");
+        t.set_creation_date(Some(1234));
+        t.set_piece_hashes(&base).unwrap();
+        let buf = t.generate().unwrap().to_vec();
+
+        let info = TorrentInfo::from_bytes(&buf).unwrap();
+        assert_eq!("a.bin", info.name());
+        assert_eq!(40_000, info.total_size());
+        assert_eq!(16 * 1024, info.piece_len());
+        assert_eq!(3, info.num_pieces());
+        assert_eq!("comment", info.comment());
+        assert_eq!("torrent-rs", info.created_by());
+        assert_eq!(Some(1234), info.creation_date());
+        assert_eq!("http://a/announce", info.trackers()[0].url);
+        for (i, chunk) in content.chunks(16 * 1024).enumerate() {
+            assert_eq!(Some(Sha1Hash::update(chunk)), info.hash_for_piece(i));
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_directory_with_pad_files() {
+        let base = temp_dir("create-dir");
+        let root = base.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.bin"), vec![2; 100]).unwrap();
+        fs::write(root.join("a.bin"), vec![1; 20_000]).unwrap();
+        fs::write(root.join("sub").join("c.bin"), vec![3; 10]).unwrap();
+
+        let mut storage = FileStorage::new();
+        add_files(&mut storage, &root).unwrap();
+        let mut t =
+            CreateTorrent::new(storage, 16 * 1024, CreateFlags::OPTIMIZE_ALIGNMENT).unwrap();
+        t.add_tracker("udp://b", 1);
+        t.add_tracker("udp://a", 0);
+        t.add_tracker("udp://c", 1);
+        t.add_url_seed("http://ws/");
+        t.add_http_seed("http://hs/");
+        t.add_node("router", 6881);
+        t.set_private(true);
+        t.set_piece_hashes(&base).unwrap();
+        let buf = t.generate().unwrap().to_vec();
+
+        let info = TorrentInfo::from_bytes(&buf).unwrap();
+        assert!(info.is_private());
+        let files: Vec<_> = info
+            .files()
+            .files()
+            .iter()
+            .map(|f| (f.path.clone(), f.offset, f.size, f.pad_file))
+            .collect();
+        let pad1 = 2 * 16 * 1024 - 20_000;
+        let pad2 = 16 * 1024 - 100;
+        assert_eq!(
+            vec![
+                (PathBuf::from("root/a.bin"), 0, 20_000, false),
+                (
+                    PathBuf::from(format!("root/.pad/{}", pad1)),
+                    20_000,
+                    pad1,
+                    true
+                ),
+                (PathBuf::from("root/b.bin"), 32 * 1024, 100, false),
+                (
+                    PathBuf::from(format!("root/.pad/{}", pad2)),
+                    32 * 1024 + 100,
+                    pad2,
+                    true
+                ),
+                (PathBuf::from("root/sub/c.bin"), 48 * 1024, 10, false),
+            ],
+            files
+        );
+
+        let trackers: Vec<_> = info
+            .trackers()
+            .iter()
+            .map(|a| (&a.url[..], a.tier))
+            .collect();
+        assert_eq!(
+            vec![("udp://a", 0), ("udp://b", 1), ("udp://c", 1)],
+            trackers
+        );
+        assert_eq!(&[String::from("http://ws/")], info.url_seeds());
+        assert_eq!(&[String::from("http://hs/")], info.http_seeds());
+        assert_eq!(&[(String::from("router"), 6881)], info.nodes());
+
+        let mut piece = vec![1; 16 * 1024];
+        assert_eq!(Some(Sha1Hash::update(&piece)), info.hash_for_piece(0));
+        piece[20_000 - 16 * 1024..].iter_mut().for_each(|b| *b = 0);
+        assert_eq!(Some(Sha1Hash::update(&piece)), info.hash_for_piece(1));
+        let mut piece = vec![0; 16 * 1024];
+        piece[..100].iter_mut().for_each(|b| *b = 2);
+        assert_eq!(Some(Sha1Hash::update(&piece)), info.hash_for_piece(2));
+        assert_eq!(Some(Sha1Hash::update(&[3; 10])), info.hash_for_piece(3));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_piece_len() {
+        let mut storage = FileStorage::new();
+        storage.add_file(FileEntry {
+            path: "a".into(),
+            size: 1,
+            ..FileEntry::default()
+        });
+        assert!(matches!(
+            CreateTorrent::new(storage.clone(), 1000, CreateFlags::default()),
+            Err(Error::TorrentInvalidPieceLength)
+        ));
+        assert!(matches!(
+            CreateTorrent::new(FileStorage::new(), 0, CreateFlags::default()),
+            Err(Error::NoFiles)
+        ));
+    }
+
+    #[test]
+    fn test_missing_piece_hashes() {
+        let mut storage = FileStorage::new();
+        storage.add_file(FileEntry {
+            path: "a".into(),
+            size: 1,
+            ..FileEntry::default()
+        });
+        let t = CreateTorrent::new(storage, 16 * 1024, CreateFlags::default()).unwrap();
+        assert!(matches!(t.generate(), Err(Error::PieceHashesNotSet)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_loop() {
+        let base = temp_dir("create-symlink-loop");
+        let root = base.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.bin"), vec![1; 100]).unwrap();
+        std::os::unix::fs::symlink("..", root.join("sub").join("up")).unwrap();
+        std::os::unix::fs::symlink("a.bin", root.join("b.bin")).unwrap();
+
+        let mut storage = FileStorage::new();
+        add_files(&mut storage, &root).unwrap();
+        assert_eq!(1, storage.num_files());
+        assert_eq!(Path::new("root/a.bin"), storage.file_at(0).path);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
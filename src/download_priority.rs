@@ -1,7 +1,22 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[repr(u8)]
 pub enum DownloadPriority {
-    DontDownload,
-    LowPriority,
-    DefaultPriority,
-    TopPriority,
+    DontDownload = 0,
+    LowPriority = 1,
+    #[default]
+    DefaultPriority = 4,
+    TopPriority = 7,
+}
+
+impl From<u8> for DownloadPriority {
+    /// Maps libtorrent's 0-7 scale onto the named levels, values in between
+    /// round down.
+    fn from(v: u8) -> Self {
+        match v {
+            0 => DownloadPriority::DontDownload,
+            1..=3 => DownloadPriority::LowPriority,
+            4..=6 => DownloadPriority::DefaultPriority,
+            _ => DownloadPriority::TopPriority,
+        }
+    }
 }
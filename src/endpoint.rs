@@ -0,0 +1,66 @@
+use crate::error::{Error, Result};
+use crate::str_utl;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::vec;
+
+/// A peer or node address as found in magnet links and torrent files.
+/// Either an IP address or a host name that still has to be resolved.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    Addr(SocketAddr),
+    Host(String, u16),
+}
+
+impl Endpoint {
+    pub fn port(&self) -> u16 {
+        match self {
+            Endpoint::Addr(addr) => addr.port(),
+            Endpoint::Host(_, port) => *port,
+        }
+    }
+
+    /// The host part, IPv6 addresses without brackets.
+    pub fn host(&self) -> String {
+        match self {
+            Endpoint::Addr(addr) => addr.ip().to_string(),
+            Endpoint::Host(host, _) => host.clone(),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Addr(addr)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        str_utl::parse_endpoint(s.as_bytes())
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Addr(addr) => write!(f, "{}", addr),
+            Endpoint::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl ToSocketAddrs for Endpoint {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        match self {
+            Endpoint::Addr(addr) => Ok(vec![*addr].into_iter()),
+            Endpoint::Host(host, port) => (&host[..], *port).to_socket_addrs(),
+        }
+    }
+}
@@ -10,6 +10,44 @@ pub enum Error {
     InvalidPort,
     ExpectedCloseBracketInAddr,
     MissingInfoHash,
+    Io(std::io::Error),
+    Bencode(bencode::Error),
+    TorrentIsNoDict,
+    TorrentMissingInfo,
+    TorrentInfoNoDict,
+    TorrentMissingName,
+    TorrentInvalidName,
+    TorrentMissingPieceLength,
+    TorrentInvalidPieceLength,
+    TorrentMissingPieces,
+    TorrentInvalidHashes,
+    TorrentInvalidLength,
+    TorrentFileParseFailed,
+    TorrentBufferTooLarge,
+    TorrentTooManyPieces,
+    DecodeDepthLimitExceeded,
+    DecodeTokenLimitExceeded,
+    NoFiles,
+    PieceHashesNotSet,
+    ResumeIsNoDict,
+    PieceOutOfRange,
+    InvalidFilePath,
+    InvalidHandshake,
+    InvalidMessage,
+    MessageTooLarge,
+    InfoHashMismatch,
+    InvalidMetadata,
+    ConnectionClosed,
+    ResumeInvalidFileFormat,
+    InvalidHttpResponse,
+    HttpBodyTooLarge,
+    HttpStatus(u16),
+    TooManyRedirects,
+    TrackerFailure(String),
+    InvalidTrackerResponse,
+    ScrapeNotSupported,
+    DuplicateTorrent,
+    InvalidTorrentHandle,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -31,3 +69,15 @@ impl From<std::num::ParseIntError> for Error {
         Self::ParseInt
     }
 }
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<bencode::Error> for Error {
+    fn from(e: bencode::Error) -> Self {
+        Self::Bencode(e)
+    }
+}
@@ -0,0 +1,370 @@
+//! The extension protocol (BEP 10). Extensions are registered per peer
+//! connection, each gets a message id which is advertised to the peer in
+//! the `m` dictionary of the extension handshake.
+
+use crate::error::{Error, Result};
+use crate::peer_message::Message;
+use bencode::{Value, ValueRef};
+use dht::detail;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Message id of the extension handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Extension messages are small, anything nested deeper or with more
+/// items than this is rejected.
+const MAX_DECODE_DEPTH: usize = 10;
+const MAX_DECODE_ITEMS: usize = 1000;
+
+/// The torrent wide part of an extension. It's asked for a
+/// [`PeerExtension`] for every new connection of the torrent.
+pub trait TorrentExtension: Send {
+    /// Called with the address of the peer. Returns `None` to not use the
+    /// extension with this peer.
+    fn new_connection(&mut self, addr: SocketAddr) -> Option<Box<dyn PeerExtension>>;
+}
+
+/// The part of a peer connection that speaks one extension.
+pub trait PeerExtension: Send {
+    /// Name in the `m` dictionary, e.g. `ut_metadata`.
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific keys to our handshake.
+    fn add_handshake(&mut self, _handshake: &mut BTreeMap<String, Value>) {}
+
+    /// Called with the peer's handshake if it supports this extension.
+    /// Returning false disables the extension for this peer.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> bool {
+        true
+    }
+
+    /// Handles a message for this extension. Payloads of replies are
+    /// pushed to `out`.
+    fn on_message(&mut self, payload: &[u8], out: &mut Vec<Vec<u8>>) -> Result<()>;
+
+    /// Called regularly so the extension can send messages on its own.
+    /// An error disconnects the peer.
+    fn tick(&mut self, _out: &mut Vec<Vec<u8>>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The keys of an extension handshake we know about.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the ids the sender wants them sent with.
+    /// Id 0 means the extension is disabled.
+    pub m: BTreeMap<String, u8>,
+
+    /// Client name and version.
+    pub v: Option<String>,
+
+    /// The sender's listen port.
+    pub p: Option<u16>,
+
+    /// Our address as seen by the sender.
+    pub yourip: Option<IpAddr>,
+
+    /// Number of outstanding requests the sender accepts.
+    pub reqq: Option<usize>,
+
+    /// Size of the info dictionary (BEP 9).
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let d =
+            ValueRef::decode_with_limits(payload, Some(MAX_DECODE_DEPTH), Some(MAX_DECODE_ITEMS))?;
+        if !d.is_dict() {
+            return Err(Error::InvalidMessage);
+        }
+
+        let mut h = Self::default();
+        if let Some(m) = d.dict_find("m").and_then(ValueRef::as_dict) {
+            for (name, id) in m {
+                match id.as_int() {
+                    Some(id) if id > 0 && id <= u8::MAX as i64 => {
+                        h.m.insert(name.to_string(), id as u8);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        h.v = d.dict_find_str_value("v").map(String::from);
+        h.p = d
+            .dict_find_int_value("p")
+            .filter(|&p| p > 0 && p <= u16::MAX as i64)
+            .map(|p| p as u16);
+        h.yourip = d
+            .dict_find("yourip")
+            .and_then(ValueRef::as_bytes)
+            .and_then(|mut ip| match ip.len() {
+                4 => detail::read_v4_address(&mut ip).ok(),
+                16 => detail::read_v6_address(&mut ip).ok(),
+                _ => None,
+            });
+        h.reqq = d
+            .dict_find_int_value("reqq")
+            .filter(|&n| n > 0)
+            .map(|n| n as usize);
+        h.metadata_size = d
+            .dict_find_int_value("metadata_size")
+            .filter(|&n| n > 0)
+            .map(|n| n as usize);
+        Ok(h)
+    }
+
+    pub fn encode(&self) -> BTreeMap<String, Value> {
+        let mut d = BTreeMap::new();
+        let m = self
+            .m
+            .iter()
+            .map(|(name, id)| (name.clone(), Value::with_int(*id as i64)))
+            .collect();
+        d.insert("m".to_string(), Value::with_dict(m));
+        if let Some(v) = &self.v {
+            d.insert("v".to_string(), Value::with_str(v));
+        }
+        if let Some(p) = self.p {
+            d.insert("p".to_string(), Value::with_int(p as i64));
+        }
+        if let Some(ip) = self.yourip {
+            let mut buf = vec![];
+            detail::write_address(&mut buf, &ip).unwrap();
+            d.insert("yourip".to_string(), Value::Bytes(buf));
+        }
+        if let Some(reqq) = self.reqq {
+            d.insert("reqq".to_string(), Value::with_int(reqq as i64));
+        }
+        if let Some(size) = self.metadata_size {
+            d.insert("metadata_size".to_string(), Value::with_int(size as i64));
+        }
+        d
+    }
+}
+
+/// The extensions of one peer connection. Our id for an extension is its
+/// position in registration order plus one.
+#[derive(Default)]
+pub struct Extensions {
+    extensions: Vec<Box<dyn PeerExtension>>,
+
+    /// Whether the extension at the same index is enabled for this peer.
+    enabled: Vec<bool>,
+    peer: Option<ExtendedHandshake>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, extension: Box<dyn PeerExtension>) {
+        debug_assert!(self.extensions.len() < u8::MAX as usize);
+        self.extensions.push(extension);
+        self.enabled.push(false);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    /// The peer's handshake, once received.
+    pub fn peer_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.peer.as_ref()
+    }
+
+    pub fn peer_supports(&self, name: &str) -> bool {
+        self.peer.as_ref().is_some_and(|h| h.m.contains_key(name))
+    }
+
+    /// Builds our handshake. `base` carries the keys filled in by the
+    /// connection (`v`, `p`, `yourip`, `reqq`), `m` is filled in here and
+    /// each extension may add its own keys.
+    pub fn handshake(&mut self, base: &ExtendedHandshake) -> Message {
+        let mut h = base.clone();
+        for (i, ext) in self.extensions.iter().enumerate() {
+            h.m.insert(ext.name().to_string(), i as u8 + 1);
+        }
+        let mut d = h.encode();
+        for ext in &mut self.extensions {
+            ext.add_handshake(&mut d);
+        }
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: Value::with_dict(d).to_vec(),
+        }
+    }
+
+    /// Handles an extended message, returns the messages to send in reply.
+    pub fn on_message(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>> {
+        if id == HANDSHAKE_ID {
+            let h = ExtendedHandshake::decode(payload)?;
+            for (ext, enabled) in self.extensions.iter_mut().zip(&mut self.enabled) {
+                *enabled = h.m.contains_key(ext.name()) && ext.on_handshake(&h);
+            }
+            self.peer = Some(h);
+            return Ok(vec![]);
+        }
+
+        // Messages for extensions we never advertised or that are disabled
+        // are ignored
+        let index = id as usize - 1;
+        if index >= self.extensions.len() || !self.enabled[index] {
+            return Ok(vec![]);
+        }
+        let mut out = vec![];
+        self.extensions[index].on_message(payload, &mut out)?;
+        Ok(self.to_messages(index, out))
+    }
+
+    pub fn tick(&mut self) -> Result<Vec<Message>> {
+        let mut msgs = vec![];
+        for index in 0..self.extensions.len() {
+            if !self.enabled[index] {
+                continue;
+            }
+            let mut out = vec![];
+            self.extensions[index].tick(&mut out)?;
+            msgs.extend(self.to_messages(index, out));
+        }
+        Ok(msgs)
+    }
+
+    /// Wraps payloads of an extension with the id the peer assigned it.
+    fn to_messages(&self, index: usize, payloads: Vec<Vec<u8>>) -> Vec<Message> {
+        let name = self.extensions[index].name();
+        let id = match self.peer.as_ref().and_then(|h| h.m.get(name)) {
+            Some(&id) => id,
+            None => return vec![],
+        };
+        payloads
+            .into_iter()
+            .map(|payload| Message::Extended { id, payload })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Replies to every message with the same payload reversed.
+    struct Echo {
+        handshake_size: usize,
+    }
+
+    impl PeerExtension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn add_handshake(&mut self, handshake: &mut BTreeMap<String, Value>) {
+            handshake.insert("echo_size".to_string(), Value::with_int(42));
+        }
+
+        fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> bool {
+            self.handshake_size = handshake.metadata_size.unwrap_or(0);
+            true
+        }
+
+        fn on_message(&mut self, payload: &[u8], out: &mut Vec<Vec<u8>>) -> Result<()> {
+            out.push(payload.iter().rev().copied().collect());
+            Ok(())
+        }
+
+        fn tick(&mut self, out: &mut Vec<Vec<u8>>) -> Result<()> {
+            out.push(b"tick".to_vec());
+            Ok(())
+        }
+    }
+
+    struct Silent;
+
+    impl PeerExtension for Silent {
+        fn name(&self) -> &'static str {
+            "silent"
+        }
+
+        fn on_message(&mut self, _payload: &[u8], _out: &mut Vec<Vec<u8>>) -> Result<()> {
+            Err(Error::InvalidMessage)
+        }
+    }
+
+    fn payload(msg: &Message) -> &[u8] {
+        match msg {
+            Message::Extended { payload, .. } => payload,
+            _ => panic!("not an extended message"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_decode() {
+        let buf = b"d1:md11:ut_metadatai3e6:ut_pexi1e8:disabledi0ee13:metadata_sizei31235e\
+            1:pi6881e4:reqqi500e1:v14:uTorrent 3.5.56:yourip4:\x0a\x00\x00\x01e";
+        let h = ExtendedHandshake::decode(buf).unwrap();
+        assert_eq!(Some(&3), h.m.get("ut_metadata"));
+        assert_eq!(Some(&1), h.m.get("ut_pex"));
+        assert!(!h.m.contains_key("disabled"));
+        assert_eq!(Some(31235), h.metadata_size);
+        assert_eq!(Some(6881), h.p);
+        assert_eq!(Some(500), h.reqq);
+        assert_eq!(Some("uTorrent 3.5.5"), h.v.as_deref());
+        assert_eq!(Some("10.0.0.1".parse().unwrap()), h.yourip);
+
+        let encoded = Value::with_dict(h.encode()).to_vec();
+        assert_eq!(h, ExtendedHandshake::decode(&encoded).unwrap());
+
+        assert!(ExtendedHandshake::decode(b"i1e").is_err());
+        assert!(ExtendedHandshake::decode(b"d1:m").is_err());
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let mut a = Extensions::new();
+        a.add(Box::new(Silent));
+        a.add(Box::new(Echo { handshake_size: 0 }));
+        let mut b = Extensions::new();
+        b.add(Box::new(Echo { handshake_size: 0 }));
+
+        let base = ExtendedHandshake {
+            v: Some("test".to_string()),
+            metadata_size: Some(7),
+            ..ExtendedHandshake::default()
+        };
+        let ha = a.handshake(&base);
+        let hb = b.handshake(&ExtendedHandshake::default());
+        let d = ValueRef::decode(payload(&ha)).unwrap();
+        assert_eq!(Some(42), d.dict_find_int_value("echo_size"));
+
+        assert!(a.on_message(HANDSHAKE_ID, payload(&hb)).unwrap().is_empty());
+        assert!(b.on_message(HANDSHAKE_ID, payload(&ha)).unwrap().is_empty());
+        assert!(a.peer_supports("echo") && !a.peer_supports("silent"));
+        assert_eq!(Some("test"), b.peer_handshake().unwrap().v.as_deref());
+
+        // b sends with a's id for echo (2), a replies with b's id (1)
+        let reply = a.on_message(2, b"abc").unwrap();
+        assert_eq!(
+            vec![Message::Extended {
+                id: 1,
+                payload: b"cba".to_vec()
+            }],
+            reply
+        );
+
+        // The peer doesn't know silent, so it's never called
+        assert!(a.on_message(1, b"x").unwrap().is_empty());
+        assert!(a.on_message(9, b"x").unwrap().is_empty());
+
+        assert_eq!(1, a.tick().unwrap().len());
+        assert_eq!(
+            vec![Message::Extended {
+                id: 2,
+                payload: b"tick".to_vec()
+            }],
+            b.tick().unwrap()
+        );
+    }
+}
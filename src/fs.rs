@@ -1,10 +1,18 @@
+use crate::peer_request::PeerRequest;
 use common::sha1::Sha1Hash;
+use std::ops::Range;
 use std::path::PathBuf;
-use std::time::Instant;
 
-#[derive(Default)]
+/// The file layout of a torrent: which files it contains, where each of
+/// them starts in the torrent's contiguous byte space and how that space
+/// is split into pieces.
+#[derive(Debug, Default, Clone)]
 pub struct FileStorage {
+    name: String,
     piece_len: usize,
+    num_pieces: usize,
+    total_size: u64,
+    files: Vec<FileEntry>,
 }
 
 impl FileStorage {
@@ -15,19 +23,286 @@ impl FileStorage {
     pub fn is_valid(&self) -> bool {
         self.piece_len > 0
     }
+
+    /// Appends a file to the storage. The offset of the entry is assigned
+    /// from the current total size, any value set by the caller is ignored.
+    pub fn add_file(&mut self, mut entry: FileEntry) {
+        entry.offset = self.total_size;
+        self.total_size += entry.size;
+        self.files.push(entry);
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn file_at(&self, index: usize) -> &FileEntry {
+        &self.files[index]
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub fn piece_len(&self) -> usize {
+        self.piece_len
+    }
+
+    pub fn set_piece_len(&mut self, piece_len: usize) {
+        self.piece_len = piece_len;
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn set_num_pieces(&mut self, num_pieces: usize) {
+        self.num_pieces = num_pieces;
+    }
+
+    /// Size of the given piece. All pieces are `piece_len` bytes long except
+    /// the last one which may be shorter.
+    pub fn piece_size(&self, index: usize) -> usize {
+        debug_assert!(index < self.num_pieces);
+        let offset = index as u64 * self.piece_len as u64;
+        (self.total_size - offset).min(self.piece_len as u64) as usize
+    }
+
+    /// Maps a range of bytes within a piece to the files it covers. The
+    /// range is cut short at the end of the piece and nothing is mapped
+    /// for pieces past the end.
+    pub fn map_block(&self, piece: usize, offset: usize, len: usize) -> Vec<FileSlice> {
+        if piece >= self.num_pieces {
+            return vec![];
+        }
+        let piece_size = self.piece_size(piece);
+        let len = len.min(piece_size.saturating_sub(offset));
+        self.map_range(
+            piece as u64 * self.piece_len as u64 + offset as u64,
+            len as u64,
+        )
+    }
+
+    /// Maps a range of bytes of the whole torrent to the files it covers,
+    /// across piece boundaries. The range is cut short at the end of the
+    /// torrent. Zero-length files are never part of the result, pad files
+    /// are and it's up to the caller to not touch them on disk.
+    pub fn map_range(&self, start: u64, len: u64) -> Vec<FileSlice> {
+        let end = start.saturating_add(len).min(self.total_size);
+        if start >= end {
+            return vec![];
+        }
+
+        let mut slices = vec![];
+        let first = self.file_index_at_offset(start);
+        let mut pos = start;
+        for (index, file) in self.files.iter().enumerate().skip(first) {
+            if pos >= end {
+                break;
+            }
+            let file_end = file.offset + file.size;
+            if file_end <= pos {
+                continue;
+            }
+            let size = file_end.min(end) - pos;
+            slices.push(FileSlice {
+                file_index: index,
+                offset: pos - file.offset,
+                size,
+            });
+            pos += size;
+        }
+        slices
+    }
+
+    /// Maps a range of bytes within a file to the piece it starts in. The
+    /// length is cut short at the end of the file but may span several
+    /// pieces. An unknown file or an offset past the end of the file maps
+    /// to the piece `num_pieces()`.
+    pub fn map_file(&self, file: usize, offset: u64, len: usize) -> PeerRequest {
+        let file = match self.files.get(file) {
+            Some(file) if offset < file.size || (offset == 0 && file.size == 0) => file,
+            _ => return PeerRequest::new(self.num_pieces, 0, 0),
+        };
+        let start = file.offset + offset;
+        if start >= self.total_size {
+            return PeerRequest::new(self.num_pieces, 0, 0);
+        }
+        let piece_len = self.piece_len as u64;
+        PeerRequest {
+            piece: (start / piece_len) as usize,
+            start: (start % piece_len) as usize,
+            length: (file.size - offset).min(len as u64) as usize,
+        }
+    }
+
+    /// Index of the file containing the byte at `offset`, skipping
+    /// zero-length files.
+    pub fn file_index_at_offset(&self, offset: u64) -> usize {
+        // Files are sorted by offset, find the last one starting at or
+        // before it that isn't empty
+        let i = self.files.partition_point(|f| f.offset + f.size <= offset);
+        i.min(self.files.len().saturating_sub(1))
+    }
+
+    /// Index of the file the given piece starts in.
+    pub fn file_index_at_piece(&self, piece: usize) -> usize {
+        self.file_index_at_offset(piece as u64 * self.piece_len as u64)
+    }
+
+    /// Pieces overlapping the given file, end exclusive. Empty for
+    /// zero-length files.
+    pub fn file_piece_range(&self, file: usize) -> Range<usize> {
+        let file = &self.files[file];
+        if file.size == 0 {
+            let piece = (file.offset / self.piece_len as u64) as usize;
+            return piece..piece;
+        }
+        let piece_len = self.piece_len as u64;
+        let first = file.offset / piece_len;
+        let last = (file.offset + file.size - 1) / piece_len;
+        first as usize..last as usize + 1
+    }
+}
+
+/// A range of bytes within a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    pub offset: u64,
+    pub size: u64,
 }
 
+#[derive(Debug, Default, Clone)]
 pub struct FileEntry {
-    path: PathBuf,
-    symlink_path: PathBuf,
-    offset: i64,
-    size: i64,
-    modified_time: Instant,
-    file_hash: Sha1Hash,
-    pad_file: bool,
-    hidden_attr: bool,
-    executable_attr: bool,
-    symlink_attr: bool,
+    /// Path of the file. For multi-file torrents the first element is the
+    /// torrent name.
+    pub path: PathBuf,
+
+    /// Target of the link if `symlink_attr` is set, empty otherwise.
+    pub symlink_path: PathBuf,
+
+    /// Offset of the first byte of this file in the torrent.
+    pub offset: u64,
+    pub size: u64,
+
+    /// Modification time in seconds since epoch, 0 if unknown.
+    pub modified_time: i64,
+
+    /// Optional SHA-1 of the whole file, all zeroes if not present.
+    pub file_hash: Sha1Hash,
+
+    /// Pad files (BEP 47) are never written to disk, they exist only to
+    /// align the next file to a piece boundary.
+    pub pad_file: bool,
+    pub hidden_attr: bool,
+    pub executable_attr: bool,
+    pub symlink_attr: bool,
 }
 
 pub struct InternalFileEntry {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn storage(piece_len: usize, files: &[(u64, bool)]) -> FileStorage {
+        let mut fs = FileStorage::new();
+        fs.set_name("test".to_string());
+        fs.set_piece_len(piece_len);
+        for (i, &(size, pad_file)) in files.iter().enumerate() {
+            fs.add_file(FileEntry {
+                path: PathBuf::from(format!("test/{}", i)),
+                size,
+                pad_file,
+                ..FileEntry::default()
+            });
+        }
+        let num_pieces = fs.total_size().div_ceil(piece_len as u64);
+        fs.set_num_pieces(num_pieces as usize);
+        fs
+    }
+
+    fn slice(file_index: usize, offset: u64, size: u64) -> FileSlice {
+        FileSlice {
+            file_index,
+            offset,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_map_block() {
+        // 0..10, empty, 10..25, 25..30
+        let fs = storage(8, &[(10, false), (0, false), (15, false), (5, false)]);
+        assert_eq!(4, fs.num_pieces());
+        assert_eq!(6, fs.piece_size(3));
+
+        assert_eq!(vec![slice(0, 0, 8)], fs.map_block(0, 0, 8));
+        assert_eq!(vec![slice(0, 8, 2), slice(2, 0, 6)], fs.map_block(1, 0, 8));
+        assert_eq!(vec![slice(2, 6, 8)], fs.map_block(2, 0, 100));
+        assert_eq!(vec![slice(3, 3, 2)], fs.map_block(3, 4, 8));
+        assert!(fs.map_block(4, 0, 8).is_empty());
+        assert!(fs.map_block(1, 0, 0).is_empty());
+        assert!(fs.map_block(1, 9, 8).is_empty());
+    }
+
+    #[test]
+    fn test_map_range() {
+        let fs = storage(8, &[(10, false), (0, false), (15, false), (5, false)]);
+        assert_eq!(vec![slice(2, 6, 9), slice(3, 0, 5)], fs.map_range(16, 100));
+        assert!(fs.map_range(30, 1).is_empty());
+        assert!(fs.map_range(u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_map_block_pad_files() {
+        let fs = storage(8, &[(5, false), (3, true), (8, false)]);
+        assert_eq!(vec![slice(0, 2, 3), slice(1, 0, 3)], fs.map_block(0, 2, 8));
+        assert_eq!(
+            vec![slice(0, 2, 3), slice(1, 0, 3), slice(2, 0, 2)],
+            fs.map_range(2, 8)
+        );
+    }
+
+    #[test]
+    fn test_map_file() {
+        let fs = storage(8, &[(10, false), (0, false), (15, false), (5, false)]);
+        assert_eq!(PeerRequest::new(0, 0, 10), fs.map_file(0, 0, 10));
+        assert_eq!(PeerRequest::new(1, 2, 4), fs.map_file(2, 0, 4));
+        assert_eq!(PeerRequest::new(1, 2, 0), fs.map_file(1, 0, 0));
+        assert_eq!(PeerRequest::new(3, 1, 5), fs.map_file(3, 0, 100));
+        assert_eq!(PeerRequest::new(4, 0, 0), fs.map_file(3, 5, 1));
+
+        // Ranges stay within the file
+        assert_eq!(PeerRequest::new(1, 0, 2), fs.map_file(0, 8, 100));
+        assert_eq!(PeerRequest::new(4, 0, 0), fs.map_file(0, 10, 1));
+        assert_eq!(PeerRequest::new(4, 0, 0), fs.map_file(4, 0, 1));
+    }
+
+    #[test]
+    fn test_file_piece_range() {
+        let fs = storage(8, &[(10, false), (0, false), (15, false), (5, false)]);
+        assert_eq!(0..2, fs.file_piece_range(0));
+        assert_eq!(1..1, fs.file_piece_range(1));
+        assert_eq!(1..4, fs.file_piece_range(2));
+        assert_eq!(3..4, fs.file_piece_range(3));
+
+        assert_eq!(0, fs.file_index_at_offset(0));
+        assert_eq!(2, fs.file_index_at_offset(10));
+        assert_eq!(3, fs.file_index_at_offset(25));
+        assert_eq!(2, fs.file_index_at_piece(3));
+    }
+}
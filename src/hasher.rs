@@ -0,0 +1,76 @@
+//! Verifies data on disk against the piece hashes of the torrent.
+
+use crate::bitfield::Bitfield;
+use crate::error::{Error, Result};
+use crate::info::TorrentInfo;
+use crate::storage::Storage;
+use common::sha1::Sha1Hash;
+use std::io;
+
+/// Reads a whole piece from the storage and hashes it.
+pub fn hash_piece(storage: &mut dyn Storage, piece: usize) -> Result<Sha1Hash> {
+    let mut buf = vec![0; storage.files().piece_size(piece)];
+    storage.read(&mut buf, piece, 0)?;
+    Ok(Sha1Hash::update(&buf))
+}
+
+/// Checks a piece against its expected hash. Pieces whose files don't
+/// exist yet simply fail the check.
+pub fn verify_piece(storage: &mut dyn Storage, info: &TorrentInfo, piece: usize) -> Result<bool> {
+    match hash_piece(storage, piece) {
+        Ok(hash) => Ok(info.hash_for_piece(piece) == Some(hash)),
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks all pieces of a torrent one at a time, building up the set of
+/// pieces we have.
+#[derive(Debug, Clone)]
+pub struct PieceChecker {
+    next: usize,
+    have: Bitfield,
+}
+
+impl PieceChecker {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            next: 0,
+            have: Bitfield::new(num_pieces),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next == self.have.len()
+    }
+
+    /// Portion of pieces checked so far, in the range [0, 1].
+    pub fn progress(&self) -> f32 {
+        if self.have.is_empty() {
+            1.0
+        } else {
+            self.next as f32 / self.have.len() as f32
+        }
+    }
+
+    /// Pieces checked so far that passed.
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn into_have(self) -> Bitfield {
+        self.have
+    }
+
+    /// Checks the next piece, returns whether it passed. On error the same
+    /// piece is checked again on the next call.
+    pub fn check_next(&mut self, storage: &mut dyn Storage, info: &TorrentInfo) -> Result<bool> {
+        debug_assert!(!self.is_done());
+        let ok = verify_piece(storage, info, self.next)?;
+        if ok {
+            self.have.set(self.next);
+        }
+        self.next += 1;
+        Ok(ok)
+    }
+}
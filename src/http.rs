@@ -0,0 +1,656 @@
+//! A minimal blocking HTTP/1.1 client, enough for trackers and web seeds.
+//! Every request uses its own connection. `https` URLs are served over
+//! rustls.
+
+use crate::error::{Error, Result};
+use crate::peer_connection::CLIENT_VERSION;
+use defaults::Defaults;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use url::{Host, Url};
+
+#[derive(Debug, Clone, Defaults)]
+pub struct HttpSettings {
+    /// Time allowed for the whole request, from connecting to receiving
+    /// the last byte of the body.
+    #[def = "Duration::from_secs(30)"]
+    pub timeout: Duration,
+
+    /// Redirects followed at most.
+    #[def = "5"]
+    pub max_redirects: usize,
+
+    /// Responses with a larger body are rejected.
+    #[def = "4 * 1024 * 1024"]
+    pub max_body_len: usize,
+
+    /// TLS setup for `https` URLs. By default servers are verified
+    /// against the Mozilla root certificates.
+    #[def = "default_tls_config()"]
+    pub tls_config: Arc<ClientConfig>,
+}
+
+fn default_tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Arc::new(config)
+        })
+        .clone()
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Value of the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status / 100 == 2
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308)
+    }
+}
+
+/// Sends a GET request, following redirects. Returns the URL the response
+/// came from along with the response, which may have any status.
+pub fn get(
+    url: &Url,
+    headers: &[(&str, &str)],
+    settings: &HttpSettings,
+) -> Result<(Url, HttpResponse)> {
+    let mut url = url.clone();
+    for _ in 0..=settings.max_redirects {
+        let response = get_once(&url, headers, settings)?;
+        if !response.is_redirect() {
+            return Ok((url, response));
+        }
+        let location = response
+            .header("location")
+            .ok_or(Error::InvalidHttpResponse)?;
+        url = url.join(location)?;
+    }
+    Err(Error::TooManyRedirects)
+}
+
+/// Sends a single GET request without following redirects.
+pub fn get_once(
+    url: &Url,
+    headers: &[(&str, &str)],
+    settings: &HttpSettings,
+) -> Result<HttpResponse> {
+    let secure = match url.scheme() {
+        "http" => false,
+        "https" => true,
+        _ => return Err(Error::UnsupportedUrlProtocol),
+    };
+    // IPv6 hosts keep their brackets here, as the Host header wants them
+    let host = url.host_str().ok_or(Error::InvalidUrl)?;
+    let deadline = Instant::now() + settings.timeout;
+
+    let addrs = url.socket_addrs(|| None)?;
+    let mut stream = None;
+    let mut last_err = io::Error::from(io::ErrorKind::NotFound);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, settings.timeout) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    let stream = stream.ok_or(last_err)?;
+    let mut stream = if secure {
+        let name = match url.host() {
+            Some(Host::Domain(domain)) => {
+                ServerName::try_from(domain.to_string()).map_err(|_| Error::InvalidUrl)?
+            }
+            Some(Host::Ipv4(ip)) => ServerName::from(IpAddr::from(ip)),
+            Some(Host::Ipv6(ip)) => ServerName::from(IpAddr::from(ip)),
+            None => return Err(Error::InvalidUrl),
+        };
+        let conn = ClientConnection::new(settings.tls_config.clone(), name)
+            .map_err(|e| Error::Io(io::Error::other(e)))?;
+        Stream::Tls(Box::new(StreamOwned::new(conn, stream)))
+    } else {
+        Stream::Plain(stream)
+    };
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nAccept-Encoding: identity\r\nConnection: close\r\n",
+        target,
+        match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        },
+        CLIENT_VERSION
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.tcp().set_write_timeout(Some(settings.timeout))?;
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut buf = vec![];
+    let mut chunk = [0; 16 * 1024];
+    let mut parser = ResponseParser::new(settings.max_body_len);
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Io(io::ErrorKind::TimedOut.into()));
+        }
+        stream.tcp().set_read_timeout(Some(deadline - now))?;
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // Many servers close TLS connections without a close_notify
+            Err(e) if secure && e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        // A response with a known length may be complete before the
+        // server closes the connection
+        if let Some(response) = parser.parse(&buf, false)? {
+            return Ok(response);
+        }
+    }
+    parser.parse(&buf, true)?.ok_or(Error::InvalidHttpResponse)
+}
+
+/// The connection of a request, encrypted for `https`.
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            Stream::Tls(s) => s.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+/// Responses whose status line and headers are longer are rejected.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// Chunk size lines, with any extensions, are rejected past this length.
+const MAX_CHUNK_LINE_LEN: usize = 1024;
+
+/// Parses a response while it arrives. The buffer passed in only ever
+/// grows, and every call picks up where the last one stopped, so the head
+/// is parsed once and chunks are decoded once.
+struct ResponseParser {
+    max_body_len: usize,
+
+    /// Status and headers once the head is complete.
+    head: Option<HttpResponse>,
+
+    /// Offset of the body in the buffer.
+    body_start: usize,
+
+    /// Where to continue: the end of the head is searched from here, or
+    /// the next chunk of a chunked body starts here.
+    pos: usize,
+
+    /// The chunks decoded so far.
+    chunks: Vec<u8>,
+}
+
+impl ResponseParser {
+    fn new(max_body_len: usize) -> Self {
+        Self {
+            max_body_len,
+            head: None,
+            body_start: 0,
+            pos: 0,
+            chunks: vec![],
+        }
+    }
+
+    /// Returns `None` if more data is needed; with `eof` set, a body
+    /// without a length runs to the end of the buffer.
+    fn parse(&mut self, buf: &[u8], eof: bool) -> Result<Option<HttpResponse>> {
+        if self.head.is_none() {
+            // The terminator may have been split across reads
+            let from = self.pos.saturating_sub(3);
+            match buf[from..].windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(i) if from + i > MAX_HEAD_LEN => return Err(Error::InvalidHttpResponse),
+                Some(i) => {
+                    let head_end = from + i;
+                    self.head = Some(parse_head(&buf[..head_end])?);
+                    self.body_start = head_end + 4;
+                    self.pos = self.body_start;
+                }
+                None if eof || buf.len() > MAX_HEAD_LEN + 3 => {
+                    return Err(Error::InvalidHttpResponse)
+                }
+                None => {
+                    self.pos = buf.len();
+                    return Ok(None);
+                }
+            }
+        }
+        let head = self.head.as_ref().unwrap();
+
+        let chunked = head
+            .header("transfer-encoding")
+            .is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return match decode_chunks(buf, &mut self.pos, &mut self.chunks, self.max_body_len)? {
+                true => {
+                    let body = std::mem::take(&mut self.chunks);
+                    Ok(self.finish(body))
+                }
+                false if eof => Err(Error::InvalidHttpResponse),
+                false => Ok(None),
+            };
+        }
+
+        let status = head.status;
+        let len = match head.header("content-length") {
+            Some(len) => Some(
+                len.parse::<usize>()
+                    .map_err(|_| Error::InvalidHttpResponse)?,
+            ),
+            None if status == 204 || status == 304 || status / 100 == 1 => Some(0),
+            None => None,
+        };
+        let body = &buf[self.body_start..];
+        if len.unwrap_or(body.len()) > self.max_body_len {
+            return Err(Error::HttpBodyTooLarge);
+        }
+        match len {
+            Some(len) if body.len() >= len => Ok(self.finish(body[..len].to_vec())),
+            Some(_) if eof => Err(Error::InvalidHttpResponse),
+            Some(_) => Ok(None),
+            None if eof => Ok(self.finish(body.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    fn finish(&mut self, body: Vec<u8>) -> Option<HttpResponse> {
+        let mut response = self.head.take()?;
+        response.body = body;
+        Some(response)
+    }
+}
+
+/// Parses the status line and headers, everything before the empty line.
+fn parse_head(head: &[u8]) -> Result<HttpResponse> {
+    let head = std::str::from_utf8(head).map_err(|_| Error::InvalidHttpResponse)?;
+    let mut lines = head.split("\r\n");
+
+    // HTTP/1.1 200 OK
+    let status_line = lines.next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts.next().unwrap_or_default().starts_with("HTTP/") {
+        return Err(Error::InvalidHttpResponse);
+    }
+    let status = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or(Error::InvalidHttpResponse)?;
+
+    let mut response = HttpResponse {
+        status,
+        headers: vec![],
+        body: vec![],
+    };
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => return Err(Error::InvalidHttpResponse),
+        };
+        response
+            .headers
+            .push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(response)
+}
+
+/// Decodes the complete chunks of a chunked body starting at `pos` into
+/// `body`, moving `pos` past them. Returns true once the last chunk is
+/// in.
+fn decode_chunks(
+    buf: &[u8],
+    pos: &mut usize,
+    body: &mut Vec<u8>,
+    max_body_len: usize,
+) -> Result<bool> {
+    loop {
+        let rest = &buf[*pos..];
+        let line_end = match rest.windows(2).position(|w| w == b"\r\n") {
+            Some(i) if i > MAX_CHUNK_LINE_LEN => return Err(Error::InvalidHttpResponse),
+            Some(i) => i,
+            None if rest.len() > MAX_CHUNK_LINE_LEN + 1 => return Err(Error::InvalidHttpResponse),
+            None => return Ok(false),
+        };
+        let line =
+            std::str::from_utf8(&rest[..line_end]).map_err(|_| Error::InvalidHttpResponse)?;
+        // Chunk extensions follow a semicolon
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| Error::InvalidHttpResponse)?;
+        let data = &rest[line_end + 2..];
+        if size == 0 {
+            return Ok(true);
+        }
+        match body.len().checked_add(size) {
+            Some(len) if len <= max_body_len => {}
+            _ => return Err(Error::HttpBodyTooLarge),
+        }
+        // The chunk's data is followed by CRLF
+        let chunk_len = size.checked_add(2).ok_or(Error::InvalidHttpResponse)?;
+        if data.len() < chunk_len {
+            return Ok(false);
+        }
+        body.extend_from_slice(&data[..size]);
+        *pos += line_end + 2 + size + 2;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::{ServerConfig, ServerConnection};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread::{self, JoinHandle};
+
+    /// Serves canned responses, one per connection, and returns the heads
+    /// of the requests it got.
+    pub(crate) fn serve(responses: Vec<Vec<u8>>) -> (SocketAddr, JoinHandle<Vec<String>>) {
+        serve_on(TcpListener::bind("127.0.0.1:0").unwrap(), responses)
+    }
+
+    fn serve_on(
+        listener: TcpListener,
+        responses: Vec<Vec<u8>>,
+    ) -> (SocketAddr, JoinHandle<Vec<String>>) {
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = vec![];
+                let mut chunk = [0; 1024];
+                while !buf.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).unwrap();
+                    assert!(n > 0);
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                requests.push(String::from_utf8(buf).unwrap());
+                stream.write_all(&response).unwrap();
+            }
+            requests
+        });
+        (addr, handle)
+    }
+
+    /// A response with the given status, headers and body.
+    pub(crate) fn response(status: u16, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut r = format!(
+            "HTTP/1.1 {} X\r\nContent-Length: {}\r\n",
+            status,
+            body.len()
+        );
+        for (name, value) in headers {
+            r.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        r.push_str("\r\n");
+        let mut r = r.into_bytes();
+        r.extend_from_slice(body);
+        r
+    }
+
+    fn url(addr: SocketAddr, path: &str) -> Url {
+        Url::parse(&format!("http://{}{}", addr, path)).unwrap()
+    }
+
+    #[test]
+    fn test_get() {
+        let (addr, server) = serve(vec![response(200, &[("X-Test", "1")], b"hello")]);
+        let (_, r) = get(
+            &url(addr, "/a?b=c"),
+            &[("Range", "bytes=0-4")],
+            &HttpSettings::default(),
+        )
+        .unwrap();
+        assert_eq!(200, r.status);
+        assert_eq!(Some("1"), r.header("x-test"));
+        assert_eq!(b"hello", &r.body[..]);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /a?b=c HTTP/1.1\r\n"));
+        assert!(requests[0].contains(&format!("\r\nHost: {}\r\n", addr)));
+        assert!(requests[0].contains("\r\nRange: bytes=0-4\r\n"));
+    }
+
+    #[test]
+    fn test_get_v6() {
+        // Skipped where there's no IPv6 loopback
+        let listener = match TcpListener::bind("[::1]:0") {
+            Ok(listener) => listener,
+            Err(_) => return,
+        };
+        let (addr, server) = serve_on(listener, vec![response(200, &[], b"hello")]);
+        let (_, r) = get(&url(addr, "/"), &[], &HttpSettings::default()).unwrap();
+        assert_eq!(b"hello", &r.body[..]);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains(&format!("\r\nHost: [::1]:{}\r\n", addr.port())));
+    }
+
+    #[test]
+    fn test_https() {
+        let key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = key.cert.der().clone();
+        let private_key = PrivatePkcs8KeyDer::from(key.key_pair.serialize_der());
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], private_key.into())
+            .unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let conn = ServerConnection::new(config.clone()).unwrap();
+                let mut stream = StreamOwned::new(conn, stream);
+                let mut buf = vec![];
+                let mut chunk = [0; 1024];
+                while !buf.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut chunk) {
+                        Ok(n) if n > 0 => buf.extend_from_slice(&chunk[..n]),
+                        // The client doesn't trust us
+                        _ => break,
+                    }
+                }
+                if buf.ends_with(b"\r\n\r\n") {
+                    stream.write_all(&response(200, &[], b"hello")).unwrap();
+                    stream.flush().unwrap();
+                    requests.push(String::from_utf8(buf).unwrap());
+                }
+            }
+            requests
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let settings = HttpSettings {
+            tls_config: Arc::new(
+                ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            ),
+            ..HttpSettings::default()
+        };
+        let url = Url::parse(&format!("https://localhost:{}/a", addr.port())).unwrap();
+        let (_, r) = get(&url, &[], &settings).unwrap();
+        assert_eq!(b"hello", &r.body[..]);
+
+        // The certificate isn't signed by a known root
+        assert!(matches!(
+            get(&url, &[], &HttpSettings::default()),
+            Err(Error::Io(_))
+        ));
+
+        let requests = server.join().unwrap();
+        assert_eq!(1, requests.len());
+        assert!(requests[0].starts_with("GET /a HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let (addr, server) = serve(vec![
+            response(302, &[("Location", "/b")], b""),
+            response(404, &[], b"not found"),
+        ]);
+        let (final_url, r) = get(&url(addr, "/a"), &[], &HttpSettings::default()).unwrap();
+        assert_eq!(url(addr, "/b"), final_url);
+        assert_eq!(404, r.status);
+        assert!(!r.is_success());
+        server.join().unwrap();
+
+        let (addr, server) = serve(vec![response(301, &[("Location", "/a")], b""); 2]);
+        let settings = HttpSettings {
+            max_redirects: 1,
+            ..HttpSettings::default()
+        };
+        assert!(matches!(
+            get(&url(addr, "/a"), &[], &settings),
+            Err(Error::TooManyRedirects)
+        ));
+        server.join().unwrap();
+    }
+
+    fn parse_response(buf: &[u8], max_body_len: usize, eof: bool) -> Result<Option<HttpResponse>> {
+        ResponseParser::new(max_body_len).parse(buf, eof)
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let r = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let r = parse_response(r, 100, false).unwrap().unwrap();
+        assert_eq!(b"abcde", &r.body[..]);
+
+        // Incomplete until the connection is closed
+        let r = b"HTTP/1.0 200 OK\r\n\r\nabc";
+        assert_eq!(None, parse_response(r, 100, false).unwrap());
+        assert_eq!(
+            b"abc",
+            &parse_response(r, 100, true).unwrap().unwrap().body[..]
+        );
+
+        let r = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabc";
+        assert_eq!(None, parse_response(r, 100, false).unwrap());
+        assert!(parse_response(r, 100, true).is_err());
+        assert!(matches!(
+            parse_response(r, 4, false),
+            Err(Error::HttpBodyTooLarge)
+        ));
+        assert!(parse_response(b"garbage\r\n\r\n", 100, false).is_err());
+
+        // A chunk size that overflows the body length
+        let r = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\nxx";
+        assert!(matches!(
+            parse_response(r, 100, false),
+            Err(Error::HttpBodyTooLarge)
+        ));
+        assert!(matches!(
+            parse_response(r, usize::MAX, false),
+            Err(Error::HttpBodyTooLarge)
+        ));
+        let r = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nfffffffffffffffe\r\nxx";
+        assert!(matches!(
+            parse_response(r, usize::MAX, false),
+            Err(Error::InvalidHttpResponse)
+        ));
+
+        // Heads and chunk size lines that never end
+        let mut r = b"HTTP/1.1 200 OK\r\nX: ".to_vec();
+        r.resize(MAX_HEAD_LEN + 4, b'x');
+        assert!(parse_response(&r, 100, false).is_err());
+        let mut r = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1".to_vec();
+        r.resize(r.len() + MAX_CHUNK_LINE_LEN + 2, b' ');
+        assert!(parse_response(&r, 100, false).is_err());
+    }
+
+    #[test]
+    fn test_parse_in_parts() {
+        let r = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let mut parser = ResponseParser::new(100);
+        for i in 1..r.len() - 4 {
+            assert_eq!(None, parser.parse(&r[..i], false).unwrap());
+        }
+        let response = parser.parse(r, false).unwrap().unwrap();
+        assert_eq!(b"abcde", &response.body[..]);
+
+        let r = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc";
+        let mut parser = ResponseParser::new(100);
+        for i in 1..r.len() {
+            assert_eq!(None, parser.parse(&r[..i], false).unwrap());
+        }
+        assert_eq!(b"abc", &parser.parse(r, false).unwrap().unwrap().body[..]);
+    }
+}
@@ -0,0 +1,456 @@
+//! Announcing to and scraping HTTP trackers (BEP 3, BEP 23, BEP 48).
+
+use crate::endpoint::Endpoint;
+use crate::error::{Error, Result};
+use crate::http::{self, HttpSettings};
+use crate::str_utl;
+use crate::tracker::{self, PeerEntry, ScrapeResponse, TrackerRequest, TrackerResponse};
+use bencode::ValueRef;
+use common::sha1::Sha1Hash;
+use dht::detail;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use url::Url;
+
+/// Tracker responses are flat apart from dictionary peer lists.
+const MAX_DECODE_DEPTH: usize = 10;
+const MAX_DECODE_ITEMS: usize = 100_000;
+
+/// Announces to an HTTP tracker.
+pub fn announce(req: &TrackerRequest) -> Result<TrackerResponse> {
+    let url = announce_url(req)?;
+    let body = fetch(&url, req.timeout)?;
+    parse_announce_response(&body)
+}
+
+/// Scrapes the tracker at the given announce URL for one torrent.
+pub fn scrape(
+    announce_url: &str,
+    info_hash: &Sha1Hash,
+    timeout: Duration,
+) -> Result<ScrapeResponse> {
+    let mut url = scrape_url(announce_url).ok_or(Error::ScrapeNotSupported)?;
+    url.push(if url.contains('?') { '&' } else { '?' });
+    url.push_str("info_hash=");
+    url.push_str(&str_utl::escape_bytes(info_hash));
+    let body = fetch(&Url::parse(&url)?, timeout)?;
+    parse_scrape_response(&body, info_hash)
+}
+
+/// The scrape URL of a tracker: the announce URL with `announce` in the
+/// last path component replaced by `scrape`. `None` if there's no such
+/// component, the tracker doesn't support scraping then.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let (path, query) = match announce_url.find('?') {
+        Some(i) => announce_url.split_at(i),
+        None => (announce_url, ""),
+    };
+    let slash = path.rfind('/')?;
+    let last = &path[slash + 1..];
+    if !last.starts_with("announce") {
+        return None;
+    }
+    Some(format!(
+        "{}/scrape{}{}",
+        &path[..slash],
+        &last["announce".len()..],
+        query
+    ))
+}
+
+/// The announce URL with the request in its query string.
+fn announce_url(req: &TrackerRequest) -> Result<Url> {
+    let mut url = req.url.clone();
+    url.push(if url.contains('?') { '&' } else { '?' });
+    url.push_str(&format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&numwant={}&key={:08X}",
+        str_utl::escape_bytes(&req.info_hash),
+        str_utl::escape_bytes(&req.peer_id),
+        req.listen_port,
+        req.uploaded,
+        req.downloaded,
+        req.left,
+        req.num_want,
+        req.key,
+    ));
+    if let Some(event) = req.event.as_str() {
+        url.push_str("&event=");
+        url.push_str(event);
+    }
+    if let Some(id) = &req.tracker_id {
+        url.push_str("&trackerid=");
+        url.push_str(&str_utl::escape_string(id));
+    }
+    Ok(Url::parse(&url)?)
+}
+
+fn fetch(url: &Url, timeout: Duration) -> Result<Vec<u8>> {
+    let settings = HttpSettings {
+        timeout,
+        ..HttpSettings::default()
+    };
+    let (_, response) = http::get(url, &[], &settings)?;
+    if !response.is_success() {
+        return Err(Error::HttpStatus(response.status));
+    }
+    Ok(response.body)
+}
+
+fn decode(body: &[u8]) -> Result<ValueRef<'_>> {
+    let d = ValueRef::decode_with_limits(body, Some(MAX_DECODE_DEPTH), Some(MAX_DECODE_ITEMS))?;
+    if !d.is_dict() {
+        return Err(Error::InvalidTrackerResponse);
+    }
+    Ok(d)
+}
+
+pub fn parse_announce_response(body: &[u8]) -> Result<TrackerResponse> {
+    let d = decode(body)?;
+    if let Some(reason) = d.dict_find_str_value("failure reason") {
+        return Err(Error::TrackerFailure(reason.to_string()));
+    }
+
+    let mut r = TrackerResponse::default();
+    let secs = |key| {
+        d.dict_find_int_value(key)
+            .filter(|&n| n > 0)
+            .map(|n| Duration::from_secs(n as u64).min(tracker::MAX_INTERVAL))
+    };
+    r.interval = secs("interval").unwrap_or(r.interval);
+    r.min_interval = secs("min interval").unwrap_or(r.min_interval);
+    let count = |key| {
+        d.dict_find_int_value(key)
+            .filter(|&n| n >= 0)
+            .map(|n| n as u64)
+    };
+    r.complete = count("complete");
+    r.incomplete = count("incomplete");
+    r.downloaded = count("downloaded");
+    r.warning_message = d.dict_find_str_value("warning message").map(String::from);
+    r.tracker_id = d.dict_find_str_value("tracker id").map(String::from);
+    r.external_ip = d
+        .dict_find("external ip")
+        .and_then(ValueRef::as_bytes)
+        .and_then(|mut ip| match ip.len() {
+            4 => detail::read_v4_address(&mut ip).ok(),
+            16 => detail::read_v6_address(&mut ip).ok(),
+            _ => None,
+        });
+
+    match d.dict_find("peers") {
+        Some(ValueRef::Bytes(peers)) => {
+            for mut c in peers.chunks_exact(6) {
+                if let Ok(addr) = detail::read_v4_socket_address(&mut c) {
+                    r.peers.push(compact_peer(addr));
+                }
+            }
+        }
+        Some(ValueRef::List(peers)) => {
+            r.peers.extend(peers.iter().filter_map(dict_peer));
+        }
+        _ => {}
+    }
+    if let Some(peers) = d.dict_find("peers6").and_then(ValueRef::as_bytes) {
+        for mut c in peers.chunks_exact(18) {
+            if let Ok(addr) = detail::read_v6_socket_address(&mut c) {
+                r.peers.push(compact_peer(addr));
+            }
+        }
+    }
+    Ok(r)
+}
+
+fn compact_peer(addr: SocketAddr) -> PeerEntry {
+    PeerEntry {
+        endpoint: Endpoint::Addr(addr),
+        peer_id: None,
+    }
+}
+
+/// A peer from a dictionary list, `None` if it's malformed.
+fn dict_peer(peer: &ValueRef<'_>) -> Option<PeerEntry> {
+    let ip = peer.dict_find_str_value("ip")?;
+    let port = peer
+        .dict_find_int_value("port")
+        .filter(|&p| p > 0 && p <= u16::MAX as i64)? as u16;
+    let endpoint = match ip.parse::<IpAddr>() {
+        Ok(ip) => Endpoint::Addr(SocketAddr::new(ip, port)),
+        Err(_) if !ip.is_empty() => Endpoint::Host(ip.to_string(), port),
+        Err(_) => return None,
+    };
+    let peer_id = peer
+        .dict_find("peer id")
+        .and_then(ValueRef::as_bytes)
+        .and_then(Sha1Hash::from_bytes);
+    Some(PeerEntry { endpoint, peer_id })
+}
+
+pub fn parse_scrape_response(body: &[u8], info_hash: &Sha1Hash) -> Result<ScrapeResponse> {
+    // The keys of `files` are raw info-hashes which aren't valid dictionary
+    // keys for our decoder, so walk the dictionaries as raw bytes
+    let items = raw_dict_items(body)?;
+    if let Some(reason) = find_raw(&items, b"failure reason") {
+        return match ValueRef::decode(reason)?.as_str() {
+            Some(reason) => Err(Error::TrackerFailure(reason.to_string())),
+            None => Err(Error::InvalidTrackerResponse),
+        };
+    }
+    let files = find_raw(&items, b"files").ok_or(Error::InvalidTrackerResponse)?;
+    let files = raw_dict_items(files)?;
+    let stats = find_raw(&files, info_hash).ok_or(Error::InvalidTrackerResponse)?;
+
+    let d = ValueRef::decode_with_limits(stats, Some(2), Some(20))?;
+    if !d.is_dict() {
+        return Err(Error::InvalidTrackerResponse);
+    }
+    let count = |key| {
+        d.dict_find_int_value(key)
+            .filter(|&n| n >= 0)
+            .map(|n| n as u64)
+            .unwrap_or(0)
+    };
+    Ok(ScrapeResponse {
+        complete: count("complete"),
+        incomplete: count("incomplete"),
+        downloaded: count("downloaded"),
+    })
+}
+
+/// Scrape responses nest no deeper than this.
+const MAX_SCRAPE_DEPTH: usize = 4;
+
+/// The keys of the dictionary in `buf` along with the raw bytes of their
+/// values. Unlike the decoder this accepts keys that aren't UTF-8.
+fn raw_dict_items(buf: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+    let len = raw_value_len(buf, 0)?;
+    if buf[0] != b'd' {
+        return Err(Error::InvalidTrackerResponse);
+    }
+    let mut items = vec![];
+    let mut pos = 1;
+    while pos < len - 1 {
+        let key_len = raw_value_len(&buf[pos..], 0)?;
+        let key = raw_string(&buf[pos..pos + key_len]).ok_or(Error::InvalidTrackerResponse)?;
+        pos += key_len;
+        if pos >= len - 1 {
+            return Err(Error::InvalidTrackerResponse);
+        }
+        let value_len = raw_value_len(&buf[pos..], 0)?;
+        items.push((key, &buf[pos..pos + value_len]));
+        pos += value_len;
+    }
+    Ok(items)
+}
+
+fn find_raw<'a>(items: &[(&[u8], &'a [u8])], key: &[u8]) -> Option<&'a [u8]> {
+    items.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+/// The length of the bencoded value at the start of `buf`.
+fn raw_value_len(buf: &[u8], depth: usize) -> Result<usize> {
+    let invalid = || Error::InvalidTrackerResponse;
+    match buf.first() {
+        Some(b'i') => Ok(buf.iter().position(|&b| b == b'e').ok_or_else(invalid)? + 1),
+        Some(b'0'..=b'9') => {
+            let colon = buf.iter().position(|&b| b == b':').ok_or_else(invalid)?;
+            let len: usize = std::str::from_utf8(&buf[..colon])
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(invalid)?;
+            let end = colon.checked_add(1 + len).ok_or_else(invalid)?;
+            if end > buf.len() {
+                return Err(invalid());
+            }
+            Ok(end)
+        }
+        Some(b'l') | Some(b'd') if depth < MAX_SCRAPE_DEPTH => {
+            let mut pos = 1;
+            loop {
+                match buf.get(pos) {
+                    Some(b'e') => return Ok(pos + 1),
+                    Some(_) => pos += raw_value_len(&buf[pos..], depth + 1)?,
+                    None => return Err(invalid()),
+                }
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// The content of a raw byte string, `None` if it's not a string.
+fn raw_string(buf: &[u8]) -> Option<&[u8]> {
+    if !buf.first()?.is_ascii_digit() {
+        return None;
+    }
+    let colon = buf.iter().position(|&b| b == b':')?;
+    Some(&buf[colon + 1..])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::test::{response, serve};
+    use crate::tracker::{Event, DEFAULT_INTERVAL};
+
+    fn request(url: String) -> TrackerRequest {
+        TrackerRequest {
+            url,
+            info_hash: Sha1Hash::from([0xab; 20]),
+            peer_id: Sha1Hash::from(*b"-TR0100-abcdefghijkl"),
+            listen_port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: Event::Started,
+            key: 0x1234,
+            ..TrackerRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_announce() {
+        let body = b"d8:intervali900e12:min intervali60e8:completei3e10:incompletei4e5:peers12:\x01\x02\x03\x04\x1a\xe1\x05\x06\x07\x08\x00\x506:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe110:tracker id3:abce";
+        let (addr, server) = serve(vec![response(200, &[], body)]);
+        let mut req = request(format!("http://{}/announce?passkey=x", addr));
+        req.tracker_id = Some("a b".to_string());
+        let r = announce(&req).unwrap();
+
+        assert_eq!(Duration::from_secs(900), r.interval);
+        assert_eq!(Duration::from_secs(60), r.min_interval);
+        assert_eq!(
+            (Some(3), Some(4), None),
+            (r.complete, r.incomplete, r.downloaded)
+        );
+        assert_eq!(Some("abc".to_string()), r.tracker_id);
+        let peers: Vec<_> = r.peers.iter().map(|p| p.endpoint.to_string()).collect();
+        assert_eq!(vec!["1.2.3.4:6881", "5.6.7.8:80", "[::1]:6881"], peers);
+
+        let requests = server.join().unwrap();
+        let line = requests[0].lines().next().unwrap();
+        assert_eq!(
+            "GET /announce?passkey=x&info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+             &peer_id=-TR0100-abcdefghijkl&port=6881&uploaded=1&downloaded=2&left=3&compact=1\
+             &numwant=50&key=00001234&event=started&trackerid=a%20b HTTP/1.1",
+            line
+        );
+    }
+
+    #[test]
+    fn test_announce_errors() {
+        let (addr, server) = serve(vec![
+            response(200, &[], b"d14:failure reason6:bannede"),
+            response(500, &[], b""),
+            response(200, &[], b"not bencode"),
+        ]);
+        let req = request(format!("http://{}/announce", addr));
+        match announce(&req) {
+            Err(Error::TrackerFailure(reason)) => assert_eq!("banned", reason),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(announce(&req), Err(Error::HttpStatus(500))));
+        assert!(announce(&req).is_err());
+        server.join().unwrap();
+
+        let req = request("ftp://example.com/announce".to_string());
+        assert!(matches!(announce(&req), Err(Error::UnsupportedUrlProtocol)));
+
+        let r = parse_announce_response(b"d8:intervali9223372036854775807e5:peers0:e").unwrap();
+        assert_eq!(tracker::MAX_INTERVAL, r.interval);
+    }
+
+    #[test]
+    fn test_dict_peers() {
+        let body = b"d15:warning message4:slow5:peersld2:ip7:1.2.3.47:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti80eed2:ip11:example.org4:porti81eed2:ip7:1.2.3.44:porti0eeee";
+        let r = parse_announce_response(body).unwrap();
+        assert_eq!(DEFAULT_INTERVAL, r.interval);
+        assert_eq!(Some("slow".to_string()), r.warning_message);
+        assert_eq!(
+            vec![
+                PeerEntry {
+                    endpoint: Endpoint::Addr("1.2.3.4:80".parse().unwrap()),
+                    peer_id: Some(Sha1Hash::from([b'a'; 20])),
+                },
+                PeerEntry {
+                    endpoint: Endpoint::Host("example.org".to_string(), 81),
+                    peer_id: None,
+                },
+            ],
+            r.peers
+        );
+    }
+
+    #[test]
+    fn test_scrape() {
+        assert_eq!(
+            Some("http://a/x/scrape?k=1".to_string()),
+            scrape_url("http://a/x/announce?k=1")
+        );
+        assert_eq!(
+            Some("http://a/scrape.php".to_string()),
+            scrape_url("http://a/announce.php")
+        );
+        assert_eq!(None, scrape_url("http://a/x/ann"));
+        assert_eq!(None, scrape_url("http://a/announce/x"));
+
+        let info_hash = Sha1Hash::from([0xab; 20]);
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&info_hash);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let (addr, server) = serve(vec![response(200, &[], &body)]);
+        let r = scrape(
+            &format!("http://{}/announce", addr),
+            &info_hash,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(
+            ScrapeResponse {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50,
+            },
+            r
+        );
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /scrape?info_hash=%AB%AB"));
+
+        assert!(matches!(
+            parse_scrape_response(b"d5:filesdee", &info_hash),
+            Err(Error::InvalidTrackerResponse)
+        ));
+        assert!(matches!(
+            parse_scrape_response(b"d14:failure reason4:nopee", &info_hash),
+            Err(Error::TrackerFailure(_))
+        ));
+
+        // Our hash showing up inside a string isn't an entry of `files`
+        let mut decoy = b"20:".to_vec();
+        decoy.extend_from_slice(&info_hash);
+        decoy.extend_from_slice(b"d8:completei9ee");
+        let mut body = format!("d7:comment{}:", decoy.len()).into_bytes();
+        body.extend_from_slice(&decoy);
+        body.extend_from_slice(b"5:filesdee");
+        assert!(matches!(
+            parse_scrape_response(&body, &info_hash),
+            Err(Error::InvalidTrackerResponse)
+        ));
+        body.truncate(body.len() - 2);
+        body.extend_from_slice(b"20:");
+        body.extend_from_slice(&info_hash);
+        body.extend_from_slice(b"d8:completei5eeee");
+        assert_eq!(
+            5,
+            parse_scrape_response(&body, &info_hash).unwrap().complete
+        );
+
+        for body in &[
+            &b"d5:filesd20:"[..],
+            b"d5:files",
+            b"d5:filesi1ee",
+            b"le",
+            b"",
+        ] {
+            assert!(parse_scrape_response(body, &info_hash).is_err());
+        }
+    }
+}
@@ -1,21 +1,432 @@
-use crate::fs::FileStorage;
+use crate::error::{Error, Result};
+use crate::fs::{FileEntry, FileStorage};
+use bencode::ValueRef;
+use common::sha1::Sha1Hash;
 use defaults::Defaults;
+use std::path::{Path, PathBuf};
 
-#[derive(Default)]
+/// A tracker URL along with the tier it belongs to (BEP 12).
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceEntry {
+    pub url: String,
+    pub tier: u8,
+}
+
+impl AnnounceEntry {
+    pub fn new(url: String, tier: u8) -> Self {
+        Self { url, tier }
+    }
+}
+
+/// The metainfo of a torrent, as loaded from a .torrent file.
+#[derive(Debug, Default, Clone)]
 pub struct TorrentInfo {
     files: FileStorage,
+
+    /// SHA-1 of the bencoded info dictionary, as it appeared in the file
+    info_hash: Sha1Hash,
+
+    /// The bencoded info dictionary, served to peers downloading metadata
+    info_section: Vec<u8>,
+
+    /// concatenated SHA1 hashes of pieces
+    piece_hashes: Vec<u8>,
+
+    trackers: Vec<AnnounceEntry>,
+    url_seeds: Vec<String>,
+    http_seeds: Vec<String>,
+    nodes: Vec<(String, u16)>,
+    comment: String,
+    created_by: String,
+    creation_date: Option<i64>,
+    private: bool,
 }
 
 impl TorrentInfo {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_file_with_limits(path, &TorrentLimits::default())
+    }
+
+    pub fn from_file_with_limits<P: AsRef<Path>>(path: P, limits: &TorrentLimits) -> Result<Self> {
+        let path = path.as_ref();
+        if std::fs::metadata(path)?.len() > limits.max_buf_size as u64 {
+            return Err(Error::TorrentBufferTooLarge);
+        }
+        let buf = std::fs::read(path)?;
+        Self::from_bytes_with_limits(&buf, limits)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        Self::from_bytes_with_limits(buf, &TorrentLimits::default())
+    }
+
+    pub fn from_bytes_with_limits(buf: &[u8], limits: &TorrentLimits) -> Result<Self> {
+        if buf.len() > limits.max_buf_size {
+            return Err(Error::TorrentBufferTooLarge);
+        }
+        let root = decode_with_limits(buf, limits)?;
+        Self::parse_torrent_file(buf, &root, limits)
+    }
+
+    /// Loads a torrent from just its info dictionary, e.g. as received from
+    /// peers for a magnet link.
+    pub fn from_info_section(buf: &[u8], limits: &TorrentLimits) -> Result<Self> {
+        if buf.len() > limits.max_buf_size {
+            return Err(Error::TorrentBufferTooLarge);
+        }
+        let info = decode_with_limits(buf, limits)?;
+        let mut t = Self::new();
+        t.parse_info_section(buf, &info, limits)?;
+        Ok(t)
+    }
+
+    fn parse_torrent_file(buf: &[u8], root: &ValueRef, limits: &TorrentLimits) -> Result<Self> {
+        if !root.is_dict() {
+            return Err(Error::TorrentIsNoDict);
+        }
+
+        let info = root.dict_find("info").ok_or(Error::TorrentMissingInfo)?;
+        let mut t = Self::new();
+        // Hash the info dictionary exactly as it was given to us.
+        // Re-encoding would produce a different hash for torrents that
+        // aren't canonical.
+        let info_section = ValueRef::dict_find_raw(buf, "info").ok_or(Error::TorrentMissingInfo)?;
+        t.parse_info_section(info_section, info, limits)?;
+
+        if let Some(tiers) = root.dict_find_list_value("announce-list") {
+            for (tier, urls) in tiers.iter().enumerate() {
+                let urls = match urls.as_list() {
+                    Some(urls) => urls,
+                    None => continue,
+                };
+                for url in urls {
+                    if let Some(url) = url.as_str().map(str::trim) {
+                        if !url.is_empty() {
+                            let tier = tier.min(u8::MAX as usize) as u8;
+                            t.trackers.push(AnnounceEntry::new(url.to_string(), tier));
+                        }
+                    }
+                }
+            }
+        }
+
+        // "announce" is only used if there's no (usable) announce-list
+        if t.trackers.is_empty() {
+            if let Some(url) = root.dict_find_str_value("announce").map(str::trim) {
+                if !url.is_empty() {
+                    t.trackers.push(AnnounceEntry::new(url.to_string(), 0));
+                }
+            }
+        }
+
+        match root.dict_find("url-list") {
+            Some(ValueRef::Bytes(_)) => {
+                if let Some(url) = root.dict_find_str_value("url-list") {
+                    push_url(&mut t.url_seeds, url);
+                }
+            }
+            Some(ValueRef::List(urls)) => {
+                for url in urls.iter().filter_map(|u| u.as_str()) {
+                    push_url(&mut t.url_seeds, url);
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(urls) = root.dict_find_list_value("httpseeds") {
+            for url in urls.iter().filter_map(|u| u.as_str()) {
+                push_url(&mut t.http_seeds, url);
+            }
+        }
+
+        if let Some(nodes) = root.dict_find_list_value("nodes") {
+            for node in nodes {
+                let host = node.list_string_value_at(0);
+                let port = node.list_int_value_at(1);
+                if let (Some(host), Some(port)) = (host, port) {
+                    if port > 0 && port <= u16::MAX as i64 {
+                        t.nodes.push((host.to_string(), port as u16));
+                    }
+                }
+            }
+        }
+
+        t.comment = find_utf8_str(root, "comment")
+            .unwrap_or_default()
+            .to_string();
+        t.created_by = root
+            .dict_find_str_value("created by")
+            .unwrap_or_default()
+            .to_string();
+        t.creation_date = root.dict_find_int_value("creation date");
+
+        Ok(t)
+    }
+
+    /// Parses the info dictionary `info`, decoded from `info_section`.
+    fn parse_info_section(
+        &mut self,
+        info_section: &[u8],
+        info: &ValueRef,
+        limits: &TorrentLimits,
+    ) -> Result<()> {
+        if !info.is_dict() {
+            return Err(Error::TorrentInfoNoDict);
+        }
+
+        let piece_len = info
+            .dict_find_int_value("piece length")
+            .ok_or(Error::TorrentMissingPieceLength)?;
+        if piece_len <= 0 || piece_len > i32::MAX as i64 / 2 {
+            return Err(Error::TorrentInvalidPieceLength);
+        }
+
+        let name = find_utf8_str(info, "name").ok_or(Error::TorrentMissingName)?;
+        let name = sanitize_segment(name).ok_or(Error::TorrentInvalidName)?;
+
+        let mut files = FileStorage::new();
+        files.set_piece_len(piece_len as usize);
+        if let Some(len) = info.dict_find("length") {
+            if info.dict_find("files").is_some() {
+                // Can't have `files` key at the same time
+                return Err(Error::TorrentFileParseFailed);
+            }
+            let size = len.as_int().ok_or(Error::TorrentInvalidLength)?;
+            let mut entry = parse_file_attributes(info)?;
+            entry.path = PathBuf::from(&name);
+            add_file(&mut files, entry, size)?;
+        } else if let Some(list) = info.dict_find("files") {
+            let list = list.as_list().ok_or(Error::TorrentFileParseFailed)?;
+            for file in list {
+                let mut entry = parse_file_attributes(file)?;
+                entry.path = PathBuf::from(&name);
+                let path = find_utf8_list(file, "path").ok_or(Error::TorrentFileParseFailed)?;
+                for segment in path {
+                    let segment = segment.as_str().ok_or(Error::TorrentFileParseFailed)?;
+                    if let Some(segment) = sanitize_segment(segment) {
+                        entry.path.push(segment);
+                    }
+                }
+                // Like libtorrent, give a file without a usable path a
+                // placeholder name rather than the torrent directory's
+                if entry.path == Path::new(&name) {
+                    entry.path.push("_");
+                }
+                let size = file
+                    .dict_find_int_value("length")
+                    .ok_or(Error::TorrentInvalidLength)?;
+                add_file(&mut files, entry, size)?;
+            }
+        } else {
+            return Err(Error::TorrentFileParseFailed);
+        }
+        files.set_name(name);
+
+        let pieces = info
+            .dict_find_str("pieces")
+            .and_then(|p| p.as_bytes())
+            .ok_or(Error::TorrentMissingPieces)?;
+        let num_pieces = pieces.len() / 20;
+        if num_pieces > limits.max_pieces {
+            return Err(Error::TorrentTooManyPieces);
+        }
+        let expected = files.total_size().div_ceil(piece_len as u64);
+        if pieces.len() % 20 != 0 || num_pieces as u64 != expected {
+            return Err(Error::TorrentInvalidHashes);
+        }
+        files.set_num_pieces(num_pieces);
+
+        self.info_section = info_section.to_vec();
+        self.info_hash = Sha1Hash::update(&self.info_section);
+        self.files = files;
+        self.piece_hashes = pieces.to_vec();
+        self.private = info.dict_find_int_value("private") == Some(1);
+        Ok(())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.files.is_valid()
+    }
+
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.info_hash
+    }
+
+    pub fn info_section(&self) -> &[u8] {
+        &self.info_section
+    }
+
+    pub fn files(&self) -> &FileStorage {
+        &self.files
+    }
+
+    pub fn name(&self) -> &str {
+        self.files.name()
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.files.total_size()
+    }
+
+    pub fn piece_len(&self) -> usize {
+        self.files.piece_len()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.files.num_pieces()
+    }
+
+    pub fn piece_size(&self, index: usize) -> usize {
+        self.files.piece_size(index)
+    }
+
+    /// Expected SHA-1 hash of the given piece.
+    pub fn hash_for_piece(&self, index: usize) -> Option<Sha1Hash> {
+        let start = index * 20;
+        Sha1Hash::from_bytes(self.piece_hashes.get(start..start + 20)?)
+    }
+
+    pub fn trackers(&self) -> &[AnnounceEntry] {
+        &self.trackers
+    }
+
+    pub fn url_seeds(&self) -> &[String] {
+        &self.url_seeds
+    }
+
+    pub fn http_seeds(&self) -> &[String] {
+        &self.http_seeds
+    }
+
+    pub fn nodes(&self) -> &[(String, u16)] {
+        &self.nodes
+    }
+
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+
+    pub fn created_by(&self) -> &str {
+        &self.created_by
+    }
+
+    /// Creation time in seconds since epoch, if present in the torrent.
+    pub fn creation_date(&self) -> Option<i64> {
+        self.creation_date
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+}
+
+/// Decodes untrusted bencoded data, reporting the limits it violates with
+/// their own error.
+pub(crate) fn decode_with_limits<'a>(
+    buf: &'a [u8],
+    limits: &TorrentLimits,
+) -> Result<ValueRef<'a>> {
+    let depth = Some(limits.max_decode_depth);
+    let tokens = Some(limits.max_decode_tokens);
+    ValueRef::decode_with_limits(buf, depth, tokens).map_err(|e| match e {
+        bencode::Error::DepthLimit => Error::DecodeDepthLimitExceeded,
+        bencode::Error::ItemLimit => Error::DecodeTokenLimitExceeded,
+        e => Error::Bencode(e),
+    })
+}
+
+/// Looks up `key`, preferring its `.utf-8` variant which some clients
+/// write next to the plain key.
+fn find_utf8_str<'a>(dict: &ValueRef<'a>, key: &str) -> Option<&'a str> {
+    dict.dict_find_str_value(&format!("{}.utf-8", key))
+        .or_else(|| dict.dict_find_str_value(key))
+}
+
+fn find_utf8_list<'a, 'b>(dict: &'b ValueRef<'a>, key: &str) -> Option<&'b [ValueRef<'a>]> {
+    dict.dict_find_list_value(&format!("{}.utf-8", key))
+        .or_else(|| dict.dict_find_list_value(key))
+}
+
+/// Makes sure a path element from the torrent can't escape the download
+/// directory. Returns `None` if nothing usable is left.
+fn sanitize_segment(segment: &str) -> Option<String> {
+    match segment.trim() {
+        "" | "." | ".." => None,
+        s => Some(s.replace(['/', '\\'], "_")),
+    }
+}
+
+fn parse_file_attributes(dict: &ValueRef) -> Result<FileEntry> {
+    if !dict.is_dict() {
+        return Err(Error::TorrentFileParseFailed);
+    }
+
+    let mut entry = FileEntry::default();
+    if let Some(attr) = dict.dict_find_str_value("attr") {
+        for c in attr.chars() {
+            match c {
+                'p' => entry.pad_file = true,
+                'h' => entry.hidden_attr = true,
+                'x' => entry.executable_attr = true,
+                'l' => entry.symlink_attr = true,
+                _ => {}
+            }
+        }
+    }
+
+    if entry.symlink_attr {
+        if let Some(target) = dict.dict_find_list_value("symlink path") {
+            for segment in target.iter().filter_map(|s| s.as_str()) {
+                if let Some(segment) = sanitize_segment(segment) {
+                    entry.symlink_path.push(segment);
+                }
+            }
+        }
+    }
+
+    entry.modified_time = dict.dict_find_int_value("mtime").unwrap_or_default();
+    if let Some(hash) = dict.dict_find_str("sha1").and_then(|h| h.as_bytes()) {
+        entry.file_hash = Sha1Hash::from_bytes(hash).ok_or(Error::TorrentFileParseFailed)?;
+    }
+    Ok(entry)
+}
+
+fn add_file(files: &mut FileStorage, mut entry: FileEntry, size: i64) -> Result<()> {
+    if size < 0 {
+        return Err(Error::TorrentInvalidLength);
+    }
+    match files.total_size().checked_add(size as u64) {
+        Some(total) if total <= i64::MAX as u64 => {}
+        _ => return Err(Error::TorrentInvalidLength),
+    }
+
+    // Old versions of some clients marked pad files by name only
+    let legacy_pad = entry
+        .path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("_____padding_file_"));
+    entry.pad_file |= legacy_pad;
+    entry.size = size as u64;
+    files.add_file(entry);
+    Ok(())
+}
+
+fn push_url(urls: &mut Vec<String>, url: &str) {
+    let url = url.trim();
+    if !url.is_empty() {
+        urls.push(url.to_string());
+    }
 }
 
 /// This object holds configuration options for limits to use when loading
 /// torrents. They are meant to prevent loading potentially malicious torrents
 /// that cause excessive memory allocations.
-#[derive(Defaults)]
+#[derive(Debug, Clone, Defaults)]
 pub struct TorrentLimits {
     #[def = "6000000"]
     pub max_buf_size: usize,
@@ -32,3 +443,218 @@ pub struct TorrentLimits {
     #[def = "2000000"]
     pub max_decode_tokens: usize,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_single_file() {
+        let bytes = include_bytes!("../bencode/tests/update.torrent");
+        let t = TorrentInfo::from_bytes(bytes).unwrap();
+
+        assert_eq!("update.txt", t.name());
+        assert_eq!(359, t.total_size());
+        assert_eq!(16384, t.piece_len());
+        assert_eq!(1, t.num_pieces());
+        assert_eq!(359, t.piece_size(0));
+        assert_eq!(
+            "07691fe65b4c51db229460ba527eb1c7487f2477",
+            t.hash_for_piece(0).unwrap().to_string()
+        );
+        assert!(t.hash_for_piece(1).is_none());
+
+        assert_eq!(
+            &[AnnounceEntry::new(
+                "http://0.0.0.0:52585/announce".to_string(),
+                0
+            )],
+            t.trackers()
+        );
+        assert_eq!("update.txt", t.comment());
+        assert_eq!("BitTorrent/7.10.5", t.created_by());
+        assert_eq!(Some(1577614375), t.creation_date());
+        assert!(!t.is_private());
+
+        let files = t.files();
+        assert_eq!(1, files.num_files());
+        assert_eq!(Path::new("update.txt"), files.file_at(0).path);
+        assert_eq!(0, files.file_at(0).offset);
+        assert_eq!(359, files.file_at(0).size);
+    }
+
+    #[test]
+    fn test_multi_file() {
+        let bytes = b"d\
+            13:announce-listll5:udp:a5:udp:bel5:udp:cee\
+            5:nodesll4:hosti6881eel4:bad!i0eee\
+            8:url-list6:http:w\
+            9:httpseedsl6:http:he\
+            4:infod\
+                5:filesl\
+                    d6:lengthi3e4:pathl3:dir5:a.txtee\
+                    d4:attr1:p6:lengthi13e4:pathl4:.pad2:13ee\
+                    d4:attr2:xh6:lengthi16e4:pathl2:..5:b.txtee\
+                    d4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl3:dir5:a.txtee\
+                    d6:lengthi0e4:pathl2:..1:.ee\
+                e\
+                4:name4:root\
+                12:piece lengthi16e\
+                6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb\
+                7:privatei1e\
+            ee";
+        let t = TorrentInfo::from_bytes(bytes).unwrap();
+
+        assert_eq!("root", t.name());
+        assert_eq!(32, t.total_size());
+        assert_eq!(2, t.num_pieces());
+        assert!(t.is_private());
+
+        let trackers: Vec<_> = t.trackers().iter().map(|a| (&a.url[..], a.tier)).collect();
+        assert_eq!(vec![("udp:a", 0), ("udp:b", 0), ("udp:c", 1)], trackers);
+        assert_eq!(&[(String::from("host"), 6881)], t.nodes());
+        assert_eq!(&[String::from("http:w")], t.url_seeds());
+        assert_eq!(&[String::from("http:h")], t.http_seeds());
+
+        let files = t.files().files();
+        assert_eq!(5, files.len());
+
+        assert_eq!(Path::new("root/dir/a.txt"), files[0].path);
+        assert_eq!((0, 3), (files[0].offset, files[0].size));
+
+        assert!(files[1].pad_file);
+        assert_eq!((3, 13), (files[1].offset, files[1].size));
+
+        // ".." must not escape the torrent directory
+        assert_eq!(Path::new("root/b.txt"), files[2].path);
+        assert!(files[2].executable_attr && files[2].hidden_attr);
+        assert_eq!((16, 16), (files[2].offset, files[2].size));
+
+        assert!(files[3].symlink_attr);
+        assert_eq!(Path::new("dir/a.txt"), files[3].symlink_path);
+        assert_eq!((32, 0), (files[3].offset, files[3].size));
+
+        // Nothing is left of the path, it gets a placeholder name
+        assert_eq!(Path::new("root/_"), files[4].path);
+    }
+
+    macro_rules! assert_err {
+        ($result: expr, $err: pat) => {
+            let e = $result.unwrap_err();
+            assert!(matches!(e, $err), "{:?}", e);
+        };
+    }
+
+    macro_rules! assert_parse_err {
+        ($bytes: expr, $err: pat) => {
+            assert_err!(TorrentInfo::from_bytes($bytes), $err);
+        };
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_parse_err!(b"le", Error::TorrentIsNoDict);
+        assert_parse_err!(b"de", Error::TorrentMissingInfo);
+        assert_parse_err!(b"d4:infoi1ee", Error::TorrentInfoNoDict);
+        assert_parse_err!(b"d4:infod4:name1:aee", Error::TorrentMissingPieceLength);
+        assert_parse_err!(
+            b"d4:infod12:piece lengthi0eee",
+            Error::TorrentInvalidPieceLength
+        );
+        assert_parse_err!(b"d4:infod12:piece lengthi1eee", Error::TorrentMissingName);
+        assert_parse_err!(
+            b"d4:infod4:name2:..12:piece lengthi1eee",
+            Error::TorrentInvalidName
+        );
+        assert_parse_err!(
+            b"d4:infod6:lengthi-1e4:name1:a12:piece lengthi1eee",
+            Error::TorrentInvalidLength
+        );
+        assert_parse_err!(
+            b"d4:infod4:name1:a12:piece lengthi1eee",
+            Error::TorrentFileParseFailed
+        );
+        assert_parse_err!(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1eee",
+            Error::TorrentMissingPieces
+        );
+        assert_parse_err!(
+            b"d4:infod6:lengthi17e4:name1:a12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+            Error::TorrentInvalidHashes
+        );
+        assert_parse_err!(b"d4:info", Error::Bencode(_));
+    }
+
+    #[test]
+    fn test_limits() {
+        let bytes = include_bytes!("../bencode/tests/update.torrent");
+
+        let limits = TorrentLimits {
+            max_buf_size: bytes.len() - 1,
+            ..TorrentLimits::default()
+        };
+        assert_err!(
+            TorrentInfo::from_bytes_with_limits(bytes, &limits),
+            Error::TorrentBufferTooLarge
+        );
+
+        let limits = TorrentLimits {
+            max_decode_depth: 0,
+            ..TorrentLimits::default()
+        };
+        assert_err!(
+            TorrentInfo::from_bytes_with_limits(bytes, &limits),
+            Error::DecodeDepthLimitExceeded
+        );
+
+        let limits = TorrentLimits {
+            max_decode_tokens: 5,
+            ..TorrentLimits::default()
+        };
+        assert_err!(
+            TorrentInfo::from_bytes_with_limits(bytes, &limits),
+            Error::DecodeTokenLimitExceeded
+        );
+
+        let limits = TorrentLimits {
+            max_pieces: 0,
+            ..TorrentLimits::default()
+        };
+        assert_err!(
+            TorrentInfo::from_bytes_with_limits(bytes, &limits),
+            Error::TorrentTooManyPieces
+        );
+
+        let limits = TorrentLimits {
+            max_buf_size: bytes.len(),
+            max_pieces: 1,
+            max_decode_depth: 2,
+            max_decode_tokens: 20,
+        };
+        TorrentInfo::from_bytes_with_limits(bytes, &limits).unwrap();
+    }
+
+    #[test]
+    fn test_info_hash() {
+        let bytes = include_bytes!("../bencode/tests/update.torrent");
+        let t = TorrentInfo::from_bytes(bytes).unwrap();
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let info = &bytes[start..bytes.len() - 1];
+        assert_eq!(&Sha1Hash::update(info), t.info_hash());
+
+        // keys out of order, re-encoding would sort them
+        let info = b"d12:piece lengthi16e4:name1:a6:lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(info);
+        bytes.push(b'e');
+        let t = TorrentInfo::from_bytes(&bytes).unwrap();
+        assert_eq!(&Sha1Hash::update(info), t.info_hash());
+        let encoded = ValueRef::decode(info).unwrap().to_vec();
+        assert_ne!(&Sha1Hash::update(&encoded), t.info_hash());
+        assert_eq!(&info[..], t.info_section());
+
+        let t = TorrentInfo::from_info_section(info, &TorrentLimits::default()).unwrap();
+        assert_eq!(&Sha1Hash::update(info), t.info_hash());
+        assert_eq!("a", t.name());
+    }
+}
@@ -0,0 +1,57 @@
+use common::sha1::Sha1Hash;
+use common::sha256::Sha256Hash;
+
+/// The info-hashes of a torrent. A v1 torrent only has a SHA-1 hash, a v2
+/// torrent only a SHA-256 hash and a hybrid torrent has both. A hash that
+/// is all zeroes is not present.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct InfoHash {
+    pub v1: Sha1Hash,
+    pub v2: Sha256Hash,
+}
+
+impl InfoHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn has_v1(&self) -> bool {
+        !self.v1.all_zeroes()
+    }
+
+    pub fn has_v2(&self) -> bool {
+        !self.v2.all_zeroes()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.has_v1() || self.has_v2()
+    }
+
+    /// The hash to use on the wire and in the DHT: the v1 hash if there
+    /// is one, the v2 hash truncated to 20 bytes otherwise.
+    pub fn get_best(&self) -> Sha1Hash {
+        if self.has_v1() {
+            self.v1.clone()
+        } else {
+            Sha1Hash::from_bytes(&self.v2[..20]).unwrap()
+        }
+    }
+}
+
+impl From<Sha1Hash> for InfoHash {
+    fn from(v1: Sha1Hash) -> Self {
+        Self {
+            v1,
+            v2: Sha256Hash::new(),
+        }
+    }
+}
+
+impl From<Sha256Hash> for InfoHash {
+    fn from(v2: Sha256Hash) -> Self {
+        Self {
+            v1: Sha1Hash::new(),
+            v2,
+        }
+    }
+}
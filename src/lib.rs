@@ -1,13 +1,38 @@
 #![allow(dead_code)]
 
 // Based on libtorrent (commit head: b2c3b4dbf8bdf6d3b5fa59c2914f7698579866b9)
-mod download_priority;
-mod error;
-mod flags;
+pub mod active_torrent;
+pub mod bitfield;
+pub mod create_torrent;
+pub mod download_priority;
+pub mod endpoint;
+pub mod error;
+pub mod extensions;
+pub mod flags;
 pub mod fs;
-mod info;
-mod magnet_uri;
-mod params;
-mod status;
+pub mod hasher;
+pub mod http;
+pub mod http_tracker;
+pub mod info;
+pub mod info_hash;
+pub mod lsd;
+pub mod magnet_uri;
+pub mod params;
+pub mod peer_connection;
+pub mod peer_message;
+pub mod peer_request;
+pub mod piece_picker;
+pub mod resume_data;
+pub mod session;
+pub mod status;
+pub mod storage;
 mod str_utl;
+#[cfg(test)]
+pub(crate) mod test_util;
 mod torrent;
+pub mod tracker;
+pub mod tracker_list;
+pub mod udp_tracker;
+pub mod ut_metadata;
+pub mod ut_pex;
+pub mod web_seed;
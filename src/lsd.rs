@@ -0,0 +1,525 @@
+//! Local service discovery (BEP 14): announcing the torrents we're in to
+//! the local network over multicast and learning of local peers in the
+//! same torrents.
+//!
+//! ```text
+//! BT-SEARCH * HTTP/1.1\r\n
+//! Host: 239.192.152.143:6771\r\n
+//! Port: <listen port>\r\n
+//! Infohash: <hex info-hash>\r\n
+//! cookie: <cookie>\r\n
+//! \r\n
+//! \r\n
+//! ```
+//!
+//! Multicast is looped back to the sender, so every announce carries a
+//! cookie that is random per instance to recognize our own.
+
+use crate::active_torrent::ActiveTorrent;
+use crate::error::{Error, Result};
+use crate::flags::TorrentFlags;
+use common::hex;
+use common::random;
+use common::sha1::Sha1Hash;
+use defaults::Defaults;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const LSD_PORT: u16 = 6771;
+pub const LSD_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0xefc0, 0x152, 0x143);
+
+/// Announces larger than this are ignored.
+const MAX_MESSAGE_LEN: usize = 1400;
+
+/// Info-hashes taken from a single announce at most.
+const MAX_INFO_HASHES: usize = 16;
+
+#[derive(Debug, Clone, Defaults)]
+pub struct LsdSettings {
+    /// Time between regular announces of a torrent.
+    #[def = "Duration::from_secs(5 * 60)"]
+    pub announce_interval: Duration,
+
+    /// A torrent is announced at most this often, and announces of a
+    /// torrent from the same host arriving sooner are ignored.
+    #[def = "Duration::from_secs(60)"]
+    pub min_interval: Duration,
+}
+
+/// Sends and receives the multicast datagrams. Swapped out in tests.
+pub trait LsdTransport: Send {
+    /// Sends a datagram to the multicast group `to`.
+    fn send(&mut self, buf: &[u8], to: SocketAddr) -> io::Result<()>;
+
+    /// A datagram that was received along with its source, `None` if
+    /// there's none waiting.
+    fn recv(&mut self) -> io::Result<Option<(Vec<u8>, SocketAddr)>>;
+}
+
+/// The multicast groups of BEP 14 on all interfaces. IPv6 is used only if
+/// the socket can be set up.
+pub struct MulticastTransport {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+}
+
+impl MulticastTransport {
+    pub fn new() -> io::Result<Self> {
+        Self::bind(LSD_PORT)
+    }
+
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let v4 = bind_shared((Ipv4Addr::UNSPECIFIED, port).into())?;
+        v4.join_multicast_v4(&LSD_MULTICAST_V4, &Ipv4Addr::UNSPECIFIED)?;
+        v4.set_multicast_loop_v4(true)?;
+        v4.set_nonblocking(true)?;
+
+        let v6 = bind_shared((Ipv6Addr::UNSPECIFIED, port).into())
+            .and_then(|s| {
+                s.join_multicast_v6(&LSD_MULTICAST_V6, 0)?;
+                s.set_nonblocking(true)?;
+                Ok(s)
+            })
+            .ok();
+        Ok(Self { v4, v6 })
+    }
+}
+
+/// Binds a UDP socket that other BitTorrent clients on this machine can
+/// bind too, to run LSD alongside us.
+fn bind_shared(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        // Leave the IPv4 port to the other socket
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+impl LsdTransport for MulticastTransport {
+    fn send(&mut self, buf: &[u8], to: SocketAddr) -> io::Result<()> {
+        let socket = match (to, &self.v6) {
+            (SocketAddr::V4(_), _) => &self.v4,
+            (SocketAddr::V6(_), Some(v6)) => v6,
+            (SocketAddr::V6(_), None) => return Ok(()),
+        };
+        socket.send_to(buf, to).map(|_| ())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<(Vec<u8>, SocketAddr)>> {
+        let mut buf = [0; MAX_MESSAGE_LEN + 1];
+        for socket in std::iter::once(&self.v4).chain(&self.v6) {
+            match socket.recv_from(&mut buf) {
+                Ok((n, from)) => return Ok(Some((buf[..n].to_vec(), from))),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdMessage {
+    pub port: u16,
+    pub info_hashes: Vec<Sha1Hash>,
+    pub cookie: Option<String>,
+}
+
+impl LsdMessage {
+    /// Encodes the announce for the multicast group `to`.
+    pub fn encode(&self, to: SocketAddr) -> Vec<u8> {
+        let mut s = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            to, self.port
+        );
+        for info_hash in &self.info_hashes {
+            s.push_str(&format!("Infohash: {}\r\n", hex::to_hex(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            s.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        s.push_str("\r\n\r\n");
+        s.into_bytes()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLarge);
+        }
+        let s = std::str::from_utf8(buf).map_err(|_| Error::InvalidMessage)?;
+        let mut lines = s.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(Error::InvalidMessage);
+        }
+
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let (name, value) = match line.find(':') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(Error::InvalidMessage),
+            };
+            if name.eq_ignore_ascii_case("port") {
+                port = value.parse().ok().filter(|&p| p != 0);
+            } else if name.eq_ignore_ascii_case("infohash") {
+                let mut h = Sha1Hash::default();
+                if value.len() == 40
+                    && hex::from_hex(value.as_bytes(), &mut h[..]).is_some()
+                    && info_hashes.len() < MAX_INFO_HASHES
+                {
+                    info_hashes.push(h);
+                }
+            } else if name.eq_ignore_ascii_case("cookie") {
+                cookie = Some(value.to_string());
+            }
+        }
+        Ok(Self {
+            port: port.ok_or(Error::InvalidMessage)?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+pub struct Lsd {
+    transport: Box<dyn LsdTransport>,
+    listen_port: u16,
+    settings: LsdSettings,
+    cookie: String,
+
+    /// When each torrent was last announced.
+    announced: BTreeMap<Sha1Hash, Instant>,
+
+    /// When a torrent was last heard of from a host.
+    heard: BTreeMap<(IpAddr, Sha1Hash), Instant>,
+}
+
+impl Lsd {
+    pub fn new(transport: Box<dyn LsdTransport>, listen_port: u16, settings: LsdSettings) -> Self {
+        let mut cookie = [0; 4];
+        random::fill_bytes(&mut cookie);
+        Self {
+            transport,
+            listen_port,
+            settings,
+            cookie: hex::to_hex(&cookie),
+            announced: BTreeMap::new(),
+            heard: BTreeMap::new(),
+        }
+    }
+
+    /// Announces a torrent to both multicast groups unless it was
+    /// announced within the minimum interval. Returns whether it was. A
+    /// group we can't send to, e.g. for lack of an IPv6 route, is skipped;
+    /// it's only an error if no group could be reached.
+    pub fn announce(&mut self, info_hash: &Sha1Hash, now: Instant) -> Result<bool> {
+        if let Some(&last) = self.announced.get(info_hash) {
+            if now < last + self.settings.min_interval {
+                return Ok(false);
+            }
+        }
+        self.announced.insert(info_hash.clone(), now);
+
+        let msg = LsdMessage {
+            port: self.listen_port,
+            info_hashes: vec![info_hash.clone()],
+            cookie: Some(self.cookie.clone()),
+        };
+        let groups = [
+            SocketAddr::from((LSD_MULTICAST_V4, LSD_PORT)),
+            SocketAddr::from((LSD_MULTICAST_V6, LSD_PORT)),
+        ];
+        let mut sent = false;
+        let mut error = None;
+        for to in &groups {
+            match self.transport.send(&msg.encode(*to), *to) {
+                Ok(()) => sent = true,
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if !sent => Err(e.into()),
+            _ => Ok(true),
+        }
+    }
+
+    /// Peers announced by others since the last call, along with the
+    /// torrent they're in. Our own announces and repeated announces are
+    /// dropped.
+    pub fn poll(&mut self, now: Instant) -> Result<Vec<(Sha1Hash, SocketAddr)>> {
+        let mut peers = vec![];
+        while let Some((buf, from)) = self.transport.recv()? {
+            let msg = match LsdMessage::decode(&buf) {
+                Ok(msg) => msg,
+                Err(_) => continue,
+            };
+            if msg.cookie.as_ref() == Some(&self.cookie) {
+                continue;
+            }
+            for info_hash in msg.info_hashes {
+                let key = (from.ip(), info_hash.clone());
+                if let Some(&last) = self.heard.get(&key) {
+                    if now < last + self.settings.min_interval {
+                        continue;
+                    }
+                }
+                self.heard.insert(key, now);
+                peers.push((info_hash, SocketAddr::new(from.ip(), msg.port)));
+            }
+        }
+        let min_interval = self.settings.min_interval;
+        self.heard.retain(|_, &mut last| now < last + min_interval);
+        Ok(peers)
+    }
+
+    /// Announces the torrents that are due and hands peers found to the
+    /// torrents they're in. Torrents with `DISABLE_LSD` set and private
+    /// torrents take no part.
+    pub fn tick(&mut self, torrents: &mut [&mut ActiveTorrent], now: Instant) -> Result<()> {
+        for t in torrents.iter() {
+            if !uses_lsd(t) {
+                continue;
+            }
+            let due = match self.announced.get(t.info_hash()) {
+                Some(&last) => now >= last + self.settings.announce_interval,
+                None => true,
+            };
+            if due {
+                self.announce(t.info_hash(), now)?;
+            }
+        }
+
+        for (info_hash, addr) in self.poll(now)? {
+            for t in torrents.iter_mut() {
+                if *t.info_hash() == info_hash && uses_lsd(t) {
+                    t.add_peer(addr);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn uses_lsd(t: &ActiveTorrent) -> bool {
+    !t.flags().contains(TorrentFlags::DISABLE_LSD) && !t.info().is_private()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::info::TorrentInfo;
+    use crate::params::TorrentParams;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// Datagrams waiting to be received, with their source.
+    type Inbox = VecDeque<(Vec<u8>, SocketAddr)>;
+
+    /// A multicast group in memory. Like the real thing, it delivers
+    /// datagrams to their sender too.
+    #[derive(Default)]
+    pub(crate) struct Group {
+        inboxes: Vec<(SocketAddr, Inbox)>,
+        sent: Vec<SocketAddr>,
+
+        /// Sending to the IPv6 group fails, as without an IPv6 route.
+        no_v6: bool,
+    }
+
+    struct Member {
+        group: Arc<Mutex<Group>>,
+        index: usize,
+    }
+
+    impl LsdTransport for Member {
+        fn send(&mut self, buf: &[u8], to: SocketAddr) -> io::Result<()> {
+            let mut group = self.group.lock().unwrap();
+            if to.is_ipv6() && group.no_v6 {
+                return Err(io::ErrorKind::NetworkUnreachable.into());
+            }
+            group.sent.push(to);
+            if to.is_ipv4() {
+                let from = group.inboxes[self.index].0;
+                for (_, inbox) in &mut group.inboxes {
+                    inbox.push_back((buf.to_vec(), from));
+                }
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self) -> io::Result<Option<(Vec<u8>, SocketAddr)>> {
+            Ok(self.group.lock().unwrap().inboxes[self.index].1.pop_front())
+        }
+    }
+
+    pub(crate) fn join(group: &Arc<Mutex<Group>>, ip: [u8; 4]) -> Box<dyn LsdTransport> {
+        let mut g = group.lock().unwrap();
+        g.inboxes
+            .push((SocketAddr::from((ip, LSD_PORT)), VecDeque::new()));
+        Box::new(Member {
+            group: group.clone(),
+            index: g.inboxes.len() - 1,
+        })
+    }
+
+    fn magnet(info_hash: u8, flags: TorrentFlags) -> ActiveTorrent {
+        let mut p = TorrentParams::default();
+        p.torrent_info = Arc::new(TorrentInfo::new());
+        p.info_hash.v1 = Sha1Hash::from([info_hash; 20]);
+        p.flags = flags;
+        ActiveTorrent::new(p).unwrap()
+    }
+
+    fn discovered(t: &ActiveTorrent) -> Vec<SocketAddr> {
+        let mut swarm = t.swarm().lock().unwrap();
+        swarm
+            .take_discovered()
+            .into_iter()
+            .map(|(a, _)| a)
+            .collect()
+    }
+
+    #[test]
+    fn test_message() {
+        let msg = LsdMessage {
+            port: 6881,
+            info_hashes: vec![Sha1Hash::from([0xab; 20])],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let to = SocketAddr::from((LSD_MULTICAST_V6, LSD_PORT));
+        let buf = msg.encode(to);
+        let expected = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:152:143]:6771\r\nPort: 6881\r\n\
+             Infohash: {}\r\ncookie: c00k1e\r\n\r\n\r\n",
+            "ab".repeat(20)
+        );
+        assert_eq!(expected.as_bytes(), &buf[..]);
+        assert_eq!(msg, LsdMessage::decode(&buf).unwrap());
+
+        // Other clients don't send a cookie, bad info-hashes are skipped
+        let buf = b"BT-SEARCH * HTTP/1.1\r\nport: 1\r\nInfohash: xyz\r\n\r\n\r\n";
+        let msg = LsdMessage::decode(buf).unwrap();
+        assert_eq!((1, None), (msg.port, msg.cookie));
+        assert!(msg.info_hashes.is_empty());
+
+        assert!(LsdMessage::decode(b"BT-SEARCH * HTTP/1.1\r\n\r\n\r\n").is_err());
+        assert!(LsdMessage::decode(b"NOTIFY * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_announce() {
+        let group = Arc::new(Mutex::new(Group::default()));
+        let mut a = Lsd::new(join(&group, [10, 0, 0, 1]), 6881, LsdSettings::default());
+        let mut b = Lsd::new(join(&group, [10, 0, 0, 2]), 6882, LsdSettings::default());
+        let now = Instant::now();
+        let info_hash = Sha1Hash::from([1; 20]);
+
+        assert!(a.announce(&info_hash, now).unwrap());
+        assert_eq!(2, group.lock().unwrap().sent.len());
+        assert!(group.lock().unwrap().sent[1].is_ipv6());
+
+        // Our own announce comes back and is dropped
+        assert!(a.poll(now).unwrap().is_empty());
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        assert_eq!(vec![(info_hash.clone(), peer)], b.poll(now).unwrap());
+
+        // Announcing again is rate limited on both ends
+        assert!(!a.announce(&info_hash, now).unwrap());
+        let later = now + Duration::from_secs(30);
+        a.announced.clear();
+        assert!(a.announce(&info_hash, later).unwrap());
+        assert!(b.poll(later).unwrap().is_empty());
+        let later = now + Duration::from_secs(90);
+        assert!(a.announce(&info_hash, later).unwrap());
+        assert_eq!(vec![(info_hash, peer)], b.poll(later).unwrap());
+    }
+
+    #[test]
+    fn test_send_errors() {
+        let group = Arc::new(Mutex::new(Group {
+            no_v6: true,
+            ..Group::default()
+        }));
+        let mut a = Lsd::new(join(&group, [10, 0, 0, 1]), 6881, LsdSettings::default());
+        let mut b = Lsd::new(join(&group, [10, 0, 0, 2]), 6882, LsdSettings::default());
+        let now = Instant::now();
+        let mut a1 = magnet(1, TorrentFlags::default());
+        let mut b1 = magnet(1, TorrentFlags::default());
+
+        // The IPv6 group is skipped
+        a.tick(&mut [&mut a1], now).unwrap();
+        assert_eq!(1, group.lock().unwrap().sent.len());
+        b.tick(&mut [&mut b1], now).unwrap();
+        assert_eq!(
+            vec![SocketAddr::from(([10, 0, 0, 1], 6881))],
+            discovered(&b1)
+        );
+    }
+
+    #[test]
+    fn test_shared_port() {
+        let port = UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        // Skipped where multicast isn't available
+        let a = match MulticastTransport::bind(port) {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        // Another client on the same machine
+        let b = MulticastTransport::bind(port).unwrap();
+        assert_eq!(a.v6.is_some(), b.v6.is_some());
+    }
+
+    #[test]
+    fn test_tick() {
+        let group = Arc::new(Mutex::new(Group::default()));
+        let mut a = Lsd::new(join(&group, [10, 0, 0, 1]), 6881, LsdSettings::default());
+        let mut b = Lsd::new(join(&group, [10, 0, 0, 2]), 6882, LsdSettings::default());
+        let now = Instant::now();
+
+        let mut a1 = magnet(1, TorrentFlags::default());
+        let mut a2 = magnet(2, TorrentFlags::DISABLE_LSD);
+        let mut b1 = magnet(1, TorrentFlags::default());
+        let mut b2 = magnet(2, TorrentFlags::default());
+        let mut b3 = magnet(3, TorrentFlags::default());
+        a.tick(&mut [&mut a1, &mut a2], now).unwrap();
+        assert_eq!(2, group.lock().unwrap().sent.len());
+
+        b.tick(&mut [&mut b1, &mut b2, &mut b3], now).unwrap();
+        assert_eq!(
+            vec![SocketAddr::from(([10, 0, 0, 1], 6881))],
+            discovered(&b1)
+        );
+        assert!(discovered(&b2).is_empty());
+        assert!(discovered(&b3).is_empty());
+
+        // b announced all of its torrents, a takes the peer only for the
+        // torrent that has LSD enabled
+        a.tick(&mut [&mut a1, &mut a2], now).unwrap();
+        assert_eq!(
+            vec![SocketAddr::from(([10, 0, 0, 2], 6882))],
+            discovered(&a1)
+        );
+        assert!(discovered(&a2).is_empty());
+
+        // Nothing is due until the announce interval is up
+        let sent = group.lock().unwrap().sent.len();
+        a.tick(&mut [&mut a1, &mut a2], now + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(sent, group.lock().unwrap().sent.len());
+        a.tick(&mut [&mut a1, &mut a2], now + Duration::from_secs(300))
+            .unwrap();
+        assert_eq!(sent + 2, group.lock().unwrap().sent.len());
+    }
+}
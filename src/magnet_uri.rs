@@ -1,10 +1,12 @@
 use crate::download_priority::DownloadPriority;
 use crate::error::{Error, Result};
+use crate::info::TorrentInfo;
 use crate::params::TorrentParams;
 use crate::str_utl;
 use common::hex;
 
 use common::sha1::Sha1Hash;
+use common::sha256::Sha256Hash;
 use std::fmt;
 use std::str::FromStr;
 use url::Url;
@@ -19,15 +21,105 @@ impl FromStr for TorrentParams {
     }
 }
 
+/// Multihash prefix of a SHA-256 digest: function code 0x12, length 0x20
+const BTMH_SHA256_PREFIX: &str = "1220";
+
 impl fmt::Display for TorrentParams {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "magnet:?xt=urn:btih:")?;
-        write!(f, "{}", hex::to_hex(&self.info_hash))?;
+        let mut params: Vec<(&str, String)> = vec![];
+        if self.info_hash.has_v1() {
+            params.push((
+                "xt",
+                format!("urn:btih:{}", hex::to_hex(&self.info_hash.v1)),
+            ));
+        }
+        if self.info_hash.has_v2() {
+            let hash = hex::to_hex(&self.info_hash.v2);
+            params.push(("xt", format!("urn:btmh:{}{}", BTMH_SHA256_PREFIX, hash)));
+        }
+
+        if !self.name.is_empty() {
+            params.push(("dn", str_utl::escape_string(&self.name)));
+        }
+
+        let mut trackers: Vec<_> = self.trackers.iter().enumerate().collect();
+        trackers.sort_by_key(|&(i, _)| self.tracker_tiers.get(i).cloned().unwrap_or(0));
+        for (_, tr) in trackers {
+            params.push(("tr", str_utl::escape_string(tr)));
+        }
+
+        for ws in &self.url_seeds {
+            params.push(("ws", str_utl::escape_string(ws)));
+        }
+
+        for peer in &self.peers {
+            params.push(("x.pe", str_utl::escape_string(&peer.to_string())));
+        }
 
+        for (host, port) in &self.dht_nodes {
+            let node = if host.contains(':') {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            };
+            params.push(("dht", str_utl::escape_string(&node)));
+        }
+
+        let so = select_only(&self.file_priorities);
+        if !so.is_empty() {
+            params.push(("so", so));
+        }
+
+        write!(f, "magnet:?")?;
+        for (i, (key, value)) in params.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "&" };
+            write!(f, "{}{}={}", sep, key, value)?;
+        }
         Ok(())
     }
 }
 
+/// Generates a magnet link for a loaded torrent, including its name,
+/// trackers and web seeds.
+pub fn make_magnet_uri(info: &TorrentInfo) -> String {
+    let mut p = TorrentParams::default();
+    p.info_hash = info.info_hash().clone().into();
+    p.name = info.name().to_string();
+    for tracker in info.trackers() {
+        p.trackers.push(tracker.url.clone());
+        p.tracker_tiers.push(tracker.tier as isize);
+    }
+    p.url_seeds = info.url_seeds().to_vec();
+    p.to_string()
+}
+
+/// Compresses the indices of the files that are to be downloaded into
+/// the `so` format: a comma separated list of indices and index ranges.
+fn select_only(priorities: &[DownloadPriority]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for (i, p) in priorities.iter().enumerate() {
+        if *p == DownloadPriority::DontDownload {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == i => *last = i,
+            _ => ranges.push((i, i)),
+        }
+    }
+
+    let ranges: Vec<_> = ranges
+        .iter()
+        .map(|&(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{}-{}", first, last)
+            }
+        })
+        .collect();
+    ranges.join(",")
+}
+
 pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
     let url = Url::parse(uri)?;
     if url.scheme() != "magnet" {
@@ -56,33 +148,19 @@ pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
                 p.url_seeds.push(value.to_string());
             }
             "xt" => {
-                if !value.starts_with("urn:btih:") {
-                    continue;
-                }
-
-                let value = &value[9..];
-                let mut s = Sha1Hash::new();
-                match value.len() {
-                    40 => {
-                        hex::from_hex(value.as_bytes(), &mut s).unwrap();
-                    }
-                    32 => {
-                        let ih = str_utl::base32_decode(value.as_bytes());
-                        if ih.len() != 20 {
-                            return Err(Error::InvalidInfoHash);
-                        }
-                        s.copy_from_slice(&ih);
-                    }
-                    _ => return Err(Error::InvalidInfoHash),
+                if let Some(value) = value.strip_prefix("urn:btih:") {
+                    p.info_hash.v1 = parse_btih(value)?;
+                    has_ih = true;
+                } else if let Some(value) = value.strip_prefix("urn:btmh:") {
+                    p.info_hash.v2 = parse_btmh(value)?;
+                    has_ih = true;
                 }
-                p.info_hash = s;
-                has_ih = true;
             }
             /* Select-Only (files) */
             "so" => {
                 if value
                     .chars()
-                    .any(|c| !c.is_digit(10) && c != '-' && c != ',')
+                    .any(|c| !c.is_ascii_digit() && c != '-' && c != ',')
                 {
                     continue;
                 }
@@ -90,6 +168,7 @@ pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
                 let mut value = value.as_bytes();
                 while !value.is_empty() {
                     let (token, rest) = str_utl::split_string(value, b',');
+                    value = rest;
                     if token.is_empty() {
                         continue;
                     }
@@ -97,6 +176,8 @@ pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
                     // TODO: What's the right number here?
                     let max_index = 10_000; // Can't risk out of memory
 
+                    // Indices too large to parse are invalid like negative
+                    // ones
                     let idx1;
                     let idx2;
                     if let Some(divider) = token.iter().position(|&c| c == b'-') {
@@ -112,13 +193,13 @@ pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
                             continue;
                         }
 
-                        idx1 = str_utl::parse_int(&token[..divider])?;
+                        idx1 = str_utl::parse_int(&token[..divider]).unwrap_or(-1);
                         if idx1 < 0 || idx1 > max_index {
                             // Invalid Index
                             continue;
                         }
 
-                        idx2 = str_utl::parse_int(&token[divider + 1..])?;
+                        idx2 = str_utl::parse_int(&token[divider + 1..]).unwrap_or(-1);
                         if idx2 < 0 || idx2 > max_index {
                             // Invalid Index
                             continue;
@@ -130,7 +211,7 @@ pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
                         }
                     } else {
                         // it's an index
-                        idx1 = str_utl::parse_int(token)?;
+                        idx1 = str_utl::parse_int(token).unwrap_or(-1);
                         if idx1 < 0 || idx1 > max_index {
                             // Invalid index
                             continue;
@@ -146,22 +227,14 @@ pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
                     for i in idx1..=idx2 {
                         p.file_priorities[i as usize] = DownloadPriority::DefaultPriority;
                     }
-
-                    value = rest;
                 }
             }
             "x.pe" => {
-                p.peers.push(value.parse()?);
+                p.peers.push(str_utl::parse_endpoint(value.as_bytes())?);
             }
             "dht" => {
-                let value = value.as_bytes();
-                let divider = value.iter().rev().position(|&c| c == b':');
-                if let Some(n) = divider {
-                    let n = value.len() - 1 - n;
-                    let host = String::from_utf8(value[..n].to_vec()).unwrap();
-                    let port = std::str::from_utf8(&value[n + 1..]).unwrap();
-                    p.dht_nodes.push((host, port.parse()?))
-                }
+                let node = str_utl::parse_endpoint(value.as_bytes())?;
+                p.dht_nodes.push((node.host(), node.port()));
             }
             _ => {}
         }
@@ -173,3 +246,274 @@ pub fn parse_magnet_uri(uri: &str, p: &mut TorrentParams) -> Result<()> {
 
     Ok(())
 }
+
+/// Parses a v1 info-hash, either hex or base32 encoded.
+fn parse_btih(value: &str) -> Result<Sha1Hash> {
+    let mut s = Sha1Hash::new();
+    match value.len() {
+        40 => {
+            hex::from_hex(value.as_bytes(), &mut s).ok_or(Error::InvalidInfoHash)?;
+        }
+        32 => {
+            let ih = str_utl::base32_decode(value.as_bytes());
+            if ih.len() != 20 {
+                return Err(Error::InvalidInfoHash);
+            }
+            s.copy_from_slice(&ih);
+        }
+        _ => return Err(Error::InvalidInfoHash),
+    }
+    Ok(s)
+}
+
+/// Parses a v2 info-hash. It's a hex encoded multihash, of which only
+/// SHA-256 (function code 0x12, length 0x20) is valid for BitTorrent.
+fn parse_btmh(value: &str) -> Result<Sha256Hash> {
+    let value = value
+        .strip_prefix(BTMH_SHA256_PREFIX)
+        .ok_or(Error::InvalidInfoHash)?;
+    let mut s = Sha256Hash::new();
+    if value.len() != 64 {
+        return Err(Error::InvalidInfoHash);
+    }
+    hex::from_hex(value.as_bytes(), &mut s).ok_or(Error::InvalidInfoHash)?;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::endpoint::Endpoint;
+
+    const INFO_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn test_parse_hex_info_hash() {
+        let p: TorrentParams = format!("magnet:?xt=urn:btih:{}", INFO_HASH)
+            .parse()
+            .unwrap();
+        assert_eq!(INFO_HASH, p.info_hash.v1.to_string());
+        assert!(!p.info_hash.has_v2());
+
+        let e = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef0123456x"
+            .parse::<TorrentParams>()
+            .unwrap_err();
+        assert!(matches!(e, Error::InvalidInfoHash));
+    }
+
+    const INFO_HASH_V2: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_parse_v2_info_hash() {
+        let uri = format!("magnet:?xt=urn:btmh:1220{}&dn=v2", INFO_HASH_V2);
+        let p: TorrentParams = uri.parse().unwrap();
+        assert!(!p.info_hash.has_v1());
+        assert_eq!(INFO_HASH_V2, p.info_hash.v2.to_string());
+        assert_eq!(&INFO_HASH_V2[..40], p.info_hash.get_best().to_string());
+        assert_eq!(uri, p.to_string());
+
+        // wrong multihash function, wrong length and bad digits
+        for xt in &[
+            format!("1320{}", INFO_HASH_V2),
+            format!("1220{}", &INFO_HASH_V2[2..]),
+            format!("1220{}x", &INFO_HASH_V2[1..]),
+        ] {
+            let e = format!("magnet:?xt=urn:btmh:{}", xt)
+                .parse::<TorrentParams>()
+                .unwrap_err();
+            assert!(matches!(e, Error::InvalidInfoHash), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_parse_hybrid_info_hash() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}&dn=hybrid",
+            INFO_HASH, INFO_HASH_V2
+        );
+        let p: TorrentParams = uri.parse().unwrap();
+        assert_eq!(INFO_HASH, p.info_hash.v1.to_string());
+        assert_eq!(INFO_HASH_V2, p.info_hash.v2.to_string());
+        assert_eq!(INFO_HASH, p.info_hash.get_best().to_string());
+        assert_eq!(uri, p.to_string());
+
+        // order of the xt parameters doesn't matter
+        let uri = format!(
+            "magnet:?xt=urn:btmh:1220{}&xt=urn:btih:{}",
+            INFO_HASH_V2, INFO_HASH
+        );
+        let q: TorrentParams = uri.parse().unwrap();
+        assert_eq!(p.info_hash, q.info_hash);
+    }
+
+    #[test]
+    fn test_make_magnet_uri() {
+        let mut p = TorrentParams::default();
+        p.info_hash = Sha1Hash::from_bytes(&[0xab; 20]).unwrap().into();
+        p.name = "foo bar&baz".to_string();
+        p.trackers = vec![
+            "udp://b:80".to_string(),
+            "http://a/announce?x=1".to_string(),
+        ];
+        p.tracker_tiers = vec![1, 0];
+        p.url_seeds = vec!["http://ws/".to_string()];
+        p.peers = vec!["1.2.3.4:5".parse().unwrap(), "[::1]:6".parse().unwrap()];
+        p.dht_nodes = vec![("router".to_string(), 6881)];
+
+        assert_eq!(
+            format!(
+                "magnet:?xt=urn:btih:{}\
+                 &dn=foo%20bar%26baz\
+                 &tr=http%3A%2F%2Fa%2Fannounce%3Fx%3D1\
+                 &tr=udp%3A%2F%2Fb%3A80\
+                 &ws=http%3A%2F%2Fws%2F\
+                 &x.pe=1.2.3.4%3A5\
+                 &x.pe=%5B%3A%3A1%5D%3A6\
+                 &dht=router%3A6881",
+                "ab".repeat(20)
+            ),
+            p.to_string()
+        );
+    }
+
+    #[test]
+    fn test_make_magnet_uri_without_info_hash() {
+        let mut p = TorrentParams::default();
+        p.name = "foo".to_string();
+        p.trackers = vec!["udp://a".to_string()];
+        assert_eq!("magnet:?dn=foo&tr=udp%3A%2F%2Fa", p.to_string());
+    }
+
+    #[test]
+    fn test_magnet_round_trip() {
+        use DownloadPriority::*;
+
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=name%20%C3%A5&tr=udp%3A%2F%2Fa&tr=udp%3A%2F%2Fb\
+             &ws=http%3A%2F%2Fws&x.pe=1.2.3.4%3A5&dht=router%3A6881&so=0-2,4,6-7",
+            INFO_HASH
+        );
+        let p: TorrentParams = uri.parse().unwrap();
+        assert_eq!("name \u{e5}", p.name);
+        assert_eq!(
+            vec![
+                DefaultPriority,
+                DefaultPriority,
+                DefaultPriority,
+                DontDownload,
+                DefaultPriority,
+                DontDownload,
+                DefaultPriority,
+                DefaultPriority
+            ],
+            p.file_priorities
+        );
+        assert_eq!(uri, p.to_string());
+
+        let q: TorrentParams = p.to_string().parse().unwrap();
+        assert_eq!(p.info_hash, q.info_hash);
+        assert_eq!(p.trackers, q.trackers);
+        assert_eq!(p.tracker_tiers, q.tracker_tiers);
+        assert_eq!(p.url_seeds, q.url_seeds);
+        assert_eq!(p.peers, q.peers);
+        assert_eq!(p.dht_nodes, q.dht_nodes);
+        assert_eq!(p.file_priorities, q.file_priorities);
+    }
+
+    #[test]
+    fn test_invalid_select_only() {
+        use DownloadPriority::*;
+
+        for so in &[
+            ",1",
+            "-1",
+            "3-1",
+            "1-",
+            "99999",
+            "1,,-,3-1,2",
+            "99999999999999999999",
+            "0-99999999999999999999,1",
+        ] {
+            let uri = format!("magnet:?xt=urn:btih:{}&so={}", INFO_HASH, so);
+            let p: TorrentParams = uri.parse().unwrap();
+            let expected = match *so {
+                ",1" => vec![DontDownload, DefaultPriority],
+                "1,,-,3-1,2" => vec![DontDownload, DefaultPriority, DefaultPriority],
+                "0-99999999999999999999,1" => vec![DontDownload, DefaultPriority],
+                _ => vec![],
+            };
+            assert_eq!(expected, p.file_priorities, "so={}", so);
+        }
+    }
+
+    #[test]
+    fn test_parse_endpoints() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&x.pe=peer.example.com%3A6881&x.pe=%5B%3A%3A1%5D%3A80\
+             &dht=%5B%3A%3A2%5D%3A6881&dht=1.2.3.4%3A5",
+            INFO_HASH
+        );
+        let p: TorrentParams = uri.parse().unwrap();
+        assert_eq!(
+            vec![
+                Endpoint::Host("peer.example.com".to_string(), 6881),
+                Endpoint::Addr("[::1]:80".parse().unwrap())
+            ],
+            p.peers
+        );
+        assert_eq!(
+            vec![("::2".to_string(), 6881), ("1.2.3.4".to_string(), 5)],
+            p.dht_nodes
+        );
+        assert_eq!(uri, p.to_string());
+
+        for (x, err) in &[
+            ("x.pe=1.2.3.4", Error::InvalidPort),
+            ("x.pe=%5B%3A%3A1%3A80", Error::ExpectedCloseBracketInAddr),
+            ("dht=router%3A70000", Error::InvalidPort),
+        ] {
+            let e = format!("magnet:?xt=urn:btih:{}&{}", INFO_HASH, x)
+                .parse::<TorrentParams>()
+                .unwrap_err();
+            assert_eq!(format!("{:?}", err), format!("{:?}", e));
+        }
+    }
+
+    #[test]
+    fn test_select_only() {
+        use DownloadPriority::*;
+
+        assert_eq!("", select_only(&[]));
+        assert_eq!("", select_only(&[DontDownload, DontDownload]));
+        assert_eq!("0", select_only(&[TopPriority]));
+        assert_eq!(
+            "1-2,4",
+            select_only(&[
+                DontDownload,
+                LowPriority,
+                DefaultPriority,
+                DontDownload,
+                TopPriority
+            ])
+        );
+    }
+
+    #[test]
+    fn test_make_magnet_uri_from_torrent() {
+        let bytes = include_bytes!("../bencode/tests/update.torrent");
+        let info = TorrentInfo::from_bytes(bytes).unwrap();
+        let uri = make_magnet_uri(&info);
+        assert_eq!(
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=update.txt\
+                 &tr=http%3A%2F%2F0.0.0.0%3A52585%2Fannounce",
+                info.info_hash()
+            ),
+            uri
+        );
+
+        let p: TorrentParams = uri.parse().unwrap();
+        assert_eq!(info.info_hash(), &p.info_hash.v1);
+        assert_eq!("update.txt", p.name);
+    }
+}
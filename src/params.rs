@@ -3,55 +3,82 @@ use defaults::Defaults;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::bitfield::Bitfield;
 use crate::download_priority::DownloadPriority;
+use crate::endpoint::Endpoint;
 use crate::flags::TorrentFlags;
 use crate::info::TorrentInfo;
+use crate::info_hash::InfoHash;
+use crate::storage::{StorageConstructor, StorageMode};
 
-#[derive(Defaults)]
+#[derive(Debug, Defaults)]
 pub struct TorrentParams {
-    version: usize,
-    torrent_info: Arc<TorrentInfo>,
+    pub version: usize,
+    pub torrent_info: Arc<TorrentInfo>,
     pub trackers: Vec<String>,
     pub tracker_tiers: Vec<isize>,
     pub dht_nodes: Vec<(String, u16)>,
     pub name: String,
-    save_path: String,
-    storage_mode: (),
-    storage: (),
+    pub save_path: String,
+    pub storage_mode: StorageMode,
+
+    /// Creates the storage for the torrent, the file-backed
+    /// `DefaultStorage` if `None`.
+    pub storage: Option<StorageConstructor>,
     user_data: (),
     pub file_priorities: Vec<DownloadPriority>,
-    flags: TorrentFlags,
-    pub info_hash: Sha1Hash,
-    max_uploads: isize,
-    max_connections: isize,
-    upload_limit: isize,
-    download_limit: isize,
-    total_uploaded: usize,
-    total_downloaded: usize,
-    active_time: Duration,
-    finished_time: Duration,
-    seeding_time: Duration,
-
-    #[def = "Instant::now()"]
-    added_time: Instant,
-
-    completed_time: Option<Instant>,
-    last_seen_complete: Option<Instant>,
-    num_complete: isize,
-    num_incomplete: isize,
-    num_downloaded: isize,
-    http_seeds: Vec<String>,
+    pub flags: TorrentFlags,
+    pub info_hash: InfoHash,
+    #[def = "-1"]
+    pub max_uploads: isize,
+    #[def = "-1"]
+    pub max_connections: isize,
+    #[def = "-1"]
+    pub upload_limit: isize,
+    #[def = "-1"]
+    pub download_limit: isize,
+    pub total_uploaded: usize,
+    pub total_downloaded: usize,
+    pub active_time: Duration,
+    pub finished_time: Duration,
+    pub seeding_time: Duration,
+
+    /// Timestamps are seconds since epoch, 0 means never.
+    #[def = "unix_time()"]
+    pub added_time: i64,
+
+    pub completed_time: i64,
+    pub last_seen_complete: i64,
+    pub num_complete: isize,
+    pub num_incomplete: isize,
+    pub num_downloaded: isize,
+    pub http_seeds: Vec<String>,
     pub url_seeds: Vec<String>,
-    pub peers: Vec<SocketAddr>,
-    banned_peers: Vec<SocketAddr>,
-    unfinished_pieces: HashMap<(), ()>,
-    have_pieces: (),
-    verified_pieces: (),
-    piece_priorities: Vec<()>,
+    pub peers: Vec<Endpoint>,
+    pub banned_peers: Vec<SocketAddr>,
+
+    /// Blocks we have of pieces that are not complete yet, keyed by piece
+    /// index.
+    pub unfinished_pieces: HashMap<usize, Bitfield>,
+    pub have_pieces: Bitfield,
+
+    /// Pieces that have been hash checked. Only meaningful in seed mode, a
+    /// piece in `have_pieces` but not here is checked before it's served.
+    pub verified_pieces: Bitfield,
+    pub piece_priorities: Vec<DownloadPriority>,
     merkle_tree: Vec<Sha1Hash>,
-    renamed_files: HashMap<(), ()>,
-    last_download: Option<Instant>,
-    last_upload: Option<Instant>,
+
+    /// New paths of renamed files, keyed by file index.
+    pub renamed_files: HashMap<usize, String>,
+    pub last_download: i64,
+    pub last_upload: i64,
+}
+
+pub(crate) fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
 }
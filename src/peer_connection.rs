@@ -0,0 +1,1573 @@
+//! A connection to a single peer: the handshake, choke and interest state
+//! on both ends, our outstanding requests and the requests the peer made.
+
+use crate::active_torrent::ActiveTorrent;
+use crate::bitfield::Bitfield;
+use crate::error::{Error, Result};
+use crate::extensions::{ExtendedHandshake, Extensions};
+use crate::peer_message::{
+    self, allowed_fast_set, Handshake, Message, PeerId, DEFAULT_MAX_MESSAGE_LEN,
+};
+use crate::peer_request::PeerRequest;
+use crate::piece_picker::{PieceBlock, BLOCK_SIZE};
+use crate::status::State;
+use crate::ut_pex::PexFlags;
+use common::sha1::Sha1Hash;
+use defaults::Defaults;
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Defaults)]
+pub struct PeerSettings {
+    #[def = "Duration::from_secs(10)"]
+    pub connect_timeout: Duration,
+
+    #[def = "Duration::from_secs(10)"]
+    pub handshake_timeout: Duration,
+
+    /// How long `tick` waits for data from the peer.
+    #[def = "Duration::from_millis(100)"]
+    pub read_timeout: Duration,
+
+    /// Never wait for the peer after the handshakes: `tick` handles what
+    /// arrived and sends what the socket takes, the rest stays queued.
+    pub nonblocking: bool,
+
+    /// Requests not answered within this time are given back to the picker
+    /// so other peers can download the block.
+    #[def = "Duration::from_secs(60)"]
+    pub request_timeout: Duration,
+
+    /// The connection is closed if nothing is received for this long.
+    #[def = "Duration::from_secs(120)"]
+    pub inactivity_timeout: Duration,
+
+    #[def = "Duration::from_secs(60)"]
+    pub keepalive_interval: Duration,
+
+    /// Our requests outstanding to the peer at most.
+    #[def = "16"]
+    pub max_out_requests: usize,
+
+    /// Requests from the peer we queue at most, extra ones are dropped.
+    #[def = "250"]
+    pub max_in_requests: usize,
+
+    #[def = "DEFAULT_MAX_MESSAGE_LEN"]
+    pub max_message_len: usize,
+
+    /// Our listen port, advertised in the extension handshake.
+    pub listen_port: Option<u16>,
+
+    /// Pieces a peer supporting the fast extension may request while we
+    /// choke it.
+    #[def = "5"]
+    pub allowed_fast_set_size: usize,
+}
+
+/// Allowed fast and suggested pieces remembered per peer at most.
+const MAX_FAST_PIECES: usize = 32;
+
+/// Have messages remembered per peer at most while we don't know the
+/// number of pieces, later ones are ignored.
+const MAX_EARLY_HAVES: usize = 4096;
+
+/// Reads per `tick` at most when non-blocking, so a fast peer can't hold
+/// up the others.
+const MAX_READS_PER_TICK: usize = 16;
+
+/// Bytes queued to the peer before serving more requests waits for it to
+/// catch up.
+const MAX_SEND_BUF: usize = 4 * BLOCK_SIZE;
+
+/// Client name and version sent in the extension handshake and as HTTP
+/// user agent.
+pub const CLIENT_VERSION: &str = concat!("torrent-rs/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone)]
+struct PendingBlock {
+    block: PieceBlock,
+    request: PeerRequest,
+    sent: Instant,
+}
+
+pub struct PeerConnection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    settings: PeerSettings,
+    peer_id: PeerId,
+    reserved: [u8; 8],
+    extensions: Extensions,
+
+    /// Whether we made the connection.
+    outgoing: bool,
+
+    /// How the peer is recorded in the torrent's swarm.
+    swarm_entry: Option<(SocketAddr, PexFlags)>,
+    recv_buf: Vec<u8>,
+    send_buf: Vec<u8>,
+
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+
+    /// Pieces the peer has, counted in the torrent's picker.
+    peer_have: Bitfield,
+    received_bitfield: bool,
+
+    /// What the peer announced before we had metadata, applied to
+    /// `peer_have` once we know the number of pieces.
+    early_bitfield: Option<Vec<u8>>,
+    early_have_all: bool,
+    early_haves: BTreeSet<usize>,
+
+    /// Pieces the peer lets us request while it chokes us, and the ones we
+    /// let it request (BEP 6).
+    allowed_fast: Vec<usize>,
+    allowed_fast_sent: Vec<usize>,
+
+    /// Pieces the peer suggested we download, most recent last.
+    suggested: Vec<usize>,
+
+    /// Our requests sent to the peer, oldest first.
+    download_queue: Vec<PendingBlock>,
+
+    /// The peer's requests to us, oldest first.
+    upload_queue: VecDeque<PeerRequest>,
+
+    last_receive: Instant,
+    last_send: Instant,
+}
+
+/// A connection whose handshakes are done but that isn't attached to its
+/// torrent yet. Connecting and waiting for the peer's handshake can take a
+/// while and needs nothing but the info hash, so it may run on a thread of
+/// its own.
+pub struct PendingConnection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    outgoing: bool,
+    handshake: Handshake,
+
+    /// What the peer sent after its handshake.
+    recv_buf: Vec<u8>,
+}
+
+impl PendingConnection {
+    /// Connects to a peer and exchanges handshakes.
+    pub fn connect(
+        addr: SocketAddr,
+        info_hash: &Sha1Hash,
+        peer_id: &PeerId,
+        settings: &PeerSettings,
+    ) -> Result<Self> {
+        let mut stream = TcpStream::connect_timeout(&addr, settings.connect_timeout)?;
+        stream.set_nodelay(true)?;
+        write_handshake(&mut stream, info_hash, peer_id)?;
+        let (handshake, recv_buf) = read_handshake(&mut stream, info_hash, settings)?;
+        Ok(Self {
+            stream,
+            addr,
+            outgoing: true,
+            handshake,
+            recv_buf,
+        })
+    }
+
+    /// Exchanges handshakes over a connection a peer made to us. The peer
+    /// sends its handshake first.
+    pub fn accept(
+        mut stream: TcpStream,
+        info_hash: &Sha1Hash,
+        peer_id: &PeerId,
+        settings: &PeerSettings,
+    ) -> Result<Self> {
+        let addr = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        let (handshake, recv_buf) = read_handshake(&mut stream, info_hash, settings)?;
+        write_handshake(&mut stream, info_hash, peer_id)?;
+        Ok(Self {
+            stream,
+            addr,
+            outgoing: false,
+            handshake,
+            recv_buf,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.handshake.info_hash
+    }
+}
+
+fn write_handshake(stream: &mut TcpStream, info_hash: &Sha1Hash, peer_id: &PeerId) -> Result<()> {
+    let mut h = Handshake::new(info_hash.clone(), peer_id.clone());
+    h.set_supports_extensions();
+    h.set_supports_fast();
+    let mut buf = vec![];
+    h.encode(&mut buf);
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+/// Waits for the peer's handshake, returning it along with whatever the
+/// peer sent after it.
+fn read_handshake(
+    stream: &mut TcpStream,
+    info_hash: &Sha1Hash,
+    settings: &PeerSettings,
+) -> Result<(Handshake, Vec<u8>)> {
+    stream.set_read_timeout(Some(settings.handshake_timeout))?;
+    let mut buf = vec![];
+    let handshake = loop {
+        if let Some((h, n)) = Handshake::decode(&buf)? {
+            buf.drain(..n);
+            break h;
+        }
+        let mut chunk = [0; 1024];
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(Error::ConnectionClosed),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(Error::Io(io::ErrorKind::TimedOut.into()));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    if handshake.info_hash != *info_hash {
+        return Err(Error::InfoHashMismatch);
+    }
+    Ok((handshake, buf))
+}
+
+impl PeerConnection {
+    /// Connects to a peer and exchanges handshakes.
+    pub fn connect(
+        addr: SocketAddr,
+        torrent: &mut ActiveTorrent,
+        peer_id: &PeerId,
+        settings: PeerSettings,
+    ) -> Result<Self> {
+        let pending = PendingConnection::connect(addr, torrent.info_hash(), peer_id, &settings)?;
+        Self::attach(pending, torrent, settings)
+    }
+
+    /// Takes over a connection a peer made to us. The peer sends its
+    /// handshake first.
+    pub fn accept(
+        stream: TcpStream,
+        torrent: &mut ActiveTorrent,
+        peer_id: &PeerId,
+        settings: PeerSettings,
+    ) -> Result<Self> {
+        let pending = PendingConnection::accept(stream, torrent.info_hash(), peer_id, &settings)?;
+        Self::attach(pending, torrent, settings)
+    }
+
+    /// Hands a connection whose handshakes are done to its torrent and
+    /// sends what follows the handshakes.
+    pub fn attach(
+        pending: PendingConnection,
+        torrent: &mut ActiveTorrent,
+        settings: PeerSettings,
+    ) -> Result<Self> {
+        if pending.info_hash() != torrent.info_hash() {
+            return Err(Error::InfoHashMismatch);
+        }
+        let PendingConnection {
+            stream,
+            addr,
+            outgoing,
+            handshake,
+            recv_buf,
+        } = pending;
+        if settings.nonblocking {
+            stream.set_nonblocking(true)?;
+        } else {
+            stream.set_read_timeout(Some(settings.read_timeout))?;
+        }
+        let now = Instant::now();
+        let mut conn = Self {
+            stream,
+            addr,
+            settings,
+            peer_id: handshake.peer_id,
+            reserved: handshake.reserved,
+            extensions: torrent.new_connection_extensions(addr),
+            outgoing,
+            swarm_entry: None,
+            recv_buf,
+            send_buf: vec![],
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer_have: Bitfield::new(torrent.info().num_pieces()),
+            received_bitfield: false,
+            early_bitfield: None,
+            early_have_all: false,
+            early_haves: BTreeSet::new(),
+            allowed_fast: vec![],
+            allowed_fast_sent: vec![],
+            suggested: vec![],
+            download_queue: vec![],
+            upload_queue: VecDeque::new(),
+            last_receive: now,
+            last_send: now,
+        };
+        conn.on_connected(torrent)?;
+        Ok(conn)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Reserved bytes from the peer's handshake, advertising extensions.
+    pub fn reserved(&self) -> &[u8; 8] {
+        &self.reserved
+    }
+
+    /// The peer's extension handshake, once received.
+    pub fn extended_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.extensions.peer_handshake()
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        peer_message::supports_extensions(&self.reserved)
+    }
+
+    /// Whether the fast extension (BEP 6) is in use, we always support it.
+    pub fn supports_fast(&self) -> bool {
+        peer_message::supports_fast(&self.reserved)
+    }
+
+    /// Pieces the peer allows us to request while it chokes us.
+    pub fn allowed_fast(&self) -> &[usize] {
+        &self.allowed_fast
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    pub fn peer_have(&self) -> &Bitfield {
+        &self.peer_have
+    }
+
+    pub fn num_pending_requests(&self) -> usize {
+        self.download_queue.len()
+    }
+
+    pub fn num_peer_requests(&self) -> usize {
+        self.upload_queue.len()
+    }
+
+    /// Sends what follows the handshakes: our bitfield, which has to come
+    /// first, the extension handshake and the pieces the peer may request
+    /// while choked.
+    fn on_connected(&mut self, torrent: &ActiveTorrent) -> Result<()> {
+        let have = torrent.have_pieces();
+        if self.supports_fast() {
+            let msg = if have.none_set() {
+                Message::HaveNone
+            } else if have.all_set() {
+                Message::HaveAll
+            } else {
+                Message::Bitfield(have.as_bytes().to_vec())
+            };
+            msg.encode(&mut self.send_buf);
+        } else if !have.none_set() {
+            Message::Bitfield(have.as_bytes().to_vec()).encode(&mut self.send_buf);
+        }
+        if self.supports_extensions() {
+            let base = ExtendedHandshake {
+                v: Some(CLIENT_VERSION.to_string()),
+                p: self.settings.listen_port,
+                yourip: Some(self.addr.ip()),
+                reqq: Some(self.settings.max_in_requests),
+                ..ExtendedHandshake::default()
+            };
+            self.extensions.handshake(&base).encode(&mut self.send_buf);
+        }
+        if self.supports_fast() && torrent.has_metadata() {
+            self.allowed_fast_sent = allowed_fast_set(
+                torrent.info_hash(),
+                self.addr.ip(),
+                torrent.info().num_pieces(),
+                self.settings.allowed_fast_set_size,
+            );
+            for &piece in &self.allowed_fast_sent {
+                Message::AllowedFast(piece).encode(&mut self.send_buf);
+            }
+        }
+        self.flush()
+    }
+
+    /// Queues a message and sends everything queued.
+    pub fn send(&mut self, msg: Message) -> Result<()> {
+        match msg {
+            Message::Choke => self.am_choking = true,
+            Message::Unchoke => self.am_choking = false,
+            Message::Interested => self.am_interested = true,
+            Message::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+        msg.encode(&mut self.send_buf);
+        self.flush()
+    }
+
+    /// Sends what's queued, or what the socket takes if non-blocking.
+    fn flush(&mut self) -> Result<()> {
+        while !self.send_buf.is_empty() {
+            match self.stream.write(&self.send_buf) {
+                Ok(0) => return Err(Error::ConnectionClosed),
+                Ok(n) => {
+                    self.send_buf.drain(..n);
+                    self.last_send = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads whatever the peer sent, waiting at most the read timeout.
+    /// Returns false if nothing arrived.
+    fn fill_recv_buf(&mut self) -> Result<bool> {
+        let mut buf = [0; 16 * 1024];
+        match self.stream.read(&mut buf) {
+            Ok(0) => Err(Error::ConnectionClosed),
+            Ok(n) => {
+                self.recv_buf.extend_from_slice(&buf[..n]);
+                self.last_receive = Instant::now();
+                Ok(true)
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stops uploading to the peer, dropping its queued requests. With the
+    /// fast extension, requests for allowed fast pieces are still served
+    /// and the others are rejected.
+    pub fn choke(&mut self) -> Result<()> {
+        if self.am_choking {
+            return Ok(());
+        }
+        let fast = self.supports_fast();
+        let allowed = &self.allowed_fast_sent;
+        let (keep, drop): (VecDeque<_>, VecDeque<_>) = self
+            .upload_queue
+            .drain(..)
+            .partition(|r| fast && allowed.contains(&r.piece));
+        self.upload_queue = keep;
+        self.reject_requests(drop);
+        self.send(Message::Choke)
+    }
+
+    pub fn unchoke(&mut self) -> Result<()> {
+        if !self.am_choking {
+            return Ok(());
+        }
+        self.send(Message::Unchoke)
+    }
+
+    /// Tells the peer about a piece we got.
+    pub fn announce_piece(&mut self, piece: usize) -> Result<()> {
+        self.send(Message::Have(piece))
+    }
+
+    /// Suggests the peer downloads a piece, e.g. one that's in the cache.
+    /// Does nothing unless the peer supports the fast extension.
+    pub fn suggest_piece(&mut self, piece: usize) -> Result<()> {
+        if !self.supports_fast() {
+            return Ok(());
+        }
+        self.send(Message::SuggestPiece(piece))
+    }
+
+    /// Tells a peer supporting the fast extension that we won't serve the
+    /// given requests. Without the extension they're dropped silently.
+    fn reject_requests(&mut self, requests: impl IntoIterator<Item = PeerRequest>) {
+        if self.supports_fast() {
+            for r in requests {
+                Message::RejectRequest(r).encode(&mut self.send_buf);
+            }
+        }
+    }
+
+    /// Runs one round of the connection: handles incoming messages, gives
+    /// back timed out requests, requests more blocks and serves the peer's
+    /// requests.
+    pub fn tick(&mut self, torrent: &mut ActiveTorrent) -> Result<()> {
+        for _ in 0..MAX_READS_PER_TICK {
+            let received = self.fill_recv_buf()?;
+            while let Some((msg, n)) =
+                Message::decode(&self.recv_buf, self.settings.max_message_len)?
+            {
+                self.recv_buf.drain(..n);
+                self.handle_message(msg, torrent)?;
+            }
+            if !received || !self.settings.nonblocking {
+                break;
+            }
+        }
+        if self.peer_have.is_empty() && torrent.update_metadata()? {
+            self.on_metadata(torrent)?;
+        }
+        self.update_swarm(torrent);
+
+        let now = Instant::now();
+        if now - self.last_receive > self.settings.inactivity_timeout {
+            return Err(Error::Io(io::ErrorKind::TimedOut.into()));
+        }
+        self.check_request_timeouts(torrent, now);
+        self.cancel_requests(torrent);
+        self.update_interest(torrent)?;
+        self.request_blocks(torrent)?;
+        self.serve_requests(torrent)?;
+        for msg in self.extensions.tick()? {
+            msg.encode(&mut self.send_buf);
+        }
+        self.flush()?;
+
+        if now - self.last_send > self.settings.keepalive_interval {
+            self.send(Message::KeepAlive)?;
+        }
+        Ok(())
+    }
+
+    /// Gives everything this peer contributed back to the torrent. Call
+    /// once the connection is done.
+    pub fn disconnect(&mut self, torrent: &mut ActiveTorrent) {
+        for pending in self.download_queue.drain(..) {
+            torrent.picker().abort_download(pending.block, self.addr);
+        }
+        torrent.take_cancels(self.addr);
+        torrent.picker().dec_refcount_bitfield(&self.peer_have);
+        self.peer_have.clear_all();
+        if let Some((addr, _)) = self.swarm_entry.take() {
+            torrent.swarm().lock().unwrap().peer_disconnected(addr);
+        }
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    /// Keeps the peer's entry in the torrent's swarm current. Peers that
+    /// connected to us are only recorded once they told us their listen
+    /// port.
+    fn update_swarm(&mut self, torrent: &ActiveTorrent) {
+        let addr = if self.outgoing {
+            Some(self.addr)
+        } else {
+            self.extensions
+                .peer_handshake()
+                .and_then(|h| h.p)
+                .map(|p| SocketAddr::new(self.addr.ip(), p))
+        };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        let mut flags = PexFlags::empty();
+        if self.outgoing {
+            flags |= PexFlags::REACHABLE;
+        }
+        if !self.peer_have.is_empty() && self.peer_have.all_set() {
+            flags |= PexFlags::SEED;
+        }
+        if self.swarm_entry != Some((addr, flags)) {
+            self.swarm_entry = Some((addr, flags));
+            torrent.swarm().lock().unwrap().peer_connected(addr, flags);
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message, torrent: &mut ActiveTorrent) -> Result<()> {
+        match msg {
+            Message::KeepAlive => {}
+            Message::Choke => {
+                self.peer_choking = true;
+                // With the fast extension, requests are rejected one by one
+                if !self.supports_fast() {
+                    for pending in self.download_queue.drain(..) {
+                        torrent.picker().abort_download(pending.block, self.addr);
+                    }
+                }
+            }
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => {
+                self.peer_interested = false;
+                let dropped: Vec<_> = self.upload_queue.drain(..).collect();
+                self.reject_requests(dropped);
+                self.flush()?;
+            }
+            Message::SuggestPiece(_)
+            | Message::HaveAll
+            | Message::HaveNone
+            | Message::RejectRequest(_)
+            | Message::AllowedFast(_)
+                if !self.supports_fast() =>
+            {
+                return Err(Error::InvalidMessage);
+            }
+            Message::HaveAll | Message::HaveNone if self.received_bitfield => {
+                return Err(Error::InvalidMessage);
+            }
+            Message::HaveAll if !torrent.has_metadata() => {
+                self.received_bitfield = true;
+                self.early_have_all = true;
+            }
+            Message::HaveAll => {
+                self.received_bitfield = true;
+                let have = Bitfield::with_all_set(self.peer_have.len());
+                torrent.picker().dec_refcount_bitfield(&self.peer_have);
+                torrent.picker().inc_refcount_bitfield(&have);
+                self.peer_have = have;
+            }
+            Message::HaveNone => self.received_bitfield = true,
+            Message::SuggestPiece(piece) => {
+                if piece < self.peer_have.len() && !self.suggested.contains(&piece) {
+                    if self.suggested.len() == MAX_FAST_PIECES {
+                        self.suggested.remove(0);
+                    }
+                    self.suggested.push(piece);
+                }
+            }
+            Message::AllowedFast(piece) => {
+                let valid = !torrent.has_metadata() || piece < self.peer_have.len();
+                if valid
+                    && !self.allowed_fast.contains(&piece)
+                    && self.allowed_fast.len() < MAX_FAST_PIECES
+                {
+                    self.allowed_fast.push(piece);
+                }
+            }
+            Message::RejectRequest(r) => {
+                if let Some(index) = self.download_queue.iter().position(|p| p.request == r) {
+                    let pending = self.download_queue.remove(index);
+                    torrent.picker().abort_download(pending.block, self.addr);
+                }
+            }
+            Message::Have(piece) if !torrent.has_metadata() => {
+                if self.early_haves.len() < MAX_EARLY_HAVES {
+                    self.early_haves.insert(piece);
+                }
+            }
+            Message::Bitfield(bits) if !torrent.has_metadata() => {
+                if self.received_bitfield {
+                    return Err(Error::InvalidMessage);
+                }
+                self.received_bitfield = true;
+                self.early_bitfield = Some(bits);
+            }
+            Message::Have(piece) => {
+                if piece >= self.peer_have.len() {
+                    return Err(Error::InvalidMessage);
+                }
+                if !self.peer_have.get(piece) {
+                    self.peer_have.set(piece);
+                    torrent.picker().inc_refcount(piece);
+                }
+            }
+            Message::Bitfield(bits) => {
+                if self.received_bitfield || bits.len() != self.peer_have.as_bytes().len() {
+                    return Err(Error::InvalidMessage);
+                }
+                self.received_bitfield = true;
+                let have = Bitfield::from_bytes(&bits, self.peer_have.len());
+                torrent.picker().dec_refcount_bitfield(&self.peer_have);
+                torrent.picker().inc_refcount_bitfield(&have);
+                self.peer_have = have;
+            }
+            Message::Request(r) => {
+                let valid = r.piece < self.peer_have.len()
+                    && r.length > 0
+                    && r.length <= BLOCK_SIZE
+                    && r.start + r.length <= torrent.info().piece_size(r.piece);
+                if !valid {
+                    return Err(Error::InvalidMessage);
+                }
+                let allowed = !self.am_choking
+                    || (self.supports_fast() && self.allowed_fast_sent.contains(&r.piece));
+                if allowed
+                    && torrent.have_pieces().get(r.piece)
+                    && self.upload_queue.len() < self.settings.max_in_requests
+                {
+                    self.upload_queue.push_back(r);
+                } else {
+                    self.reject_requests(Some(r));
+                    self.flush()?;
+                }
+            }
+            Message::Cancel(r) => {
+                // The fast extension wants an answer to every request
+                if let Some(index) = self.upload_queue.iter().position(|q| *q == r) {
+                    self.upload_queue.remove(index);
+                    self.reject_requests(Some(r));
+                    self.flush()?;
+                }
+            }
+            Message::Piece { piece, start, data } => {
+                self.handle_piece(piece, start, &data, torrent)?;
+            }
+            Message::Port(_) | Message::Unknown { .. } => {}
+            Message::Extended { id, payload } => {
+                if !self.supports_extensions() {
+                    return Err(Error::InvalidMessage);
+                }
+                for msg in self.extensions.on_message(id, &payload)? {
+                    msg.encode(&mut self.send_buf);
+                }
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sizes `peer_have` for the metadata we just got and applies what the
+    /// peer announced so far.
+    fn on_metadata(&mut self, torrent: &mut ActiveTorrent) -> Result<()> {
+        let num_pieces = torrent.info().num_pieces();
+        let mut have = match self.early_bitfield.take() {
+            Some(bits) if bits.len() == num_pieces.div_ceil(8) => {
+                Bitfield::from_bytes(&bits, num_pieces)
+            }
+            Some(_) => return Err(Error::InvalidMessage),
+            None if self.early_have_all => Bitfield::with_all_set(num_pieces),
+            None => Bitfield::new(num_pieces),
+        };
+        self.allowed_fast.retain(|&piece| piece < num_pieces);
+        for piece in std::mem::take(&mut self.early_haves) {
+            if piece >= num_pieces {
+                return Err(Error::InvalidMessage);
+            }
+            have.set(piece);
+        }
+        torrent.picker().inc_refcount_bitfield(&have);
+        self.peer_have = have;
+        Ok(())
+    }
+
+    fn handle_piece(
+        &mut self,
+        piece: usize,
+        start: usize,
+        data: &[u8],
+        torrent: &mut ActiveTorrent,
+    ) -> Result<()> {
+        let request = PeerRequest::new(piece, start, data.len());
+        let index = match self
+            .download_queue
+            .iter()
+            .position(|p| p.request == request)
+        {
+            Some(index) => index,
+            // Unsolicited or already timed out
+            None => return Ok(()),
+        };
+        let pending = self.download_queue.remove(index);
+        torrent.add_downloaded(data.len());
+
+        let block = pending.block;
+        if torrent.picker().block_state(block) == Some(crate::piece_picker::BlockState::Finished) {
+            return Ok(());
+        }
+        torrent.storage().write(data, piece, start)?;
+        if torrent.finish_block(block, self.addr) {
+            torrent.verify_piece(piece)?;
+        }
+        Ok(())
+    }
+
+    /// Cancels requests for blocks another peer delivered first.
+    fn cancel_requests(&mut self, torrent: &mut ActiveTorrent) {
+        for block in torrent.take_cancels(self.addr) {
+            if let Some(index) = self.download_queue.iter().position(|p| p.block == block) {
+                let pending = self.download_queue.remove(index);
+                Message::Cancel(pending.request).encode(&mut self.send_buf);
+            }
+        }
+    }
+
+    fn check_request_timeouts(&mut self, torrent: &mut ActiveTorrent, now: Instant) {
+        let timeout = self.settings.request_timeout;
+        let addr = self.addr;
+        self.download_queue.retain(|p| {
+            let expired = now - p.sent > timeout;
+            if expired {
+                torrent.picker().abort_download(p.block, addr);
+            }
+            !expired
+        });
+    }
+
+    /// We're interested as long as the peer has a piece we want.
+    fn update_interest(&mut self, torrent: &mut ActiveTorrent) -> Result<()> {
+        let picker = torrent.picker();
+        let interested = self
+            .peer_have
+            .iter()
+            .enumerate()
+            .any(|(piece, has)| has && picker.is_wanted(piece));
+        if interested != self.am_interested {
+            let msg = if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            };
+            self.send(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Requests blocks while unchoked, preferring the pieces the peer
+    /// suggested. While choked, only allowed fast pieces can be requested.
+    fn request_blocks(&mut self, torrent: &mut ActiveTorrent) -> Result<()> {
+        if !self.am_interested || torrent.state() != State::Downloading {
+            return Ok(());
+        }
+        if self.peer_choking && (!self.supports_fast() || self.allowed_fast.is_empty()) {
+            return Ok(());
+        }
+        let mut max = self.settings.max_out_requests;
+        if let Some(reqq) = self.extensions.peer_handshake().and_then(|h| h.reqq) {
+            max = max.min(reqq);
+        }
+        let free = max.saturating_sub(self.download_queue.len());
+        if free == 0 {
+            return Ok(());
+        }
+
+        let blocks = if self.peer_choking {
+            let mut allowed = Bitfield::new(self.peer_have.len());
+            for &piece in &self.allowed_fast {
+                if self.peer_have.get(piece) {
+                    allowed.set(piece);
+                }
+            }
+            torrent
+                .picker()
+                .pick_pieces_prefer(&allowed, &self.allowed_fast, free, self.addr)
+        } else {
+            torrent
+                .picker()
+                .pick_pieces_prefer(&self.peer_have, &self.suggested, free, self.addr)
+        };
+        let now = Instant::now();
+        for block in blocks {
+            let request = torrent.picker().block_request(block);
+            torrent.picker().mark_as_requested(block, self.addr);
+            Message::Request(request).encode(&mut self.send_buf);
+            self.download_queue.push(PendingBlock {
+                block,
+                request,
+                sent: now,
+            });
+        }
+        self.flush()
+    }
+
+    fn serve_requests(&mut self, torrent: &mut ActiveTorrent) -> Result<()> {
+        while !self.upload_queue.is_empty() {
+            self.flush()?;
+            if self.send_buf.len() >= MAX_SEND_BUF {
+                // Served once the peer read what's queued
+                break;
+            }
+            let r = self.upload_queue.pop_front().unwrap();
+            let mut data = vec![0; r.length];
+            torrent.storage().read(&mut data, r.piece, r.start)?;
+            torrent.add_uploaded(data.len());
+            Message::Piece {
+                piece: r.piece,
+                start: r.start,
+                data,
+            }
+            .encode(&mut self.send_buf);
+        }
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::extensions::{PeerExtension, TorrentExtension};
+    use crate::params::TorrentParams;
+    use crate::peer_message::HANDSHAKE_LEN;
+    use crate::storage::memory_storage_constructor;
+    use crate::test_util::{content, memory_torrent, temp_dir, torrent_info, write_pieces};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const PIECE_LEN: usize = 32 * 1024;
+    const CONTENT_LEN: usize = PIECE_LEN * 2 + 1000;
+
+    /// A torrent over `content(CONTENT_LEN)` kept in memory.
+    fn torrent(name: &str, seed: bool) -> ActiveTorrent {
+        let dir = temp_dir(name);
+        let data = content(CONTENT_LEN);
+        let info = torrent_info(&dir, PIECE_LEN, &[("data", &data)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut t = memory_torrent(&info);
+        if seed {
+            write_pieces(&mut t, &data);
+        }
+        t
+    }
+
+    fn peer_id(c: u8) -> PeerId {
+        PeerId::from([c; 20])
+    }
+
+    fn settings() -> PeerSettings {
+        PeerSettings {
+            read_timeout: Duration::from_millis(20),
+            ..PeerSettings::default()
+        }
+    }
+
+    /// The far end of a connection, driven by the test script.
+    struct ScriptedPeer {
+        stream: TcpStream,
+        buf: Vec<u8>,
+    }
+
+    impl ScriptedPeer {
+        fn handshake(stream: TcpStream, info_hash: Sha1Hash) -> Self {
+            Self::handshake_with(stream, Handshake::new(info_hash, peer_id(b'p')))
+        }
+
+        fn handshake_with(stream: TcpStream, ours: Handshake) -> Self {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut peer = Self {
+                stream,
+                buf: vec![],
+            };
+            let mut h = [0; HANDSHAKE_LEN];
+            peer.stream.read_exact(&mut h).unwrap();
+            let mut buf = vec![];
+            ours.encode(&mut buf);
+            peer.stream.write_all(&buf).unwrap();
+            peer
+        }
+
+        fn send(&mut self, msg: Message) {
+            let mut buf = vec![];
+            msg.encode(&mut buf);
+            self.stream.write_all(&buf).unwrap();
+        }
+
+        /// Next message that isn't a keep-alive, `None` on EOF.
+        fn recv(&mut self) -> Option<Message> {
+            loop {
+                if let Some((msg, n)) = Message::decode(&self.buf, usize::MAX).unwrap() {
+                    self.buf.drain(..n);
+                    if msg != Message::KeepAlive {
+                        return Some(msg);
+                    }
+                    continue;
+                }
+                let mut buf = [0; 4096];
+                match self.stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return None,
+                    Ok(n) => self.buf.extend_from_slice(&buf[..n]),
+                }
+            }
+        }
+    }
+
+    fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[test]
+    fn test_download() {
+        let mut t = torrent("peer-download", false);
+        let info_hash = t.info().info_hash().clone();
+        let (listener, addr) = listen();
+        let seeder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut peer = ScriptedPeer::handshake(stream, info_hash);
+            // A BEP 52 hash request we don't know shouldn't drop us
+            peer.send(Message::Unknown {
+                id: 21,
+                payload: vec![0; 48],
+            });
+            peer.send(Message::Bitfield(vec![0xe0]));
+            assert_eq!(Some(Message::Interested), peer.recv());
+            peer.send(Message::Unchoke);
+
+            let data = content(CONTENT_LEN);
+            while let Some(msg) = peer.recv() {
+                if let Message::Request(r) = msg {
+                    let offset = r.piece * PIECE_LEN + r.start;
+                    peer.send(Message::Piece {
+                        piece: r.piece,
+                        start: r.start,
+                        data: data[offset..offset + r.length].to_vec(),
+                    });
+                }
+            }
+        });
+
+        let mut conn = PeerConnection::connect(addr, &mut t, &peer_id(b'a'), settings()).unwrap();
+        assert_eq!(&peer_id(b'p'), conn.peer_id());
+        assert!(conn.am_choking() && conn.peer_choking());
+
+        let start = Instant::now();
+        while t.state() != State::Seeding {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        assert!(!conn.peer_choking());
+        assert_eq!(
+            Some(PexFlags::REACHABLE | PexFlags::SEED),
+            t.swarm().lock().unwrap().peer_flags(addr)
+        );
+        assert_eq!(vec![0, 1, 2], t.take_new_pieces());
+        assert_eq!(
+            content(CONTENT_LEN).len() as u64,
+            t.status().total_payload_download
+        );
+
+        let mut buf = vec![0; 1000];
+        t.storage().read(&mut buf, 2, 0).unwrap();
+        assert_eq!(&content(CONTENT_LEN)[PIECE_LEN * 2..], &buf[..]);
+
+        // Nothing left to want
+        conn.tick(&mut t).unwrap();
+        assert!(!conn.am_interested());
+        conn.disconnect(&mut t);
+        assert!(!t.swarm().lock().unwrap().is_connected(addr));
+        seeder.join().unwrap();
+    }
+
+    #[test]
+    fn test_upload() {
+        let mut t = torrent("peer-upload", true);
+        let info_hash = t.info().info_hash().clone();
+        let (listener, addr) = listen();
+        let leecher = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut peer = ScriptedPeer {
+                stream,
+                buf: vec![],
+            };
+            let mut buf = vec![];
+            Handshake::new(info_hash, peer_id(b'p')).encode(&mut buf);
+            peer.stream.write_all(&buf).unwrap();
+            let mut h = [0; HANDSHAKE_LEN];
+            peer.stream.read_exact(&mut h).unwrap();
+
+            assert_eq!(Some(Message::Bitfield(vec![0xe0])), peer.recv());
+            peer.send(Message::Interested);
+            assert_eq!(Some(Message::Unchoke), peer.recv());
+            peer.send(Message::Request(PeerRequest::new(2, 0, 1000)));
+            match peer.recv() {
+                Some(Message::Piece { piece, start, data }) => {
+                    assert_eq!((2, 0), (piece, start));
+                    assert_eq!(&content(CONTENT_LEN)[PIECE_LEN * 2..], &data[..]);
+                }
+                msg => panic!("unexpected {:?}", msg),
+            }
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = PeerConnection::accept(stream, &mut t, &peer_id(b'a'), settings()).unwrap();
+        let start = Instant::now();
+        while !conn.peer_interested() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        conn.unchoke().unwrap();
+        while t.status().total_payload_upload == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        assert_eq!(1000, t.status().total_payload_upload);
+        leecher.join().unwrap();
+    }
+
+    #[test]
+    fn test_nonblocking_upload() {
+        const REQUESTS: usize = 4000;
+        let mut t = torrent("peer-nonblocking", true);
+        let info_hash = t.info().info_hash().clone();
+        let (listener, addr) = listen();
+        let (go_tx, go_rx) = std::sync::mpsc::channel();
+        let leecher = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut peer = ScriptedPeer {
+                stream,
+                buf: vec![],
+            };
+            let mut buf = vec![];
+            Handshake::new(info_hash, peer_id(b'p')).encode(&mut buf);
+            peer.stream.write_all(&buf).unwrap();
+            let mut h = [0; HANDSHAKE_LEN];
+            peer.stream.read_exact(&mut h).unwrap();
+
+            assert_eq!(Some(Message::Bitfield(vec![0xe0])), peer.recv());
+            peer.send(Message::Interested);
+            assert_eq!(Some(Message::Unchoke), peer.recv());
+            for i in 0..REQUESTS {
+                peer.send(Message::Request(PeerRequest::new(i % 2, 0, BLOCK_SIZE)));
+            }
+            // Far more than the socket buffers hold is sent before we read
+            go_rx.recv().unwrap();
+            for _ in 0..REQUESTS {
+                assert!(matches!(peer.recv(), Some(Message::Piece { .. })));
+            }
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let settings = PeerSettings {
+            nonblocking: true,
+            max_in_requests: REQUESTS,
+            ..settings()
+        };
+        let mut conn = PeerConnection::accept(stream, &mut t, &peer_id(b'a'), settings).unwrap();
+        let start = Instant::now();
+        while !conn.peer_interested() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        conn.unchoke().unwrap();
+        let uploaded = |t: &ActiveTorrent| t.status().total_payload_upload as usize / BLOCK_SIZE;
+        while uploaded(&t) + conn.num_peer_requests() < REQUESTS {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        // The peer doesn't read, ticks return anyway
+        for _ in 0..100 {
+            conn.tick(&mut t).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(conn.num_peer_requests() > 0);
+
+        go_tx.send(()).unwrap();
+        while uploaded(&t) < REQUESTS {
+            assert!(start.elapsed() < Duration::from_secs(20));
+            conn.tick(&mut t).unwrap();
+        }
+        leecher.join().unwrap();
+    }
+
+    fn fast_handshake(info_hash: Sha1Hash) -> Handshake {
+        let mut h = Handshake::new(info_hash, peer_id(b'p'));
+        h.set_supports_fast();
+        h
+    }
+
+    #[test]
+    fn test_fast_download() {
+        let mut t = torrent("peer-fast-download", false);
+        let info_hash = t.info().info_hash().clone();
+        let (listener, addr) = listen();
+        let seeder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut peer = ScriptedPeer::handshake_with(stream, fast_handshake(info_hash));
+            assert_eq!(Some(Message::HaveNone), peer.recv());
+            peer.send(Message::HaveAll);
+            peer.send(Message::AllowedFast(1));
+
+            // Only the allowed fast piece is requested while choked
+            let data = content(CONTENT_LEN);
+            let serve = |peer: &mut ScriptedPeer, r: PeerRequest| {
+                let offset = r.piece * PIECE_LEN + r.start;
+                peer.send(Message::Piece {
+                    piece: r.piece,
+                    start: r.start,
+                    data: data[offset..offset + r.length].to_vec(),
+                });
+            };
+            let mut served = 0;
+            while served < PIECE_LEN {
+                match peer.recv() {
+                    Some(Message::Request(r)) => {
+                        assert_eq!(1, r.piece);
+                        serve(&mut peer, r);
+                        served += r.length;
+                    }
+                    Some(_) => {}
+                    None => panic!("closed"),
+                }
+            }
+
+            // The suggested piece goes first, and is requested again after
+            // being rejected
+            peer.send(Message::SuggestPiece(2));
+            peer.send(Message::Unchoke);
+            loop {
+                match peer.recv() {
+                    Some(Message::Request(r)) => {
+                        assert_eq!(2, r.piece);
+                        peer.send(Message::RejectRequest(r));
+                        break;
+                    }
+                    Some(_) => {}
+                    None => panic!("closed"),
+                }
+            }
+            while let Some(msg) = peer.recv() {
+                if let Message::Request(r) = msg {
+                    serve(&mut peer, r);
+                }
+            }
+        });
+
+        let mut conn = PeerConnection::connect(addr, &mut t, &peer_id(b'a'), settings()).unwrap();
+        assert!(conn.supports_fast());
+        let start = Instant::now();
+        while t.state() != State::Seeding {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        assert_eq!(&[1], conn.allowed_fast());
+        assert_eq!(1, t.take_new_pieces()[0]);
+        assert_eq!(1, t.picker().availability(0));
+        conn.disconnect(&mut t);
+        seeder.join().unwrap();
+    }
+
+    #[test]
+    fn test_fast_upload() {
+        let mut t = torrent("peer-fast-upload", true);
+        let info_hash = t.info().info_hash().clone();
+        let allowed = allowed_fast_set(&info_hash, [127, 0, 0, 1].into(), 3, 1)[0];
+        let other = (allowed + 1) % 3;
+        let (listener, addr) = listen();
+        let leecher = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut peer = ScriptedPeer {
+                stream,
+                buf: vec![],
+            };
+            let mut buf = vec![];
+            fast_handshake(info_hash).encode(&mut buf);
+            peer.stream.write_all(&buf).unwrap();
+            let mut h = [0; HANDSHAKE_LEN];
+            peer.stream.read_exact(&mut h).unwrap();
+
+            assert_eq!(Some(Message::HaveAll), peer.recv());
+            assert_eq!(Some(Message::AllowedFast(allowed)), peer.recv());
+            peer.send(Message::Interested);
+
+            // Choked, so only the allowed fast piece is served
+            let r = PeerRequest::new(other, 0, 1000);
+            peer.send(Message::Request(r));
+            assert_eq!(Some(Message::RejectRequest(r)), peer.recv());
+            peer.send(Message::Request(PeerRequest::new(allowed, 0, 1000)));
+            match peer.recv() {
+                Some(Message::Piece { piece, start, data }) => {
+                    assert_eq!((allowed, 0), (piece, start));
+                    let offset = allowed * PIECE_LEN;
+                    assert_eq!(&content(CONTENT_LEN)[offset..offset + 1000], &data[..]);
+                }
+                msg => panic!("unexpected {:?}", msg),
+            }
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let settings = PeerSettings {
+            allowed_fast_set_size: 1,
+            ..settings()
+        };
+        let mut conn = PeerConnection::accept(stream, &mut t, &peer_id(b'a'), settings).unwrap();
+        let start = Instant::now();
+        while t.status().total_payload_upload == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        assert!(conn.am_choking());
+        assert_eq!(1000, t.status().total_payload_upload);
+        leecher.join().unwrap();
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let mut t = torrent("peer-request-timeout", false);
+        let info_hash = t.info().info_hash().clone();
+        let (listener, addr) = listen();
+        let peer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut peer = ScriptedPeer::handshake(stream, info_hash);
+            peer.send(Message::Bitfield(vec![0x80]));
+            peer.send(Message::Unchoke);
+            // Never answer, just wait for the connection to close
+            while peer.recv().is_some() {}
+        });
+
+        let settings = PeerSettings {
+            request_timeout: Duration::from_millis(200),
+            ..settings()
+        };
+        let mut conn = PeerConnection::connect(addr, &mut t, &peer_id(b'a'), settings).unwrap();
+        let start = Instant::now();
+        while conn.num_pending_requests() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        assert_eq!(2, conn.num_pending_requests());
+        let block = PieceBlock::new(0, 0);
+        assert_eq!(
+            Some(crate::piece_picker::BlockState::Requested),
+            t.picker().block_state(block)
+        );
+        assert_eq!(1, t.picker().availability(0));
+
+        // The timed out requests are given back and requested again
+        thread::sleep(Duration::from_millis(250));
+        conn.check_request_timeouts(&mut t, Instant::now());
+        assert_eq!(0, conn.num_pending_requests());
+        assert_eq!(
+            Some(crate::piece_picker::BlockState::Open),
+            t.picker().block_state(block)
+        );
+
+        conn.disconnect(&mut t);
+        assert_eq!(0, t.picker().availability(0));
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_end_game_cancel() {
+        let mut t = torrent("peer-end-game-cancel", false);
+        let info_hash = t.info().info_hash().clone();
+        let (listener, addr) = listen();
+        let peer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut peer = ScriptedPeer::handshake(stream, info_hash);
+            peer.send(Message::Bitfield(vec![0x80]));
+            peer.send(Message::Unchoke);
+            let mut requests = vec![];
+            loop {
+                match peer.recv() {
+                    Some(Message::Request(r)) => requests.push(r),
+                    Some(Message::Cancel(r)) => return (requests, r),
+                    Some(_) => {}
+                    None => panic!("closed"),
+                }
+            }
+        });
+
+        let mut conn = PeerConnection::connect(addr, &mut t, &peer_id(b'a'), settings()).unwrap();
+        let start = Instant::now();
+        while conn.num_pending_requests() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+
+        // Another peer delivers the first block
+        let block = PieceBlock::new(0, 0);
+        let other = "127.0.0.2:6881".parse().unwrap();
+        assert!(!t.finish_block(block, other));
+        conn.tick(&mut t).unwrap();
+        assert_eq!(1, conn.num_pending_requests());
+        assert!(t.take_cancels(addr).is_empty());
+
+        let (requests, cancelled) = peer.join().unwrap();
+        assert_eq!(requests[0], cancelled);
+        assert_eq!(PeerRequest::new(0, 0, BLOCK_SIZE), cancelled);
+        conn.disconnect(&mut t);
+    }
+
+    struct Echo;
+
+    impl TorrentExtension for Echo {
+        fn new_connection(&mut self, _addr: SocketAddr) -> Option<Box<dyn PeerExtension>> {
+            Some(Box::new(Echo))
+        }
+    }
+
+    impl PeerExtension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_message(&mut self, payload: &[u8], out: &mut Vec<Vec<u8>>) -> Result<()> {
+            out.push(payload.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut t = torrent("peer-extensions", false);
+        t.add_extension(Box::new(Echo));
+        let info_hash = t.info().info_hash().clone();
+        let (listener, addr) = listen();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut h = [0; HANDSHAKE_LEN];
+            stream.read_exact(&mut h).unwrap();
+            let (h, _) = Handshake::decode(&h).unwrap().unwrap();
+            assert!(h.supports_extensions());
+            let mut ours = Handshake::new(info_hash, peer_id(b'p'));
+            ours.set_supports_extensions();
+            let mut buf = vec![];
+            ours.encode(&mut buf);
+            stream.write_all(&buf).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut peer = ScriptedPeer {
+                stream,
+                buf: vec![],
+            };
+
+            let payload = match peer.recv() {
+                Some(Message::Extended { id: 0, payload }) => payload,
+                msg => panic!("unexpected {:?}", msg),
+            };
+            let theirs = ExtendedHandshake::decode(&payload).unwrap();
+            // The built-in extensions are registered first
+            assert_eq!(Some(&1), theirs.m.get("ut_metadata"));
+            assert_eq!(Some(&2), theirs.m.get("ut_pex"));
+            assert_eq!(Some(&3), theirs.m.get("echo"));
+            assert_eq!(Some(250), theirs.reqq);
+            assert!(theirs.v.unwrap().starts_with("torrent-rs/"));
+
+            let mut m = std::collections::BTreeMap::new();
+            m.insert("echo".to_string(), 5);
+            let ours = ExtendedHandshake {
+                m,
+                reqq: Some(1),
+                ..ExtendedHandshake::default()
+            };
+            peer.send(Message::Extended {
+                id: 0,
+                payload: bencode::Value::with_dict(ours.encode()).to_vec(),
+            });
+            peer.send(Message::Extended {
+                id: 3,
+                payload: b"hi".to_vec(),
+            });
+            assert_eq!(
+                Some(Message::Extended {
+                    id: 5,
+                    payload: b"hi".to_vec()
+                }),
+                peer.recv()
+            );
+            peer.send(Message::Bitfield(vec![0xe0]));
+            peer.send(Message::Unchoke);
+            while peer.recv().is_some() {}
+        });
+
+        let mut conn = PeerConnection::connect(addr, &mut t, &peer_id(b'a'), settings()).unwrap();
+        assert!(conn.supports_extensions());
+        let start = Instant::now();
+        while conn.num_pending_requests() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+        }
+        assert_eq!(Some(1), conn.extended_handshake().unwrap().reqq);
+        assert_eq!(1, conn.num_pending_requests());
+        conn.disconnect(&mut t);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn test_magnet_download() {
+        let mut seed = torrent("peer-magnet", true);
+        let info_hash = seed.info_hash().clone();
+        let (listener, addr) = listen();
+        let seeder = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn =
+                PeerConnection::accept(stream, &mut seed, &peer_id(b's'), settings()).unwrap();
+            // Serve until the leecher hangs up
+            while conn.tick(&mut seed).is_ok() {
+                if conn.peer_interested() {
+                    conn.unchoke().unwrap();
+                }
+            }
+        });
+
+        let mut p = TorrentParams::default();
+        p.info_hash.v1 = info_hash.clone();
+        p.storage = Some(memory_storage_constructor);
+        let mut t = ActiveTorrent::new(p).unwrap();
+        assert!(!t.has_metadata());
+        assert_eq!(State::DownloadingMetadata, t.state());
+
+        let mut conn = PeerConnection::connect(addr, &mut t, &peer_id(b'l'), settings()).unwrap();
+        let start = Instant::now();
+        while t.state() != State::Seeding {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            conn.tick(&mut t).unwrap();
+            t.check_pieces(usize::MAX).unwrap();
+        }
+        assert_eq!(info_hash, *t.info().info_hash());
+        assert_eq!(3, conn.peer_have().count());
+
+        let data = content(CONTENT_LEN);
+        let mut buf = vec![0; data.len() - PIECE_LEN * 2];
+        t.storage().read(&mut buf, 2, 0).unwrap();
+        assert_eq!(&data[PIECE_LEN * 2..], &buf[..]);
+
+        conn.disconnect(&mut t);
+        seeder.join().unwrap();
+    }
+
+    #[test]
+    fn test_info_hash_mismatch() {
+        let mut t = torrent("peer-info-hash-mismatch", false);
+        let (listener, addr) = listen();
+        let peer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            ScriptedPeer::handshake(stream, Sha1Hash::update(b"other"));
+        });
+        let r = PeerConnection::connect(addr, &mut t, &peer_id(b'a'), settings());
+        assert!(matches!(r, Err(Error::InfoHashMismatch)));
+        peer.join().unwrap();
+    }
+}
@@ -0,0 +1,537 @@
+//! The BitTorrent peer wire protocol (BEP 3). Messages are length-prefixed
+//! frames, decoding works on whatever part of the stream has arrived so
+//! far and reports incomplete frames instead of blocking.
+
+use crate::error::{Error, Result};
+use crate::peer_request::PeerRequest;
+use common::sha1::Sha1Hash;
+use std::net::IpAddr;
+
+pub type PeerId = Sha1Hash;
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// Length of the handshake: pstrlen, pstr, reserved, info-hash, peer id.
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Largest frame accepted by default. Large enough for a 16 KiB block and
+/// the bitfield of a torrent with 8 million pieces.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+mod id {
+    pub const CHOKE: u8 = 0;
+    pub const UNCHOKE: u8 = 1;
+    pub const INTERESTED: u8 = 2;
+    pub const NOT_INTERESTED: u8 = 3;
+    pub const HAVE: u8 = 4;
+    pub const BITFIELD: u8 = 5;
+    pub const REQUEST: u8 = 6;
+    pub const PIECE: u8 = 7;
+    pub const CANCEL: u8 = 8;
+    pub const PORT: u8 = 9;
+    pub const SUGGEST_PIECE: u8 = 13;
+    pub const HAVE_ALL: u8 = 14;
+    pub const HAVE_NONE: u8 = 15;
+    pub const REJECT_REQUEST: u8 = 16;
+    pub const ALLOWED_FAST: u8 = 17;
+    pub const EXTENDED: u8 = 20;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerId,
+}
+
+impl Handshake {
+    pub fn new(info_hash: Sha1Hash, peer_id: PeerId) -> Self {
+        Self {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Whether the extension protocol (BEP 10) is supported.
+    pub fn supports_extensions(&self) -> bool {
+        supports_extensions(&self.reserved)
+    }
+
+    pub fn set_supports_extensions(&mut self) {
+        self.reserved[5] |= 0x10;
+    }
+
+    /// Whether the fast extension (BEP 6) is supported.
+    pub fn supports_fast(&self) -> bool {
+        supports_fast(&self.reserved)
+    }
+
+    pub fn set_supports_fast(&mut self) {
+        self.reserved[7] |= 0x04;
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(PROTOCOL.len() as u8);
+        buf.extend_from_slice(PROTOCOL);
+        buf.extend_from_slice(&self.reserved);
+        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(&self.peer_id);
+    }
+
+    /// Decodes a handshake from the start of `buf`. Returns `None` if more
+    /// bytes are needed, otherwise the handshake and the bytes it took up.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>> {
+        // Fail early on anything that doesn't look like BitTorrent
+        let n = buf.len().min(1 + PROTOCOL.len());
+        if n > 0 && (buf[0] as usize != PROTOCOL.len() || buf[1..n] != PROTOCOL[..n - 1]) {
+            return Err(Error::InvalidHandshake);
+        }
+        if buf.len() < HANDSHAKE_LEN {
+            return Ok(None);
+        }
+
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&buf[20..28]);
+        let h = Self {
+            reserved,
+            info_hash: Sha1Hash::from_bytes(&buf[28..48]).unwrap(),
+            peer_id: Sha1Hash::from_bytes(&buf[48..68]).unwrap(),
+        };
+        Ok(Some((h, HANDSHAKE_LEN)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(usize),
+    Bitfield(Vec<u8>),
+    Request(PeerRequest),
+    Piece {
+        piece: usize,
+        start: usize,
+        data: Vec<u8>,
+    },
+    Cancel(PeerRequest),
+    Port(u16),
+
+    /// The fast extension (BEP 6), only sent when both ends support it.
+    SuggestPiece(usize),
+    HaveAll,
+    HaveNone,
+    RejectRequest(PeerRequest),
+    AllowedFast(usize),
+
+    /// A BEP 10 message. Id 0 is the extension handshake, others are the
+    /// ids the receiver assigned in its handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+
+    /// A message with an id we don't implement, kept so the receiver can
+    /// skip it instead of dropping the peer.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let (id, payload_len) = match self {
+            Message::KeepAlive => {
+                buf.extend_from_slice(&0u32.to_be_bytes());
+                return;
+            }
+            Message::Choke => (id::CHOKE, 0),
+            Message::Unchoke => (id::UNCHOKE, 0),
+            Message::Interested => (id::INTERESTED, 0),
+            Message::NotInterested => (id::NOT_INTERESTED, 0),
+            Message::Have(_) => (id::HAVE, 4),
+            Message::Bitfield(bits) => (id::BITFIELD, bits.len()),
+            Message::Request(_) => (id::REQUEST, 12),
+            Message::Piece { data, .. } => (id::PIECE, 8 + data.len()),
+            Message::Cancel(_) => (id::CANCEL, 12),
+            Message::Port(_) => (id::PORT, 2),
+            Message::SuggestPiece(_) => (id::SUGGEST_PIECE, 4),
+            Message::HaveAll => (id::HAVE_ALL, 0),
+            Message::HaveNone => (id::HAVE_NONE, 0),
+            Message::RejectRequest(_) => (id::REJECT_REQUEST, 12),
+            Message::AllowedFast(_) => (id::ALLOWED_FAST, 4),
+            Message::Extended { payload, .. } => (id::EXTENDED, 1 + payload.len()),
+            Message::Unknown { id, payload } => (*id, payload.len()),
+        };
+        buf.extend_from_slice(&(1 + payload_len as u32).to_be_bytes());
+        buf.push(id);
+
+        let put = |buf: &mut Vec<u8>, v: usize| buf.extend_from_slice(&(v as u32).to_be_bytes());
+        match self {
+            Message::Have(piece) | Message::SuggestPiece(piece) | Message::AllowedFast(piece) => {
+                put(buf, *piece)
+            }
+            Message::Bitfield(bits) => buf.extend_from_slice(bits),
+            Message::Request(r) | Message::Cancel(r) | Message::RejectRequest(r) => {
+                put(buf, r.piece);
+                put(buf, r.start);
+                put(buf, r.length);
+            }
+            Message::Piece { piece, start, data } => {
+                put(buf, *piece);
+                put(buf, *start);
+                buf.extend_from_slice(data);
+            }
+            Message::Port(port) => buf.extend_from_slice(&port.to_be_bytes()),
+            Message::Extended { id, payload } => {
+                buf.push(*id);
+                buf.extend_from_slice(payload);
+            }
+            Message::Unknown { payload, .. } => buf.extend_from_slice(payload),
+            _ => {}
+        }
+    }
+
+    /// Decodes a message from the start of `buf`. Returns `None` if the
+    /// frame isn't complete yet, otherwise the message and the bytes it
+    /// took up. Frames longer than `max_len` are an error as soon as their
+    /// length prefix arrives.
+    pub fn decode(buf: &[u8], max_len: usize) -> Result<Option<(Self, usize)>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = read_u32(&buf[..4]) as usize;
+        if len > max_len {
+            return Err(Error::MessageTooLarge);
+        }
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+        if len == 0 {
+            return Ok(Some((Message::KeepAlive, 4)));
+        }
+
+        let id = buf[4];
+        let payload = &buf[5..4 + len];
+        let expect = |n: usize| {
+            if payload.len() == n {
+                Ok(())
+            } else {
+                Err(Error::InvalidMessage)
+            }
+        };
+        let request = || PeerRequest {
+            piece: read_u32(&payload[0..4]) as usize,
+            start: read_u32(&payload[4..8]) as usize,
+            length: read_u32(&payload[8..12]) as usize,
+        };
+
+        let msg = match id {
+            id::CHOKE => expect(0).map(|_| Message::Choke)?,
+            id::UNCHOKE => expect(0).map(|_| Message::Unchoke)?,
+            id::INTERESTED => expect(0).map(|_| Message::Interested)?,
+            id::NOT_INTERESTED => expect(0).map(|_| Message::NotInterested)?,
+            id::HAVE => expect(4).map(|_| Message::Have(read_u32(payload) as usize))?,
+            id::BITFIELD => Message::Bitfield(payload.to_vec()),
+            id::REQUEST => expect(12).map(|_| Message::Request(request()))?,
+            id::PIECE => {
+                if payload.len() < 8 {
+                    return Err(Error::InvalidMessage);
+                }
+                Message::Piece {
+                    piece: read_u32(&payload[0..4]) as usize,
+                    start: read_u32(&payload[4..8]) as usize,
+                    data: payload[8..].to_vec(),
+                }
+            }
+            id::CANCEL => expect(12).map(|_| Message::Cancel(request()))?,
+            id::PORT => {
+                expect(2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            id::SUGGEST_PIECE => {
+                expect(4).map(|_| Message::SuggestPiece(read_u32(payload) as usize))?
+            }
+            id::HAVE_ALL => expect(0).map(|_| Message::HaveAll)?,
+            id::HAVE_NONE => expect(0).map(|_| Message::HaveNone)?,
+            id::REJECT_REQUEST => expect(12).map(|_| Message::RejectRequest(request()))?,
+            id::ALLOWED_FAST => {
+                expect(4).map(|_| Message::AllowedFast(read_u32(payload) as usize))?
+            }
+            id::EXTENDED => match payload.split_first() {
+                Some((&id, payload)) => Message::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                },
+                None => return Err(Error::InvalidMessage),
+            },
+            _ => Message::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        };
+        Ok(Some((msg, 4 + len)))
+    }
+}
+
+/// Whether the reserved bytes of a handshake advertise the extension
+/// protocol (BEP 10).
+pub fn supports_extensions(reserved: &[u8; 8]) -> bool {
+    reserved[5] & 0x10 != 0
+}
+
+/// Whether the reserved bytes of a handshake advertise the fast extension
+/// (BEP 6).
+pub fn supports_fast(reserved: &[u8; 8]) -> bool {
+    reserved[7] & 0x04 != 0
+}
+
+/// The pieces a peer at `ip` may request while choked (BEP 6), `k` of them
+/// or all pieces if there are fewer. The set is only defined for IPv4
+/// peers and is empty for others.
+pub fn allowed_fast_set(
+    info_hash: &Sha1Hash,
+    ip: IpAddr,
+    num_pieces: usize,
+    k: usize,
+) -> Vec<usize> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return vec![],
+    };
+    let k = k.min(num_pieces);
+    let mut set = Vec::with_capacity(k);
+
+    // Peers in the same /24 get the same set
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        let hash = Sha1Hash::update(&x);
+        for chunk in hash.chunks(4) {
+            if set.len() == k {
+                break;
+            }
+            let index = read_u32(chunk) as usize % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+        x = hash.to_vec();
+    }
+    set
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HANDSHAKE: &[u8] = b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x10\x00\x05\
+        \xd8\x4e\x2b\x4e\x4c\x7a\x4f\x2d\x72\x8e\x09\x2f\x0d\x3b\x22\x4b\x0b\x2c\x4e\x1e\
+        -qB4250-abcdefghijkl";
+
+    fn encode(msg: &Message) -> Vec<u8> {
+        let mut buf = vec![];
+        msg.encode(&mut buf);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>> {
+        Message::decode(buf, DEFAULT_MAX_MESSAGE_LEN)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (h, n) = Handshake::decode(HANDSHAKE).unwrap().unwrap();
+        assert_eq!(HANDSHAKE_LEN, n);
+        assert_eq!([0, 0, 0, 0, 0, 0x10, 0, 0x05], h.reserved);
+        assert_eq!(
+            "d84e2b4e4c7a4f2d728e092f0d3b224b0b2c4e1e",
+            h.info_hash.to_string()
+        );
+        assert_eq!(b"-qB4250-abcdefghijkl", &h.peer_id[..]);
+        assert!(h.supports_extensions());
+
+        let mut buf = vec![];
+        h.encode(&mut buf);
+        assert_eq!(HANDSHAKE, &buf[..]);
+
+        // Partial handshakes need more data, garbage is rejected early
+        assert!(Handshake::decode(&HANDSHAKE[..30]).unwrap().is_none());
+        assert!(Handshake::decode(&[]).unwrap().is_none());
+        assert!(matches!(
+            Handshake::decode(b"GET / HTTP/1.1"),
+            Err(Error::InvalidHandshake)
+        ));
+        assert!(matches!(
+            Handshake::decode(b"\x13BitTorrent prot0col"),
+            Err(Error::InvalidHandshake)
+        ));
+    }
+
+    #[test]
+    fn test_fixtures() {
+        let cases: Vec<(&[u8], Message)> = vec![
+            (b"\x00\x00\x00\x00", Message::KeepAlive),
+            (b"\x00\x00\x00\x01\x00", Message::Choke),
+            (b"\x00\x00\x00\x01\x01", Message::Unchoke),
+            (b"\x00\x00\x00\x01\x02", Message::Interested),
+            (b"\x00\x00\x00\x01\x03", Message::NotInterested),
+            (b"\x00\x00\x00\x05\x04\x00\x00\x01\x02", Message::Have(258)),
+            (
+                b"\x00\x00\x00\x03\x05\xff\xe0",
+                Message::Bitfield(vec![0xff, 0xe0]),
+            ),
+            (
+                b"\x00\x00\x00\x0d\x06\x00\x00\x00\x01\x00\x00\x40\x00\x00\x00\x40\x00",
+                Message::Request(PeerRequest::new(1, 0x4000, 0x4000)),
+            ),
+            (
+                b"\x00\x00\x00\x0c\x07\x00\x00\x00\x02\x00\x00\x00\x10abc",
+                Message::Piece {
+                    piece: 2,
+                    start: 16,
+                    data: b"abc".to_vec(),
+                },
+            ),
+            (
+                b"\x00\x00\x00\x0d\x08\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x40\x00",
+                Message::Cancel(PeerRequest::new(1, 0, 0x4000)),
+            ),
+            (b"\x00\x00\x00\x03\x09\x1a\xe1", Message::Port(6881)),
+            (
+                b"\x00\x00\x00\x05\x0d\x00\x00\x00\x03",
+                Message::SuggestPiece(3),
+            ),
+            (b"\x00\x00\x00\x01\x0e", Message::HaveAll),
+            (b"\x00\x00\x00\x01\x0f", Message::HaveNone),
+            (
+                b"\x00\x00\x00\x0d\x10\x00\x00\x00\x01\x00\x00\x40\x00\x00\x00\x40\x00",
+                Message::RejectRequest(PeerRequest::new(1, 0x4000, 0x4000)),
+            ),
+            (
+                b"\x00\x00\x00\x05\x11\x00\x00\x01\x00",
+                Message::AllowedFast(256),
+            ),
+            (
+                b"\x00\x00\x00\x05\x14\x00de\x00",
+                Message::Extended {
+                    id: 0,
+                    payload: b"de\x00".to_vec(),
+                },
+            ),
+        ];
+        for (bytes, msg) in cases {
+            assert_eq!(bytes, &encode(&msg)[..], "{:?}", msg);
+            let (decoded, n) = decode(bytes).unwrap().unwrap();
+            assert_eq!(msg, decoded);
+            assert_eq!(bytes.len(), n);
+        }
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        // The example from BEP 6
+        let info_hash = Sha1Hash::from([0xaa; 20]);
+        let ip = IpAddr::from([80, 4, 4, 200]);
+        assert_eq!(
+            vec![1059, 431, 808, 1217, 287, 376, 1188],
+            allowed_fast_set(&info_hash, ip, 1313, 7)
+        );
+        assert_eq!(
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508],
+            allowed_fast_set(&info_hash, ip, 1313, 9)
+        );
+
+        // The last octet doesn't matter, k is capped by the piece count
+        let other = IpAddr::from([80, 4, 4, 1]);
+        assert_eq!(
+            allowed_fast_set(&info_hash, ip, 1313, 7),
+            allowed_fast_set(&info_hash, other, 1313, 7)
+        );
+        let mut all = allowed_fast_set(&info_hash, ip, 3, 10);
+        all.sort_unstable();
+        assert_eq!(vec![0, 1, 2], all);
+        assert!(allowed_fast_set(&info_hash, "::1".parse().unwrap(), 10, 5).is_empty());
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let mut stream = encode(&Message::Have(7));
+        stream.extend_from_slice(&encode(&Message::Piece {
+            piece: 1,
+            start: 0,
+            data: vec![9; 100],
+        }));
+        stream.extend_from_slice(&encode(&Message::KeepAlive));
+
+        // Feed the stream a few bytes at a time
+        let mut received = vec![];
+        let mut buf = vec![];
+        for chunk in stream.chunks(7) {
+            buf.extend_from_slice(chunk);
+            while let Some((msg, n)) = decode(&buf).unwrap() {
+                buf.drain(..n);
+                received.push(msg);
+            }
+        }
+        assert!(buf.is_empty());
+        assert_eq!(3, received.len());
+        assert_eq!(Message::Have(7), received[0]);
+        assert_eq!(Message::KeepAlive, received[2]);
+    }
+
+    #[test]
+    fn test_errors() {
+        // The length prefix alone is enough to reject an oversized frame
+        assert!(matches!(
+            Message::decode(b"\x00\x01\x00\x00", 1024),
+            Err(Error::MessageTooLarge)
+        ));
+        assert!(matches!(
+            decode(b"\x00\x00\x00\x02\x00\x00"),
+            Err(Error::InvalidMessage)
+        ));
+        assert!(matches!(
+            decode(b"\x00\x00\x00\x04\x04\x00\x00\x01"),
+            Err(Error::InvalidMessage)
+        ));
+        assert!(matches!(
+            decode(b"\x00\x00\x00\x05\x07\x00\x00\x00\x01"),
+            Err(Error::InvalidMessage)
+        ));
+        assert!(matches!(
+            decode(b"\x00\x00\x00\x01\x14"),
+            Err(Error::InvalidMessage)
+        ));
+    }
+
+    #[test]
+    fn test_unknown() {
+        // A BEP 52 hash request followed by a have
+        let mut buf = b"\x00\x00\x00\x03\x15\xab\xcd".to_vec();
+        Message::Have(7).encode(&mut buf);
+
+        let (msg, n) = decode(&buf).unwrap().unwrap();
+        assert_eq!(
+            Message::Unknown {
+                id: 21,
+                payload: vec![0xab, 0xcd]
+            },
+            msg
+        );
+        assert_eq!(7, n);
+        assert_eq!(Message::Have(7), decode(&buf[n..]).unwrap().unwrap().0);
+
+        let mut encoded = vec![];
+        msg.encode(&mut encoded);
+        assert_eq!(&buf[..n], &encoded[..]);
+    }
+}
@@ -0,0 +1,19 @@
+/// A range of bytes within a piece, as sent in request, piece and cancel
+/// messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerRequest {
+    pub piece: usize,
+    /// Offset within the piece.
+    pub start: usize,
+    pub length: usize,
+}
+
+impl PeerRequest {
+    pub fn new(piece: usize, start: usize, length: usize) -> Self {
+        Self {
+            piece,
+            start,
+            length,
+        }
+    }
+}
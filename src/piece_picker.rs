@@ -0,0 +1,708 @@
+//! Decides which blocks to request from which peer. Pieces are picked by
+//! priority class first, then rarest-first (or in order in sequential
+//! mode). Within a priority class, partially downloaded pieces are
+//! finished before new ones are started. The picker is deterministic:
+//! ties are broken by piece index.
+
+use crate::bitfield::Bitfield;
+use crate::download_priority::DownloadPriority;
+use crate::fs::FileStorage;
+use crate::peer_request::PeerRequest;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::net::SocketAddr;
+
+/// Size of the blocks pieces are requested in, the last block of the last
+/// piece may be shorter.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// A block, identified by its piece and its index within the piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PieceBlock {
+    pub piece: usize,
+    pub block: usize,
+}
+
+impl PieceBlock {
+    pub fn new(piece: usize, block: usize) -> Self {
+        Self { piece, block }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    Open,
+    Requested,
+    Finished,
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    state: BlockState,
+
+    /// Peers the block is requested from, more than one only in end-game.
+    peers: Vec<SocketAddr>,
+}
+
+#[derive(Debug, Clone)]
+struct PieceState {
+    availability: u32,
+    priority: DownloadPriority,
+    have: bool,
+}
+
+pub struct PiecePicker {
+    piece_len: usize,
+    total_size: u64,
+    pieces: Vec<PieceState>,
+
+    /// Pieces with at least one block requested or finished.
+    downloading: BTreeMap<usize, Vec<Block>>,
+    sequential: bool,
+
+    /// Number of peers that have all pieces.
+    seeds: u32,
+}
+
+impl PiecePicker {
+    pub fn new(piece_len: usize, total_size: u64) -> Self {
+        debug_assert!(piece_len > 0);
+        let num_pieces = total_size.div_ceil(piece_len as u64) as usize;
+        let piece = PieceState {
+            availability: 0,
+            priority: DownloadPriority::DefaultPriority,
+            have: false,
+        };
+        Self {
+            piece_len,
+            total_size,
+            pieces: vec![piece; num_pieces],
+            downloading: BTreeMap::new(),
+            sequential: false,
+            seeds: 0,
+        }
+    }
+
+    pub fn from_files(files: &FileStorage) -> Self {
+        Self::new(files.piece_len(), files.total_size())
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn piece_size(&self, piece: usize) -> usize {
+        let offset = piece as u64 * self.piece_len as u64;
+        (self.total_size - offset).min(self.piece_len as u64) as usize
+    }
+
+    pub fn blocks_in_piece(&self, piece: usize) -> usize {
+        self.piece_size(piece).div_ceil(BLOCK_SIZE)
+    }
+
+    /// The request to send for a block.
+    pub fn block_request(&self, block: PieceBlock) -> PeerRequest {
+        let start = block.block * BLOCK_SIZE;
+        let length = (self.piece_size(block.piece) - start).min(BLOCK_SIZE);
+        PeerRequest::new(block.piece, start, length)
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    // Availability
+
+    pub fn availability(&self, piece: usize) -> u32 {
+        self.pieces[piece].availability + self.seeds
+    }
+
+    pub fn inc_refcount(&mut self, piece: usize) {
+        self.pieces[piece].availability += 1;
+    }
+
+    pub fn dec_refcount(&mut self, piece: usize) {
+        let p = &mut self.pieces[piece];
+        debug_assert!(p.availability > 0);
+        p.availability = p.availability.saturating_sub(1);
+    }
+
+    /// Adds a peer with the given bitfield to the availability.
+    pub fn inc_refcount_bitfield(&mut self, bitfield: &Bitfield) {
+        for (piece, has) in bitfield.iter().enumerate().take(self.pieces.len()) {
+            if has {
+                self.inc_refcount(piece);
+            }
+        }
+    }
+
+    pub fn dec_refcount_bitfield(&mut self, bitfield: &Bitfield) {
+        for (piece, has) in bitfield.iter().enumerate().take(self.pieces.len()) {
+            if has {
+                self.dec_refcount(piece);
+            }
+        }
+    }
+
+    /// Adds a peer that has every piece.
+    pub fn inc_refcount_all(&mut self) {
+        self.seeds += 1;
+    }
+
+    pub fn dec_refcount_all(&mut self) {
+        debug_assert!(self.seeds > 0);
+        self.seeds -= 1;
+    }
+
+    // Priorities
+
+    pub fn piece_priority(&self, piece: usize) -> DownloadPriority {
+        self.pieces[piece].priority
+    }
+
+    pub fn set_piece_priority(&mut self, piece: usize, priority: DownloadPriority) {
+        self.pieces[piece].priority = priority;
+    }
+
+    /// Sets piece priorities from file priorities. A piece gets the highest
+    /// priority of the files it overlaps, files without an entry in
+    /// `priorities` have the default priority. Pad files are ignored.
+    pub fn set_file_priorities(&mut self, files: &FileStorage, priorities: &[DownloadPriority]) {
+        for piece in &mut self.pieces {
+            piece.priority = DownloadPriority::DontDownload;
+        }
+        for (index, file) in files.files().iter().enumerate() {
+            if file.pad_file {
+                continue;
+            }
+            let priority = priorities.get(index).copied().unwrap_or_default();
+            for piece in files.file_piece_range(index) {
+                let p = &mut self.pieces[piece].priority;
+                if priority as u8 > *p as u8 {
+                    *p = priority;
+                }
+            }
+        }
+    }
+
+    // Pieces we have
+
+    pub fn have_piece(&self, piece: usize) -> bool {
+        self.pieces[piece].have
+    }
+
+    pub fn num_have(&self) -> usize {
+        self.pieces.iter().filter(|p| p.have).count()
+    }
+
+    pub fn we_have(&mut self, piece: usize) {
+        self.pieces[piece].have = true;
+        self.downloading.remove(&piece);
+    }
+
+    pub fn we_dont_have(&mut self, piece: usize) {
+        self.pieces[piece].have = false;
+        self.downloading.remove(&piece);
+    }
+
+    /// Whether we still want to download the piece.
+    pub fn is_wanted(&self, piece: usize) -> bool {
+        let p = &self.pieces[piece];
+        !p.have && is_wanted(p)
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.pieces.iter().all(|p| p.have)
+    }
+
+    /// Whether all pieces we want are downloaded.
+    pub fn is_finished(&self) -> bool {
+        self.pieces.iter().all(|p| p.have || !is_wanted(p))
+    }
+
+    // Block bookkeeping
+
+    fn is_valid_block(&self, block: PieceBlock) -> bool {
+        block.piece < self.pieces.len() && block.block < self.blocks_in_piece(block.piece)
+    }
+
+    /// The state of `block`, None if the torrent has no such block.
+    pub fn block_state(&self, block: PieceBlock) -> Option<BlockState> {
+        if !self.is_valid_block(block) {
+            return None;
+        }
+        if self.pieces[block.piece].have {
+            return Some(BlockState::Finished);
+        }
+        match self.downloading.get(&block.piece) {
+            Some(blocks) => Some(blocks[block.block].state),
+            None => Some(BlockState::Open),
+        }
+    }
+
+    /// Records that `block` was requested from `peer`. Returns false if the
+    /// block was already finished or doesn't exist.
+    pub fn mark_as_requested(&mut self, block: PieceBlock, peer: SocketAddr) -> bool {
+        if !self.is_valid_block(block) || self.pieces[block.piece].have {
+            return false;
+        }
+        let num_blocks = self.blocks_in_piece(block.piece);
+        let blocks = self.downloading.entry(block.piece).or_insert_with(|| {
+            let open = Block {
+                state: BlockState::Open,
+                peers: vec![],
+            };
+            vec![open; num_blocks]
+        });
+        let b = &mut blocks[block.block];
+        if b.state == BlockState::Finished {
+            return false;
+        }
+        b.state = BlockState::Requested;
+        if !b.peers.contains(&peer) {
+            b.peers.push(peer);
+        }
+        true
+    }
+
+    /// Records that `block` was received. Returns true if all blocks of the
+    /// piece are in, i.e. the piece is ready to be hash checked. Peers it's
+    /// still requested from are returned in `cancel` (end-game).
+    pub fn mark_as_finished(
+        &mut self,
+        block: PieceBlock,
+        peer: SocketAddr,
+        cancel: &mut Vec<SocketAddr>,
+    ) -> bool {
+        if !self.is_valid_block(block) || self.pieces[block.piece].have {
+            return false;
+        }
+        self.mark_as_requested(block, peer);
+        let blocks = self.downloading.get_mut(&block.piece).unwrap();
+        let b = &mut blocks[block.block];
+        b.state = BlockState::Finished;
+        cancel.extend(b.peers.drain(..).filter(|p| *p != peer));
+        blocks.iter().all(|b| b.state == BlockState::Finished)
+    }
+
+    /// Returns a requested block to the pool, e.g. because the peer choked
+    /// us, rejected the request or the request timed out.
+    pub fn abort_download(&mut self, block: PieceBlock, peer: SocketAddr) {
+        let blocks = match self.downloading.get_mut(&block.piece) {
+            Some(blocks) => blocks,
+            None => return,
+        };
+        let b = match blocks.get_mut(block.block) {
+            Some(b) if b.state == BlockState::Requested => b,
+            _ => return,
+        };
+        b.peers.retain(|p| *p != peer);
+        if b.peers.is_empty() {
+            b.state = BlockState::Open;
+        }
+        if blocks.iter().all(|b| b.state == BlockState::Open) {
+            self.downloading.remove(&block.piece);
+        }
+    }
+
+    /// Aborts all blocks requested from a peer that went away.
+    pub fn abort_peer(&mut self, peer: SocketAddr) {
+        let blocks: Vec<_> = self
+            .downloading
+            .iter()
+            .flat_map(|(&piece, blocks)| {
+                blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, b)| b.peers.contains(&peer))
+                    .map(move |(block, _)| PieceBlock::new(piece, block))
+            })
+            .collect();
+        for block in blocks {
+            self.abort_download(block, peer);
+        }
+    }
+
+    /// Call after the hash check of a piece whose blocks were all finished.
+    /// A failed piece is downloaded again from scratch.
+    pub fn piece_checked(&mut self, piece: usize, passed: bool) {
+        if passed {
+            self.we_have(piece);
+        } else {
+            self.downloading.remove(&piece);
+        }
+    }
+
+    /// End-game starts once every block we still want is requested. From
+    /// then on blocks are requested from more than one peer.
+    pub fn is_end_game(&self) -> bool {
+        self.pieces.iter().enumerate().all(|(index, p)| {
+            if p.have || !is_wanted(p) {
+                return true;
+            }
+            match self.downloading.get(&index) {
+                Some(blocks) => blocks.iter().all(|b| b.state != BlockState::Open),
+                None => false,
+            }
+        })
+    }
+
+    /// Picks up to `num_blocks` blocks to request from a peer that has the
+    /// pieces in `peer_has`. Blocks already requested from this peer are
+    /// never returned.
+    pub fn pick_pieces(
+        &self,
+        peer_has: &Bitfield,
+        num_blocks: usize,
+        peer: SocketAddr,
+    ) -> Vec<PieceBlock> {
+        self.pick_pieces_prefer(peer_has, &[], num_blocks, peer)
+    }
+
+    /// Like `pick_pieces`, but the pieces in `prefer` (e.g. ones the peer
+    /// suggested or allows us to request while choked) go before others
+    /// of the same priority.
+    pub fn pick_pieces_prefer(
+        &self,
+        peer_has: &Bitfield,
+        prefer: &[usize],
+        num_blocks: usize,
+        peer: SocketAddr,
+    ) -> Vec<PieceBlock> {
+        let mut picked = vec![];
+        if num_blocks == 0 {
+            return picked;
+        }
+
+        for piece in self.candidates(peer_has, prefer) {
+            let open = |block: usize| match self.downloading.get(&piece) {
+                Some(blocks) => blocks[block].state == BlockState::Open,
+                None => true,
+            };
+            for block in (0..self.blocks_in_piece(piece)).filter(|&b| open(b)) {
+                picked.push(PieceBlock::new(piece, block));
+                if picked.len() == num_blocks {
+                    return picked;
+                }
+            }
+        }
+
+        if picked.is_empty() && self.is_end_game() {
+            for piece in self.candidates(peer_has, prefer) {
+                let blocks = match self.downloading.get(&piece) {
+                    Some(blocks) => blocks,
+                    None => continue,
+                };
+                for (block, b) in blocks.iter().enumerate() {
+                    if b.state == BlockState::Requested && !b.peers.contains(&peer) {
+                        picked.push(PieceBlock::new(piece, block));
+                        if picked.len() == num_blocks {
+                            return picked;
+                        }
+                    }
+                }
+            }
+        }
+        picked
+    }
+
+    /// Pieces the peer has that we want, in the order to pick them. They
+    /// are ordered as they're taken, so picking a few blocks doesn't sort
+    /// all pieces.
+    fn candidates<'a>(
+        &'a self,
+        peer_has: &'a Bitfield,
+        prefer: &[usize],
+    ) -> impl Iterator<Item = usize> + 'a {
+        let prefer: HashSet<usize> = prefer.iter().copied().collect();
+        let mut heap: BinaryHeap<Reverse<CandidateKey>> = (0..self.pieces.len())
+            .filter(|&i| i < peer_has.len() && peer_has.get(i))
+            .filter(|&i| !self.pieces[i].have && is_wanted(&self.pieces[i]))
+            .map(|i| {
+                let p = &self.pieces[i];
+                let partial = self.downloading.contains_key(&i);
+                let rarity = if self.sequential { 0 } else { p.availability };
+                Reverse((
+                    Reverse(p.priority as u8),
+                    !partial,
+                    !prefer.contains(&i),
+                    rarity,
+                    i,
+                ))
+            })
+            .collect();
+        std::iter::from_fn(move || heap.pop().map(|Reverse(key)| key.4))
+    }
+}
+
+/// Orders candidate pieces: by priority, partial pieces first, then
+/// preferred ones, rarest first and by index.
+type CandidateKey = (Reverse<u8>, bool, bool, u32, usize);
+
+fn is_wanted(piece: &PieceState) -> bool {
+    piece.priority != DownloadPriority::DontDownload
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::FileEntry;
+    use std::path::PathBuf;
+
+    const PIECE_LEN: usize = 2 * BLOCK_SIZE;
+
+    fn peer(n: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], n))
+    }
+
+    fn bitfield(bits: &str) -> Bitfield {
+        let mut b = Bitfield::new(bits.len());
+        for (i, c) in bits.chars().enumerate() {
+            if c == '1' {
+                b.set(i);
+            }
+        }
+        b
+    }
+
+    /// A picker with `n` full pieces and peers holding the given pieces.
+    fn picker(n: usize, swarm: &[&str]) -> PiecePicker {
+        let mut p = PiecePicker::new(PIECE_LEN, (n * PIECE_LEN) as u64);
+        for bits in swarm {
+            p.inc_refcount_bitfield(&bitfield(bits));
+        }
+        p
+    }
+
+    fn pieces(blocks: &[PieceBlock]) -> Vec<usize> {
+        let mut pieces: Vec<_> = blocks.iter().map(|b| b.piece).collect();
+        pieces.dedup();
+        pieces
+    }
+
+    #[test]
+    fn test_blocks() {
+        let p = PiecePicker::new(PIECE_LEN, PIECE_LEN as u64 + 100);
+        assert_eq!(2, p.num_pieces());
+        assert_eq!(2, p.blocks_in_piece(0));
+        assert_eq!(1, p.blocks_in_piece(1));
+        assert_eq!(
+            PeerRequest::new(0, BLOCK_SIZE, BLOCK_SIZE),
+            p.block_request(PieceBlock::new(0, 1))
+        );
+        assert_eq!(
+            PeerRequest::new(1, 0, 100),
+            p.block_request(PieceBlock::new(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let mut p = picker(4, &["1111", "1101", "0100", "0100"]);
+        let availability: Vec<_> = (0..4).map(|i| p.availability(i)).collect();
+        assert_eq!(vec![2, 4, 1, 2], availability);
+        let picked = p.pick_pieces(&bitfield("1111"), 8, peer(1));
+        assert_eq!(vec![2, 0, 3, 1], pieces(&picked));
+        assert_eq!(PieceBlock::new(2, 0), picked[0]);
+
+        // Seeds count towards every piece
+        p.inc_refcount_all();
+        assert_eq!(2, p.availability(2));
+        p.dec_refcount_bitfield(&bitfield("1101"));
+        p.dec_refcount_all();
+        assert_eq!(1, p.availability(3));
+        let picked = p.pick_pieces(&bitfield("1111"), 8, peer(1));
+        assert_eq!(vec![0, 2, 3, 1], pieces(&picked));
+    }
+
+    #[test]
+    fn test_only_pieces_peer_has() {
+        let p = picker(4, &["1111"]);
+        let picked = p.pick_pieces(&bitfield("0010"), 8, peer(1));
+        assert_eq!(vec![PieceBlock::new(2, 0), PieceBlock::new(2, 1)], picked);
+        assert!(p.pick_pieces(&bitfield("0000"), 8, peer(1)).is_empty());
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut p = picker(4, &["1111", "0001"]);
+        p.set_piece_priority(0, DownloadPriority::DontDownload);
+        p.set_piece_priority(1, DownloadPriority::LowPriority);
+        p.set_piece_priority(2, DownloadPriority::TopPriority);
+        let picked = p.pick_pieces(&bitfield("1111"), 8, peer(1));
+        assert_eq!(vec![2, 3, 1], pieces(&picked));
+    }
+
+    #[test]
+    fn test_file_priorities() {
+        let mut files = FileStorage::new();
+        files.set_piece_len(PIECE_LEN);
+        for size in &[PIECE_LEN + 10, PIECE_LEN - 10, PIECE_LEN] {
+            files.add_file(FileEntry {
+                path: PathBuf::from("f"),
+                size: *size as u64,
+                ..FileEntry::default()
+            });
+        }
+        files.set_num_pieces(3);
+
+        let mut p = PiecePicker::from_files(&files);
+        p.set_file_priorities(
+            &files,
+            &[
+                DownloadPriority::DontDownload,
+                DownloadPriority::LowPriority,
+            ],
+        );
+        assert_eq!(DownloadPriority::DontDownload, p.piece_priority(0));
+        assert_eq!(DownloadPriority::LowPriority, p.piece_priority(1));
+        assert_eq!(DownloadPriority::DefaultPriority, p.piece_priority(2));
+    }
+
+    #[test]
+    fn test_sequential() {
+        let mut p = picker(4, &["1111", "1100", "1100"]);
+        p.set_sequential(true);
+        p.set_piece_priority(3, DownloadPriority::TopPriority);
+        let picked = p.pick_pieces(&bitfield("1111"), 8, peer(1));
+        assert_eq!(vec![3, 0, 1, 2], pieces(&picked));
+    }
+
+    #[test]
+    fn test_prefer() {
+        let mut p = picker(4, &["1111", "0111"]);
+        p.set_piece_priority(3, DownloadPriority::TopPriority);
+        let picked = p.pick_pieces_prefer(&bitfield("1111"), &[2, 1], 8, peer(1));
+        assert_eq!(vec![3, 1, 2, 0], pieces(&picked));
+        let picked = p.pick_pieces_prefer(&bitfield("1111"), &[2], 8, peer(1));
+        assert_eq!(vec![3, 2, 0, 1], pieces(&picked));
+    }
+
+    #[test]
+    fn test_partial_pieces_first() {
+        let mut p = picker(4, &["1111", "1111", "0001"]);
+        assert!(p.mark_as_requested(PieceBlock::new(0, 0), peer(1)));
+        assert_eq!(
+            Some(BlockState::Requested),
+            p.block_state(PieceBlock::new(0, 0))
+        );
+
+        let picked = p.pick_pieces(&bitfield("1111"), 3, peer(2));
+        assert_eq!(
+            vec![
+                PieceBlock::new(0, 1),
+                PieceBlock::new(1, 0),
+                PieceBlock::new(1, 1)
+            ],
+            picked
+        );
+    }
+
+    #[test]
+    fn test_priority_before_partial() {
+        let mut p = picker(2, &["11"]);
+        p.set_piece_priority(0, DownloadPriority::LowPriority);
+        p.set_piece_priority(1, DownloadPriority::TopPriority);
+        assert!(p.mark_as_requested(PieceBlock::new(0, 0), peer(1)));
+
+        let picked = p.pick_pieces(&bitfield("11"), 3, peer(2));
+        assert_eq!(
+            vec![
+                PieceBlock::new(1, 0),
+                PieceBlock::new(1, 1),
+                PieceBlock::new(0, 1)
+            ],
+            picked
+        );
+    }
+
+    #[test]
+    fn test_block_bookkeeping() {
+        let mut p = picker(2, &["11"]);
+        let mut cancel = vec![];
+        let b0 = PieceBlock::new(0, 0);
+        let b1 = PieceBlock::new(0, 1);
+
+        p.mark_as_requested(b0, peer(1));
+        p.mark_as_requested(b1, peer(1));
+        p.abort_download(b1, peer(1));
+        assert_eq!(Some(BlockState::Open), p.block_state(b1));
+
+        assert!(!p.mark_as_finished(b0, peer(1), &mut cancel));
+        assert!(!p.mark_as_requested(b0, peer(2)));
+        assert!(p.mark_as_finished(b1, peer(2), &mut cancel));
+        assert!(cancel.is_empty());
+
+        // Hash check failed, the piece starts over
+        p.piece_checked(0, false);
+        assert_eq!(Some(BlockState::Open), p.block_state(b0));
+        p.mark_as_finished(b0, peer(1), &mut cancel);
+        p.mark_as_finished(b1, peer(1), &mut cancel);
+        p.piece_checked(0, true);
+        assert!(p.have_piece(0));
+        assert_eq!(Some(BlockState::Finished), p.block_state(b1));
+        assert_eq!(1, p.num_have());
+        assert!(!p.is_finished());
+
+        p.we_have(1);
+        assert!(p.is_seed() && p.is_finished());
+        assert!(p.pick_pieces(&bitfield("11"), 8, peer(1)).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_block() {
+        let mut p = picker(2, &["11"]);
+        let mut cancel = vec![];
+        p.mark_as_requested(PieceBlock::new(0, 0), peer(1));
+        for block in [PieceBlock::new(0, 2), PieceBlock::new(2, 0)] {
+            assert_eq!(None, p.block_state(block));
+            assert!(!p.mark_as_requested(block, peer(1)));
+            assert!(!p.mark_as_finished(block, peer(1), &mut cancel));
+            p.abort_download(block, peer(1));
+        }
+        assert_eq!(
+            Some(BlockState::Requested),
+            p.block_state(PieceBlock::new(0, 0))
+        );
+    }
+
+    #[test]
+    fn test_abort_peer() {
+        let mut p = picker(2, &["11"]);
+        p.mark_as_requested(PieceBlock::new(0, 0), peer(1));
+        p.mark_as_requested(PieceBlock::new(1, 1), peer(1));
+        p.mark_as_requested(PieceBlock::new(1, 0), peer(2));
+        p.abort_peer(peer(1));
+        assert_eq!(Some(BlockState::Open), p.block_state(PieceBlock::new(0, 0)));
+        assert_eq!(Some(BlockState::Open), p.block_state(PieceBlock::new(1, 1)));
+        assert_eq!(
+            Some(BlockState::Requested),
+            p.block_state(PieceBlock::new(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_end_game() {
+        let mut p = picker(2, &["11", "11"]);
+        p.set_piece_priority(1, DownloadPriority::DontDownload);
+        let picked = p.pick_pieces(&bitfield("11"), 8, peer(1));
+        assert_eq!(2, picked.len());
+        assert!(!p.is_end_game());
+        for b in &picked {
+            p.mark_as_requested(*b, peer(1));
+        }
+        assert!(p.is_end_game());
+
+        // The same peer gets nothing, another one gets the busy blocks
+        assert!(p.pick_pieces(&bitfield("11"), 8, peer(1)).is_empty());
+        let picked = p.pick_pieces(&bitfield("11"), 8, peer(2));
+        assert_eq!(vec![PieceBlock::new(0, 0), PieceBlock::new(0, 1)], picked);
+        for b in &picked {
+            p.mark_as_requested(*b, peer(2));
+        }
+
+        let mut cancel = vec![];
+        p.mark_as_finished(PieceBlock::new(0, 0), peer(2), &mut cancel);
+        assert_eq!(vec![peer(1)], cancel);
+    }
+}
@@ -0,0 +1,540 @@
+//! Fast-resume data in the libtorrent resume file format, so a restarted
+//! client can pick up a torrent without rechecking all of its pieces.
+
+use crate::bitfield::Bitfield;
+use crate::download_priority::DownloadPriority;
+use crate::endpoint::Endpoint;
+use crate::error::{Error, Result};
+use crate::flags::TorrentFlags;
+use crate::info::{self, TorrentLimits};
+use crate::params::TorrentParams;
+use crate::storage::{self, StorageMode};
+use bencode::{Value, ValueRef};
+use common::sha1::Sha1Hash;
+use common::sha256::Sha256Hash;
+use dht::detail;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const FILE_FORMAT: &str = "libtorrent resume file";
+const FILE_VERSION: i64 = 1;
+
+/// Flags stored in the resume file, each as an integer 0 or 1.
+const FLAG_KEYS: &[(&str, TorrentFlags)] = &[
+    ("seed_mode", TorrentFlags::SEED_MODE),
+    ("upload_mode", TorrentFlags::UPLOAD_MODE),
+    ("share_mode", TorrentFlags::SHARE_MODE),
+    ("apply_ip_filter", TorrentFlags::APPLY_IP_FILTER),
+    ("paused", TorrentFlags::PAUSED),
+    ("auto_managed", TorrentFlags::AUTO_MANAGED),
+    ("super_seeding", TorrentFlags::SUPER_SEEDING),
+    ("sequential_download", TorrentFlags::SEQUENTIAL_DOWNLOAD),
+    ("stop_when_ready", TorrentFlags::STOP_WHEN_READY),
+    ("disable_dht", TorrentFlags::DISABLE_DHT),
+    ("disable_lsd", TorrentFlags::DISABLE_LSD),
+    ("disable_pex", TorrentFlags::DISABLE_PEX),
+];
+
+/// Bits of the per piece bytes in the "pieces" string.
+const PIECE_HAVE: u8 = 1;
+const PIECE_VERIFIED: u8 = 2;
+
+/// Encodes the resume state of `params` as a bencoded dictionary.
+pub fn write_resume_data(params: &TorrentParams) -> Value {
+    let mut d = BTreeMap::new();
+    let mut put = |key: &str, value: Value| {
+        d.insert(key.to_string(), value);
+    };
+
+    put("file-format", Value::with_str(FILE_FORMAT));
+    put("file-version", Value::with_int(FILE_VERSION));
+
+    if params.info_hash.has_v1() {
+        put("info-hash", Value::Bytes(params.info_hash.v1.to_vec()));
+    }
+    if params.info_hash.has_v2() {
+        put("info-hash2", Value::Bytes(params.info_hash.v2.to_vec()));
+    }
+    if !params.name.is_empty() {
+        put("name", Value::with_str(&params.name));
+    }
+    put("save_path", Value::with_str(&params.save_path));
+    let allocation = match params.storage_mode {
+        StorageMode::Sparse => "sparse",
+        StorageMode::Allocate => "allocate",
+    };
+    put("allocation", Value::with_str(allocation));
+
+    put(
+        "total_uploaded",
+        Value::with_int(params.total_uploaded as i64),
+    );
+    put(
+        "total_downloaded",
+        Value::with_int(params.total_downloaded as i64),
+    );
+    put(
+        "active_time",
+        Value::with_int(params.active_time.as_secs() as i64),
+    );
+    put(
+        "finished_time",
+        Value::with_int(params.finished_time.as_secs() as i64),
+    );
+    put(
+        "seeding_time",
+        Value::with_int(params.seeding_time.as_secs() as i64),
+    );
+    put("added_time", Value::with_int(params.added_time));
+    put("completed_time", Value::with_int(params.completed_time));
+    put(
+        "last_seen_complete",
+        Value::with_int(params.last_seen_complete),
+    );
+    put("last_download", Value::with_int(params.last_download));
+    put("last_upload", Value::with_int(params.last_upload));
+    put("num_complete", Value::with_int(params.num_complete as i64));
+    put(
+        "num_incomplete",
+        Value::with_int(params.num_incomplete as i64),
+    );
+    put(
+        "num_downloaded",
+        Value::with_int(params.num_downloaded as i64),
+    );
+
+    put("max_uploads", Value::with_int(params.max_uploads as i64));
+    put(
+        "max_connections",
+        Value::with_int(params.max_connections as i64),
+    );
+    put(
+        "upload_rate_limit",
+        Value::with_int(params.upload_limit as i64),
+    );
+    put(
+        "download_rate_limit",
+        Value::with_int(params.download_limit as i64),
+    );
+
+    for (key, flag) in FLAG_KEYS {
+        let set = params.flags.contains(*flag);
+        put(key, Value::with_int(set as i64));
+    }
+
+    let num_pieces = params.have_pieces.len().max(params.verified_pieces.len());
+    let pieces = (0..num_pieces)
+        .map(|i| {
+            let mut b = 0;
+            if i < params.have_pieces.len() && params.have_pieces.get(i) {
+                b |= PIECE_HAVE;
+            }
+            if i < params.verified_pieces.len() && params.verified_pieces.get(i) {
+                b |= PIECE_VERIFIED;
+            }
+            b
+        })
+        .collect();
+    put("pieces", Value::Bytes(pieces));
+
+    let mut unfinished: Vec<_> = params.unfinished_pieces.iter().collect();
+    unfinished.sort_by_key(|(piece, _)| **piece);
+    let unfinished = unfinished
+        .into_iter()
+        .map(|(piece, blocks)| {
+            let mut e = BTreeMap::new();
+            e.insert("piece".to_string(), Value::with_int(*piece as i64));
+            e.insert(
+                "bitmask".to_string(),
+                Value::Bytes(blocks.as_bytes().to_vec()),
+            );
+            Value::with_dict(e)
+        })
+        .collect();
+    put("unfinished", Value::with_list(unfinished));
+
+    if !params.piece_priorities.is_empty() {
+        let prio = params.piece_priorities.iter().map(|p| *p as u8).collect();
+        put("piece_priority", Value::Bytes(prio));
+    }
+    if !params.file_priorities.is_empty() {
+        let prio = params
+            .file_priorities
+            .iter()
+            .map(|p| Value::with_int(*p as i64))
+            .collect();
+        put("file_priority", Value::with_list(prio));
+    }
+
+    if !params.renamed_files.is_empty() {
+        let len = params.renamed_files.keys().max().map_or(0, |i| i + 1);
+        let mapped = (0..len)
+            .map(|i| match params.renamed_files.get(&i) {
+                Some(path) => Value::with_str(path),
+                None => Value::with_str(""),
+            })
+            .collect();
+        put("mapped_files", Value::with_list(mapped));
+    }
+
+    let mut tiers: BTreeMap<isize, Vec<Value>> = BTreeMap::new();
+    for (i, url) in params.trackers.iter().enumerate() {
+        let tier = params.tracker_tiers.get(i).copied().unwrap_or(0);
+        tiers.entry(tier).or_default().push(Value::with_str(url));
+    }
+    let trackers = tiers.into_values().map(Value::with_list).collect();
+    put("trackers", Value::with_list(trackers));
+
+    let urls =
+        |urls: &[String]| Value::with_list(urls.iter().map(|u| Value::with_str(u)).collect());
+    put("url-list", urls(&params.url_seeds));
+    put("httpseeds", urls(&params.http_seeds));
+
+    let peers = params.peers.iter().filter_map(|p| match p {
+        Endpoint::Addr(addr) => Some(addr),
+        Endpoint::Host(..) => None,
+    });
+    let (peers, peers6) = compact_peers(peers);
+    put("peers", Value::Bytes(peers));
+    put("peers6", Value::Bytes(peers6));
+
+    let (banned, banned6) = compact_peers(params.banned_peers.iter());
+    put("banned_peers", Value::Bytes(banned));
+    put("banned_peers6", Value::Bytes(banned6));
+
+    Value::with_dict(d)
+}
+
+/// Decodes a resume file written by [`write_resume_data`] (or libtorrent).
+pub fn read_resume_data(buf: &[u8]) -> Result<TorrentParams> {
+    read_resume_data_with_limits(buf, &TorrentLimits::default())
+}
+
+pub fn read_resume_data_with_limits(buf: &[u8], limits: &TorrentLimits) -> Result<TorrentParams> {
+    if buf.len() > limits.max_buf_size {
+        return Err(Error::TorrentBufferTooLarge);
+    }
+    let rd = info::decode_with_limits(buf, limits)?;
+    if !rd.is_dict() {
+        return Err(Error::ResumeIsNoDict);
+    }
+    if rd.dict_find_str_value("file-format") != Some(FILE_FORMAT) {
+        return Err(Error::ResumeInvalidFileFormat);
+    }
+
+    let mut p = TorrentParams::default();
+
+    let bytes = |key| rd.dict_find(key).and_then(ValueRef::as_bytes);
+    let int = |key| rd.dict_find_int_value(key).unwrap_or(0);
+
+    if let Some(h) = bytes("info-hash").and_then(Sha1Hash::from_bytes) {
+        p.info_hash.v1 = h;
+    }
+    if let Some(h) = bytes("info-hash2").and_then(Sha256Hash::from_bytes) {
+        p.info_hash.v2 = h;
+    }
+    if !p.info_hash.is_valid() {
+        return Err(Error::MissingInfoHash);
+    }
+
+    if let Some(name) = rd.dict_find_str_value("name") {
+        p.name = name.to_string();
+    }
+    if let Some(path) = rd.dict_find_str_value("save_path") {
+        p.save_path = path.to_string();
+    }
+    if rd.dict_find_str_value("allocation") == Some("allocate") {
+        p.storage_mode = StorageMode::Allocate;
+    }
+
+    p.total_uploaded = int("total_uploaded").max(0) as usize;
+    p.total_downloaded = int("total_downloaded").max(0) as usize;
+    p.active_time = Duration::from_secs(int("active_time").max(0) as u64);
+    p.finished_time = Duration::from_secs(int("finished_time").max(0) as u64);
+    p.seeding_time = Duration::from_secs(int("seeding_time").max(0) as u64);
+    if let Some(t) = rd.dict_find_int_value("added_time") {
+        p.added_time = t;
+    }
+    p.completed_time = int("completed_time");
+    p.last_seen_complete = int("last_seen_complete");
+    p.last_download = int("last_download");
+    p.last_upload = int("last_upload");
+    p.num_complete = int("num_complete") as isize;
+    p.num_incomplete = int("num_incomplete") as isize;
+    p.num_downloaded = int("num_downloaded") as isize;
+
+    p.max_uploads = rd.dict_find_int_value("max_uploads").unwrap_or(-1) as isize;
+    p.max_connections = rd.dict_find_int_value("max_connections").unwrap_or(-1) as isize;
+    p.upload_limit = rd.dict_find_int_value("upload_rate_limit").unwrap_or(-1) as isize;
+    p.download_limit = rd.dict_find_int_value("download_rate_limit").unwrap_or(-1) as isize;
+
+    // Flags missing from the file keep their defaults
+    for (key, flag) in FLAG_KEYS {
+        if let Some(v) = rd.dict_find_int_value(key) {
+            p.flags.set(*flag, v != 0);
+        }
+    }
+
+    if let Some(pieces) = bytes("pieces") {
+        p.have_pieces = Bitfield::new(pieces.len());
+        p.verified_pieces = Bitfield::new(pieces.len());
+        for (i, b) in pieces.iter().enumerate() {
+            if b & PIECE_HAVE != 0 {
+                p.have_pieces.set(i);
+            }
+            if b & PIECE_VERIFIED != 0 {
+                p.verified_pieces.set(i);
+            }
+        }
+    }
+
+    if let Some(unfinished) = rd.dict_find_list_value("unfinished") {
+        for e in unfinished {
+            let piece = match e.dict_find_int_value("piece") {
+                Some(piece) if piece >= 0 => piece as usize,
+                _ => continue,
+            };
+            let mask = match e.dict_find("bitmask").and_then(ValueRef::as_bytes) {
+                Some(mask) if !mask.is_empty() => mask,
+                _ => continue,
+            };
+            let blocks = Bitfield::from_bytes(mask, mask.len() * 8);
+            p.unfinished_pieces.insert(piece, blocks);
+        }
+    }
+
+    if let Some(prio) = bytes("piece_priority") {
+        p.piece_priorities = prio.iter().map(|&b| DownloadPriority::from(b)).collect();
+    }
+    if let Some(prio) = rd.dict_find_list_value("file_priority") {
+        p.file_priorities = prio
+            .iter()
+            .map(|v| {
+                let v = v
+                    .as_int()
+                    .unwrap_or(DownloadPriority::DefaultPriority as i64);
+                DownloadPriority::from(v.clamp(0, 7) as u8)
+            })
+            .collect();
+    }
+
+    if let Some(mapped) = rd.dict_find_list_value("mapped_files") {
+        for (i, path) in mapped.iter().enumerate() {
+            match path.as_str() {
+                // Renames may not leave the save path
+                Some(path) if storage::is_contained_path(path) => {
+                    p.renamed_files.insert(i, path.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(tiers) = rd.dict_find_list_value("trackers") {
+        for (tier, urls) in tiers.iter().enumerate() {
+            for url in urls.as_list().unwrap_or_default() {
+                if let Some(url) = url.as_str() {
+                    p.trackers.push(url.to_string());
+                    p.tracker_tiers.push(tier as isize);
+                }
+            }
+        }
+    }
+
+    let urls = |key| {
+        rd.dict_find_list_value(key)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| v.as_str().map(String::from))
+            .collect()
+    };
+    p.url_seeds = urls("url-list");
+    p.http_seeds = urls("httpseeds");
+
+    read_compact_peers(bytes("peers"), bytes("peers6"), |addr| {
+        p.peers.push(addr.into())
+    });
+    read_compact_peers(bytes("banned_peers"), bytes("banned_peers6"), |addr| {
+        p.banned_peers.push(addr)
+    });
+
+    Ok(p)
+}
+
+/// Splits peers into the compact IPv4 and IPv6 forms (BEP 23, BEP 7).
+fn compact_peers<'a>(peers: impl Iterator<Item = &'a SocketAddr>) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = vec![];
+    let mut v6 = vec![];
+    for addr in peers {
+        let w = if addr.is_ipv4() { &mut v4 } else { &mut v6 };
+        detail::write_socket_addr(w, addr).unwrap();
+    }
+    (v4, v6)
+}
+
+fn read_compact_peers(v4: Option<&[u8]>, v6: Option<&[u8]>, mut f: impl FnMut(SocketAddr)) {
+    for mut c in v4.unwrap_or_default().chunks_exact(6) {
+        if let Ok(addr) = detail::read_v4_socket_address(&mut c) {
+            f(addr);
+        }
+    }
+    for mut c in v6.unwrap_or_default().chunks_exact(18) {
+        if let Ok(addr) = detail::read_v6_socket_address(&mut c) {
+            f(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample_params() -> TorrentParams {
+        let mut p = TorrentParams::default();
+        p.info_hash.v1 = Sha1Hash::update(b"resume");
+        p.name = "test".to_string();
+        p.save_path = "/tmp/downloads".to_string();
+        p.storage_mode = StorageMode::Allocate;
+        p.flags = TorrentFlags::SEQUENTIAL_DOWNLOAD | TorrentFlags::DISABLE_PEX;
+        p.total_uploaded = 1000;
+        p.total_downloaded = 2000;
+        p.active_time = Duration::from_secs(60);
+        p.added_time = 1577614375;
+        p.completed_time = 1577614400;
+        p.num_complete = 5;
+        p.max_connections = 50;
+
+        p.have_pieces = Bitfield::new(10);
+        p.have_pieces.set(0);
+        p.have_pieces.set(3);
+        p.verified_pieces = Bitfield::new(10);
+        p.verified_pieces.set(3);
+        let mut blocks = Bitfield::new(16);
+        blocks.set(1);
+        p.unfinished_pieces.insert(4, blocks);
+        p.piece_priorities = vec![DownloadPriority::TopPriority; 10];
+        p.file_priorities = vec![
+            DownloadPriority::DontDownload,
+            DownloadPriority::LowPriority,
+        ];
+        p.renamed_files.insert(1, "renamed.txt".to_string());
+
+        p.trackers = vec![
+            "http://a/announce".to_string(),
+            "http://b/announce".to_string(),
+            "udp://c:80".to_string(),
+        ];
+        p.tracker_tiers = vec![0, 1, 0];
+        p.url_seeds = vec!["http://seed/".to_string()];
+        p.http_seeds = vec!["http://hseed/".to_string()];
+        p.peers = vec![
+            "1.2.3.4:6881".parse().unwrap(),
+            "[::1]:6882".parse().unwrap(),
+            "example.com:80".parse().unwrap(),
+        ];
+        p.banned_peers = vec!["5.6.7.8:1".parse().unwrap()];
+        p
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let p = sample_params();
+        let buf = write_resume_data(&p).to_vec();
+        let q = read_resume_data(&buf).unwrap();
+
+        assert_eq!(p.info_hash.v1, q.info_hash.v1);
+        assert!(!q.info_hash.has_v2());
+        assert_eq!(p.name, q.name);
+        assert_eq!(p.save_path, q.save_path);
+        assert_eq!(StorageMode::Allocate, q.storage_mode);
+        assert!(q
+            .flags
+            .contains(TorrentFlags::SEQUENTIAL_DOWNLOAD | TorrentFlags::DISABLE_PEX));
+        assert!(!q
+            .flags
+            .intersects(TorrentFlags::PAUSED | TorrentFlags::AUTO_MANAGED));
+        assert_eq!(1000, q.total_uploaded);
+        assert_eq!(2000, q.total_downloaded);
+        assert_eq!(Duration::from_secs(60), q.active_time);
+        assert_eq!(1577614375, q.added_time);
+        assert_eq!(1577614400, q.completed_time);
+        assert_eq!(0, q.last_upload);
+        assert_eq!(5, q.num_complete);
+        assert_eq!(50, q.max_connections);
+
+        assert_eq!(p.have_pieces, q.have_pieces);
+        assert_eq!(p.verified_pieces, q.verified_pieces);
+        assert_eq!(p.unfinished_pieces, q.unfinished_pieces);
+        assert_eq!(p.piece_priorities, q.piece_priorities);
+        assert_eq!(p.file_priorities, q.file_priorities);
+        assert_eq!(p.renamed_files, q.renamed_files);
+
+        assert_eq!(
+            vec!["http://a/announce", "udp://c:80", "http://b/announce"],
+            q.trackers
+        );
+        assert_eq!(vec![0, 0, 1], q.tracker_tiers);
+        assert_eq!(p.url_seeds, q.url_seeds);
+        assert_eq!(p.http_seeds, q.http_seeds);
+
+        // Host names can't be stored in the compact format
+        assert_eq!(&p.peers[..2], &q.peers[..]);
+        assert_eq!(p.banned_peers, q.banned_peers);
+    }
+
+    #[test]
+    fn test_pieces_format() {
+        let v = write_resume_data(&sample_params());
+        assert_eq!(Some(FILE_FORMAT), v.dict_find_str_value("file-format"));
+        assert_eq!(
+            Some(&[1, 0, 0, 3, 0, 0, 0, 0, 0, 0][..]),
+            v.dict_find("pieces").and_then(Value::as_bytes)
+        );
+        assert_eq!(
+            Some(&[7; 10][..]),
+            v.dict_find("piece_priority").and_then(Value::as_bytes)
+        );
+        assert_eq!(
+            Some(&b"\x01\x02\x03\x04\x1a\xe1"[..]),
+            v.dict_find("peers").and_then(Value::as_bytes)
+        );
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert!(matches!(
+            read_resume_data(b"i1e"),
+            Err(Error::ResumeIsNoDict)
+        ));
+        assert!(matches!(
+            read_resume_data(b"d11:file-format3:fooe"),
+            Err(Error::ResumeInvalidFileFormat)
+        ));
+        assert!(matches!(
+            read_resume_data(b"d11:file-format22:libtorrent resume filee"),
+            Err(Error::MissingInfoHash)
+        ));
+        assert!(matches!(read_resume_data(b"d1:a"), Err(Error::Bencode(_))));
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        let buf = b"d11:file-format22:libtorrent resume file9:info-hash20:aaaaaaaaaaaaaaaaaaaa6:pausedi0ee";
+        let p = read_resume_data(buf).unwrap();
+        assert_eq!(Sha1Hash::from_bytes(&[b'a'; 20]), Some(p.info_hash.v1));
+        assert!(!p.flags.contains(TorrentFlags::PAUSED));
+        assert!(p.flags.contains(TorrentFlags::AUTO_MANAGED));
+        assert!(p.have_pieces.is_empty());
+        assert!(p.trackers.is_empty());
+        assert_eq!(-1, p.max_connections);
+    }
+
+    #[test]
+    fn test_mapped_files_outside_save_path() {
+        let buf = b"d11:file-format22:libtorrent resume file9:info-hash20:aaaaaaaaaaaaaaaaaaaa12:mapped_filesl4:../x2:/x1:a0:ee";
+        let p = read_resume_data(buf).unwrap();
+        assert_eq!(1, p.renamed_files.len());
+        assert_eq!("a", p.renamed_files[&2]);
+    }
+}
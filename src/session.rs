@@ -0,0 +1,1182 @@
+//! The session: the torrents added to the client along with the sockets
+//! peers reach us on, local service discovery, the DHT node's state and
+//! the settings they share.
+//!
+//! Torrents are accessed through [`TorrentHandle`]s, which stop working
+//! once the torrent is removed. Each torrent runs on a thread of its own
+//! that connects, serves and unchokes its peers; peer sockets don't block
+//! it. Connecting and the handshakes happen on short-lived threads, so a
+//! slow peer holds up nobody, and so do tracker announces and requests to
+//! web seeds. The torrent's lock is only held while it's ticked, so ticking
+//! the session, which hands incoming connections to the torrent they ask
+//! for and runs local service discovery, never waits on the network.
+//!
+//! The DHT isn't run: the dht crate can't talk to other nodes yet. The
+//! session only keeps the node's state and the bootstrap nodes of added
+//! torrents for when it can.
+
+use crate::active_torrent::ActiveTorrent;
+use crate::error::{Error, Result};
+use crate::flags::TorrentFlags;
+use crate::lsd::{Lsd, LsdSettings, LsdTransport, MulticastTransport};
+use crate::params::TorrentParams;
+use crate::peer_connection::{PeerConnection, PeerSettings, PendingConnection};
+use crate::peer_message::PeerId;
+use crate::status::{State, TorrentStatus};
+use crate::tracker::TrackerRequest;
+use crate::tracker_list::TrackerList;
+use crate::udp_tracker::{UdpTrackerClient, UdpTrackerSettings};
+use crate::ut_pex::PexFlags;
+use crate::web_seed::{WebSeed, WebSeedRequest, WebSeedSettings, WebSeedType};
+use bitflags::bitflags;
+use common::random;
+use common::sha1::Sha1Hash;
+use defaults::Defaults;
+use dht::settings::DhtSettings;
+use dht::state::DhtState;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Where the info hash is in a handshake, after the protocol string and
+/// the reserved bytes.
+const HANDSHAKE_INFO_HASH: Range<usize> = 28..48;
+
+#[derive(Debug, Clone, Defaults)]
+pub struct SessionSettings {
+    /// Addresses to accept peer connections on, port 0 picks a free port.
+    #[def = "vec![SocketAddr::from(([0, 0, 0, 0], 6881))]"]
+    pub listen_interfaces: Vec<SocketAddr>,
+
+    #[def = "generate_peer_id()"]
+    pub peer_id: PeerId,
+
+    /// Used for all peer connections. The session fills in the listen
+    /// port and makes them non-blocking.
+    pub peer: PeerSettings,
+
+    /// Peer connections across all torrents.
+    #[def = "200"]
+    pub connections_limit: usize,
+
+    /// Peers we upload to at the same time, per torrent.
+    #[def = "8"]
+    pub unchoke_slots_limit: usize,
+
+    /// Pieces checked per torrent and tick while checking files.
+    #[def = "16"]
+    pub checking_pieces_per_tick: usize,
+
+    /// Time a torrent's thread waits between rounds.
+    #[def = "Duration::from_millis(10)"]
+    pub tick_interval: Duration,
+
+    pub udp_tracker: UdpTrackerSettings,
+    pub web_seed: WebSeedSettings,
+
+    /// Finds peers on the local network with BEP 14 multicast. The session
+    /// can't be created if the multicast sockets can't be set up.
+    #[def = "true"]
+    pub enable_lsd: bool,
+    pub lsd: LsdSettings,
+
+    /// Keeps DHT node state, see the module docs.
+    #[def = "true"]
+    pub enable_dht: bool,
+}
+
+/// A random peer id in Azureus style, tagged with our client and version.
+pub fn generate_peer_id() -> PeerId {
+    let mut id = [0; 20];
+    id[..8].copy_from_slice(b"-RS0100-");
+    random::fill_bytes(&mut id[8..]);
+    PeerId::from(id)
+}
+
+bitflags! {
+    /// Options for `Session::remove_torrent`.
+    pub struct RemoveFlags: u8 {
+        /// Deletes the torrent's files along with directories left empty.
+        const DELETE_FILES = 1;
+    }
+}
+
+impl Default for RemoveFlags {
+    fn default() -> RemoveFlags {
+        RemoveFlags::empty()
+    }
+}
+
+/// What torrent threads share with the session.
+#[derive(Clone)]
+struct Context {
+    settings: SessionSettings,
+
+    /// The settings of peer connections, with our listen port.
+    peer: PeerSettings,
+
+    /// Peer connections across all torrents, including those being made.
+    connections: Arc<AtomicUsize>,
+
+    /// Identifies us to trackers.
+    tracker_key: u32,
+}
+
+/// Counts towards `connections_limit` for as long as it's kept, along with
+/// a peer connection or while one is made.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn reserve(connections: &Arc<AtomicUsize>, limit: usize) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < limit).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Self(connections.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// How the handshakes with a peer went, sent back by the thread that did
+/// them.
+struct Handshaked {
+    addr: SocketAddr,
+    result: Result<PendingConnection>,
+    slot: ConnectionSlot,
+}
+
+/// What an announce thread gives back: the torrent's trackers with the
+/// outcome recorded, the client it used and the peers returned.
+type Announced = (TrackerList, UdpTrackerClient, Vec<SocketAddr>);
+
+/// A web seed taken out of its torrent while a thread fetches blocks
+/// from it.
+struct Fetching {
+    url: String,
+    kind: WebSeedType,
+    addr: SocketAddr,
+    thread: JoinHandle<(WebSeed, WebSeedRequest)>,
+}
+
+/// A torrent along with its peer connections, web seeds and trackers.
+struct SessionTorrent {
+    torrent: ActiveTorrent,
+    peers: Vec<(PeerConnection, ConnectionSlot)>,
+    web_seeds: Vec<WebSeed>,
+    fetching: Vec<Fetching>,
+
+    /// Taken by the announce thread while it runs.
+    udp: Option<UdpTrackerClient>,
+    announcing: Option<JoinHandle<Announced>>,
+
+    /// Peers we're connecting to, until their handshake is done.
+    connecting: HashSet<SocketAddr>,
+    handshakes: Receiver<Handshaked>,
+    handshakes_tx: Sender<Handshaked>,
+
+    /// Set when the torrent is removed, ends its thread.
+    stopped: bool,
+}
+
+impl SessionTorrent {
+    fn new(torrent: ActiveTorrent, settings: &SessionSettings) -> Self {
+        let (handshakes_tx, handshakes) = mpsc::channel();
+        Self {
+            torrent,
+            peers: vec![],
+            web_seeds: vec![],
+            fetching: vec![],
+            udp: Some(UdpTrackerClient::new(settings.udp_tracker.clone())),
+            announcing: None,
+            connecting: HashSet::new(),
+            handshakes,
+            handshakes_tx,
+            stopped: false,
+        }
+    }
+
+    fn status(&self) -> TorrentStatus {
+        TorrentStatus {
+            num_peers: self.peers.len(),
+            ..self.torrent.status()
+        }
+    }
+
+    fn pause(&mut self) {
+        self.torrent.pause();
+        self.disconnect_all();
+    }
+
+    fn disconnect_all(&mut self) {
+        for (mut peer, _) in self.peers.drain(..) {
+            peer.disconnect(&mut self.torrent);
+        }
+        for mut seed in self.web_seeds.drain(..) {
+            seed.disconnect(&mut self.torrent);
+        }
+    }
+
+    /// Keeps the files checking, or exchanges pieces with our peers and
+    /// web seeds, announces to trackers and connects to new peers while
+    /// there are connection slots left.
+    fn tick(&mut self, ctx: &Context) {
+        self.take_handshakes(ctx);
+        if self.torrent.is_paused() {
+            return;
+        }
+        if self
+            .torrent
+            .check_pieces(ctx.settings.checking_pieces_per_tick)
+            .is_err()
+        {
+            // The files can't be read
+            self.pause();
+            return;
+        }
+        if self.torrent.state() == State::CheckingFiles {
+            return;
+        }
+
+        let new_pieces = self.torrent.take_new_pieces();
+        let torrent = &mut self.torrent;
+        self.peers.retain_mut(|(p, _)| {
+            let result = p
+                .tick(torrent)
+                .and_then(|_| new_pieces.iter().try_for_each(|&i| p.announce_piece(i)));
+            if result.is_err() {
+                p.disconnect(torrent);
+            }
+            result.is_ok()
+        });
+        self.update_web_seeds(&ctx.settings.web_seed);
+        self.update_unchokes(ctx.settings.unchoke_slots_limit);
+        self.update_trackers(ctx);
+        self.connect_peers(ctx);
+    }
+
+    /// Keeps a `WebSeed` for each URL and HTTP seed of the torrent and
+    /// downloads from them, each request on a thread of its own. Seeds that
+    /// fail for good remove themselves from the torrent and are dropped.
+    fn update_web_seeds(&mut self, settings: &WebSeedSettings) {
+        let done: Vec<_> = self
+            .fetching
+            .extract_if(.., |f| f.thread.is_finished())
+            .collect();
+        let torrent = &mut self.torrent;
+        for f in done {
+            match f.thread.join() {
+                Ok((mut seed, req)) => {
+                    if seed.finish(torrent, req).is_err() {
+                        seed.disconnect(torrent);
+                    } else if !seed.is_banned() {
+                        self.web_seeds.push(seed);
+                    }
+                }
+                // The seed is lost, give its blocks to others
+                Err(_) => torrent.picker().abort_peer(f.addr),
+            }
+        }
+
+        let urls = self
+            .torrent
+            .web_seeds()
+            .iter()
+            .map(|u| (u, WebSeedType::UrlSeed));
+        let http = self
+            .torrent
+            .http_seeds()
+            .iter()
+            .map(|u| (u, WebSeedType::HttpSeed));
+        let added: Vec<_> = urls
+            .chain(http)
+            .filter(|&(url, kind)| {
+                !self
+                    .web_seeds
+                    .iter()
+                    .any(|s| s.url() == url && s.kind() == kind)
+                    && !self
+                        .fetching
+                        .iter()
+                        .any(|f| f.url == *url && f.kind == kind)
+            })
+            .map(|(url, kind)| (url.clone(), kind))
+            .collect();
+        for (url, kind) in added {
+            match WebSeed::new(url.clone(), kind, settings.clone()) {
+                Ok(seed) => self.web_seeds.push(seed),
+                Err(_) if kind == WebSeedType::UrlSeed => self.torrent.remove_web_seed(&url),
+                Err(_) => self.torrent.remove_http_seed(&url),
+            }
+        }
+
+        let torrent = &mut self.torrent;
+        for mut seed in std::mem::take(&mut self.web_seeds) {
+            match seed.request(torrent) {
+                Some(mut req) => self.fetching.push(Fetching {
+                    url: seed.url().to_string(),
+                    kind: seed.kind(),
+                    addr: seed.addr(),
+                    thread: thread::spawn(move || {
+                        seed.fetch(&mut req);
+                        (seed, req)
+                    }),
+                }),
+                None if !seed.is_banned() => self.web_seeds.push(seed),
+                None => {}
+            }
+        }
+    }
+
+    /// Announces to the trackers that are due on a thread of its own, and
+    /// takes the peers they returned once it's done.
+    fn update_trackers(&mut self, ctx: &Context) {
+        match &self.announcing {
+            Some(thread) if !thread.is_finished() => return,
+            Some(_) => match self.announcing.take().unwrap().join() {
+                Ok((mut trackers, udp, peers)) => {
+                    // Trackers may have been added meanwhile, e.g. with the
+                    // metadata, or the download finished
+                    for t in self.torrent.trackers().trackers() {
+                        trackers.add_tracker(t.url.clone(), t.tier);
+                    }
+                    if self.torrent.state() == State::Seeding {
+                        trackers.set_completed();
+                    }
+                    *self.torrent.trackers_mut() = trackers;
+                    self.udp = Some(udp);
+                    for addr in peers {
+                        self.torrent.add_peer(addr);
+                    }
+                }
+                Err(_) => {
+                    // The thread panicked: count it as a failure of the
+                    // trackers it announced to, so they're retried later
+                    let now = Instant::now();
+                    let trackers = self.torrent.trackers_mut();
+                    for url in trackers.due(now) {
+                        trackers.on_failure(&url, "announce failed".to_string(), now);
+                    }
+                    self.udp = Some(UdpTrackerClient::new(ctx.settings.udp_tracker.clone()));
+                }
+            },
+            None => {}
+        }
+        if self.torrent.trackers().due(Instant::now()).is_empty() {
+            return;
+        }
+
+        let status = self.torrent.status();
+        let left = if self.torrent.has_metadata() {
+            self.torrent.bytes_left()
+        } else {
+            // Anything but 0, which would make us a seed
+            16 * 1024
+        };
+        let req = TrackerRequest {
+            info_hash: self.torrent.info_hash().clone(),
+            peer_id: ctx.settings.peer_id.clone(),
+            listen_port: ctx.peer.listen_port.unwrap_or(0),
+            uploaded: status.total_payload_upload,
+            downloaded: status.total_payload_download,
+            left,
+            key: ctx.tracker_key,
+            ..TrackerRequest::default()
+        };
+        let mut trackers = self.torrent.trackers().clone();
+        let mut udp = self.udp.take().unwrap();
+        self.announcing = Some(thread::spawn(move || {
+            let peers = trackers
+                .announce(&req, &mut udp)
+                .iter()
+                .flat_map(|r| &r.peers)
+                .filter_map(|p| resolve(&p.endpoint))
+                .collect();
+            (trackers, udp, peers)
+        }));
+    }
+
+    /// Takes on the peers whose handshakes are done, unless the torrent
+    /// stopped taking peers meanwhile.
+    fn take_handshakes(&mut self, ctx: &Context) {
+        while let Ok(h) = self.handshakes.try_recv() {
+            self.connecting.remove(&h.addr);
+            let pending = match h.result {
+                Ok(pending) => pending,
+                Err(_) => continue,
+            };
+            if self.torrent.is_paused()
+                || self.torrent.state() == State::CheckingFiles
+                || self.peers.iter().any(|(p, _)| p.addr() == pending.addr())
+            {
+                continue;
+            }
+            if let Ok(conn) = PeerConnection::attach(pending, &mut self.torrent, ctx.peer.clone()) {
+                self.peers.push((conn, h.slot));
+            }
+        }
+    }
+
+    /// Unchokes interested peers while there are slots, freeing the slots
+    /// of peers that lost interest.
+    fn update_unchokes(&mut self, slots: usize) {
+        let mut unchoked = 0;
+        for (p, _) in &mut self.peers {
+            if !p.am_choking() && !p.peer_interested() {
+                let _ = p.choke();
+            }
+            if !p.am_choking() {
+                unchoked += 1;
+            }
+        }
+        for (p, _) in &mut self.peers {
+            if unchoked >= slots {
+                break;
+            }
+            if p.am_choking() && p.peer_interested() && p.unchoke().is_ok() {
+                unchoked += 1;
+            }
+        }
+    }
+
+    /// Starts connecting to the peers discovered since the last round, on
+    /// a thread each.
+    fn connect_peers(&mut self, ctx: &Context) {
+        let discovered = self.torrent.swarm().lock().unwrap().take_discovered();
+        let seeding = self.torrent.state() == State::Seeding;
+        for (addr, flags) in discovered {
+            if seeding && flags.contains(PexFlags::SEED) {
+                continue;
+            }
+            if self.connecting.contains(&addr) || self.peers.iter().any(|(p, _)| p.addr() == addr) {
+                continue;
+            }
+            let slot =
+                match ConnectionSlot::reserve(&ctx.connections, ctx.settings.connections_limit) {
+                    Some(slot) => slot,
+                    None => {
+                        // Kept for when a connection slot frees up
+                        self.torrent
+                            .swarm()
+                            .lock()
+                            .unwrap()
+                            .add_discovered(addr, flags);
+                        continue;
+                    }
+                };
+            self.connecting.insert(addr);
+            let info_hash = self.torrent.info_hash().clone();
+            let peer_id = ctx.settings.peer_id.clone();
+            let settings = ctx.peer.clone();
+            let tx = self.handshakes_tx.clone();
+            thread::spawn(move || {
+                let result = PendingConnection::connect(addr, &info_hash, &peer_id, &settings);
+                // Fails if the torrent was removed meanwhile
+                let _ = tx.send(Handshaked { addr, result, slot });
+            });
+        }
+    }
+}
+
+/// A torrent and the thread running it.
+struct TorrentThread {
+    torrent: Arc<Mutex<SessionTorrent>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TorrentThread {
+    fn spawn(torrent: SessionTorrent, ctx: Context) -> Self {
+        let torrent = Arc::new(Mutex::new(torrent));
+        let t = torrent.clone();
+        let thread = thread::spawn(move || loop {
+            {
+                let mut t = t.lock().unwrap();
+                if t.stopped {
+                    return;
+                }
+                t.tick(&ctx);
+            }
+            thread::sleep(ctx.settings.tick_interval);
+        });
+        Self {
+            torrent,
+            thread: Some(thread),
+        }
+    }
+
+    /// Ends the thread and waits for it.
+    fn stop(&mut self) {
+        self.torrent.lock().unwrap().stopped = true;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for TorrentThread {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Refers to a torrent in a session. Handles stay valid until the torrent
+/// is removed, after which everything but `info_hash` fails with
+/// `Error::InvalidTorrentHandle`.
+#[derive(Debug, Clone)]
+pub struct TorrentHandle {
+    info_hash: Sha1Hash,
+    torrent: Weak<Mutex<SessionTorrent>>,
+}
+
+impl PartialEq for TorrentHandle {
+    fn eq(&self, other: &Self) -> bool {
+        self.torrent.ptr_eq(&other.torrent)
+    }
+}
+
+impl Eq for TorrentHandle {}
+
+impl TorrentHandle {
+    fn new(info_hash: Sha1Hash, torrent: &Arc<Mutex<SessionTorrent>>) -> Self {
+        Self {
+            info_hash,
+            torrent: Arc::downgrade(torrent),
+        }
+    }
+
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.info_hash
+    }
+
+    /// Whether the torrent is still in the session.
+    pub fn is_valid(&self) -> bool {
+        self.torrent.strong_count() > 0
+    }
+
+    pub fn status(&self) -> Result<TorrentStatus> {
+        self.with(|t| t.status())
+    }
+
+    /// Disconnects all peers and stops the torrent until `resume`.
+    pub fn pause(&self) -> Result<()> {
+        self.with(|t| t.pause())
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.with(|t| t.torrent.resume())
+    }
+
+    /// Adds a peer to connect to on the next tick.
+    pub fn connect_peer(&self, addr: SocketAddr) -> Result<()> {
+        self.with(|t| t.torrent.add_peer(addr))
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut SessionTorrent) -> T) -> Result<T> {
+        let torrent = self.torrent.upgrade().ok_or(Error::InvalidTorrentHandle)?;
+        let mut torrent = torrent.lock().unwrap();
+        Ok(f(&mut torrent))
+    }
+}
+
+/// The DHT node's routing state, kept until the node can be run.
+struct DhtNode {
+    settings: DhtSettings,
+    state: DhtState,
+}
+
+pub struct Session {
+    ctx: Context,
+    torrents: HashMap<Sha1Hash, TorrentThread>,
+    listeners: Vec<TcpListener>,
+
+    /// Connections made to us that haven't sent the info hash of their
+    /// handshake yet.
+    incoming: Vec<(TcpStream, Instant)>,
+    lsd: Option<Lsd>,
+    dht: Option<DhtNode>,
+}
+
+impl Session {
+    /// Opens the listen sockets and starts local service discovery and the
+    /// DHT node if enabled.
+    pub fn new(settings: SessionSettings) -> Result<Self> {
+        let mut listeners = vec![];
+        for addr in &settings.listen_interfaces {
+            let listener = TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            listeners.push(listener);
+        }
+        let listen_port = listeners.first().map(|l| l.local_addr()).transpose()?;
+        let peer = PeerSettings {
+            listen_port: listen_port.map(|a| a.port()),
+            nonblocking: true,
+            ..settings.peer.clone()
+        };
+        let mut key = [0; 4];
+        random::fill_bytes(&mut key);
+        let mut session = Self {
+            ctx: Context {
+                settings,
+                peer,
+                connections: Arc::new(AtomicUsize::new(0)),
+                tracker_key: u32::from_be_bytes(key),
+            },
+            torrents: HashMap::new(),
+            listeners,
+            incoming: vec![],
+            lsd: None,
+            dht: None,
+        };
+        if session.ctx.settings.enable_lsd {
+            session.start_lsd(Box::new(MulticastTransport::new()?));
+        }
+        if session.ctx.settings.enable_dht {
+            session.start_dht(DhtState::default());
+        }
+        Ok(session)
+    }
+
+    pub fn settings(&self) -> &SessionSettings {
+        &self.ctx.settings
+    }
+
+    /// Addresses we accept peer connections on, with the ports picked for
+    /// those bound to port 0.
+    pub fn listen_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|l| l.local_addr().ok())
+            .collect()
+    }
+
+    /// Port of the first listen socket, advertised to peers and trackers.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_addrs().first().map(|a| a.port())
+    }
+
+    /// Runs local service discovery over `transport`, replacing a running
+    /// one. Does nothing without a listen socket, there'd be no port to
+    /// announce.
+    pub fn start_lsd(&mut self, transport: Box<dyn LsdTransport>) {
+        if let Some(port) = self.listen_port() {
+            let settings = self.ctx.settings.lsd.clone();
+            self.lsd = Some(Lsd::new(transport, port, settings));
+        }
+    }
+
+    pub fn stop_lsd(&mut self) {
+        self.lsd = None;
+    }
+
+    pub fn is_lsd_running(&self) -> bool {
+        self.lsd.is_some()
+    }
+
+    /// Starts the DHT node from a saved state, replacing a running one.
+    pub fn start_dht(&mut self, state: DhtState) {
+        self.dht = Some(DhtNode {
+            settings: DhtSettings::default(),
+            state,
+        });
+    }
+
+    /// Stops the DHT node, returning its state to start it with later.
+    pub fn stop_dht(&mut self) -> Option<DhtState> {
+        self.dht.take().map(|dht| dht.state)
+    }
+
+    pub fn is_dht_running(&self) -> bool {
+        self.dht.is_some()
+    }
+
+    pub fn dht_state(&self) -> Option<&DhtState> {
+        self.dht.as_ref().map(|dht| &dht.state)
+    }
+
+    pub fn dht_settings(&self) -> Option<&DhtSettings> {
+        self.dht.as_ref().map(|dht| &dht.settings)
+    }
+
+    /// Adds a node to bootstrap the DHT from. Ignored if the DHT isn't
+    /// running.
+    pub fn add_dht_node(&mut self, addr: SocketAddr) {
+        let dht = match &mut self.dht {
+            Some(dht) => dht,
+            None => return,
+        };
+        let nodes = if addr.is_ipv4() {
+            &mut dht.state.nodes
+        } else {
+            &mut dht.state.nodes6
+        };
+        if !nodes.contains(&addr) {
+            nodes.push(addr);
+        }
+    }
+
+    /// Adds a torrent, or returns the handle of the one with the same info
+    /// hash unless `TorrentFlags::DUPLICATE_IS_ERROR` is set. Without a
+    /// queue, auto managed torrents are started right away.
+    ///
+    /// The peers and DHT nodes in `params` are resolved and added.
+    pub fn add_torrent(&mut self, params: TorrentParams) -> Result<TorrentHandle> {
+        let info_hash = if params.torrent_info.is_valid() {
+            params.torrent_info.info_hash().clone()
+        } else if params.info_hash.has_v1() {
+            params.info_hash.v1.clone()
+        } else {
+            return Err(Error::MissingInfoHash);
+        };
+        if let Some(t) = self.torrents.get(&info_hash) {
+            if params.flags.contains(TorrentFlags::DUPLICATE_IS_ERROR) {
+                return Err(Error::DuplicateTorrent);
+            }
+            return Ok(TorrentHandle::new(info_hash, &t.torrent));
+        }
+
+        let peers = params.peers.clone();
+        let dht_nodes = params.dht_nodes.clone();
+        let mut torrent = ActiveTorrent::new(params)?;
+        if torrent.flags().contains(TorrentFlags::AUTO_MANAGED) {
+            torrent.resume();
+        }
+        for addr in peers.iter().filter_map(resolve) {
+            torrent.add_peer(addr);
+        }
+        for addr in dht_nodes.iter().filter_map(resolve) {
+            self.add_dht_node(addr);
+        }
+
+        let t = SessionTorrent::new(torrent, &self.ctx.settings);
+        let t = TorrentThread::spawn(t, self.ctx.clone());
+        let handle = TorrentHandle::new(info_hash.clone(), &t.torrent);
+        self.torrents.insert(info_hash, t);
+        Ok(handle)
+    }
+
+    pub fn find_torrent(&self, info_hash: &Sha1Hash) -> Option<TorrentHandle> {
+        self.torrents
+            .get(info_hash)
+            .map(|t| TorrentHandle::new(info_hash.clone(), &t.torrent))
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.torrents
+            .iter()
+            .map(|(info_hash, t)| TorrentHandle::new(info_hash.clone(), &t.torrent))
+            .collect()
+    }
+
+    /// Disconnects the torrent's peers and removes it, optionally along
+    /// with its files. Its handles become invalid.
+    pub fn remove_torrent(&mut self, handle: &TorrentHandle, flags: RemoveFlags) -> Result<()> {
+        match self.torrents.get(handle.info_hash()) {
+            Some(t) if handle.torrent.ptr_eq(&Arc::downgrade(&t.torrent)) => {}
+            _ => return Err(Error::InvalidTorrentHandle),
+        }
+        let mut t = self.torrents.remove(handle.info_hash()).unwrap();
+        t.stop();
+        let mut torrent = t.torrent.lock().unwrap();
+        torrent.disconnect_all();
+        let storage = torrent.torrent.storage();
+        storage.release_files();
+        if flags.contains(RemoveFlags::DELETE_FILES) {
+            storage.delete_files()?;
+        }
+        Ok(())
+    }
+
+    /// Hands incoming connections to the torrent they ask for and runs
+    /// local service discovery. Torrents run on their own, this only has
+    /// to be called regularly for peers to get through to them.
+    pub fn tick(&mut self) {
+        self.accept_incoming();
+        self.update_lsd();
+    }
+
+    /// Announces the torrents taking peers on the local network and hands
+    /// them the peers found there.
+    fn update_lsd(&mut self) {
+        let lsd = match &mut self.lsd {
+            Some(lsd) => lsd,
+            None => return,
+        };
+        let mut guards: Vec<_> = self
+            .torrents
+            .values()
+            .map(|t| t.torrent.lock().unwrap())
+            .collect();
+        let mut torrents: Vec<_> = guards
+            .iter_mut()
+            .map(|t| &mut t.torrent)
+            .filter(|t| !t.is_paused())
+            .collect();
+        // Tried again on the next tick
+        let _ = lsd.tick(&mut torrents, Instant::now());
+    }
+
+    /// Hands incoming connections to their torrent once the info hash of
+    /// their handshake arrived. Connections for torrents we don't have or
+    /// that don't take peers right now are closed.
+    fn accept_incoming(&mut self) {
+        let now = Instant::now();
+        for listener in &self.listeners {
+            while let Ok((stream, _)) = listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    self.incoming.push((stream, now));
+                }
+            }
+        }
+
+        let mut buf = [0; HANDSHAKE_INFO_HASH.end];
+        for (stream, since) in std::mem::take(&mut self.incoming) {
+            match stream.peek(&mut buf) {
+                Ok(n) if n == buf.len() => {
+                    let info_hash = Sha1Hash::from_bytes(&buf[HANDSHAKE_INFO_HASH]).unwrap();
+                    self.accept_peer(stream, &info_hash);
+                }
+                Ok(0) => {}
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => {}
+                _ if now - since > self.ctx.peer.handshake_timeout => {}
+                _ => self.incoming.push((stream, since)),
+            }
+        }
+    }
+
+    /// Does the handshakes with a peer that connected to us on a thread
+    /// of its own and hands it to the torrent.
+    fn accept_peer(&mut self, stream: TcpStream, info_hash: &Sha1Hash) {
+        let torrent = match self.torrents.get(info_hash) {
+            Some(t) => &t.torrent,
+            None => return,
+        };
+        let tx = {
+            let t = torrent.lock().unwrap();
+            if t.torrent.is_paused() || t.torrent.state() == State::CheckingFiles {
+                return;
+            }
+            t.handshakes_tx.clone()
+        };
+        let slot = match ConnectionSlot::reserve(
+            &self.ctx.connections,
+            self.ctx.settings.connections_limit,
+        ) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+        if stream.set_nonblocking(false).is_err() {
+            return;
+        }
+        let info_hash = info_hash.clone();
+        let peer_id = self.ctx.settings.peer_id.clone();
+        let settings = self.ctx.peer.clone();
+        thread::spawn(move || {
+            let result = PendingConnection::accept(stream, &info_hash, &peer_id, &settings);
+            let _ = tx.send(Handshaked { addr, result, slot });
+        });
+    }
+}
+
+fn resolve(addr: &impl ToSocketAddrs) -> Option<SocketAddr> {
+    addr.to_socket_addrs().ok()?.next()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::test::{response, serve};
+    use crate::info::TorrentInfo;
+    use crate::lsd::test::{join, Group};
+    use crate::test_util::{self, content, memory_params, temp_dir};
+    use crate::web_seed::test::serve_files;
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    const PIECE_LEN: usize = 16 * 1024;
+    const CONTENT_LEN: usize = PIECE_LEN * 3 + 500;
+
+    /// Metadata of `content(CONTENT_LEN)` in a single file, written to
+    /// `dir`.
+    fn torrent_info(dir: &Path) -> Arc<TorrentInfo> {
+        test_util::torrent_info(dir, PIECE_LEN, &[("data", &content(CONTENT_LEN))])
+    }
+
+    fn settings() -> SessionSettings {
+        SessionSettings {
+            listen_interfaces: vec!["127.0.0.1:0".parse().unwrap()],
+            enable_lsd: false,
+            enable_dht: false,
+            ..SessionSettings::default()
+        }
+    }
+
+    /// Ticks the sessions until the torrent is in `state`.
+    fn wait_for(sessions: &mut [&mut Session], h: &TorrentHandle, state: State) {
+        let start = Instant::now();
+        while h.status().unwrap().state != state {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            for s in sessions.iter_mut() {
+                s.tick();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// A session seeding `info` from the file written to `dir`.
+    fn seed(dir: &Path, info: &Arc<TorrentInfo>) -> (Session, TorrentHandle) {
+        let mut s = Session::new(settings()).unwrap();
+        let mut p = memory_params(info);
+        p.storage = None;
+        p.save_path = dir.to_str().unwrap().to_string();
+        let h = s.add_torrent(p).unwrap();
+        wait_for(&mut [&mut s], &h, State::Seeding);
+        (s, h)
+    }
+
+    #[test]
+    fn test_add_torrent() {
+        let dir = temp_dir("session-add");
+        let info = torrent_info(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut s = Session::new(settings()).unwrap();
+        let h = s.add_torrent(memory_params(&info)).unwrap();
+        assert_eq!(info.info_hash(), h.info_hash());
+        assert_eq!(Some(h.clone()), s.find_torrent(info.info_hash()));
+        assert_eq!(vec![h.clone()], s.torrents());
+
+        // Auto managed torrents are started
+        let flags = h.status().unwrap().flags;
+        assert!(!flags.contains(TorrentFlags::PAUSED));
+
+        assert_eq!(h, s.add_torrent(memory_params(&info)).unwrap());
+        let mut p = memory_params(&info);
+        p.flags |= TorrentFlags::DUPLICATE_IS_ERROR;
+        assert!(matches!(s.add_torrent(p), Err(Error::DuplicateTorrent)));
+        assert_eq!(1, s.torrents().len());
+
+        assert!(matches!(
+            s.add_torrent(TorrentParams::default()),
+            Err(Error::MissingInfoHash)
+        ));
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let dir = temp_dir("session-pause");
+        let info = torrent_info(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut s = Session::new(settings()).unwrap();
+        let mut p = memory_params(&info);
+        p.flags = TorrentFlags::PAUSED;
+        let h = s.add_torrent(p).unwrap();
+        assert!(h.status().unwrap().flags.contains(TorrentFlags::PAUSED));
+
+        h.resume().unwrap();
+        assert!(!h.status().unwrap().flags.contains(TorrentFlags::PAUSED));
+        h.pause().unwrap();
+        assert!(h.status().unwrap().flags.contains(TorrentFlags::PAUSED));
+    }
+
+    #[test]
+    fn test_remove_torrent() {
+        let dir = temp_dir("session-remove");
+        let info = torrent_info(&dir);
+
+        let (mut s, h) = seed(&dir, &info);
+        s.remove_torrent(&h, RemoveFlags::DELETE_FILES).unwrap();
+        assert!(!dir.join("data").exists());
+        assert!(!h.is_valid());
+        assert!(matches!(h.status(), Err(Error::InvalidTorrentHandle)));
+        assert!(matches!(
+            s.remove_torrent(&h, RemoveFlags::empty()),
+            Err(Error::InvalidTorrentHandle)
+        ));
+        assert!(s.torrents().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dht() {
+        let dir = temp_dir("session-dht");
+        let info = torrent_info(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut s = Session::new(SessionSettings {
+            enable_dht: true,
+            ..settings()
+        })
+        .unwrap();
+        assert!(s.is_dht_running());
+
+        let mut p = memory_params(&info);
+        p.dht_nodes = vec![("127.0.0.1".to_string(), 6881)];
+        s.add_torrent(p).unwrap();
+        let node: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        assert_eq!(vec![node], s.dht_state().unwrap().nodes);
+
+        let state = s.stop_dht().unwrap();
+        assert!(!s.is_dht_running());
+        assert_eq!(vec![node], state.nodes);
+    }
+
+    #[test]
+    fn test_download() {
+        let dir = temp_dir("session-download");
+        let info = torrent_info(&dir);
+
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let seed_info = info.clone();
+        let seed_dir = dir.clone();
+        let seeder = thread::spawn(move || {
+            let (mut s, h) = seed(&seed_dir, &seed_info);
+            addr_tx.send(s.listen_addrs()[0]).unwrap();
+            while stop_rx.try_recv().is_err() {
+                s.tick();
+                thread::sleep(Duration::from_millis(1));
+            }
+            h.status().unwrap()
+        });
+
+        let mut s = Session::new(settings()).unwrap();
+        let h = s.add_torrent(memory_params(&info)).unwrap();
+        let seed_addr = addr_rx.recv().unwrap();
+        // A peer that never sends its handshake doesn't hold up the others
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let start = Instant::now();
+        h.connect_peer(silent.local_addr().unwrap()).unwrap();
+        h.connect_peer(seed_addr).unwrap();
+        wait_for(&mut [&mut s], &h, State::Seeding);
+        assert!(start.elapsed() < settings().peer.handshake_timeout / 2);
+        let status = h.status().unwrap();
+        assert_eq!(1, status.num_peers);
+        assert_eq!(
+            content(CONTENT_LEN).len() as u64,
+            status.total_payload_download
+        );
+
+        h.pause().unwrap();
+        let status = h.status().unwrap();
+        assert_eq!(0, status.num_peers);
+        assert_eq!(0, status.total_payload_download);
+
+        stop_tx.send(()).unwrap();
+        let seed_status = seeder.join().unwrap();
+        assert_eq!(
+            content(CONTENT_LEN).len() as u64,
+            seed_status.total_payload_upload
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_trackers() {
+        let dir = temp_dir("session-trackers");
+        let info = torrent_info(&dir);
+        let (mut seeder, _) = seed(&dir, &info);
+        let mut body = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01".to_vec();
+        body.extend_from_slice(&seeder.listen_port().unwrap().to_be_bytes());
+        body.push(b'e');
+        let (addr, requests) = serve(vec![response(200, &[], &body)]);
+
+        let mut s = Session::new(settings()).unwrap();
+        let url = format!("http://{}/announce", addr);
+        let mut p = memory_params(&info);
+        p.trackers = vec![url.clone()];
+        let h = s.add_torrent(p).unwrap();
+        wait_for(&mut [&mut s, &mut seeder], &h, State::Seeding);
+        assert_eq!(url, h.status().unwrap().current_tracker);
+
+        let request = &requests.join().unwrap()[0];
+        assert!(request.contains("event=started"));
+        assert!(request.contains(&format!("left={}&", content(CONTENT_LEN).len())));
+        assert!(request.contains(&format!("port={}&", s.listen_port().unwrap())));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lsd() {
+        let dir = temp_dir("session-lsd");
+        let info = torrent_info(&dir);
+        let group = Arc::new(Mutex::new(Group::default()));
+        let (mut seeder, _) = seed(&dir, &info);
+        seeder.start_lsd(join(&group, [127, 0, 0, 1]));
+
+        let mut s = Session::new(settings()).unwrap();
+        assert!(!s.is_lsd_running());
+        s.start_lsd(join(&group, [127, 0, 0, 1]));
+        assert!(s.is_lsd_running());
+        let h = s.add_torrent(memory_params(&info)).unwrap();
+        wait_for(&mut [&mut s, &mut seeder], &h, State::Seeding);
+        // Each side may have connected to the other
+        assert!(h.status().unwrap().num_peers > 0);
+
+        s.stop_lsd();
+        assert!(!s.is_lsd_running());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_web_seeds() {
+        let dir = temp_dir("session-web-seeds");
+        let info = torrent_info(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (addr, requests) = serve_files(vec![("/data", content(CONTENT_LEN))], vec![]);
+
+        let mut s = Session::new(settings()).unwrap();
+        let mut p = memory_params(&info);
+        p.url_seeds = vec![format!("http://{}/data", addr)];
+        let h = s.add_torrent(p).unwrap();
+        wait_for(&mut [&mut s], &h, State::Seeding);
+        let status = h.status().unwrap();
+        assert_eq!(0, status.num_peers);
+        assert_eq!(
+            content(CONTENT_LEN).len() as u64,
+            status.total_payload_download
+        );
+        assert!(!requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_silent_web_seed() {
+        let dir = temp_dir("session-silent-web-seed");
+        let info = torrent_info(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        // Takes the request and never answers
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut s = Session::new(settings()).unwrap();
+        let mut p = memory_params(&info);
+        p.url_seeds = vec![format!("http://{}/data", server.local_addr().unwrap())];
+        let h = s.add_torrent(p).unwrap();
+        let _conn = server.accept().unwrap();
+
+        // Neither the torrent nor the session wait for the seed
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(State::Downloading, h.status().unwrap().state);
+            s.tick();
+        }
+        s.remove_torrent(&h, RemoveFlags::empty()).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
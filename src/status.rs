@@ -1,38 +1,56 @@
+use crate::flags::TorrentFlags;
 use crate::info::TorrentInfo;
 use std::sync::Weak;
 use std::time::Duration;
 
+#[derive(Debug, Default, Clone)]
 pub struct TorrentStatus {
-    save_path: String,
-    name: String,
-    torrent_file: Weak<TorrentInfo>,
-    next_announce: Duration,
+    pub save_path: String,
+    pub name: String,
+    pub torrent_file: Weak<TorrentInfo>,
+    pub next_announce: Duration,
+
+    pub state: State,
+    pub flags: TorrentFlags,
+
+    // the progress of the current task, in the range [0, 1]. While checking
+    // files this is the portion of pieces checked, otherwise the portion of
+    // pieces we have.
+    pub progress: f32,
+
+    // the number of pieces we have and the total number of pieces in the
+    // torrent.
+    pub num_pieces: usize,
+    pub total_pieces: usize,
+
+    // the number of peers this torrent is connected to.
+    pub num_peers: usize,
 
     // the URL of the last working tracker. If no tracker request has
     // been successful yet, it's set to an empty string.
-    current_tracker: String,
+    pub current_tracker: String,
 
     // the number of bytes downloaded and uploaded to all peers, accumulated,
     // *this session* only. The session is considered to restart when a
     // torrent is paused and restarted again. When a torrent is paused, these
     // counters are reset to 0. If you want complete, persistent, stats, see
     // ``all_time_upload`` and ``all_time_download``.
-    total_downloaded: u64,
-    total_upload: u64,
+    pub total_downloaded: u64,
+    pub total_upload: u64,
 
     // counts the amount of bytes send and received this session, but only
     // the actual payload data (i.e the interesting data), these counters
     // ignore any protocol overhead. The session is considered to restart
     // when a torrent is paused and restarted again. When a torrent is
     // paused, these counters are reset to 0.
-    total_payload_download: u64,
-    total_payload_upload: u64,
+    pub total_payload_download: u64,
+    pub total_payload_upload: u64,
 
     // the number of bytes that has been downloaded and that has failed the
     // piece hash test. In other words, this is just how much crap that has
     // been downloaded since the torrent was last started. If a torrent is
     // paused and then restarted again, this counter will be reset.
-    total_failed_bytes: u64,
+    pub total_failed_bytes: u64,
 
     // the number of bytes that has been downloaded even though that data
     // already was downloaded. The reason for this is that in some situations
@@ -45,10 +63,11 @@ pub struct TorrentStatus {
     // low as possible. This only counts bytes since the torrent was last
     // started. If a torrent is paused and then restarted again, this counter
     // will be reset.
-    total_redundant_bytes: u64,
+    pub total_redundant_bytes: u64,
 }
 
 #[non_exhaustive]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     // The torrent has not started its download yet, and is
     // currently checking existing files.
@@ -82,5 +101,6 @@ pub enum State {
     // comparing it to the files on disk. This is typically
     // completed in a fraction of a second, but if you add a
     // large number of torrents at once, they will queue up.
+    #[default]
     CheckingResumeData,
 }
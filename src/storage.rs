@@ -0,0 +1,516 @@
+//! Disk I/O. A torrent's pieces are read and written through a [`Storage`],
+//! which maps them onto the files described by its [`FileStorage`].
+
+use crate::error::{Error, Result};
+use crate::fs::{FileSlice, FileStorage};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// How files are allocated on disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Files are only extended as data is written. Relies on the file
+    /// system to support sparse files to avoid fragmentation.
+    #[default]
+    Sparse,
+
+    /// All files are written out in full up front.
+    Allocate,
+}
+
+/// Everything a storage implementation is constructed from.
+#[derive(Debug, Clone, Default)]
+pub struct StorageParams {
+    pub files: FileStorage,
+    pub save_path: PathBuf,
+    pub mode: StorageMode,
+
+    /// New paths of renamed files relative to `save_path`, keyed by file
+    /// index.
+    pub renamed_files: HashMap<usize, String>,
+}
+
+/// Creates the storage for a torrent, see `TorrentParams::storage`.
+pub type StorageConstructor = fn(StorageParams) -> Box<dyn Storage>;
+
+pub fn default_storage_constructor(params: StorageParams) -> Box<dyn Storage> {
+    Box::new(DefaultStorage::new(params))
+}
+
+pub fn memory_storage_constructor(params: StorageParams) -> Box<dyn Storage> {
+    Box::new(MemoryStorage::new(params.files))
+}
+
+/// Block level access to a torrent's data.
+pub trait Storage: Send {
+    fn files(&self) -> &FileStorage;
+
+    /// Prepares the storage for use, e.g. creates directories and
+    /// allocates files.
+    fn initialize(&mut self) -> Result<()>;
+
+    /// Reads `buf.len()` bytes at `offset` within `piece`. Data that was
+    /// never written reads as zeroes.
+    fn read(&mut self, buf: &mut [u8], piece: usize, offset: usize) -> Result<()>;
+
+    fn write(&mut self, buf: &[u8], piece: usize, offset: usize) -> Result<()>;
+
+    /// Closes any open file handles.
+    fn release_files(&mut self);
+
+    /// Removes all files of the torrent along with directories left empty.
+    fn delete_files(&mut self) -> Result<()>;
+}
+
+/// Whether `path` names a file below the directory it's joined to: it's
+/// relative and has no `..` components.
+pub(crate) fn is_contained_path(path: &str) -> bool {
+    let path = Path::new(path);
+    path.components().any(|c| matches!(c, Component::Normal(_)))
+        && path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Makes sure a block lies within the given piece.
+fn check_bounds(files: &FileStorage, piece: usize, offset: usize, len: usize) -> Result<()> {
+    if piece >= files.num_pieces() || offset + len > files.piece_size(piece) {
+        return Err(Error::PieceOutOfRange);
+    }
+    Ok(())
+}
+
+struct OpenFile {
+    file: File,
+    writable: bool,
+}
+
+/// Stores the torrent's files below `save_path`, the way they are laid out
+/// in the torrent. Pad files are never created.
+pub struct DefaultStorage {
+    params: StorageParams,
+    open_files: HashMap<usize, OpenFile>,
+}
+
+impl DefaultStorage {
+    pub fn new(params: StorageParams) -> Self {
+        Self {
+            params,
+            open_files: HashMap::new(),
+        }
+    }
+
+    pub fn file_path(&self, index: usize) -> PathBuf {
+        match self.params.renamed_files.get(&index) {
+            // Other renames are rejected by initialize
+            Some(path) if is_contained_path(path) => self.params.save_path.join(path),
+            Some(_) | None => self
+                .params
+                .save_path
+                .join(&self.params.files.file_at(index).path),
+        }
+    }
+
+    fn open_file(&mut self, index: usize, writable: bool) -> io::Result<&mut File> {
+        let reopen = match self.open_files.get(&index) {
+            Some(f) => writable && !f.writable,
+            None => true,
+        };
+        if reopen {
+            let path = self.file_path(index);
+            let file = if writable {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&path)?
+            } else {
+                File::open(&path)?
+            };
+            self.open_files.insert(index, OpenFile { file, writable });
+        }
+        Ok(&mut self.open_files.get_mut(&index).unwrap().file)
+    }
+
+    fn allocate(&mut self, index: usize) -> io::Result<()> {
+        let size = self.params.files.file_at(index).size;
+        let file = self.open_file(index, true)?;
+        let mut len = file.metadata()?.len();
+        if len >= size {
+            return Ok(());
+        }
+        file.seek(SeekFrom::Start(len))?;
+        let zeroes = [0; 16 * 1024];
+        while len < size {
+            let n = (size - len).min(zeroes.len() as u64) as usize;
+            file.write_all(&zeroes[..n])?;
+            len += n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for DefaultStorage {
+    fn files(&self) -> &FileStorage {
+        &self.params.files
+    }
+
+    fn initialize(&mut self) -> Result<()> {
+        if !self
+            .params
+            .renamed_files
+            .values()
+            .all(|p| is_contained_path(p))
+        {
+            return Err(Error::InvalidFilePath);
+        }
+        fs::create_dir_all(&self.params.save_path)?;
+        for index in 0..self.params.files.num_files() {
+            let file = self.params.files.file_at(index);
+            if file.pad_file {
+                continue;
+            }
+            // Empty files are never written to so they're created here
+            if file.size == 0 || self.params.mode == StorageMode::Allocate {
+                self.allocate(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8], piece: usize, offset: usize) -> Result<()> {
+        check_bounds(&self.params.files, piece, offset, buf.len())?;
+        let slices = self.params.files.map_block(piece, offset, buf.len());
+        let mut pos = 0;
+        for FileSlice {
+            file_index,
+            offset,
+            size,
+        } in slices
+        {
+            let dst = &mut buf[pos..pos + size as usize];
+            pos += size as usize;
+            if self.params.files.file_at(file_index).pad_file {
+                dst.iter_mut().for_each(|b| *b = 0);
+                continue;
+            }
+
+            let file = match self.open_file(file_index, false) {
+                Ok(file) => file,
+                // Files are only created once written to
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    dst.iter_mut().for_each(|b| *b = 0);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            file.seek(SeekFrom::Start(offset))?;
+            let mut n = 0;
+            while n < dst.len() {
+                match file.read(&mut dst[n..]) {
+                    Ok(0) => break,
+                    Ok(k) => n += k,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            // Sparse files end where the last write did
+            dst[n..].iter_mut().for_each(|b| *b = 0);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8], piece: usize, offset: usize) -> Result<()> {
+        check_bounds(&self.params.files, piece, offset, buf.len())?;
+        let slices = self.params.files.map_block(piece, offset, buf.len());
+        let mut pos = 0;
+        for FileSlice {
+            file_index,
+            offset,
+            size,
+        } in slices
+        {
+            let src = &buf[pos..pos + size as usize];
+            pos += size as usize;
+            if self.params.files.file_at(file_index).pad_file {
+                continue;
+            }
+
+            let file = self.open_file(file_index, true)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(src)?;
+        }
+        Ok(())
+    }
+
+    fn release_files(&mut self) {
+        self.open_files.clear();
+    }
+
+    fn delete_files(&mut self) -> Result<()> {
+        self.release_files();
+        let mut dirs = vec![];
+        for index in 0..self.params.files.num_files() {
+            if self.params.files.file_at(index).pad_file {
+                continue;
+            }
+            let path = self.file_path(index);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            let mut dir = path.parent();
+            while let Some(d) = dir {
+                if d == self.params.save_path {
+                    break;
+                }
+                dirs.push(d.to_path_buf());
+                dir = d.parent();
+            }
+        }
+
+        // Deepest first, directories that still have files in them are
+        // left alone
+        dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+        dirs.dedup();
+        for dir in dirs {
+            let _ = fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps all data in memory. Meant for tests and torrents that are only
+/// passed through.
+pub struct MemoryStorage {
+    files: FileStorage,
+    pieces: HashMap<usize, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(files: FileStorage) -> Self {
+        Self {
+            files,
+            pieces: HashMap::new(),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn files(&self) -> &FileStorage {
+        &self.files
+    }
+
+    fn initialize(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8], piece: usize, offset: usize) -> Result<()> {
+        check_bounds(&self.files, piece, offset, buf.len())?;
+        match self.pieces.get(&piece) {
+            Some(data) => buf.copy_from_slice(&data[offset..offset + buf.len()]),
+            None => buf.iter_mut().for_each(|b| *b = 0),
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8], piece: usize, offset: usize) -> Result<()> {
+        check_bounds(&self.files, piece, offset, buf.len())?;
+        let size = self.files.piece_size(piece);
+        let data = self.pieces.entry(piece).or_insert_with(|| vec![0; size]);
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn release_files(&mut self) {}
+
+    fn delete_files(&mut self) -> Result<()> {
+        self.pieces.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fs::FileEntry;
+    use crate::test_util::temp_dir;
+
+    /// test/a (10 bytes), test/.pad/6, test/sub/b (0 bytes), test/sub/c (20 bytes)
+    fn files() -> FileStorage {
+        let mut fs = FileStorage::new();
+        fs.set_name("test".to_string());
+        fs.set_piece_len(16);
+        for (path, size, pad_file) in &[
+            ("test/a", 10, false),
+            ("test/.pad/6", 6, true),
+            ("test/sub/b", 0, false),
+            ("test/sub/c", 20, false),
+        ] {
+            fs.add_file(FileEntry {
+                path: PathBuf::from(path),
+                size: *size,
+                pad_file: *pad_file,
+                ..FileEntry::default()
+            });
+        }
+        fs.set_num_pieces(3);
+        fs
+    }
+
+    /// The 36 bytes of `files()`, zero in the pad file.
+    fn padded_content() -> Vec<u8> {
+        let mut data: Vec<u8> = (1..=36).collect();
+        data[10..16].iter_mut().for_each(|b| *b = 0);
+        data
+    }
+
+    fn write_all(s: &mut dyn Storage, data: &[u8]) {
+        for (piece, chunk) in data.chunks(16).enumerate() {
+            // Write in two blocks to cross the file boundaries at odd offsets
+            let (a, b) = chunk.split_at(chunk.len() / 2);
+            s.write(a, piece, 0).unwrap();
+            s.write(b, piece, a.len()).unwrap();
+        }
+    }
+
+    fn read_all(s: &mut dyn Storage) -> Vec<u8> {
+        let files = s.files().clone();
+        let mut data = vec![];
+        for piece in 0..files.num_pieces() {
+            let mut buf = vec![0xff; files.piece_size(piece)];
+            s.read(&mut buf, piece, 0).unwrap();
+            data.extend_from_slice(&buf);
+        }
+        data
+    }
+
+    #[test]
+    fn test_default_storage() {
+        let dir = temp_dir("storage-default");
+        let mut s = DefaultStorage::new(StorageParams {
+            files: files(),
+            save_path: dir.clone(),
+            ..StorageParams::default()
+        });
+        s.initialize().unwrap();
+        assert!(dir.join("test/sub/b").is_file());
+        assert!(!dir.join("test/a").exists());
+
+        write_all(&mut s, &padded_content());
+        assert_eq!(padded_content(), read_all(&mut s));
+        assert_eq!(
+            &padded_content()[..10],
+            &fs::read(dir.join("test/a")).unwrap()[..]
+        );
+        assert_eq!(
+            &padded_content()[16..],
+            &fs::read(dir.join("test/sub/c")).unwrap()[..]
+        );
+        assert!(!dir.join("test/.pad").exists());
+
+        s.delete_files().unwrap();
+        assert!(!dir.join("test").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sparse_read() {
+        let dir = temp_dir("storage-sparse");
+        let mut s = DefaultStorage::new(StorageParams {
+            files: files(),
+            save_path: dir.clone(),
+            ..StorageParams::default()
+        });
+        s.initialize().unwrap();
+        s.write(&[7; 4], 1, 4).unwrap();
+
+        let mut buf = [0xff; 16];
+        s.read(&mut buf, 1, 0).unwrap();
+        assert_eq!([0, 0, 0, 0, 7, 7, 7, 7, 0, 0, 0, 0, 0, 0, 0, 0], buf);
+        assert_eq!(8, fs::metadata(dir.join("test/sub/c")).unwrap().len());
+
+        // The first file was never written
+        let mut buf = [0xff; 4];
+        s.read(&mut buf, 0, 0).unwrap();
+        assert_eq!([0; 4], buf);
+        assert!(!dir.join("test/a").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_allocate() {
+        let dir = temp_dir("storage-allocate");
+        let mut renamed_files = HashMap::new();
+        renamed_files.insert(3, "renamed".to_string());
+        let mut s = DefaultStorage::new(StorageParams {
+            files: files(),
+            save_path: dir.clone(),
+            mode: StorageMode::Allocate,
+            renamed_files,
+        });
+        s.initialize().unwrap();
+        assert_eq!(10, fs::metadata(dir.join("test/a")).unwrap().len());
+        assert_eq!(20, fs::metadata(dir.join("renamed")).unwrap().len());
+        assert!(!dir.join("test/sub/c").exists());
+
+        write_all(&mut s, &padded_content());
+        assert_eq!(padded_content(), read_all(&mut s));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename_outside_save_path() {
+        let dir = temp_dir("storage-rename-outside");
+        for path in &["../x", "sub/../../x", "/x", "", "."] {
+            let mut renamed_files = HashMap::new();
+            renamed_files.insert(3, path.to_string());
+            let mut s = DefaultStorage::new(StorageParams {
+                files: files(),
+                save_path: dir.join("save"),
+                mode: StorageMode::Sparse,
+                renamed_files,
+            });
+            assert!(matches!(s.initialize(), Err(Error::InvalidFilePath)));
+            assert_eq!(dir.join("save/test/sub/c"), s.file_path(3));
+        }
+        assert!(!dir.join("x").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let mut s = memory_storage_constructor(StorageParams {
+            files: files(),
+            ..StorageParams::default()
+        });
+        s.initialize().unwrap();
+        assert_eq!(vec![0; 36], read_all(&mut *s));
+        write_all(&mut *s, &padded_content());
+        assert_eq!(padded_content(), read_all(&mut *s));
+        s.delete_files().unwrap();
+        assert_eq!(vec![0; 36], read_all(&mut *s));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut s = MemoryStorage::new(files());
+        let mut buf = [0; 8];
+        assert!(matches!(
+            s.read(&mut buf, 3, 0),
+            Err(Error::PieceOutOfRange)
+        ));
+        assert!(matches!(
+            s.read(&mut buf, 2, 0),
+            Err(Error::PieceOutOfRange)
+        ));
+        assert!(matches!(s.write(&buf, 0, 9), Err(Error::PieceOutOfRange)));
+        assert!(s.write(&buf[..4], 2, 0).is_ok());
+    }
+}
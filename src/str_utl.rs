@@ -1,6 +1,7 @@
 use std::io::{Cursor, Write};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
+use crate::endpoint::Endpoint;
 use crate::error::{Error, Result};
 
 pub fn split_string(s: &[u8], separator: u8) -> (&[u8], &[u8]) {
@@ -75,6 +76,32 @@ pub fn unescape_bytes(s: &[u8]) -> Result<Vec<u8>> {
     Ok(v)
 }
 
+/// Percent-encodes everything except the unreserved characters of
+/// RFC 3986, making `s` safe to use as a URL query value.
+pub fn escape_string(s: &str) -> String {
+    escape_bytes(s.as_bytes())
+}
+
+/// Like `escape_string`, for binary data such as info-hashes.
+pub fn escape_bytes(s: &[u8]) -> String {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut v = String::with_capacity(s.len());
+    for &c in s {
+        match c {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                v.push(c as char)
+            }
+            _ => {
+                v.push('%');
+                v.push(HEX[(c >> 4) as usize] as char);
+                v.push(HEX[(c & 0xf) as usize] as char);
+            }
+        }
+    }
+    v
+}
+
 #[inline(always)]
 pub fn to_upper(c: u8) -> u8 {
     match c {
@@ -85,7 +112,7 @@ pub fn to_upper(c: u8) -> u8 {
 
 #[inline(always)]
 pub fn is_digit(c: u8) -> bool {
-    c >= b'0' && c <= b'9'
+    c.is_ascii_digit()
 }
 
 #[inline(always)]
@@ -97,13 +124,13 @@ pub fn parse_int(s: &[u8]) -> Result<isize> {
 
 #[inline(always)]
 pub fn is_whitespace(c: u8) -> bool {
-    match c {
-        b' ' | b'\r' | b'\n' | b'\t' => true,
-        _ => false,
-    }
+    matches!(c, b' ' | b'\r' | b'\n' | b'\t')
 }
 
-pub fn parse_endpoint(s: &[u8]) -> Result<SocketAddr> {
+/// Parses `host:port`, `ipv4:port` or `[ipv6]:port`. IP addresses are
+/// returned as socket addresses, anything else that looks like a host
+/// name is returned as is.
+pub fn parse_endpoint(s: &[u8]) -> Result<Endpoint> {
     let s = trim(s);
     if s.is_empty() {
         return Err(Error::InvalidPort);
@@ -111,21 +138,50 @@ pub fn parse_endpoint(s: &[u8]) -> Result<SocketAddr> {
 
     // this is for IPv6 Addr
     if s[0] == b'[' {
-        if let Some(p) = s.iter().position(|&c| c == b']') {
-            let addr = &s[1..p];
-            if s.len() <= p + 2 {
-                return Err(Error::InvalidPort);
-            }
-            let port = &s[p + 1..];
-            if port.is_empty() || port[0] != b':' {
-                return Err(Error::InvalidPort);
-            }
-            let port = &port[1..];
-        } else {
-            return Err(Error::ExpectedCloseBracketInAddr);
-        }
+        let p = s
+            .iter()
+            .position(|&c| c == b']')
+            .ok_or(Error::ExpectedCloseBracketInAddr)?;
+        let addr = std::str::from_utf8(&s[1..p]).map_err(|_| Error::ParseEndpoint)?;
+        let addr: Ipv6Addr = addr.parse()?;
+        let port = match &s[p + 1..] {
+            [b':', port @ ..] => parse_port(port)?,
+            _ => return Err(Error::InvalidPort),
+        };
+        return Ok(Endpoint::Addr(SocketAddr::new(IpAddr::V6(addr), port)));
+    }
+
+    let divider = s
+        .iter()
+        .rposition(|&c| c == b':')
+        .ok_or(Error::InvalidPort)?;
+    let port = parse_port(&s[divider + 1..])?;
+    let host = std::str::from_utf8(&s[..divider]).map_err(|_| Error::ParseEndpoint)?;
+    if host.contains(':') {
+        // IPv6 addresses need brackets to tell them from the port
+        return Err(Error::ParseEndpoint);
     }
-    unimplemented!();
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(Endpoint::Addr(SocketAddr::new(ip, port)));
+    }
+
+    let valid_host = !host.is_empty()
+        && host
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_');
+    if !valid_host {
+        return Err(Error::ParseEndpoint);
+    }
+    Ok(Endpoint::Host(host.to_string(), port))
+}
+
+fn parse_port(s: &[u8]) -> Result<u16> {
+    if s.is_empty() || !s.iter().all(|&c| is_digit(c)) {
+        return Err(Error::InvalidPort);
+    }
+    std::str::from_utf8(s)
+        .map_err(|_| Error::InvalidPort)
+        .and_then(|s| s.parse().map_err(|_| Error::InvalidPort))
 }
 
 pub fn trim(mut s: &[u8]) -> &[u8] {
@@ -157,10 +213,10 @@ pub fn base32_decode(s: &[u8]) -> Vec<u8> {
         }
 
         in_buf.iter_mut().for_each(|c| *c = 0);
-        for j in 0..available {
+        for (j, b) in in_buf.iter_mut().enumerate().take(available) {
             let c = to_upper(s[i]);
             i += 1;
-            in_buf[j] = match c {
+            *b = match c {
                 b'A'..=b'Z' => c - b'A',
                 b'2'..=b'7' => c - b'2' + (b'Z' - b'A') + 1,
                 b'=' => {
@@ -172,7 +228,7 @@ pub fn base32_decode(s: &[u8]) -> Vec<u8> {
                 b'1' => b'I' - b'A',
                 _ => return vec![],
             };
-            debug_assert_eq!(in_buf[j], in_buf[j] & 0x1f);
+            debug_assert_eq!(*b, *b & 0x1f);
         }
 
         out_buf[0] = in_buf[0] << 3;
@@ -229,6 +285,76 @@ mod test {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_escape_string() {
+        assert_eq!("", escape_string(""));
+        assert_eq!("abcXYZ019-_.~", escape_string("abcXYZ019-_.~"));
+        assert_eq!(
+            "http%3A%2F%2Fa.b%2Fc%3Fd%3De%26f",
+            escape_string("http://a.b/c?d=e&f")
+        );
+        assert_eq!("a%20b%2Bc%25", escape_string("a b+c%"));
+        assert_eq!("%C3%A5", escape_string("\u{e5}"));
+
+        let test_string = "!@#$%^&*()-_=+/,. %?";
+        let escaped = escape_string(test_string);
+        assert_eq!(test_string, unescape_string(escaped.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        fn addr(s: &str) -> Result<Endpoint> {
+            Ok(Endpoint::Addr(s.parse().unwrap()))
+        }
+
+        fn host(h: &str, port: u16) -> Result<Endpoint> {
+            Ok(Endpoint::Host(h.to_string(), port))
+        }
+
+        let cases: &[(&str, Result<Endpoint>)] = &[
+            ("127.0.0.1:6881", addr("127.0.0.1:6881")),
+            ("  10.0.0.1:1 \n", addr("10.0.0.1:1")),
+            ("[::1]:6881", addr("[::1]:6881")),
+            (
+                "[2001:db8::ff00:42:8329]:65535",
+                addr("[2001:db8::ff00:42:8329]:65535"),
+            ),
+            ("2001:db8::1:80", Err(Error::ParseEndpoint)),
+            ("::1:80", Err(Error::ParseEndpoint)),
+            (
+                "router.bittorrent.com:6881",
+                host("router.bittorrent.com", 6881),
+            ),
+            ("local_host-1:80", host("local_host-1", 80)),
+            ("", Err(Error::InvalidPort)),
+            ("   ", Err(Error::InvalidPort)),
+            ("127.0.0.1", Err(Error::InvalidPort)),
+            ("127.0.0.1:", Err(Error::InvalidPort)),
+            ("127.0.0.1:65536", Err(Error::InvalidPort)),
+            ("127.0.0.1:-1", Err(Error::InvalidPort)),
+            ("127.0.0.1:12a", Err(Error::InvalidPort)),
+            ("host", Err(Error::InvalidPort)),
+            ("[::1]", Err(Error::InvalidPort)),
+            ("[::1]:", Err(Error::InvalidPort)),
+            ("[::1]6881", Err(Error::InvalidPort)),
+            ("[::1:6881", Err(Error::ExpectedCloseBracketInAddr)),
+            ("[foo]:6881", Err(Error::ParseEndpoint)),
+            (":6881", Err(Error::ParseEndpoint)),
+            ("a b:6881", Err(Error::ParseEndpoint)),
+            ("g::1:6881", Err(Error::ParseEndpoint)),
+        ];
+
+        for (s, expected) in cases {
+            let actual = parse_endpoint(s.as_bytes());
+            assert_eq!(
+                format!("{:?}", expected),
+                format!("{:?}", actual),
+                "parsing {:?}",
+                s
+            );
+        }
+    }
+
     #[test]
     fn test_base32_decode() {
         assert_eq!(base32_decode(b""), b"");
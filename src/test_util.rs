@@ -0,0 +1,85 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::active_torrent::ActiveTorrent;
+use crate::create_torrent::{CreateFlags, CreateTorrent};
+use crate::fs::{FileEntry, FileStorage};
+use crate::info::TorrentInfo;
+use crate::params::TorrentParams;
+use crate::storage::memory_storage_constructor;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// An empty directory for a test, named after it.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("torrent-rs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `len` bytes of data whose pieces all differ.
+pub(crate) fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Metadata of a torrent over `files`, given as paths and data. The files
+/// are written below `dir` to be hashed, and left there to seed from.
+pub(crate) fn torrent_info(
+    dir: &Path,
+    piece_len: usize,
+    files: &[(&str, &[u8])],
+) -> Arc<TorrentInfo> {
+    torrent_info_with(dir, piece_len, files, |_| {})
+}
+
+/// Like `torrent_info`, with `edit` adding to the torrent before it's
+/// generated.
+pub(crate) fn torrent_info_with(
+    dir: &Path,
+    piece_len: usize,
+    files: &[(&str, &[u8])],
+    edit: impl FnOnce(&mut CreateTorrent),
+) -> Arc<TorrentInfo> {
+    let mut storage = FileStorage::new();
+    let name = Path::new(files[0].0).components().next().unwrap();
+    storage.set_name(name.as_os_str().to_string_lossy().into_owned());
+    for (path, data) in files {
+        storage.add_file(FileEntry {
+            path: PathBuf::from(path),
+            size: data.len() as u64,
+            ..FileEntry::default()
+        });
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+    let mut ct = CreateTorrent::new(storage, piece_len, CreateFlags::default()).unwrap();
+    edit(&mut ct);
+    ct.set_piece_hashes(dir).unwrap();
+    Arc::new(TorrentInfo::from_bytes(&ct.generate().unwrap().to_vec()).unwrap())
+}
+
+/// Parameters adding `info` with its data kept in memory.
+pub(crate) fn memory_params(info: &Arc<TorrentInfo>) -> TorrentParams {
+    let mut p = TorrentParams::default();
+    p.torrent_info = info.clone();
+    p.storage = Some(memory_storage_constructor);
+    p
+}
+
+/// A torrent over `info` kept in memory, checked and so far empty.
+pub(crate) fn memory_torrent(info: &Arc<TorrentInfo>) -> ActiveTorrent {
+    let mut t = ActiveTorrent::new(memory_params(info)).unwrap();
+    t.check_pieces(usize::MAX).unwrap();
+    t
+}
+
+/// Writes `data` to the torrent piece by piece, verifying each.
+pub(crate) fn write_pieces(t: &mut ActiveTorrent, data: &[u8]) {
+    let piece_len = t.info().piece_len();
+    for (piece, chunk) in data.chunks(piece_len).enumerate() {
+        t.storage().write(chunk, piece, 0).unwrap();
+        assert!(t.verify_piece(piece).unwrap());
+    }
+}
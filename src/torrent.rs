@@ -1,3 +1,4 @@
+use common::sha1::Sha1Hash;
 use std::path::PathBuf;
 use url::Url;
 
@@ -18,6 +19,9 @@ pub struct Torrent {
 
     /// Kind of torrent - file or directory
     kind: TorrentKind,
+
+    /// SHA1 hash of the raw info dictionary
+    info_hash: Sha1Hash,
 }
 
 #[derive(Debug)]
@@ -38,6 +42,7 @@ impl Torrent {
         let dict = parsed.as_dict()?;
 
         let announce_url = dict.get("announce")?.as_str()?;
+        let info_hash = Sha1Hash::update(bencode::ValueRef::dict_find_raw(bytes, "info")?);
         let info = dict.get("info")?.as_dict()?;
         let name = info.get("name")?.as_str()?;
         let piece_len = info.get("piece length")?.as_int()? as usize;
@@ -82,8 +87,13 @@ impl Torrent {
             piece_len,
             pieces: pieces.to_vec(),
             kind,
+            info_hash,
         })
     }
+
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.info_hash
+    }
 }
 
 #[cfg(test)]
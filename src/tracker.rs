@@ -0,0 +1,124 @@
+//! What announcing to and scraping a tracker takes and gives back,
+//! independent of the protocol spoken with it.
+
+use crate::endpoint::Endpoint;
+use crate::error::{Error, Result};
+use crate::http_tracker;
+use crate::peer_message::PeerId;
+use crate::udp_tracker::UdpTrackerClient;
+use common::sha1::Sha1Hash;
+use defaults::Defaults;
+use std::net::IpAddr;
+use std::time::Duration;
+use url::Url;
+
+/// Announce interval used when the tracker doesn't send one.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1800);
+
+/// Minimum announce interval used when the tracker doesn't send one.
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longer intervals sent by a tracker are cut down to this.
+pub const MAX_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Numbered as in the UDP tracker protocol (BEP 15).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    #[default]
+    None = 0,
+    Completed = 1,
+    Started = 2,
+    Stopped = 3,
+}
+
+impl Event {
+    /// Value of the `event` parameter of an HTTP announce.
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Defaults)]
+pub struct TrackerRequest {
+    pub url: String,
+    pub info_hash: Sha1Hash,
+    pub peer_id: PeerId,
+
+    /// Port we accept peer connections on.
+    pub listen_port: u16,
+
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+
+    /// Peers we'd like to get, -1 for the tracker's default.
+    #[def = "50"]
+    pub num_want: i32,
+
+    /// Identifies us to the tracker across IP address changes.
+    pub key: u32,
+
+    /// Sent back to the tracker if a previous announce returned one.
+    pub tracker_id: Option<String>,
+
+    /// Time allowed for the whole announce.
+    #[def = "Duration::from_secs(30)"]
+    pub timeout: Duration,
+}
+
+/// A peer returned by a tracker. Peers from dictionary lists may have a
+/// host name instead of an address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerEntry {
+    pub endpoint: Endpoint,
+    pub peer_id: Option<PeerId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Defaults)]
+pub struct TrackerResponse {
+    pub peers: Vec<PeerEntry>,
+
+    /// Time to wait before the next regular announce.
+    #[def = "DEFAULT_INTERVAL"]
+    pub interval: Duration,
+
+    /// Announcing sooner than this is not allowed.
+    #[def = "DEFAULT_MIN_INTERVAL"]
+    pub min_interval: Duration,
+
+    /// Number of seeds, peers and finished downloads, if sent.
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    pub downloaded: Option<u64>,
+
+    /// A warning from the tracker. The announce still succeeded.
+    pub warning_message: Option<String>,
+
+    /// To send back with the next announce.
+    pub tracker_id: Option<String>,
+
+    /// Our address as seen by the tracker.
+    pub external_ip: Option<IpAddr>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeResponse {
+    pub complete: u64,
+    pub incomplete: u64,
+    pub downloaded: u64,
+}
+
+/// Announces to a tracker of any supported protocol.
+pub fn announce(req: &TrackerRequest, udp: &mut UdpTrackerClient) -> Result<TrackerResponse> {
+    match Url::parse(&req.url)?.scheme() {
+        "http" | "https" => http_tracker::announce(req),
+        "udp" => udp.announce(req),
+        _ => Err(Error::UnsupportedUrlProtocol),
+    }
+}
@@ -0,0 +1,491 @@
+//! Which trackers of a torrent to announce to and when (BEP 12).
+//!
+//! Trackers are grouped in tiers and shuffled within their tier. The first
+//! tracker of the first tier is tried first, on failure the next one in the
+//! tier and once the whole tier failed the next tier. A tracker that works
+//! is moved to the front of its tier and used until it fails.
+//!
+//! Failures and backoff are tracked per tracker URL, not per resolved
+//! endpoint as libtorrent does. Announces go out from a single socket per
+//! address family to the first address a host resolves to, so each URL
+//! only ever has one endpoint.
+
+use crate::error::Result;
+use crate::tracker::{self, Event, TrackerRequest, TrackerResponse};
+use crate::udp_tracker::UdpTrackerClient;
+use common::random;
+use std::time::{Duration, Instant};
+
+/// Retry delay after the first failure, grows with the square of the
+/// number of failures.
+const RETRY_DELAY_MIN: Duration = Duration::from_secs(5);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone)]
+pub struct TrackerEntry {
+    pub url: String,
+    pub tier: u8,
+
+    /// Failures since the last successful announce.
+    pub fails: u32,
+
+    /// The next regular announce or retry is due at this time, now if
+    /// `None`.
+    pub next_announce: Option<Instant>,
+
+    /// Announcing sooner than this is not allowed by the tracker.
+    pub min_announce: Option<Instant>,
+
+    /// Why the last announce failed.
+    pub last_error: Option<String>,
+
+    /// Sent with every announce once the tracker gave us one.
+    pub tracker_id: Option<String>,
+
+    /// Whether the tracker was told we started and completed.
+    pub start_sent: bool,
+    pub complete_sent: bool,
+}
+
+impl TrackerEntry {
+    pub fn new(url: String, tier: u8) -> Self {
+        Self {
+            url,
+            tier,
+            fails: 0,
+            next_announce: None,
+            min_announce: None,
+            last_error: None,
+            tracker_id: None,
+            start_sent: false,
+            complete_sent: false,
+        }
+    }
+
+    /// The last announce succeeded.
+    pub fn is_working(&self) -> bool {
+        self.fails == 0 && self.min_announce.is_some()
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.next_announce.is_none_or(|t| t <= now)
+    }
+}
+
+/// The trackers of a torrent, ordered by tier.
+#[derive(Debug, Default, Clone)]
+pub struct TrackerList {
+    trackers: Vec<TrackerEntry>,
+    announce_to_all_tiers: bool,
+
+    /// Set once we have all pieces, for `completed` to be sent.
+    completed: bool,
+
+    /// URL of the last tracker that worked.
+    current_tracker: String,
+}
+
+impl TrackerList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trackers(&self) -> &[TrackerEntry] {
+        &self.trackers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trackers.is_empty()
+    }
+
+    /// Adds a tracker at a random position within its tier, so the
+    /// trackers of a tier end up shuffled. Returns false if it's already
+    /// in the list.
+    pub fn add_tracker(&mut self, url: String, tier: u8) -> bool {
+        if self.trackers.iter().any(|t| t.url == url) {
+            return false;
+        }
+        let start = self.trackers.partition_point(|t| t.tier < tier);
+        let end = self.trackers.partition_point(|t| t.tier <= tier);
+        let index = start + random::random_usize(end - start + 1);
+        self.trackers.insert(index, TrackerEntry::new(url, tier));
+        true
+    }
+
+    /// Announce to one tracker in every tier instead of only the first
+    /// tier that works.
+    pub fn set_announce_to_all_tiers(&mut self, enabled: bool) {
+        self.announce_to_all_tiers = enabled;
+    }
+
+    pub fn current_tracker(&self) -> &str {
+        &self.current_tracker
+    }
+
+    /// Marks the download as complete. Trackers told we started are
+    /// announced to again as soon as they allow it.
+    pub fn set_completed(&mut self) {
+        if self.completed {
+            return;
+        }
+        self.completed = true;
+        for t in &mut self.trackers {
+            if t.start_sent && !t.complete_sent {
+                t.next_announce = t.min_announce;
+            }
+        }
+    }
+
+    /// Announces to every tracker as soon as they allow it.
+    pub fn force_reannounce(&mut self) {
+        for t in &mut self.trackers {
+            t.next_announce = t.min_announce;
+        }
+    }
+
+    /// The event to send with the next announce to a tracker.
+    pub fn event(&self, url: &str) -> Event {
+        match self.find(url) {
+            Some(i) if !self.trackers[i].start_sent => Event::Started,
+            Some(i) if self.completed && !self.trackers[i].complete_sent => Event::Completed,
+            _ => Event::None,
+        }
+    }
+
+    /// Trackers to announce to now.
+    pub fn due(&self, now: Instant) -> Vec<String> {
+        self.walk(now).0
+    }
+
+    /// Time until an announce is due, zero if one is due now.
+    pub fn next_announce(&self, now: Instant) -> Duration {
+        match self.walk(now) {
+            (due, _) if !due.is_empty() => Duration::from_secs(0),
+            (_, Some(next)) => next - now,
+            (_, None) => Duration::from_secs(0),
+        }
+    }
+
+    /// Goes through the tiers in order. A tier with a working tracker only
+    /// uses that one, otherwise the first tracker not waiting for a retry.
+    /// Unless announcing to all tiers, stops at the first tier that has a
+    /// tracker to use. Returns the trackers due now and the earliest time
+    /// one of the others will be.
+    fn walk(&self, now: Instant) -> (Vec<String>, Option<Instant>) {
+        let mut due = vec![];
+        let mut next: Option<Instant> = None;
+        let mut later = |t: &TrackerEntry| {
+            if let Some(at) = t.next_announce {
+                next = Some(next.map_or(at, |n| n.min(at)));
+            }
+        };
+
+        for tier in self.trackers.chunk_by(|a, b| a.tier == b.tier) {
+            let found = match tier.iter().find(|t| t.is_working()) {
+                Some(t) => {
+                    if t.is_due(now) {
+                        due.push(t.url.clone());
+                    } else {
+                        later(t);
+                    }
+                    true
+                }
+                None => match tier.iter().find(|t| t.is_due(now)) {
+                    Some(t) => {
+                        due.push(t.url.clone());
+                        true
+                    }
+                    None => {
+                        tier.iter().for_each(&mut later);
+                        false
+                    }
+                },
+            };
+            if found && !self.announce_to_all_tiers {
+                break;
+            }
+        }
+        (due, next)
+    }
+
+    /// Records a successful announce made with the given event. The tracker
+    /// moves to the front of its tier.
+    pub fn on_success(&mut self, url: &str, event: Event, r: &TrackerResponse, now: Instant) {
+        let index = match self.find(url) {
+            Some(index) => index,
+            None => return,
+        };
+        let t = &mut self.trackers[index];
+        t.fails = 0;
+        t.last_error = None;
+        // Responses not parsed here may carry any interval
+        let after = |d: Duration| now.checked_add(d.min(tracker::MAX_INTERVAL));
+        t.next_announce = after(r.interval.max(r.min_interval)).or(Some(now));
+        t.min_announce = after(r.min_interval).or(Some(now));
+        if r.tracker_id.is_some() {
+            t.tracker_id = r.tracker_id.clone();
+        }
+        match event {
+            Event::Started => {
+                t.start_sent = true;
+                // Starting as a seed, there's no completion to announce
+                t.complete_sent |= self.completed;
+            }
+            Event::Completed => t.complete_sent = true,
+            Event::Stopped => t.start_sent = false,
+            Event::None => {}
+        }
+        self.current_tracker = t.url.clone();
+
+        let first = self
+            .trackers
+            .partition_point(|e| e.tier < self.trackers[index].tier);
+        let entry = self.trackers.remove(index);
+        self.trackers.insert(first, entry);
+    }
+
+    /// Records a failed announce, the tracker is retried after a delay
+    /// growing with the number of failures.
+    pub fn on_failure(&mut self, url: &str, error: String, now: Instant) {
+        let index = match self.find(url) {
+            Some(index) => index,
+            None => return,
+        };
+        let t = &mut self.trackers[index];
+        t.fails += 1;
+        t.last_error = Some(error);
+        let delay = RETRY_DELAY_MIN + RETRY_DELAY_MIN * t.fails.saturating_mul(t.fails) * 5 / 2;
+        let mut at = now + delay.min(RETRY_DELAY_MAX);
+        if let Some(min) = t.min_announce {
+            at = at.max(min);
+        }
+        t.next_announce = Some(at);
+        if self.current_tracker == t.url {
+            self.current_tracker.clear();
+        }
+    }
+
+    /// Announces to the trackers that are due, falling through to the next
+    /// ones as they fail. `base` carries everything but the URL, event and
+    /// tracker id; its timeout caps the whole call; trackers not reached
+    /// in time stay due for the next one. Returns the responses of the
+    /// trackers that worked.
+    pub fn announce(
+        &mut self,
+        base: &TrackerRequest,
+        udp: &mut UdpTrackerClient,
+    ) -> Vec<TrackerResponse> {
+        let deadline = Instant::now() + base.timeout;
+        let mut responses = vec![];
+        loop {
+            let now = Instant::now();
+            let due = self.due(now);
+            if due.is_empty() {
+                return responses;
+            }
+            for url in due {
+                let now = Instant::now();
+                if now >= deadline {
+                    return responses;
+                }
+                let event = self.event(&url);
+                let i = self.find(&url).unwrap();
+                let req = TrackerRequest {
+                    url: url.clone(),
+                    event,
+                    tracker_id: self.trackers[i].tracker_id.clone(),
+                    timeout: deadline - now,
+                    ..base.clone()
+                };
+                let result: Result<_> = tracker::announce(&req, udp);
+                match result {
+                    Ok(r) => {
+                        self.on_success(&url, event, &r, Instant::now());
+                        responses.push(r);
+                    }
+                    Err(e) => self.on_failure(&url, format!("{:?}", e), Instant::now()),
+                }
+            }
+        }
+    }
+
+    fn find(&self, url: &str) -> Option<usize> {
+        self.trackers.iter().position(|t| t.url == url)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http::test::{response, serve};
+    use crate::udp_tracker::UdpTrackerSettings;
+
+    fn list(trackers: &[(&str, u8)]) -> TrackerList {
+        let mut l = TrackerList::new();
+        for (url, tier) in trackers {
+            assert!(l.add_tracker(url.to_string(), *tier));
+        }
+        l
+    }
+
+    fn urls(l: &TrackerList) -> Vec<&str> {
+        l.trackers().iter().map(|t| &t.url[..]).collect()
+    }
+
+    fn ok(interval: u64) -> TrackerResponse {
+        TrackerResponse {
+            interval: Duration::from_secs(interval),
+            ..TrackerResponse::default()
+        }
+    }
+
+    #[test]
+    fn test_tiers() {
+        let l = list(&[("c", 1), ("a", 0), ("d", 2), ("b", 0)]);
+        let mut tier0 = urls(&l)[..2].to_vec();
+        tier0.sort_unstable();
+        assert_eq!(vec!["a", "b"], tier0);
+        assert_eq!(vec!["c", "d"], urls(&l)[2..].to_vec());
+
+        let mut l = l;
+        assert!(!l.add_tracker("a".to_string(), 3));
+        assert_eq!(4, l.trackers().len());
+    }
+
+    #[test]
+    fn test_fall_through() {
+        let now = Instant::now();
+        let mut l = list(&[("a", 0), ("b", 1), ("c", 1)]);
+        l.trackers.sort_by(|x, y| x.url.cmp(&y.url));
+        assert_eq!(vec!["a"], l.due(now));
+        assert_eq!(Event::Started, l.event("a"));
+
+        l.on_failure("a", "timeout".to_string(), now);
+        assert_eq!(1, l.trackers()[0].fails);
+        assert_eq!(vec!["b"], l.due(now));
+        l.on_failure("b", "timeout".to_string(), now);
+        assert_eq!(vec!["c"], l.due(now));
+
+        // The working tracker moves to the front of its tier
+        l.on_success("c", Event::Started, &ok(1800), now);
+        assert_eq!(vec!["a", "c", "b"], urls(&l));
+        assert_eq!("c", l.current_tracker());
+        assert!(l.due(now).is_empty());
+        assert_eq!(Event::None, l.event("c"));
+
+        // The first tier is retried after its backoff
+        let retry = Duration::from_millis(17_500);
+        assert_eq!(retry, l.next_announce(now));
+        assert_eq!(vec!["a"], l.due(now + retry));
+        l.on_failure("a", "timeout".to_string(), now + retry);
+        assert_eq!(
+            Duration::from_secs(5 + 50),
+            l.trackers()[0].next_announce.unwrap() - (now + retry)
+        );
+        // Until the first tier fails again, the working tracker waits
+        let later = now + Duration::from_secs(1800);
+        assert_eq!(vec!["a"], l.due(later));
+        l.on_failure("a", "timeout".to_string(), later);
+        assert_eq!(vec!["c"], l.due(later));
+    }
+
+    #[test]
+    fn test_all_tiers() {
+        let now = Instant::now();
+        let mut l = list(&[("a", 0), ("b", 1), ("c", 2)]);
+        l.set_announce_to_all_tiers(true);
+        assert_eq!(vec!["a", "b", "c"], l.due(now));
+
+        l.on_success("a", Event::Started, &ok(100), now);
+        l.on_failure("b", "timeout".to_string(), now);
+        assert_eq!(vec!["c"], l.due(now));
+        l.on_success("c", Event::Started, &ok(1800), now);
+        assert_eq!(Duration::from_millis(17_500), l.next_announce(now));
+    }
+
+    #[test]
+    fn test_events() {
+        let now = Instant::now();
+        let mut l = list(&[("a", 0)]);
+        let mut r = ok(1800);
+        r.min_interval = Duration::from_secs(60);
+        r.tracker_id = Some("id".to_string());
+        l.on_success("a", Event::Started, &r, now);
+        assert_eq!(Some("id".to_string()), l.trackers()[0].tracker_id);
+
+        // Completion is announced once the min interval allows
+        l.set_completed();
+        assert_eq!(Event::Completed, l.event("a"));
+        assert_eq!(Duration::from_secs(60), l.next_announce(now));
+        l.on_success("a", Event::Completed, &ok(1800), now);
+        assert_eq!(Event::None, l.event("a"));
+
+        l.force_reannounce();
+        assert_eq!(Duration::from_secs(30), l.next_announce(now));
+    }
+
+    #[test]
+    fn test_huge_interval() {
+        let now = Instant::now();
+        let mut l = list(&[("a", 0)]);
+        let mut r = ok(0);
+        r.interval = Duration::from_secs(i64::MAX as u64);
+        r.min_interval = Duration::from_secs(u64::MAX);
+        l.on_success("a", Event::Started, &r, now);
+        assert_eq!(tracker::MAX_INTERVAL, l.next_announce(now));
+    }
+
+    #[test]
+    fn test_announce() {
+        let (addr, server) = serve(vec![
+            response(404, &[], b""),
+            response(
+                200,
+                &[],
+                b"d8:intervali900e5:peers6:\x01\x02\x03\x04\x00\x50e",
+            ),
+        ]);
+        let bad = format!("http://{}/bad/announce", addr);
+        let good = format!("http://{}/announce", addr);
+        let mut l = list(&[(&bad, 0), (&good, 1)]);
+        let mut udp = UdpTrackerClient::new(UdpTrackerSettings::default());
+
+        let responses = l.announce(&TrackerRequest::default(), &mut udp);
+        assert_eq!(1, responses.len());
+        assert_eq!(1, responses[0].peers.len());
+        assert_eq!(good, l.current_tracker());
+        assert_eq!(1, l.trackers()[0].fails);
+        assert!(l.trackers()[0].last_error.is_some());
+        assert!(l.trackers()[1].is_working());
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /bad/announce?"));
+        assert!(requests[1].contains("&event=started "));
+    }
+
+    #[test]
+    fn test_announce_timeout() {
+        // Trackers that never answer
+        let a = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let a = format!("udp://{}", a.local_addr().unwrap());
+        let b = format!("udp://{}", b.local_addr().unwrap());
+        let mut l = list(&[(&a, 0), (&b, 1)]);
+        let mut udp = UdpTrackerClient::new(UdpTrackerSettings {
+            timeout: Duration::from_millis(50),
+            max_retries: 8,
+        });
+        let base = TrackerRequest {
+            timeout: Duration::from_millis(300),
+            ..TrackerRequest::default()
+        };
+
+        let start = Instant::now();
+        assert!(l.announce(&base, &mut udp).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(1, l.trackers()[0].fails);
+        // The second tier wasn't reached and is still due
+        assert_eq!(0, l.trackers()[1].fails);
+        assert_eq!(vec![b], l.due(Instant::now()));
+    }
+}
@@ -0,0 +1,549 @@
+//! Announcing to and scraping UDP trackers (BEP 15, BEP 41).
+
+use crate::endpoint::Endpoint;
+use crate::error::{Error, Result};
+use crate::tracker::{self, PeerEntry, ScrapeResponse, TrackerRequest, TrackerResponse};
+use common::random;
+use common::sha1::Sha1Hash;
+use defaults::Defaults;
+use dht::detail;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Magic constant starting a connect request.
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+/// How long a connection id may be used after it was received.
+pub const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+mod action {
+    pub const CONNECT: u32 = 0;
+    pub const ANNOUNCE: u32 = 1;
+    pub const SCRAPE: u32 = 2;
+    pub const ERROR: u32 = 3;
+}
+
+/// BEP 41 option types.
+mod option {
+    pub const END: u8 = 0;
+    pub const URL_DATA: u8 = 2;
+}
+
+#[derive(Debug, Clone, Defaults)]
+pub struct UdpTrackerSettings {
+    /// Time to wait for the first response. Doubled after every
+    /// retransmission, so with the defaults they're sent after 15 * 2^n
+    /// seconds as in BEP 15.
+    #[def = "Duration::from_secs(15)"]
+    pub timeout: Duration,
+
+    /// Retransmissions of a request before giving up, unless the time
+    /// allowed for the whole announce or scrape runs out first.
+    #[def = "8"]
+    pub max_retries: u32,
+}
+
+impl UdpTrackerSettings {
+    /// Time to wait for a response after the `n`th retransmission,
+    /// `Duration::MAX` if that overflows.
+    pub fn retransmit_timeout(&self, n: u32) -> Duration {
+        2u32.checked_pow(n)
+            .and_then(|factor| self.timeout.checked_mul(factor))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    id: u64,
+    received: Instant,
+}
+
+/// Talks to UDP trackers, remembering connection ids so they can be
+/// reused by following requests to the same tracker.
+pub struct UdpTrackerClient {
+    settings: UdpTrackerSettings,
+    socket4: Option<UdpSocket>,
+    socket6: Option<UdpSocket>,
+    connections: HashMap<SocketAddr, Connection>,
+}
+
+impl UdpTrackerClient {
+    pub fn new(settings: UdpTrackerSettings) -> Self {
+        Self {
+            settings,
+            socket4: None,
+            socket6: None,
+            connections: HashMap::new(),
+        }
+    }
+
+    /// Announces to a `udp://` tracker, giving up after `req.timeout`.
+    pub fn announce(&mut self, req: &TrackerRequest) -> Result<TrackerResponse> {
+        let deadline = Instant::now() + req.timeout;
+        let (addr, url_data) = resolve(&req.url)?;
+        let conn_id = self.connect(addr, deadline)?;
+
+        let mut msg = Vec::with_capacity(100 + url_data.len());
+        msg.extend_from_slice(&conn_id.to_be_bytes());
+        msg.extend_from_slice(&action::ANNOUNCE.to_be_bytes());
+        // Transaction id, filled in when sent
+        msg.extend_from_slice(&[0; 4]);
+        msg.extend_from_slice(&req.info_hash);
+        msg.extend_from_slice(&req.peer_id);
+        msg.extend_from_slice(&req.downloaded.to_be_bytes());
+        msg.extend_from_slice(&req.left.to_be_bytes());
+        msg.extend_from_slice(&req.uploaded.to_be_bytes());
+        msg.extend_from_slice(&(req.event as u32).to_be_bytes());
+        // Our IP address, 0 for the sender's
+        msg.extend_from_slice(&[0; 4]);
+        msg.extend_from_slice(&req.key.to_be_bytes());
+        msg.extend_from_slice(&req.num_want.to_be_bytes());
+        msg.extend_from_slice(&req.listen_port.to_be_bytes());
+        for chunk in url_data.chunks(u8::MAX as usize) {
+            msg.push(option::URL_DATA);
+            msg.push(chunk.len() as u8);
+            msg.extend_from_slice(chunk);
+        }
+        if !url_data.is_empty() {
+            msg.push(option::END);
+        }
+
+        let body = self.transact(addr, action::ANNOUNCE, msg, deadline)?;
+        if body.len() < 12 {
+            return Err(Error::InvalidTrackerResponse);
+        }
+        let mut r = TrackerResponse {
+            interval: Duration::from_secs(read_u32(&body, 0) as u64).min(tracker::MAX_INTERVAL),
+            incomplete: Some(read_u32(&body, 4) as u64),
+            complete: Some(read_u32(&body, 8) as u64),
+            ..TrackerResponse::default()
+        };
+        // Peers are of the same address family as the tracker
+        let len = if addr.is_ipv4() { 6 } else { 18 };
+        for mut c in body[12..].chunks_exact(len) {
+            let peer = if addr.is_ipv4() {
+                detail::read_v4_socket_address(&mut c)
+            } else {
+                detail::read_v6_socket_address(&mut c)
+            };
+            if let Ok(peer) = peer {
+                r.peers.push(PeerEntry {
+                    endpoint: Endpoint::Addr(peer),
+                    peer_id: None,
+                });
+            }
+        }
+        Ok(r)
+    }
+
+    pub fn scrape(
+        &mut self,
+        url: &str,
+        info_hash: &Sha1Hash,
+        timeout: Duration,
+    ) -> Result<ScrapeResponse> {
+        let deadline = Instant::now() + timeout;
+        let (addr, _) = resolve(url)?;
+        let conn_id = self.connect(addr, deadline)?;
+
+        let mut msg = Vec::with_capacity(36);
+        msg.extend_from_slice(&conn_id.to_be_bytes());
+        msg.extend_from_slice(&action::SCRAPE.to_be_bytes());
+        msg.extend_from_slice(&[0; 4]);
+        msg.extend_from_slice(info_hash);
+
+        let body = self.transact(addr, action::SCRAPE, msg, deadline)?;
+        if body.len() < 12 {
+            return Err(Error::InvalidTrackerResponse);
+        }
+        Ok(ScrapeResponse {
+            complete: read_u32(&body, 0) as u64,
+            downloaded: read_u32(&body, 4) as u64,
+            incomplete: read_u32(&body, 8) as u64,
+        })
+    }
+
+    /// A connection id for the tracker, cached or newly requested.
+    fn connect(&mut self, addr: SocketAddr, deadline: Instant) -> Result<u64> {
+        if let Some(conn) = self.connections.get(&addr) {
+            if conn.received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(conn.id);
+            }
+        }
+        self.connections.remove(&addr);
+
+        let mut msg = Vec::with_capacity(16);
+        msg.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        msg.extend_from_slice(&action::CONNECT.to_be_bytes());
+        msg.extend_from_slice(&[0; 4]);
+        let body = self.transact(addr, action::CONNECT, msg, deadline)?;
+        if body.len() < 8 {
+            return Err(Error::InvalidTrackerResponse);
+        }
+        let id = u64::from_be_bytes(body[..8].try_into().unwrap());
+        let conn = Connection {
+            id,
+            received: Instant::now(),
+        };
+        self.connections.insert(addr, conn);
+        Ok(id)
+    }
+
+    /// Sends a request until a response arrives, we run out of retries or
+    /// `deadline` passes. `msg` has room for the transaction id at bytes
+    /// 12..16. Returns what follows the action and transaction id of the
+    /// response.
+    fn transact(
+        &mut self,
+        addr: SocketAddr,
+        action: u32,
+        mut msg: Vec<u8>,
+        deadline: Instant,
+    ) -> Result<Vec<u8>> {
+        let mut tid = [0; 4];
+        random::fill_bytes(&mut tid);
+        msg[12..16].copy_from_slice(&tid);
+
+        let settings = self.settings.clone();
+        let socket = self.socket(addr)?;
+        let mut buf = [0; 2048];
+        for n in 0..=settings.max_retries {
+            if Instant::now() >= deadline {
+                break;
+            }
+            socket.send_to(&msg, addr)?;
+            let retransmit_at = Instant::now()
+                .checked_add(settings.retransmit_timeout(n))
+                .map_or(deadline, |t| t.min(deadline));
+            loop {
+                let now = Instant::now();
+                if now >= retransmit_at {
+                    break;
+                }
+                socket.set_read_timeout(Some(retransmit_at - now))?;
+                let (len, from) = match socket.recv_from(&mut buf) {
+                    Ok(r) => r,
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut
+                            || e.kind() == io::ErrorKind::Interrupted =>
+                    {
+                        continue
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                // Anything not answering this request is dropped
+                let packet = &buf[..len];
+                if from != addr || len < 8 || packet[4..8] != tid {
+                    continue;
+                }
+                match read_u32(packet, 0) {
+                    a if a == action => return Ok(packet[8..].to_vec()),
+                    action::ERROR => {
+                        let msg = String::from_utf8_lossy(&packet[8..]).into_owned();
+                        return Err(Error::TrackerFailure(msg));
+                    }
+                    _ => return Err(Error::InvalidTrackerResponse),
+                }
+            }
+        }
+        self.connections.remove(&addr);
+        Err(Error::Io(io::ErrorKind::TimedOut.into()))
+    }
+
+    fn socket(&mut self, addr: SocketAddr) -> Result<&UdpSocket> {
+        let (socket, bind) = if addr.is_ipv4() {
+            (&mut self.socket4, "0.0.0.0:0")
+        } else {
+            (&mut self.socket6, "[::]:0")
+        };
+        if socket.is_none() {
+            *socket = Some(UdpSocket::bind(bind)?);
+        }
+        Ok(socket.as_ref().unwrap())
+    }
+}
+
+/// The tracker's address and the BEP 41 URL data (path and query) of a
+/// `udp://` URL.
+fn resolve(url: &str) -> Result<(SocketAddr, Vec<u8>)> {
+    let url = Url::parse(url)?;
+    if url.scheme() != "udp" {
+        return Err(Error::UnsupportedUrlProtocol);
+    }
+    let host = url.host_str().ok_or(Error::InvalidUrl)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port().ok_or(Error::InvalidPort)?;
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or(Error::ParseEndpoint)?;
+
+    let mut url_data = url.path().to_string();
+    if let Some(query) = url.query() {
+        url_data.push('?');
+        url_data.push_str(query);
+    }
+    if url_data == "/" {
+        url_data.clear();
+    }
+    Ok((addr, url_data.into_bytes()))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tracker::Event;
+    use std::thread::{self, JoinHandle};
+
+    fn settings() -> UdpTrackerSettings {
+        UdpTrackerSettings {
+            timeout: Duration::from_millis(50),
+            max_retries: 2,
+        }
+    }
+
+    /// A tracker that answers requests with `reply`, which gets the request
+    /// and returns the response body after action and transaction id, if
+    /// any. Returns the requests it got once `count` were handled.
+    fn tracker(
+        bind: &str,
+        count: usize,
+        mut reply: impl FnMut(usize, &[u8]) -> Option<(u32, Vec<u8>)> + Send + 'static,
+    ) -> Option<(SocketAddr, JoinHandle<Vec<Vec<u8>>>)> {
+        let socket = UdpSocket::bind(bind).ok()?;
+        let addr = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut requests = vec![];
+            let mut buf = [0; 2048];
+            for i in 0..count {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                let req = buf[..len].to_vec();
+                if let Some((action, body)) = reply(i, &req) {
+                    let mut msg = action.to_be_bytes().to_vec();
+                    msg.extend_from_slice(&req[12..16]);
+                    msg.extend_from_slice(&body);
+                    socket.send_to(&msg, from).unwrap();
+                }
+                requests.push(req);
+            }
+            requests
+        });
+        Some((addr, handle))
+    }
+
+    fn connect_reply(req: &[u8]) -> Option<(u32, Vec<u8>)> {
+        assert_eq!(&PROTOCOL_ID.to_be_bytes(), &req[..8]);
+        assert_eq!(16, req.len());
+        Some((
+            action::CONNECT,
+            0x1122_3344_5566_7788u64.to_be_bytes().to_vec(),
+        ))
+    }
+
+    fn request(url: String) -> TrackerRequest {
+        TrackerRequest {
+            url,
+            info_hash: Sha1Hash::from([0xab; 20]),
+            peer_id: Sha1Hash::from([b'p'; 20]),
+            listen_port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: Event::Started,
+            key: 0x1234,
+            ..TrackerRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_retransmit_timeout() {
+        let s = UdpTrackerSettings::default();
+        assert_eq!(Duration::from_secs(15), s.retransmit_timeout(0));
+        assert_eq!(Duration::from_secs(60), s.retransmit_timeout(2));
+        assert_eq!(Duration::from_secs(3840), s.retransmit_timeout(8));
+        assert_eq!(Duration::MAX, s.retransmit_timeout(32));
+        let s = UdpTrackerSettings {
+            timeout: Duration::MAX / 2,
+            max_retries: 8,
+        };
+        assert_eq!(Duration::MAX, s.retransmit_timeout(2));
+    }
+
+    #[test]
+    fn test_announce_and_scrape() {
+        let (addr, server) = tracker("127.0.0.1:0", 3, |i, req| match i {
+            0 => connect_reply(req),
+            1 => {
+                let mut body = vec![];
+                body.extend_from_slice(&1800u32.to_be_bytes());
+                body.extend_from_slice(&5u32.to_be_bytes());
+                body.extend_from_slice(&7u32.to_be_bytes());
+                body.extend_from_slice(&[1, 2, 3, 4, 0x1a, 0xe1, 5, 6, 7, 8, 0, 80]);
+                Some((action::ANNOUNCE, body))
+            }
+            _ => {
+                let mut body = vec![];
+                for n in &[3u32, 10, 4] {
+                    body.extend_from_slice(&n.to_be_bytes());
+                }
+                Some((action::SCRAPE, body))
+            }
+        })
+        .unwrap();
+
+        let mut client = UdpTrackerClient::new(settings());
+        let url = format!("udp://{}/announce?k=1", addr);
+        let r = client.announce(&request(url.clone())).unwrap();
+        assert_eq!(Duration::from_secs(1800), r.interval);
+        assert_eq!((Some(7), Some(5)), (r.complete, r.incomplete));
+        let peers: Vec<_> = r.peers.iter().map(|p| p.endpoint.to_string()).collect();
+        assert_eq!(vec!["1.2.3.4:6881", "5.6.7.8:80"], peers);
+
+        // The connection id is reused
+        let r = client
+            .scrape(&url, &Sha1Hash::from([0xab; 20]), Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(
+            ScrapeResponse {
+                complete: 3,
+                downloaded: 10,
+                incomplete: 4,
+            },
+            r
+        );
+
+        let requests = server.join().unwrap();
+        let announce = &requests[1];
+        assert_eq!(&0x1122_3344_5566_7788u64.to_be_bytes(), &announce[..8]);
+        assert_eq!(&action::ANNOUNCE.to_be_bytes(), &announce[8..12]);
+        assert_eq!(&[0xab; 20], &announce[16..36]);
+        assert_eq!(&[b'p'; 20], &announce[36..56]);
+        assert_eq!(&2u64.to_be_bytes(), &announce[56..64]);
+        assert_eq!(&3u64.to_be_bytes(), &announce[64..72]);
+        assert_eq!(&1u64.to_be_bytes(), &announce[72..80]);
+        assert_eq!(&2u32.to_be_bytes(), &announce[80..84]);
+        assert_eq!(&0x1234u32.to_be_bytes(), &announce[88..92]);
+        assert_eq!(&50i32.to_be_bytes(), &announce[92..96]);
+        assert_eq!(&6881u16.to_be_bytes(), &announce[96..98]);
+        assert_eq!(b"\x02\x0d/announce?k=1\x00", &announce[98..]);
+
+        let scrape = &requests[2];
+        assert_eq!(&action::SCRAPE.to_be_bytes(), &scrape[8..12]);
+        assert_eq!(&[0xab; 20], &scrape[16..]);
+    }
+
+    #[test]
+    fn test_retransmit() {
+        // The first connect is lost
+        let (addr, server) = tracker("127.0.0.1:0", 3, |i, req| match i {
+            0 => None,
+            1 => connect_reply(req),
+            _ => Some((action::ERROR, b"not registered".to_vec())),
+        })
+        .unwrap();
+
+        let mut client = UdpTrackerClient::new(settings());
+        let start = Instant::now();
+        match client.announce(&request(format!("udp://{}", addr))) {
+            Err(Error::TrackerFailure(msg)) => assert_eq!("not registered", msg),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // No URL data without a path
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], requests[1]);
+        assert_eq!(98, requests[2].len());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (addr, server) = tracker("127.0.0.1:0", 3, |_, _| None).unwrap();
+        let mut client = UdpTrackerClient::new(settings());
+        let start = Instant::now();
+        match client.announce(&request(format!("udp://{}", addr))) {
+            Err(Error::Io(e)) => assert_eq!(io::ErrorKind::TimedOut, e.kind()),
+            r => panic!("unexpected {:?}", r),
+        }
+        // 50 + 100 + 200 ms
+        assert!(start.elapsed() >= Duration::from_millis(350));
+        assert_eq!(3, server.join().unwrap().len());
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        // Without the request's timeout this would retry for 25 seconds
+        let (addr, server) = tracker("127.0.0.1:0", 3, |_, _| None).unwrap();
+        let mut client = UdpTrackerClient::new(UdpTrackerSettings {
+            max_retries: 8,
+            ..settings()
+        });
+        let req = TrackerRequest {
+            timeout: Duration::from_millis(300),
+            ..request(format!("udp://{}", addr))
+        };
+        let start = Instant::now();
+        assert!(client.announce(&req).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        // Sent at 0, 50 and 150 ms
+        assert_eq!(3, server.join().unwrap().len());
+    }
+
+    #[test]
+    fn test_connection_expiry() {
+        let (addr, server) = tracker("127.0.0.1:0", 4, |i, req| match i {
+            0 | 2 => connect_reply(req),
+            _ => Some((action::ANNOUNCE, vec![0; 12])),
+        })
+        .unwrap();
+        let mut client = UdpTrackerClient::new(settings());
+        let req = request(format!("udp://{}", addr));
+        client.announce(&req).unwrap();
+        let conn = client.connections.get_mut(&addr).unwrap();
+        conn.received = Instant::now().checked_sub(CONNECTION_ID_LIFETIME).unwrap();
+        client.announce(&req).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(&PROTOCOL_ID.to_be_bytes(), &requests[2][..8]);
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let (addr, server) = match tracker("[::1]:0", 2, |i, req| match i {
+            0 => connect_reply(req),
+            _ => {
+                let mut body = vec![0; 12];
+                let mut peer = [0; 18];
+                peer[15] = 1;
+                peer[16..].copy_from_slice(&6881u16.to_be_bytes());
+                body.extend_from_slice(&peer);
+                Some((action::ANNOUNCE, body))
+            }
+        }) {
+            Some(t) => t,
+            // No IPv6 here
+            None => return,
+        };
+        let mut client = UdpTrackerClient::new(settings());
+        let r = client
+            .announce(&request(format!("udp://{}/announce", addr)))
+            .unwrap();
+        assert_eq!(1, r.peers.len());
+        assert_eq!("[::1]:6881", r.peers[0].endpoint.to_string());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,674 @@
+//! Metadata exchange (BEP 9). Lets a torrent added from a magnet link
+//! download its info dictionary from peers, and lets us serve ours.
+
+use crate::error::{Error, Result};
+use crate::extensions::{ExtendedHandshake, PeerExtension, TorrentExtension};
+use crate::info::{TorrentInfo, TorrentLimits};
+use bencode::{Value, ValueRef};
+use common::sha1::Sha1Hash;
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+pub const EXTENSION_NAME: &str = "ut_metadata";
+
+/// Metadata is exchanged in pieces of this size, the last one may be
+/// shorter.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+/// Requests outstanding to a single peer at most.
+const MAX_OUTSTANDING: usize = 2;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Debug, Clone, Default)]
+struct MetadataPiece {
+    received: bool,
+
+    /// Number of peers the piece is requested from.
+    requested: usize,
+
+    /// The peer the piece came from.
+    source: Option<SocketAddr>,
+}
+
+/// The metadata of a torrent, shared by all its connections. Either
+/// complete and served to peers, or being downloaded.
+#[derive(Debug)]
+pub struct Metadata {
+    info_hash: Sha1Hash,
+    limits: TorrentLimits,
+    buf: Vec<u8>,
+    complete: bool,
+    pieces: Vec<MetadataPiece>,
+
+    /// The parsed metadata once downloaded and verified, until taken.
+    torrent_info: Option<TorrentInfo>,
+
+    /// Peers that sent pieces of metadata that failed the hash check.
+    bad_peers: HashSet<SocketAddr>,
+
+    /// Set once metadata from several peers failed the hash check. We
+    /// can't tell which of them sent bad data, so from then on all pieces
+    /// are downloaded from a single peer, and only it is blamed if the
+    /// check fails again.
+    single_source: bool,
+
+    /// The peer all pieces are downloaded from in single source mode.
+    owner: Option<SocketAddr>,
+}
+
+impl Metadata {
+    /// Metadata we need to download.
+    pub fn new(info_hash: Sha1Hash, limits: TorrentLimits) -> Self {
+        Self {
+            info_hash,
+            limits,
+            buf: vec![],
+            complete: false,
+            pieces: vec![],
+            torrent_info: None,
+            bad_peers: HashSet::new(),
+            single_source: false,
+            owner: None,
+        }
+    }
+
+    /// Metadata we already have.
+    pub fn with_info_section(info_hash: Sha1Hash, info_section: &[u8]) -> Self {
+        Self {
+            info_hash,
+            limits: TorrentLimits::default(),
+            buf: info_section.to_vec(),
+            complete: true,
+            pieces: vec![],
+            torrent_info: None,
+            bad_peers: HashSet::new(),
+            single_source: false,
+            owner: None,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Size of the metadata, 0 while unknown.
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The downloaded metadata, returned once.
+    pub fn take_torrent_info(&mut self) -> Option<TorrentInfo> {
+        self.torrent_info.take()
+    }
+
+    fn num_pieces(&self) -> usize {
+        self.buf.len().div_ceil(METADATA_PIECE_LEN)
+    }
+
+    fn piece_range(&self, piece: usize) -> std::ops::Range<usize> {
+        let start = piece * METADATA_PIECE_LEN;
+        start..(start + METADATA_PIECE_LEN).min(self.buf.len())
+    }
+
+    /// Starts the download once a peer told us the size. Sizes above the
+    /// buffer limit are ignored. The size is learned again if the
+    /// downloaded metadata fails the hash check.
+    fn set_size(&mut self, size: usize) {
+        if self.complete || !self.buf.is_empty() {
+            return;
+        }
+        if size == 0 || size > self.limits.max_buf_size {
+            return;
+        }
+        self.buf = vec![0; size];
+        self.pieces = vec![MetadataPiece::default(); self.num_pieces()];
+    }
+
+    /// The piece to request next: one we don't have that is requested from
+    /// the fewest peers, skipping those in `exclude`.
+    fn pick_piece(&self, exclude: &[usize]) -> Option<usize> {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| !p.received && !exclude.contains(i))
+            .min_by_key(|(i, p)| (p.requested, *i))
+            .map(|(i, _)| i)
+    }
+
+    fn is_bad(&self, peer: SocketAddr) -> bool {
+        self.bad_peers.contains(&peer)
+    }
+
+    /// Whether pieces may be downloaded from `peer`. In single source mode
+    /// the first peer to ask becomes the only one.
+    fn may_download(&mut self, peer: SocketAddr) -> bool {
+        if !self.single_source {
+            return true;
+        }
+        *self.owner.get_or_insert(peer) == peer
+    }
+
+    /// Forgets what was downloaded from `peer` when it goes away in
+    /// single source mode, so the next peer starts from scratch.
+    fn release_owner(&mut self, peer: SocketAddr) {
+        if self.owner != Some(peer) {
+            return;
+        }
+        self.owner = None;
+        for p in &mut self.pieces {
+            p.received = false;
+            p.source = None;
+        }
+    }
+
+    fn received(&mut self, piece: usize, data: &[u8], source: SocketAddr) {
+        let range = self.piece_range(piece);
+        self.buf[range].copy_from_slice(data);
+        self.pieces[piece].received = true;
+        self.pieces[piece].source = Some(source);
+        if self.pieces.iter().any(|p| !p.received) {
+            return;
+        }
+
+        let info = if Sha1Hash::update(&self.buf) == self.info_hash {
+            TorrentInfo::from_info_section(&self.buf, &self.limits).ok()
+        } else {
+            None
+        };
+        match info {
+            Some(info) => {
+                self.complete = true;
+                self.pieces.clear();
+                self.torrent_info = Some(info);
+            }
+            // Someone sent bad data or lied about the size. A peer that
+            // sent every piece is to blame, otherwise start over with one.
+            None => {
+                let sources: HashSet<_> = self.pieces.iter().filter_map(|p| p.source).collect();
+                if sources.len() == 1 {
+                    self.bad_peers.extend(sources);
+                } else {
+                    self.single_source = true;
+                }
+                self.owner = None;
+                self.buf.clear();
+                self.pieces.clear();
+            }
+        }
+    }
+}
+
+/// Registers ut_metadata with the connections of a torrent.
+pub struct UtMetadata {
+    metadata: Arc<Mutex<Metadata>>,
+}
+
+impl UtMetadata {
+    pub fn new(metadata: Arc<Mutex<Metadata>>) -> Self {
+        Self { metadata }
+    }
+}
+
+impl TorrentExtension for UtMetadata {
+    fn new_connection(&mut self, addr: SocketAddr) -> Option<Box<dyn PeerExtension>> {
+        Some(Box::new(PeerMetadata {
+            addr,
+            metadata: self.metadata.clone(),
+            metadata_size: None,
+            outstanding: vec![],
+        }))
+    }
+}
+
+struct PeerMetadata {
+    addr: SocketAddr,
+    metadata: Arc<Mutex<Metadata>>,
+
+    /// The size the peer told us in its handshake. Cleared once it rejects
+    /// a request, so it isn't asked again.
+    metadata_size: Option<usize>,
+
+    /// Pieces requested from this peer.
+    outstanding: Vec<usize>,
+}
+
+impl PeerMetadata {
+    fn message(msg_type: i64, piece: usize, total_size: Option<usize>) -> Vec<u8> {
+        let mut d = BTreeMap::new();
+        d.insert("msg_type".to_string(), Value::with_int(msg_type));
+        d.insert("piece".to_string(), Value::with_int(piece as i64));
+        if let Some(size) = total_size {
+            d.insert("total_size".to_string(), Value::with_int(size as i64));
+        }
+        Value::with_dict(d).to_vec()
+    }
+
+    /// Forgets a request to the peer, returns false if there was none.
+    fn release(outstanding: &mut Vec<usize>, metadata: &mut Metadata, piece: usize) -> bool {
+        match outstanding.iter().position(|&p| p == piece) {
+            Some(i) => {
+                outstanding.remove(i);
+                if let Some(p) = metadata.pieces.get_mut(piece) {
+                    p.requested = p.requested.saturating_sub(1);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl PeerExtension for PeerMetadata {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn add_handshake(&mut self, handshake: &mut BTreeMap<String, Value>) {
+        let metadata = self.metadata.lock().unwrap();
+        if metadata.is_complete() {
+            let size = Value::with_int(metadata.size() as i64);
+            handshake.insert("metadata_size".to_string(), size);
+        }
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> bool {
+        if let Some(size) = handshake.metadata_size {
+            self.metadata_size = Some(size);
+            self.metadata.lock().unwrap().set_size(size);
+        }
+        true
+    }
+
+    fn on_message(&mut self, payload: &[u8], out: &mut Vec<Vec<u8>>) -> Result<()> {
+        let (msg, n) = ValueRef::decode_prefix_with_limits(payload, Some(2), Some(10))?;
+        let msg_type = msg.dict_find_int_value("msg_type");
+        let piece = msg
+            .dict_find_int_value("piece")
+            .filter(|&p| p >= 0)
+            .ok_or(Error::InvalidMessage)? as usize;
+
+        let mut metadata = self.metadata.lock().unwrap();
+        match msg_type {
+            Some(MSG_REQUEST) => {
+                if metadata.is_complete() && piece < metadata.num_pieces() {
+                    let mut data = Self::message(MSG_DATA, piece, Some(metadata.size()));
+                    data.extend_from_slice(&metadata.buf[metadata.piece_range(piece)]);
+                    out.push(data);
+                } else {
+                    out.push(Self::message(MSG_REJECT, piece, None));
+                }
+            }
+            Some(MSG_DATA) => {
+                // Unrequested pieces are dropped
+                if metadata.is_complete()
+                    || !Self::release(&mut self.outstanding, &mut metadata, piece)
+                {
+                    return Ok(());
+                }
+                let total_size = msg.dict_find_int_value("total_size");
+                let data = &payload[n..];
+                if total_size != Some(metadata.size() as i64)
+                    || data.len() != metadata.piece_range(piece).len()
+                {
+                    return Err(Error::InvalidMessage);
+                }
+                if !metadata.may_download(self.addr) {
+                    return Ok(());
+                }
+                metadata.received(piece, data, self.addr);
+            }
+            Some(MSG_REJECT) if Self::release(&mut self.outstanding, &mut metadata, piece) => {
+                self.metadata_size = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, out: &mut Vec<Vec<u8>>) -> Result<()> {
+        let mut metadata = self.metadata.lock().unwrap();
+        if metadata.is_bad(self.addr) {
+            return Err(Error::InvalidMetadata);
+        }
+        let size = match self.metadata_size {
+            Some(size) if !metadata.is_complete() => size,
+            _ => {
+                self.outstanding.clear();
+                return Ok(());
+            }
+        };
+        // After a failed hash check the size is learned again, maybe
+        // this peer's is right. Only peers that agree on it are asked.
+        if metadata.size() == 0 {
+            metadata.set_size(size);
+        }
+        if metadata.size() != size || !metadata.may_download(self.addr) {
+            self.outstanding.clear();
+            return Ok(());
+        }
+        while self.outstanding.len() < MAX_OUTSTANDING {
+            let piece = match metadata.pick_piece(&self.outstanding) {
+                Some(piece) => piece,
+                None => break,
+            };
+            metadata.pieces[piece].requested += 1;
+            self.outstanding.push(piece);
+            out.push(Self::message(MSG_REQUEST, piece, None));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PeerMetadata {
+    fn drop(&mut self) {
+        if let Ok(mut metadata) = self.metadata.lock() {
+            metadata.release_owner(self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An info dictionary spanning three metadata pieces.
+    fn info_section() -> Vec<u8> {
+        let pieces = vec![b'x'; 2000 * 20];
+        let mut info = BTreeMap::new();
+        info.insert("length".to_string(), Value::with_int(2000 * 16384));
+        info.insert("name".to_string(), Value::with_str("test"));
+        info.insert("piece length".to_string(), Value::with_int(16384));
+        info.insert("pieces".to_string(), Value::Bytes(pieces));
+        Value::with_dict(info).to_vec()
+    }
+
+    fn addr() -> SocketAddr {
+        "10.0.0.1:6881".parse().unwrap()
+    }
+
+    fn handshake(metadata_size: Option<usize>) -> ExtendedHandshake {
+        ExtendedHandshake {
+            metadata_size,
+            ..ExtendedHandshake::default()
+        }
+    }
+
+    /// A connected pair: `a` downloads from `b`.
+    fn pair(
+        downloader: &Arc<Mutex<Metadata>>,
+        seeder: &Arc<Mutex<Metadata>>,
+    ) -> (Box<dyn PeerExtension>, Box<dyn PeerExtension>) {
+        let mut a = UtMetadata::new(downloader.clone())
+            .new_connection(addr())
+            .unwrap();
+        let mut b = UtMetadata::new(seeder.clone())
+            .new_connection(addr())
+            .unwrap();
+        let size = seeder.lock().unwrap().size();
+        assert!(a.on_handshake(&handshake(Some(size))));
+        assert!(b.on_handshake(&handshake(None)));
+        (a, b)
+    }
+
+    /// Delivers messages back and forth until both sides are quiet.
+    fn exchange(a: &mut dyn PeerExtension, b: &mut dyn PeerExtension) {
+        let mut to_b = vec![];
+        a.tick(&mut to_b).unwrap();
+        while !to_b.is_empty() {
+            let mut to_a = vec![];
+            for msg in to_b.drain(..) {
+                b.on_message(&msg, &mut to_a).unwrap();
+            }
+            for msg in to_a {
+                a.on_message(&msg, &mut to_b).unwrap();
+            }
+            a.tick(&mut to_b).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_download() {
+        let info = info_section();
+        let info_hash = Sha1Hash::update(&info);
+        assert_eq!(3, info.len().div_ceil(METADATA_PIECE_LEN));
+
+        let seeder = Arc::new(Mutex::new(Metadata::with_info_section(
+            info_hash.clone(),
+            &info,
+        )));
+        let downloader = Arc::new(Mutex::new(Metadata::new(
+            info_hash.clone(),
+            TorrentLimits::default(),
+        )));
+        let (mut a, mut b) = pair(&downloader, &seeder);
+        assert_eq!(info.len(), downloader.lock().unwrap().size());
+
+        let mut hs = BTreeMap::new();
+        b.add_handshake(&mut hs);
+        assert_eq!(
+            Some(&Value::with_int(info.len() as i64)),
+            hs.get("metadata_size")
+        );
+
+        exchange(&mut *a, &mut *b);
+        let mut m = downloader.lock().unwrap();
+        assert!(m.is_complete());
+        let t = m.take_torrent_info().unwrap();
+        assert_eq!(&info_hash, t.info_hash());
+        assert_eq!("test", t.name());
+        assert!(m.take_torrent_info().is_none());
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let info = info_section();
+        let seeder = Arc::new(Mutex::new(Metadata::with_info_section(
+            Sha1Hash::update(&info),
+            &info,
+        )));
+        let downloader = Arc::new(Mutex::new(Metadata::new(
+            Sha1Hash::update(b"other"),
+            TorrentLimits::default(),
+        )));
+        let (mut a, mut b) = pair(&downloader, &seeder);
+
+        // All pieces arrive but fail the check, so the peer that sent them
+        // is dropped
+        let mut to_b = vec![];
+        for _ in 0..3 {
+            if a.tick(&mut to_b).is_err() {
+                break;
+            }
+            let mut to_a = vec![];
+            for msg in to_b.drain(..) {
+                b.on_message(&msg, &mut to_a).unwrap();
+            }
+            for msg in to_a {
+                a.on_message(&msg, &mut to_b).unwrap();
+            }
+        }
+        assert!(matches!(a.tick(&mut to_b), Err(Error::InvalidMetadata)));
+        let m = downloader.lock().unwrap();
+        assert!(!m.is_complete());
+        assert_eq!(0, m.size());
+        assert!(m.is_bad(addr()));
+    }
+
+    #[test]
+    fn test_bad_piece_from_one_of_many() {
+        let info = info_section();
+        let seeder = Arc::new(Mutex::new(Metadata::with_info_section(
+            Sha1Hash::update(&info),
+            &info,
+        )));
+        let downloader = Arc::new(Mutex::new(Metadata::new(
+            Sha1Hash::update(&info),
+            TorrentLimits::default(),
+        )));
+        let bad_addr = "10.0.0.2:6881".parse().unwrap();
+        let mut bad = UtMetadata::new(downloader.clone())
+            .new_connection(bad_addr)
+            .unwrap();
+        bad.on_handshake(&handshake(Some(info.len())));
+        let (mut a, mut b) = pair(&downloader, &seeder);
+
+        // Answers every request of the bad peer with zeros
+        let send_zeros = |bad: &mut Box<dyn PeerExtension>| {
+            let mut requests = vec![];
+            bad.tick(&mut requests)?;
+            for req in requests {
+                let piece = ValueRef::decode(&req)
+                    .unwrap()
+                    .dict_find_int_value("piece")
+                    .unwrap() as usize;
+                let len = METADATA_PIECE_LEN.min(info.len() - piece * METADATA_PIECE_LEN);
+                let mut data = format!(
+                    "d8:msg_typei1e5:piecei{}e10:total_sizei{}ee",
+                    piece,
+                    info.len()
+                )
+                .into_bytes();
+                data.extend(vec![0; len]);
+                bad.on_message(&data, &mut vec![]).unwrap();
+            }
+            Ok::<_, Error>(())
+        };
+
+        // The honest peer sends two pieces and the bad one the third
+        let mut to_b = vec![];
+        a.tick(&mut to_b).unwrap();
+        assert_eq!(2, to_b.len());
+        send_zeros(&mut bad).unwrap();
+        let mut to_a = vec![];
+        for msg in to_b {
+            b.on_message(&msg, &mut to_a).unwrap();
+        }
+        for msg in to_a {
+            a.on_message(&msg, &mut vec![]).unwrap();
+        }
+
+        // Nobody is banned, the next attempt uses only the bad peer
+        {
+            let m = downloader.lock().unwrap();
+            assert!(!m.is_complete());
+            assert!(!m.is_bad(addr()));
+            assert!(!m.is_bad(bad_addr));
+        }
+        send_zeros(&mut bad).unwrap();
+        let mut out = vec![];
+        a.tick(&mut out).unwrap();
+        assert!(out.is_empty());
+        send_zeros(&mut bad).unwrap();
+
+        // Which fails on its own, so only it is banned
+        assert!(matches!(send_zeros(&mut bad), Err(Error::InvalidMetadata)));
+        assert!(!downloader.lock().unwrap().is_bad(addr()));
+        drop(bad);
+        exchange(&mut *a, &mut *b);
+        assert!(downloader.lock().unwrap().is_complete());
+    }
+
+    #[test]
+    fn test_wrong_size() {
+        let info = info_section();
+        let seeder = Arc::new(Mutex::new(Metadata::with_info_section(
+            Sha1Hash::update(&info),
+            &info,
+        )));
+        let downloader = Arc::new(Mutex::new(Metadata::new(
+            Sha1Hash::update(&info),
+            TorrentLimits::default(),
+        )));
+        let liar_addr = "10.0.0.2:6881".parse().unwrap();
+        let mut liar = UtMetadata::new(downloader.clone())
+            .new_connection(liar_addr)
+            .unwrap();
+        liar.on_handshake(&handshake(Some(100)));
+        let (mut a, mut b) = pair(&downloader, &seeder);
+        assert_eq!(100, downloader.lock().unwrap().size());
+
+        // The honest peer doesn't agree on the size, so it isn't asked
+        let mut out = vec![];
+        a.tick(&mut out).unwrap();
+        assert!(out.is_empty());
+
+        // The liar's piece fails the check and the size is learned again
+        liar.tick(&mut out).unwrap();
+        assert_eq!(1, out.len());
+        let mut data = b"d8:msg_typei1e5:piecei0e10:total_sizei100ee".to_vec();
+        data.extend_from_slice(&[0; 100]);
+        liar.on_message(&data, &mut vec![]).unwrap();
+        assert!(liar.tick(&mut vec![]).is_err());
+
+        exchange(&mut *a, &mut *b);
+        let mut m = downloader.lock().unwrap();
+        assert!(m.is_complete());
+        assert!(m.take_torrent_info().is_some());
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let limits = TorrentLimits {
+            max_buf_size: 1000,
+            ..TorrentLimits::default()
+        };
+        let metadata = Arc::new(Mutex::new(Metadata::new(Sha1Hash::new(), limits)));
+        let mut a = UtMetadata::new(metadata.clone())
+            .new_connection(addr())
+            .unwrap();
+        a.on_handshake(&handshake(Some(1001)));
+        assert_eq!(0, metadata.lock().unwrap().size());
+
+        let mut out = vec![];
+        a.tick(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_reject() {
+        let downloader = Arc::new(Mutex::new(Metadata::new(
+            Sha1Hash::new(),
+            TorrentLimits::default(),
+        )));
+        let empty = Arc::new(Mutex::new(Metadata::new(
+            Sha1Hash::new(),
+            TorrentLimits::default(),
+        )));
+        let mut a = UtMetadata::new(downloader.clone())
+            .new_connection(addr())
+            .unwrap();
+        let mut b = UtMetadata::new(empty).new_connection(addr()).unwrap();
+        a.on_handshake(&handshake(Some(100)));
+
+        let mut requests = vec![];
+        a.tick(&mut requests).unwrap();
+        assert_eq!(vec![b"d8:msg_typei0e5:piecei0ee".to_vec()], requests);
+
+        // We don't have metadata either
+        let mut replies = vec![];
+        b.on_message(&requests[0], &mut replies).unwrap();
+        assert_eq!(vec![b"d8:msg_typei2e5:piecei0ee".to_vec()], replies);
+        a.on_message(&replies[0], &mut vec![]).unwrap();
+        assert_eq!(0, downloader.lock().unwrap().pieces[0].requested);
+
+        // A peer that rejected isn't asked again
+        let mut out = vec![];
+        a.tick(&mut out).unwrap();
+        assert!(out.is_empty());
+
+        // Unrequested data is ignored, bad data is an error
+        let mut c = UtMetadata::new(downloader.clone())
+            .new_connection(addr())
+            .unwrap();
+        c.on_handshake(&handshake(Some(100)));
+        let data = b"d8:msg_typei1e5:piecei0e10:total_sizei100eeXX";
+        c.on_message(data, &mut vec![]).unwrap();
+        c.tick(&mut vec![]).unwrap();
+        assert!(c.on_message(data, &mut vec![]).is_err());
+        assert!(c.on_message(b"d8:msg_typei0ee", &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+//! Peer exchange (BEP 11). Connected peers regularly tell each other which
+//! peers they are connected to, so a swarm can be found without trackers.
+
+use crate::error::{Error, Result};
+use crate::extensions::{ExtendedHandshake, PeerExtension, TorrentExtension};
+use bencode::{Value, ValueRef};
+use bitflags::bitflags;
+use dht::detail;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const EXTENSION_NAME: &str = "ut_pex";
+
+/// How often a peer is sent the changes to our connected peers.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages arriving sooner than this after the previous one are ignored.
+/// Peers should send at most one a minute, this leaves room for jitter.
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Entries in the added and dropped lists of a message at most. Changes
+/// beyond that are sent with the next message, received ones are ignored.
+const MAX_PEER_ENTRIES: usize = 50;
+
+/// Peers heard of through PEX that are kept until taken.
+const MAX_DISCOVERED: usize = 1000;
+
+bitflags! {
+    /// What we know about an added peer, sent in `added.f`.
+    pub struct PexFlags: u8 {
+        const ENCRYPTION = 0x01;
+        const SEED = 0x02;
+        const UTP = 0x04;
+        const HOLEPUNCH = 0x08;
+
+        /// We connected to the peer, so it accepts connections.
+        const REACHABLE = 0x10;
+    }
+}
+
+/// The peers connected and disconnected since the previous message.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    /// Parses a message. Malformed entries are skipped and each list is
+    /// cut short after `MAX_PEER_ENTRIES`.
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let d = ValueRef::decode_with_limits(payload, Some(2), Some(20))?;
+        if !d.is_dict() {
+            return Err(Error::InvalidMessage);
+        }
+        let bytes = |key| {
+            d.dict_find(key)
+                .and_then(ValueRef::as_bytes)
+                .unwrap_or_default()
+        };
+
+        let mut msg = Self::default();
+        for (peers, flags, len) in &[
+            (bytes("added"), bytes("added.f"), 6),
+            (bytes("added6"), bytes("added6.f"), 18),
+        ] {
+            let added = read_compact_peers(peers, *len)
+                .enumerate()
+                .map(|(i, addr)| {
+                    let f = flags.get(i).copied().unwrap_or_default();
+                    (addr, PexFlags::from_bits_truncate(f))
+                });
+            msg.added.extend(added.take(MAX_PEER_ENTRIES));
+        }
+        for (peers, len) in &[(bytes("dropped"), 6), (bytes("dropped6"), 18)] {
+            let dropped = read_compact_peers(peers, *len);
+            msg.dropped.extend(dropped.take(MAX_PEER_ENTRIES));
+        }
+        Ok(msg)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut added = vec![];
+        let mut added_f = vec![];
+        let mut added6 = vec![];
+        let mut added6_f = vec![];
+        for (addr, flags) in &self.added {
+            let (w, f) = if addr.is_ipv4() {
+                (&mut added, &mut added_f)
+            } else {
+                (&mut added6, &mut added6_f)
+            };
+            detail::write_socket_addr(w, addr).unwrap();
+            f.push(flags.bits());
+        }
+        let mut dropped = vec![];
+        let mut dropped6 = vec![];
+        for addr in &self.dropped {
+            let w = if addr.is_ipv4() {
+                &mut dropped
+            } else {
+                &mut dropped6
+            };
+            detail::write_socket_addr(w, addr).unwrap();
+        }
+
+        let mut d = BTreeMap::new();
+        let mut put = |key: &str, v| {
+            d.insert(key.to_string(), Value::Bytes(v));
+        };
+        put("added", added);
+        put("added.f", added_f);
+        put("added6", added6);
+        put("added6.f", added6_f);
+        put("dropped", dropped);
+        put("dropped6", dropped6);
+        Value::with_dict(d).to_vec()
+    }
+}
+
+/// Compact peers (BEP 23), `len` bytes each. A trailing partial entry is
+/// ignored.
+fn read_compact_peers(buf: &[u8], len: usize) -> impl Iterator<Item = SocketAddr> + '_ {
+    buf.chunks_exact(len).filter_map(move |mut c| {
+        if len == 6 {
+            detail::read_v4_socket_address(&mut c).ok()
+        } else {
+            detail::read_v6_socket_address(&mut c).ok()
+        }
+    })
+}
+
+/// The peers a torrent is connected to, by the address they accept
+/// connections on, and the peers it heard of through peer exchange.
+/// Shared by the torrent and its connections.
+#[derive(Debug, Default)]
+pub struct Swarm {
+    connected: BTreeMap<SocketAddr, PexFlags>,
+    discovered: Vec<(SocketAddr, PexFlags)>,
+
+    /// Set for private torrents, which must not exchange peers.
+    pex_disabled: bool,
+}
+
+impl Swarm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a connected peer or updates its flags.
+    pub fn peer_connected(&mut self, addr: SocketAddr, flags: PexFlags) {
+        self.connected.insert(addr, flags);
+        self.discovered.retain(|(a, _)| *a != addr);
+    }
+
+    pub fn peer_disconnected(&mut self, addr: SocketAddr) {
+        self.connected.remove(&addr);
+    }
+
+    pub fn is_connected(&self, addr: SocketAddr) -> bool {
+        self.connected.contains_key(&addr)
+    }
+
+    /// Flags of a connected peer.
+    pub fn peer_flags(&self, addr: SocketAddr) -> Option<PexFlags> {
+        self.connected.get(&addr).copied()
+    }
+
+    pub fn num_connected(&self) -> usize {
+        self.connected.len()
+    }
+
+    /// Peers heard of since the last call.
+    pub fn take_discovered(&mut self) -> Vec<(SocketAddr, PexFlags)> {
+        std::mem::take(&mut self.discovered)
+    }
+
+    /// Stops peer exchange on all connections.
+    pub fn disable_pex(&mut self) {
+        self.pex_disabled = true;
+        self.discovered.clear();
+    }
+
+    pub fn is_pex_disabled(&self) -> bool {
+        self.pex_disabled
+    }
+
+    /// Adds a peer learned of through peer exchange or another source,
+    /// unless we're connected to it already.
+    pub fn add_discovered(&mut self, addr: SocketAddr, flags: PexFlags) {
+        if self.connected.contains_key(&addr)
+            || self.discovered.len() >= MAX_DISCOVERED
+            || self.discovered.iter().any(|(a, _)| *a == addr)
+        {
+            return;
+        }
+        self.discovered.push((addr, flags));
+    }
+}
+
+/// Registers ut_pex with the connections of a torrent.
+pub struct UtPex {
+    swarm: Arc<Mutex<Swarm>>,
+}
+
+impl UtPex {
+    pub fn new(swarm: Arc<Mutex<Swarm>>) -> Self {
+        Self { swarm }
+    }
+}
+
+impl TorrentExtension for UtPex {
+    fn new_connection(&mut self, addr: SocketAddr) -> Option<Box<dyn PeerExtension>> {
+        if self.swarm.lock().unwrap().is_pex_disabled() {
+            return None;
+        }
+        Some(Box::new(PeerPex {
+            swarm: self.swarm.clone(),
+            addr,
+            listen_addr: None,
+            sent: BTreeSet::new(),
+            last_sent: None,
+            last_received: None,
+        }))
+    }
+}
+
+struct PeerPex {
+    swarm: Arc<Mutex<Swarm>>,
+    addr: SocketAddr,
+
+    /// Where the peer accepts connections, from its handshake.
+    listen_addr: Option<SocketAddr>,
+
+    /// The connected peers as we last told them to the peer.
+    sent: BTreeSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PeerPex {
+    /// Whether `addr` is this peer itself. It's neither told about itself
+    /// nor trusted to tell us about itself.
+    fn is_peer(&self, addr: SocketAddr) -> bool {
+        addr == self.addr || Some(addr) == self.listen_addr
+    }
+
+    /// Changes to the connected peers since the last message to the peer.
+    fn diff(&mut self) -> PexMessage {
+        let swarm = self.swarm.lock().unwrap();
+        let mut msg = PexMessage::default();
+        if swarm.is_pex_disabled() {
+            return msg;
+        }
+        for (&addr, &flags) in &swarm.connected {
+            if msg.added.len() == MAX_PEER_ENTRIES {
+                break;
+            }
+            if !self.is_peer(addr) && !self.sent.contains(&addr) {
+                msg.added.push((addr, flags));
+            }
+        }
+        for &addr in &self.sent {
+            if msg.dropped.len() == MAX_PEER_ENTRIES {
+                break;
+            }
+            if !swarm.connected.contains_key(&addr) {
+                msg.dropped.push(addr);
+            }
+        }
+        drop(swarm);
+
+        for (addr, _) in &msg.added {
+            self.sent.insert(*addr);
+        }
+        for addr in &msg.dropped {
+            self.sent.remove(addr);
+        }
+        msg
+    }
+}
+
+impl PeerExtension for PeerPex {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> bool {
+        self.listen_addr = handshake.p.map(|p| SocketAddr::new(self.addr.ip(), p));
+        true
+    }
+
+    fn on_message(&mut self, payload: &[u8], _out: &mut Vec<Vec<u8>>) -> Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.last_received {
+            if now - last < MIN_RECEIVE_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.last_received = Some(now);
+
+        let msg = PexMessage::decode(payload)?;
+        let mut swarm = self.swarm.lock().unwrap();
+        if swarm.is_pex_disabled() {
+            return Ok(());
+        }
+        for (addr, flags) in msg.added {
+            if !self.is_peer(addr) && addr.port() != 0 {
+                swarm.add_discovered(addr, flags);
+            }
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, out: &mut Vec<Vec<u8>>) -> Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.last_sent {
+            if now - last < PEX_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.last_sent = Some(now);
+        let msg = self.diff();
+        if !msg.is_empty() {
+            out.push(msg.encode());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn connection(swarm: &Arc<Mutex<Swarm>>, peer: &str) -> PeerPex {
+        PeerPex {
+            swarm: swarm.clone(),
+            addr: addr(peer),
+            listen_addr: None,
+            sent: BTreeSet::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+
+    fn tick(p: &mut PeerPex) -> Option<PexMessage> {
+        let mut out = vec![];
+        p.tick(&mut out).unwrap();
+        assert!(out.len() <= 1);
+        out.pop().map(|m| PexMessage::decode(&m).unwrap())
+    }
+
+    fn long_ago() -> Option<Instant> {
+        Instant::now().checked_sub(PEX_INTERVAL)
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let msg = PexMessage {
+            added: vec![
+                (addr("1.2.3.4:80"), PexFlags::SEED | PexFlags::REACHABLE),
+                (addr("[::1]:81"), PexFlags::UTP),
+            ],
+            dropped: vec![addr("5.6.7.8:82")],
+        };
+        let buf = msg.encode();
+        assert_eq!(
+            &b"d5:added6:\x01\x02\x03\x04\x00\x507:added.f1:\x12"[..],
+            &buf[..28]
+        );
+        assert_eq!(msg, PexMessage::decode(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        assert!(PexMessage::decode(b"le").is_err());
+        assert!(PexMessage::decode(b"d5:added").is_err());
+
+        // Partial entries are dropped, missing flags are empty
+        let msg = PexMessage::decode(b"d5:added8:\x01\x02\x03\x04\x00\x50\x01\x02e").unwrap();
+        assert_eq!(vec![(addr("1.2.3.4:80"), PexFlags::empty())], msg.added);
+
+        let mut added = vec![];
+        for i in 0..60u8 {
+            added.extend_from_slice(&[10, 0, 0, i, 0, 80]);
+        }
+        let mut d = BTreeMap::new();
+        d.insert("added".to_string(), Value::Bytes(added));
+        let msg = PexMessage::decode(&Value::with_dict(d).to_vec()).unwrap();
+        assert_eq!(MAX_PEER_ENTRIES, msg.added.len());
+    }
+
+    #[test]
+    fn test_diff() {
+        let swarm = Arc::new(Mutex::new(Swarm::new()));
+        let mut p = connection(&swarm, "10.0.0.1:50000");
+        assert!(p.on_handshake(&ExtendedHandshake {
+            p: Some(6881),
+            ..ExtendedHandshake::default()
+        }));
+        {
+            let mut s = swarm.lock().unwrap();
+            s.peer_connected(addr("10.0.0.1:6881"), PexFlags::empty());
+            s.peer_connected(addr("10.0.0.2:6881"), PexFlags::SEED);
+            s.peer_connected(addr("[::2]:6881"), PexFlags::REACHABLE);
+        }
+
+        // The peer isn't told about itself
+        let msg = tick(&mut p).unwrap();
+        assert_eq!(
+            vec![
+                (addr("10.0.0.2:6881"), PexFlags::SEED),
+                (addr("[::2]:6881"), PexFlags::REACHABLE),
+            ],
+            msg.added
+        );
+        assert!(msg.dropped.is_empty());
+
+        swarm
+            .lock()
+            .unwrap()
+            .peer_connected(addr("10.0.0.3:6881"), PexFlags::empty());
+        swarm.lock().unwrap().peer_disconnected(addr("[::2]:6881"));
+        assert_eq!(None, tick(&mut p));
+
+        p.last_sent = long_ago();
+        let msg = tick(&mut p).unwrap();
+        assert_eq!(vec![(addr("10.0.0.3:6881"), PexFlags::empty())], msg.added);
+        assert_eq!(vec![addr("[::2]:6881")], msg.dropped);
+
+        // Nothing changed
+        p.last_sent = long_ago();
+        assert_eq!(None, tick(&mut p));
+    }
+
+    #[test]
+    fn test_diff_limit() {
+        let swarm = Arc::new(Mutex::new(Swarm::new()));
+        for i in 0..60 {
+            let a = SocketAddr::new([10, 0, 1, i].into(), 6881);
+            swarm.lock().unwrap().peer_connected(a, PexFlags::empty());
+        }
+        let mut p = connection(&swarm, "10.0.0.1:6881");
+        assert_eq!(MAX_PEER_ENTRIES, tick(&mut p).unwrap().added.len());
+        p.last_sent = long_ago();
+        assert_eq!(10, tick(&mut p).unwrap().added.len());
+    }
+
+    #[test]
+    fn test_receive() {
+        let swarm = Arc::new(Mutex::new(Swarm::new()));
+        swarm
+            .lock()
+            .unwrap()
+            .peer_connected(addr("10.0.0.2:6881"), PexFlags::empty());
+        let mut p = connection(&swarm, "10.0.0.1:6881");
+
+        let msg = PexMessage {
+            added: vec![
+                (addr("10.0.0.1:6881"), PexFlags::empty()),
+                (addr("10.0.0.2:6881"), PexFlags::empty()),
+                (addr("10.0.0.3:6881"), PexFlags::SEED),
+                (addr("10.0.0.4:0"), PexFlags::empty()),
+            ],
+            dropped: vec![],
+        };
+        let mut out = vec![];
+        p.on_message(&msg.encode(), &mut out).unwrap();
+        assert!(out.is_empty());
+
+        // Only peers we aren't connected to and that aren't the sender
+        let discovered = swarm.lock().unwrap().take_discovered();
+        assert_eq!(vec![(addr("10.0.0.3:6881"), PexFlags::SEED)], discovered);
+
+        // Too soon after the last one
+        let msg = PexMessage {
+            added: vec![(addr("10.0.0.5:6881"), PexFlags::empty())],
+            dropped: vec![],
+        };
+        p.on_message(&msg.encode(), &mut out).unwrap();
+        assert!(swarm.lock().unwrap().take_discovered().is_empty());
+
+        p.last_received = Instant::now().checked_sub(MIN_RECEIVE_INTERVAL);
+        p.on_message(&msg.encode(), &mut out).unwrap();
+        assert_eq!(1, swarm.lock().unwrap().take_discovered().len());
+
+        swarm.lock().unwrap().disable_pex();
+        p.last_received = Instant::now().checked_sub(MIN_RECEIVE_INTERVAL);
+        p.on_message(&msg.encode(), &mut out).unwrap();
+        assert!(swarm.lock().unwrap().take_discovered().is_empty());
+        assert_eq!(None, tick(&mut p));
+        assert!(UtPex::new(swarm)
+            .new_connection(addr("10.0.0.1:6881"))
+            .is_none());
+    }
+}
@@ -0,0 +1,780 @@
+//! Downloading from web seeds. URL seeds (BEP 19) are HTTP servers that
+//! have the torrent's files laid out the way they would be on disk, blocks
+//! are requested with `Range` requests against the files they map to. HTTP
+//! seeds (BEP 17) run a script that serves ranges of a piece by info-hash.
+//! Either kind takes part in the piece picker like a peer that has every
+//! piece it can serve.
+
+use crate::active_torrent::ActiveTorrent;
+use crate::bitfield::Bitfield;
+use crate::error::{Error, Result};
+use crate::fs::FileStorage;
+use crate::http::{self, HttpSettings};
+use crate::piece_picker::{PieceBlock, BLOCK_SIZE};
+use crate::status::State;
+use crate::str_utl;
+use common::sha1::Sha1Hash;
+use defaults::Defaults;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// A busy HTTP seed asking to wait longer is asked again after this.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Defaults)]
+pub struct WebSeedSettings {
+    #[def = "HttpSettings { max_body_len: 256 * 1024, ..HttpSettings::default() }"]
+    pub http: HttpSettings,
+
+    /// Bytes requested at once at most, rounded down to whole blocks.
+    #[def = "256 * 1024"]
+    pub max_request_len: usize,
+
+    /// Time to wait after a failed request before trying again.
+    #[def = "Duration::from_secs(30)"]
+    pub retry_delay: Duration,
+
+    /// The seed is banned after this many failed requests in a row.
+    #[def = "3"]
+    pub max_failures: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedType {
+    UrlSeed,
+    HttpSeed,
+}
+
+pub struct WebSeed {
+    url: String,
+    kind: WebSeedType,
+
+    /// Stands in for the seed's address in the piece picker.
+    addr: SocketAddr,
+    settings: WebSeedSettings,
+
+    /// Pieces the seed has, counted in the torrent's picker. Empty until
+    /// the torrent has metadata.
+    have: Bitfield,
+
+    /// Files the server doesn't have, their pieces are left out of `have`.
+    missing_files: BTreeSet<usize>,
+
+    /// Where requests for a file ended up after redirects.
+    redirects: BTreeMap<usize, Url>,
+
+    /// Failed requests since the last one that worked.
+    fails: u32,
+    retry_at: Option<Instant>,
+    banned: bool,
+}
+
+impl WebSeed {
+    pub fn new(url: String, kind: WebSeedType, settings: WebSeedSettings) -> Result<Self> {
+        if !matches!(Url::parse(&url)?.scheme(), "http" | "https") {
+            return Err(Error::UnsupportedUrlProtocol);
+        }
+        Ok(Self {
+            addr: pseudo_addr(&url),
+            url,
+            kind,
+            settings,
+            have: Bitfield::new(0),
+            missing_files: BTreeSet::new(),
+            redirects: BTreeMap::new(),
+            fails: 0,
+            retry_at: None,
+            banned: false,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn kind(&self) -> WebSeedType {
+        self.kind
+    }
+
+    /// The address blocks requested from this seed are recorded under in
+    /// the piece picker.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Whether the seed failed too often and was removed from the torrent.
+    pub fn is_banned(&self) -> bool {
+        self.banned
+    }
+
+    pub fn missing_files(&self) -> &BTreeSet<usize> {
+        &self.missing_files
+    }
+
+    /// Requests the next run of blocks the picker hands out and writes
+    /// them to storage. Failed requests are retried after a delay.
+    pub fn tick(&mut self, torrent: &mut ActiveTorrent) -> Result<()> {
+        match self.request(torrent) {
+            Some(mut req) => {
+                self.fetch(&mut req);
+                self.finish(torrent, req)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Picks the next run of blocks to fetch and marks them requested,
+    /// unless the seed is waiting to retry or there's nothing to download.
+    /// The blocks are fetched with `fetch` and written with `finish`.
+    pub fn request(&mut self, torrent: &mut ActiveTorrent) -> Option<WebSeedRequest> {
+        if self.banned || !torrent.has_metadata() {
+            return None;
+        }
+        if self.have.is_empty() {
+            self.have = Bitfield::with_all_set(torrent.info().num_pieces());
+            torrent.picker().inc_refcount_bitfield(&self.have);
+        }
+        let now = Instant::now();
+        if self.retry_at.is_some_and(|t| t > now) || torrent.state() != State::Downloading {
+            return None;
+        }
+
+        let blocks = self.pick_blocks(torrent);
+        if blocks.is_empty() {
+            return None;
+        }
+        for &block in &blocks {
+            torrent.picker().mark_as_requested(block, self.addr);
+        }
+
+        let first = torrent.picker().block_request(blocks[0]);
+        let len = blocks
+            .iter()
+            .map(|&b| torrent.picker().block_request(b).length)
+            .sum();
+        Some(WebSeedRequest {
+            blocks,
+            offset: first.start,
+            len,
+            files: torrent.info().files().clone(),
+            info_hash: torrent.info_hash().clone(),
+            result: None,
+        })
+    }
+
+    /// Downloads the blocks of a request. Doesn't need the torrent, so it
+    /// may run on another thread.
+    pub fn fetch(&mut self, req: &mut WebSeedRequest) {
+        let piece = req.blocks[0].piece;
+        req.result = Some(match self.kind {
+            WebSeedType::UrlSeed => self.fetch_range(&req.files, piece, req.offset, req.len),
+            WebSeedType::HttpSeed => self.fetch_piece(&req.info_hash, piece, req.offset, req.len),
+        });
+    }
+
+    /// Writes the fetched blocks to storage, or gives them back to the
+    /// picker if the request failed.
+    pub fn finish(&mut self, torrent: &mut ActiveTorrent, req: WebSeedRequest) -> Result<()> {
+        let fetched = match req.result {
+            Some(Ok(data)) if torrent.state() == State::Downloading => Ok(data),
+            Some(Err(e)) => Err(e),
+            // Not fetched, or the torrent stopped downloading meanwhile
+            _ => {
+                for &block in &req.blocks {
+                    torrent.picker().abort_download(block, self.addr);
+                }
+                return Ok(());
+            }
+        };
+        let now = Instant::now();
+        match fetched {
+            Ok(data) => {
+                self.fails = 0;
+                let piece_len = req.files.piece_len() as u64;
+                let start = req.blocks[0].piece as u64 * piece_len + req.offset as u64;
+                self.write_blocks(torrent, &req.blocks, start, &data)
+            }
+            Err(e) => {
+                for &block in &req.blocks {
+                    torrent.picker().abort_download(block, self.addr);
+                }
+                match e {
+                    FetchError::MissingFile(file) => {
+                        self.set_missing(torrent, &req.files, file);
+                        Ok(())
+                    }
+                    FetchError::RetryAfter(delay) => {
+                        self.retry_at = Some(now.checked_add(delay).unwrap_or(now));
+                        Ok(())
+                    }
+                    FetchError::Failed => {
+                        self.on_failure(torrent, now);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes the seed out of the picker, e.g. when it's dropped.
+    pub fn disconnect(&mut self, torrent: &mut ActiveTorrent) {
+        torrent.picker().abort_peer(self.addr);
+        torrent.picker().dec_refcount_bitfield(&self.have);
+        self.have = Bitfield::new(0);
+    }
+
+    /// Blocks to request next, contiguous in the torrent so they can be
+    /// fetched with one range request per file. An HTTP seed request
+    /// covers a single piece.
+    fn pick_blocks(&self, torrent: &mut ActiveTorrent) -> Vec<PieceBlock> {
+        let max = (self.settings.max_request_len / BLOCK_SIZE).max(1);
+        let picker = torrent.picker();
+        let picked = picker.pick_pieces(&self.have, max, self.addr);
+        let mut blocks: Vec<PieceBlock> = vec![];
+        for block in picked {
+            if let Some(last) = blocks.last() {
+                let next = if last.block + 1 < picker.blocks_in_piece(last.piece) {
+                    PieceBlock::new(last.piece, last.block + 1)
+                } else {
+                    PieceBlock::new(last.piece + 1, 0)
+                };
+                if block != next
+                    || (self.kind == WebSeedType::HttpSeed && block.piece != last.piece)
+                {
+                    break;
+                }
+            }
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// Downloads `len` bytes starting at `offset` within `piece`, with a
+    /// request per file the range spans.
+    fn fetch_range(
+        &mut self,
+        files: &FileStorage,
+        piece: usize,
+        offset: usize,
+        len: usize,
+    ) -> std::result::Result<Vec<u8>, FetchError> {
+        let mut data = Vec::with_capacity(len);
+        let start = piece as u64 * files.piece_len() as u64 + offset as u64;
+        for slice in files.map_range(start, len as u64) {
+            let file = files.file_at(slice.file_index);
+            if file.pad_file {
+                data.resize(data.len() + slice.size as usize, 0);
+                continue;
+            }
+            let url = match self.redirects.get(&slice.file_index) {
+                Some(url) => url.clone(),
+                None => {
+                    file_url(&self.url, files, slice.file_index).map_err(|_| FetchError::Failed)?
+                }
+            };
+            let range = format!("bytes={}-{}", slice.offset, slice.offset + slice.size - 1);
+            let (final_url, response) = http::get(&url, &[("Range", &range)], &self.settings.http)
+                .map_err(|_| FetchError::Failed)?;
+            if final_url != url {
+                self.redirects.insert(slice.file_index, final_url);
+            }
+
+            let size = slice.size as usize;
+            match response.status {
+                206 if response.body.len() == size => data.extend_from_slice(&response.body),
+                // The server ignored the range and sent the whole file
+                200 if response.body.len() as u64 == file.size => {
+                    let start = slice.offset as usize;
+                    data.extend_from_slice(&response.body[start..start + size]);
+                }
+                404 | 410 => return Err(FetchError::MissingFile(slice.file_index)),
+                _ => return Err(FetchError::Failed),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Downloads `len` bytes starting at `offset` within `piece` from an
+    /// HTTP seed. A busy seed answers 503 with the seconds to wait before
+    /// asking again.
+    fn fetch_piece(
+        &mut self,
+        info_hash: &Sha1Hash,
+        piece: usize,
+        offset: usize,
+        len: usize,
+    ) -> std::result::Result<Vec<u8>, FetchError> {
+        let url = Url::parse(&http_seed_url(&self.url, info_hash, piece, offset, len))
+            .map_err(|_| FetchError::Failed)?;
+        let (_, response) =
+            http::get(&url, &[], &self.settings.http).map_err(|_| FetchError::Failed)?;
+        match response.status {
+            200 if response.body.len() == len => Ok(response.body),
+            503 => {
+                let secs = std::str::from_utf8(&response.body)
+                    .ok()
+                    .and_then(|s| s.trim().parse().ok());
+                match secs {
+                    Some(secs) => Err(FetchError::RetryAfter(
+                        Duration::from_secs(secs).min(MAX_RETRY_AFTER),
+                    )),
+                    None => Err(FetchError::Failed),
+                }
+            }
+            _ => Err(FetchError::Failed),
+        }
+    }
+
+    fn write_blocks(
+        &mut self,
+        torrent: &mut ActiveTorrent,
+        blocks: &[PieceBlock],
+        start: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let piece_len = torrent.info().piece_len() as u64;
+        torrent.add_downloaded(data.len());
+        for &block in blocks {
+            let r = torrent.picker().block_request(block);
+            let pos = (r.piece as u64 * piece_len + r.start as u64 - start) as usize;
+            torrent
+                .storage()
+                .write(&data[pos..pos + r.length], r.piece, r.start)?;
+            if torrent.finish_block(block, self.addr) && !torrent.verify_piece(block.piece)? {
+                // The seed serves data that doesn't match the torrent
+                self.ban(torrent);
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops asking for the pieces of a file the server doesn't have.
+    fn set_missing(&mut self, torrent: &mut ActiveTorrent, files: &FileStorage, file: usize) {
+        self.missing_files.insert(file);
+        for piece in files.file_piece_range(file) {
+            if self.have.get(piece) {
+                self.have.clear(piece);
+                torrent.picker().dec_refcount(piece);
+            }
+        }
+        if self.have.none_set() {
+            self.ban(torrent);
+        }
+    }
+
+    fn on_failure(&mut self, torrent: &mut ActiveTorrent, now: Instant) {
+        self.fails += 1;
+        if self.fails >= self.settings.max_failures {
+            self.ban(torrent);
+        } else {
+            self.retry_at = Some(now + self.settings.retry_delay);
+        }
+    }
+
+    fn ban(&mut self, torrent: &mut ActiveTorrent) {
+        self.banned = true;
+        self.disconnect(torrent);
+        match self.kind {
+            WebSeedType::UrlSeed => torrent.remove_web_seed(&self.url),
+            WebSeedType::HttpSeed => torrent.remove_http_seed(&self.url),
+        }
+    }
+}
+
+/// A run of contiguous blocks requested from a web seed, and what came
+/// of fetching them.
+pub struct WebSeedRequest {
+    blocks: Vec<PieceBlock>,
+
+    /// Where the first block starts within its piece.
+    offset: usize,
+    len: usize,
+    files: FileStorage,
+    info_hash: Sha1Hash,
+    result: Option<std::result::Result<Vec<u8>, FetchError>>,
+}
+
+enum FetchError {
+    /// The server doesn't have the file, by index.
+    MissingFile(usize),
+
+    /// The server is busy, ask again after the given time.
+    RetryAfter(Duration),
+    Failed,
+}
+
+/// The request for a range of a piece from an HTTP seed. The end of the
+/// range is inclusive.
+pub fn http_seed_url(
+    base: &str,
+    info_hash: &Sha1Hash,
+    piece: usize,
+    offset: usize,
+    len: usize,
+) -> String {
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!(
+        "{}{}info_hash={}&piece={}&ranges={}-{}",
+        base,
+        separator,
+        str_utl::escape_bytes(&info_hash[..]),
+        piece,
+        offset,
+        offset + len - 1
+    )
+}
+
+/// The URL of a file on a web seed. A URL ending in a slash is the
+/// directory the torrent's files are in, otherwise it points straight at
+/// the file of a single-file torrent.
+pub fn file_url(base: &str, files: &FileStorage, file: usize) -> Result<Url> {
+    let path = &files.file_at(file).path;
+    let multi_file = path.components().count() > 1;
+    let mut url = base.to_string();
+    if !url.ends_with('/') {
+        if !multi_file {
+            return Ok(Url::parse(&url)?);
+        }
+        url.push('/');
+    }
+    let components: Vec<_> = path
+        .iter()
+        .map(|c| str_utl::escape_string(&c.to_string_lossy()))
+        .collect();
+    url.push_str(&components.join("/"));
+    Ok(Url::parse(&url)?)
+}
+
+/// An address in the discard-only prefix 100::/64 (RFC 6666) derived from
+/// the URL, so it never collides with a real peer.
+fn pseudo_addr(url: &str) -> SocketAddr {
+    let hash = Sha1Hash::update(url.as_bytes());
+    let mut bytes = [0; 16];
+    bytes[0] = 1;
+    bytes[8..].copy_from_slice(&hash[..8]);
+    SocketAddr::from((Ipv6Addr::from(bytes), 0))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::test_util::{content, memory_torrent, temp_dir, torrent_info_with};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const PIECE_LEN: usize = 32 * 1024;
+    const CONTENT_LEN: usize = PIECE_LEN * 3 + 1000;
+
+    /// A torrent over `content(CONTENT_LEN)` kept in memory, as a single
+    /// file or split in two files `name/a` and `name/b`.
+    fn torrent(name: &str, multi_file: bool) -> ActiveTorrent {
+        torrent_with_http_seeds(name, multi_file, &[])
+    }
+
+    fn torrent_with_http_seeds(name: &str, multi_file: bool, http_seeds: &[&str]) -> ActiveTorrent {
+        let data = content(CONTENT_LEN);
+        let layout: Vec<(&str, &[u8])> = if multi_file {
+            vec![("name/a", &data[..40_000]), ("name/b", &data[40_000..])]
+        } else {
+            vec![("name", &data[..])]
+        };
+        let dir = temp_dir(name);
+        let info = torrent_info_with(&dir, PIECE_LEN, &layout, |ct| {
+            for url in http_seeds {
+                ct.add_http_seed(url);
+            }
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        memory_torrent(&info)
+    }
+
+    fn settings() -> WebSeedSettings {
+        WebSeedSettings {
+            max_request_len: PIECE_LEN,
+            retry_delay: Duration::from_secs(0),
+            ..WebSeedSettings::default()
+        }
+    }
+
+    /// Path and range of each request.
+    pub(crate) type RequestLog = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Serves files by path with support for single byte ranges, and
+    /// redirects from one path to another. Returns the request lines and
+    /// range headers of the requests it got.
+    pub(crate) fn serve_files(
+        files: Vec<(&str, Vec<u8>)>,
+        redirects: Vec<(&str, &str)>,
+    ) -> (SocketAddr, RequestLog) {
+        let files: BTreeMap<String, Vec<u8>> =
+            files.into_iter().map(|(p, d)| (p.to_string(), d)).collect();
+        let redirects: BTreeMap<String, String> = redirects
+            .into_iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = vec![];
+                let mut chunk = [0; 1024];
+                while !buf.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let head = String::from_utf8(buf).unwrap();
+                let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                let range = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Range: bytes="))
+                    .unwrap_or_default()
+                    .to_string();
+                log.lock().unwrap().push((path.clone(), range.clone()));
+
+                let response = if let Some(to) = redirects.get(&path) {
+                    http::test::response(302, &[("Location", to)], b"")
+                } else if let Some(data) = files.get(&path) {
+                    let mut bounds = range.split('-').map(|n| n.parse::<usize>().unwrap());
+                    let start = bounds.next().unwrap();
+                    let end = bounds.next().unwrap();
+                    http::test::response(206, &[], &data[start..=end])
+                } else {
+                    http::test::response(404, &[], b"")
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+        (addr, requests)
+    }
+
+    fn run(seed: &mut WebSeed, t: &mut ActiveTorrent) {
+        for _ in 0..100 {
+            if t.state() == State::Seeding || seed.is_banned() {
+                return;
+            }
+            seed.tick(t).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_file_url() {
+        let t = torrent("ws-url-single", false);
+        let files = t.info().files();
+        let url = |base: &str| file_url(base, files, 0).unwrap().to_string();
+        assert_eq!("http://host/x/file", url("http://host/x/file"));
+        assert_eq!("http://host/x/name", url("http://host/x/"));
+
+        let t = torrent("ws-url-multi", true);
+        let files = t.info().files();
+        let url = |base: &str| file_url(base, files, 1).unwrap().to_string();
+        assert_eq!("http://host/x/name/b", url("http://host/x/"));
+        assert_eq!("http://host/x/name/b", url("http://host/x"));
+    }
+
+    #[test]
+    fn test_single_file() {
+        let (addr, requests) = serve_files(vec![("/seed/file", content(CONTENT_LEN))], vec![]);
+        let mut t = torrent("ws-single", false);
+        let mut seed = WebSeed::new(
+            format!("http://{}/seed/file", addr),
+            WebSeedType::UrlSeed,
+            settings(),
+        )
+        .unwrap();
+        run(&mut seed, &mut t);
+        assert_eq!(State::Seeding, t.state());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(4, requests.len());
+        assert_eq!(
+            ("/seed/file".to_string(), "0-32767".to_string()),
+            requests[0]
+        );
+        assert_eq!("98304-99303", requests[3].1);
+    }
+
+    #[test]
+    fn test_multi_file() {
+        let data = content(CONTENT_LEN);
+        let (addr, requests) = serve_files(
+            vec![
+                ("/seed/name/a", data[..40_000].to_vec()),
+                ("/seed/name/b", data[40_000..].to_vec()),
+            ],
+            vec![],
+        );
+        let mut t = torrent("ws-multi", true);
+        let mut seed = WebSeed::new(
+            format!("http://{}/seed/", addr),
+            WebSeedType::UrlSeed,
+            settings(),
+        )
+        .unwrap();
+        run(&mut seed, &mut t);
+        assert_eq!(State::Seeding, t.state());
+
+        // The second piece spans both files
+        let requests = requests.lock().unwrap();
+        assert!(requests.contains(&("/seed/name/a".to_string(), "32768-39999".to_string())));
+        assert!(requests.contains(&("/seed/name/b".to_string(), "0-25535".to_string())));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let (addr, requests) = serve_files(
+            vec![("/new/file", content(CONTENT_LEN))],
+            vec![("/old/file", "/new/file")],
+        );
+        let mut t = torrent("ws-redirect", false);
+        let mut seed = WebSeed::new(
+            format!("http://{}/old/file", addr),
+            WebSeedType::UrlSeed,
+            settings(),
+        )
+        .unwrap();
+        run(&mut seed, &mut t);
+        assert_eq!(State::Seeding, t.state());
+
+        // Only the first request is redirected
+        let requests = requests.lock().unwrap();
+        let paths: Vec<_> = requests.iter().map(|(p, _)| &p[..]).collect();
+        assert_eq!(
+            vec![
+                "/old/file",
+                "/new/file",
+                "/new/file",
+                "/new/file",
+                "/new/file"
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let data = content(CONTENT_LEN);
+        let (addr, _) = serve_files(vec![("/name/a", data[..40_000].to_vec())], vec![]);
+        let mut t = torrent("ws-missing", true);
+        let url = format!("http://{}/", addr);
+        t.add_web_seed(url.clone());
+        let mut seed = WebSeed::new(url, WebSeedType::UrlSeed, settings()).unwrap();
+        run(&mut seed, &mut t);
+
+        // Only the first piece lies entirely within the file it has
+        assert!(!seed.is_banned());
+        assert_eq!(&BTreeSet::from([1]), seed.missing_files());
+        assert_eq!(State::Downloading, t.state());
+        assert!(t.have_pieces().get(0));
+        assert_eq!(1, t.have_pieces().count());
+        assert_eq!(1, t.picker().availability(0));
+        assert_eq!(0, t.picker().availability(1));
+        assert_eq!(1, t.web_seeds().len());
+    }
+
+    #[test]
+    fn test_ban() {
+        let (addr, requests) = serve_files(vec![], vec![]);
+        let mut t = torrent("ws-ban", false);
+        let url = format!("http://{}/broken/", addr);
+        t.add_web_seed(url.clone());
+        let mut seed = WebSeed::new(url, WebSeedType::UrlSeed, settings()).unwrap();
+        run(&mut seed, &mut t);
+
+        // A single-file torrent missing its only file is useless
+        assert!(seed.is_banned());
+        assert_eq!(1, requests.lock().unwrap().len());
+        assert!(t.web_seeds().is_empty());
+        assert_eq!(0, t.picker().availability(0));
+
+        // Requests failing for other reasons are retried first
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let mut seed = WebSeed::new(
+            format!("http://{}/file", addr),
+            WebSeedType::UrlSeed,
+            settings(),
+        )
+        .unwrap();
+        seed.tick(&mut t).unwrap();
+        assert!(!seed.is_banned());
+        assert_eq!(1, t.picker().availability(0));
+        run(&mut seed, &mut t);
+        assert!(seed.is_banned());
+        assert_eq!(0, t.picker().availability(0));
+
+        assert!(matches!(
+            WebSeed::new(
+                "ftp://host/file".to_string(),
+                WebSeedType::UrlSeed,
+                settings()
+            ),
+            Err(Error::UnsupportedUrlProtocol)
+        ));
+    }
+
+    #[test]
+    fn test_http_seed() {
+        let data = content(CONTENT_LEN);
+        let mut responses = vec![http::test::response(503, &[], b"0")];
+        for chunk in data.chunks(PIECE_LEN) {
+            responses.push(http::test::response(200, &[], chunk));
+        }
+        let (addr, server) = http::test::serve(responses);
+
+        let url = format!("http://{}/seed.php", addr);
+        let mut t = torrent_with_http_seeds("ws-http-seed", false, &[&url]);
+        assert_eq!(vec![url.clone()], t.http_seeds());
+        let mut seed = WebSeed::new(url, WebSeedType::HttpSeed, settings()).unwrap();
+        run(&mut seed, &mut t);
+        assert_eq!(State::Seeding, t.state());
+
+        let requests = server.join().unwrap();
+        let query = format!(
+            "GET /seed.php?info_hash={}&piece=0&ranges=0-32767 HTTP/1.1",
+            str_utl::escape_bytes(&t.info_hash()[..])
+        );
+        assert!(requests[0].starts_with(&query));
+        assert!(requests[1].starts_with(&query));
+        assert!(requests[4].contains("&piece=3&ranges=0-999 "));
+    }
+
+    #[test]
+    fn test_http_seed_busy() {
+        let (addr, server) = http::test::serve(vec![http::test::response(503, &[], b"3600")]);
+        let mut t = torrent("ws-http-seed-busy", false);
+        let url = format!("http://{}/seed?x=1", addr);
+        let mut seed = WebSeed::new(url, WebSeedType::HttpSeed, settings()).unwrap();
+
+        // The seed is asked again only once the time it gave is up
+        seed.tick(&mut t).unwrap();
+        seed.tick(&mut t).unwrap();
+        assert!(!seed.is_banned());
+        assert!(t.have_pieces().none_set());
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /seed?x=1&info_hash="));
+
+        // However long it asks to wait, it's asked again within the hour
+        let (addr, server) = http::test::serve(vec![http::test::response(
+            503,
+            &[],
+            b"18446744073709551615",
+        )]);
+        let url = format!("http://{}/seed", addr);
+        let mut seed = WebSeed::new(url, WebSeedType::HttpSeed, settings()).unwrap();
+        seed.tick(&mut t).unwrap();
+        server.join().unwrap();
+        assert!(seed.retry_at.unwrap() <= Instant::now() + MAX_RETRY_AFTER);
+    }
+}